@@ -0,0 +1,69 @@
+use std::io::IsTerminal;
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use dialoguer::Confirm;
+use reqwest::{Method, Url};
+use serde_json::json;
+
+use crate::{request_url, transport::HttpClient, warn};
+
+// Ask if the files which failed to transfer should be given up on
+pub fn confirm_abort_files(abort_failed_files: bool, failed: usize) -> Result<bool> {
+    if abort_failed_files {
+        return Ok(true);
+    }
+
+    if !std::io::stdin().is_terminal() {
+        return Ok(false);
+    }
+
+    warn!(
+        "{failed} file(s) failed to transfer, the synchronization can be finalized without them."
+    );
+
+    Confirm::new()
+        .with_prompt("Finalize without these files?".bright_blue().to_string())
+        .interact()
+        .context("Failed to ask for confirmation")
+}
+
+// Remove files from the open synchronization so it can be finalized without them
+// Returns the number of aborted files
+pub async fn abort_files<'a>(
+    client: &HttpClient,
+    base_url: &Url,
+    access_token: &str,
+    slot: &str,
+    sync_token: &str,
+    relative_paths: impl IntoIterator<Item = &'a String>,
+) -> Result<usize> {
+    let mut aborted_files = 0;
+
+    for relative_path in relative_paths {
+        let aborted = request_url::<Vec<String>>(
+            client,
+            Method::POST,
+            "/sync/abort-file",
+            base_url,
+            access_token,
+            |client| {
+                client.json(&json!({
+                    "slot_name": slot,
+                    "sync_token": sync_token,
+                    "path": relative_path
+                }))
+            },
+        )
+        .await
+        .with_context(|| format!("Failed to abort the transfer of file '{relative_path}'"))?;
+
+        aborted_files += 1;
+
+        for path in aborted {
+            warn!("File '{}' won't be synchronized.", path.bright_cyan());
+        }
+    }
+
+    Ok(aborted_files)
+}
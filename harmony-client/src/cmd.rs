@@ -1,24 +1,64 @@
-use std::path::PathBuf;
+use std::{
+    path::PathBuf,
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
-use clap::Parser;
+use anyhow::{bail, Context};
+use clap::{Parser, Subcommand};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
 
-#[derive(Parser)]
+use crate::throttle::ByteRate;
+
+#[derive(Clone, Parser)]
+#[clap(
+    subcommand_negates_reqs = true,
+    args_conflicts_with_subcommands = true,
+    after_help = "Transfers can be paused by sending SIGUSR1 to the client (e.g. 'kill -USR1 <pid>', Unix only): the ones in progress are completed, but no new one starts until SIGUSR1 is sent again.
+
+Exit codes:
+  0    Success
+  1    Unclassified failure
+  2    Some files failed to transfer
+  3    Server refused the secret password or device key
+  4    Server is unreachable or stopped answering (worth retrying later)
+  5    Synchronization was declined when asked for confirmation
+  6    Server refused a synchronization which would delete a large part of the slot
+  7    Local and remote contents differ (with '--verify')
+  8    Nothing to synchronize, including for dry runs (with '--detailed-exit-codes', 0 otherwise)
+  130  Interrupted with Ctrl-C"
+)]
 pub struct Args {
-    #[clap(help = "Directory to synchronize")]
-    pub source_dir: PathBuf,
+    // Only optional when using a subcommand
+    #[clap(required = true, help = "Directory to synchronize (or a single file)")]
+    pub source_dir: Option<PathBuf>,
 
-    #[clap(help = "Address of the server")]
-    pub address: String,
+    #[clap(required = true, help = "Address of the server")]
+    pub address: Option<String>,
 
-    #[clap(help = "Slot name to use")]
-    pub slot: String,
+    #[clap(required = true, help = "Slot name to use")]
+    pub slot: Option<String>,
 
-    #[clap(long, help = "Server's secret password")]
-    pub secret: String,
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    #[clap(
+        global = true,
+        long,
+        help = "Server's secret password (if not provided with '--export-snapshot', the local snapshot is only exported)"
+    )]
+    pub secret: Option<String>,
 
-    #[clap(long, help = "Device name")]
+    #[clap(global = true, long, help = "Device name")]
     pub device_name: Option<String>,
 
+    #[clap(
+        global = true,
+        long,
+        help = "Path to this device's private key, which is generated and enrolled with the secret password if it doesn't exist yet, then used to authenticate instead of the secret password"
+    )]
+    pub device_key: Option<PathBuf>,
+
     #[clap(flatten)]
     pub sync_args: SyncArgs,
 
@@ -29,22 +69,489 @@ pub struct Args {
     )]
     pub max_parallel_transfers: Option<usize>,
 
+    #[clap(
+        long,
+        help = "Always transfer modified files entirely instead of only their changed parts"
+    )]
+    pub no_delta: bool,
+
+    #[clap(
+        long,
+        help = "Protect uploaded data against corruption with a checksum on each chunk (requires server support)"
+    )]
+    pub crc_frames: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "encryption_passphrase",
+        help = "Hash the files to transfer first, and skip the ones the server already has (e.g. fully written by a previous failed run, or already uploaded with deduplication enabled on the server) (requires server support)"
+    )]
+    pub skip_present: bool,
+
+    #[clap(
+        long,
+        help = "Maximum upload rate across all transfers (e.g. '2MB/s', '500KiB/s'), unlimited by default"
+    )]
+    pub max_upload_rate: Option<ByteRate>,
+
+    #[clap(
+        long,
+        help = "Passphrase used to encrypt files before sending them (required for encrypted slots)"
+    )]
+    pub encryption_passphrase: Option<String>,
+
+    #[clap(
+        long,
+        help = "Wait for a random duration up to this one before starting (e.g. '30s', '10m'), to spread the load of scheduled synchronizations"
+    )]
+    pub jitter: Option<DurationArg>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "human",
+        help = "Format of the progress reporting: 'human' for progress bars, 'json' for newline-delimited JSON events (for wrappers)"
+    )]
+    pub progress_format: ProgressFormat,
+
+    #[clap(
+        long,
+        help = "File to write the JSON progress events to instead of STDERR (e.g. '/dev/fd/3')"
+    )]
+    pub progress_output: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "Exit with a dedicated code when there is nothing to synchronize instead of 0 (see the exit codes below)"
+    )]
+    pub detailed_exit_codes: bool,
+
+    #[clap(flatten)]
+    pub timeout_args: TimeoutArgs,
+
+    #[clap(flatten)]
+    pub tls_args: TlsArgs,
+
     #[clap(global = true, short, long, help = "Display debug messages")]
     pub verbose: bool,
 }
 
-#[derive(clap::Args)]
+// Operations which only talk to the server, without a directory to synchronize
+#[derive(Clone, Subcommand)]
+pub enum Command {
+    #[clap(
+        about = "Check that the server is reachable and that authentication succeeds, then list its slots (exits with a non-zero code on failure)"
+    )]
+    Check {
+        #[clap(help = "Address of the server")]
+        address: String,
+    },
+
+    #[clap(
+        about = "List a slot's content on the server, with the ignore rules applied (requires server support)"
+    )]
+    RemoteLs {
+        #[clap(help = "Address of the server")]
+        address: String,
+
+        #[clap(help = "Slot name to list")]
+        slot: String,
+    },
+}
+
+#[derive(Clone, clap::Args)]
 pub struct SyncArgs {
     #[clap(
+        global = true,
+        long,
+        help = "Names of items to ignore wherever they are (e.g. 'node_modules')"
+    )]
+    pub ignore_name: Vec<String>,
+
+    #[clap(
+        global = true,
+        long,
+        help = "Paths or globs to ignore, relative to the synchronized directory (e.g. 'build', 'logs/*.txt'). Paths are anchored at the root and match whole components: use a glob like '**/build' to ignore at any depth"
+    )]
+    pub ignore_path: Vec<String>,
+
+    #[clap(
+        global = true,
+        long,
+        alias = "ignore-exts",
+        help = "File extensions to ignore (without the leading dot)"
+    )]
+    pub ignore_ext: Vec<String>,
+
+    #[clap(
+        global = true,
         short,
         long,
-        help = "Item names to ignore (start with a '/' for root-only)"
+        help = "[deprecated: use --ignore-name or --ignore-path instead] Item names to ignore (start with a '/' for root-only)"
     )]
     pub ignore_items: Vec<String>,
 
-    #[clap(long, help = "File extensions to ignore")]
-    pub ignore_exts: Vec<String>,
+    #[clap(
+        global = true,
+        long,
+        help = "Only synchronize items matching these paths or globs (e.g. 'src/**', 'Cargo.toml'), everything else is ignored"
+    )]
+    pub only: Vec<String>,
+
+    #[clap(
+        global = true,
+        long,
+        help = "Synchronize into this subdirectory of the slot instead of its root, leaving the rest of the slot untouched (e.g. 'photos/2024')"
+    )]
+    pub subpath: Option<SlotSubpath>,
+
+    #[clap(
+        global = true,
+        long,
+        help = "Maximum depth of directories to synchronize (1 = only items at the root)"
+    )]
+    pub max_depth: Option<usize>,
+
+    #[clap(
+        long,
+        help = "Only synchronize files modified since this date (RFC 3339, e.g. '2024-01-31T00:00:00Z') or for this duration (e.g. '7d'). Deletions won't be synchronized."
+    )]
+    pub since: Option<SinceArg>,
+
+    #[clap(
+        long,
+        help = "Skip items which can't be analyzed instead of aborting the synchronization"
+    )]
+    pub skip_errors: bool,
+
+    #[clap(
+        long,
+        help = "Fail when encountering special files (FIFOs, sockets, devices) instead of skipping them"
+    )]
+    pub strict_special_files: bool,
+
+    #[clap(
+        long,
+        help = "Follow symbolic links and synchronize their targets as regular items"
+    )]
+    pub follow_symlinks: bool,
+
+    #[clap(
+        long,
+        help = "Synchronize the POSIX ACLs of files and directories (Linux only, requires server support, ignored on filesystems which don't support them)"
+    )]
+    pub acls: bool,
+
+    #[clap(
+        long,
+        help = "Synchronize the extended attributes of files and directories (Linux only, requires server support, ignored on filesystems which don't support them)"
+    )]
+    pub xattrs: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "subpath",
+        help = "Delete items from the server which are excluded by the ignore rules (requires server support)"
+    )]
+    pub delete_excluded: bool,
+
+    #[clap(
+        long,
+        help = "Don't synchronize directories which don't contain any file (even through their subdirectories), so directories left empty by deleting their files are removed from the server too"
+    )]
+    pub prune_empty_dirs: bool,
+
+    #[clap(
+        long,
+        help = "Only update the modification time of modified files whose size didn't change instead of transferring them again (requires server support)"
+    )]
+    pub mtime_only: bool,
+
+    #[clap(
+        long,
+        requires = "mtime_only",
+        help = "With '--mtime-only', ensure these files' content didn't change by comparing their hash with the server's (reads them entirely on both sides)"
+    )]
+    pub mtime_only_check_hash: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "mtime_only",
+        help = "Consider files whose size didn't change as unchanged whatever their modification time is, so they are neither transferred nor updated on the server"
+    )]
+    pub ignore_mtime: bool,
+
+    #[clap(
+        long,
+        requires = "ignore_mtime",
+        help = "With '--ignore-mtime', still transfer these files if their content changed by comparing their hash with the server's (reads them entirely on both sides, requires server support)"
+    )]
+    pub ignore_mtime_check_hash: bool,
+
+    #[clap(
+        long,
+        help = "Compare the content of files which look unchanged with the server's and transfer the ones which differ again, to repair corrupted files on the server (reads them entirely on both sides, requires server support)"
+    )]
+    pub repair: bool,
+
+    #[clap(
+        long,
+        alias = "fast-scan",
+        help = "Reuse the previous local snapshot for directories which didn't change since then (much faster on large trees, but files modified in place without their directory changing won't be detected)"
+    )]
+    pub incremental: bool,
+
+    #[clap(long, help = "Write the local snapshot to a file (JSON)")]
+    pub export_snapshot: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "Diff against a snapshot exported with '--export-snapshot' instead of the server's one, without contacting the server (implies '--dry-run')"
+    )]
+    pub remote_snapshot: Option<PathBuf>,
+
+    #[clap(
+        long,
+        conflicts_with = "remote_snapshot",
+        help = "Make the server walk through the slot's content even if its snapshot is cached, to detect changes made to it outside of synchronizations (requires server support)"
+    )]
+    pub rescan_remote: bool,
 
     #[clap(long, help = "Perform a dry run")]
     pub dry_run: bool,
+
+    #[clap(
+        long,
+        conflicts_with = "dry_run",
+        help = "Only check if local and remote contents are identical, exit with an error if they aren't"
+    )]
+    pub verify: bool,
+
+    #[clap(
+        short,
+        long,
+        alias = "non-interactive",
+        help = "Continue without asking for confirmation (required when not running in a terminal)"
+    )]
+    pub yes: bool,
+
+    #[clap(
+        long,
+        help = "Synchronize even if the server considers that it would delete too many items"
+    )]
+    pub force: bool,
+
+    #[clap(
+        long,
+        help = "Give up on files which failed to transfer and finalize the synchronization without them instead of asking (requires server support)"
+    )]
+    pub abort_failed_files: bool,
+
+    #[clap(
+        long,
+        help = "Fail when files disappear from the local directory before being transferred, instead of synchronizing without them"
+    )]
+    pub fail_on_vanished_files: bool,
+
+    #[clap(
+        long,
+        help = "Number of times files locked by another process are retried, after all other files were transferred (Windows only, as files are never locked on other platforms)",
+        default_value = "3"
+    )]
+    pub locked_files_retries: usize,
+
+    #[clap(
+        long,
+        conflicts_with = "verify",
+        help = "Only transfer up to this number of files, leaving the other ones for the next synchronizations (deletions are still performed entirely)"
+    )]
+    pub max_files_per_sync: Option<usize>,
+
+    #[clap(
+        long,
+        value_enum,
+        default_value = "path",
+        requires = "max_files_per_sync",
+        help = "Files transferred first when their number is limited with '--max-files-per-sync': 'path' in alphabetical order, 'size' from the smallest to the largest"
+    )]
+    pub files_order: FilesOrder,
+}
+
+#[derive(Clone, clap::Args)]
+pub struct TimeoutArgs {
+    #[clap(
+        global = true,
+        long,
+        help = "Maximum time (in seconds) to wait for a connection to the server",
+        default_value = "10"
+    )]
+    pub connect_timeout: u64,
+
+    #[clap(
+        global = true,
+        long,
+        help = "Maximum time (in seconds) for a single request to complete (file transfers get more time depending on their size, see '--min-transfer-rate')",
+        default_value = "300"
+    )]
+    pub transfer_timeout: u64,
+
+    #[clap(
+        global = true,
+        long,
+        help = "Minimum rate a file transfer must sustain: each file gets the transfer timeout plus the time to send it at this rate before being considered as failed",
+        default_value = "64KiB/s"
+    )]
+    pub min_transfer_rate: ByteRate,
+
+    #[clap(
+        global = true,
+        long,
+        conflicts_with = "min_transfer_rate",
+        help = "Maximum time (in seconds) for a single file to be transferred, regardless of its size"
+    )]
+    pub file_timeout: Option<u64>,
+
+    #[clap(
+        global = true,
+        long,
+        help = "Maximum time (in seconds) for the server to build its snapshot",
+        default_value = "3600"
+    )]
+    pub snapshot_timeout: u64,
+}
+
+#[derive(Clone, clap::Args)]
+pub struct TlsArgs {
+    #[clap(
+        global = true,
+        long,
+        help = "Path to a PEM-encoded CA certificate to trust when connecting to the server"
+    )]
+    pub ca_cert: Option<PathBuf>,
+
+    #[clap(
+        global = true,
+        long,
+        help = "SHA-256 fingerprint the server's certificate must match (hexadecimal, colons allowed)"
+    )]
+    pub cert_fingerprint: Option<String>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressFormat {
+    Human,
+    Json,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum FilesOrder {
+    Path,
+    Size,
+}
+
+#[derive(Clone, Copy)]
+pub struct DurationArg(pub Duration);
+
+impl FromStr for DurationArg {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+
+        let (value, multiplier) = if let Some(value) = input.strip_suffix('d') {
+            (value, 24 * 3600)
+        } else if let Some(value) = input.strip_suffix('h') {
+            (value, 3600)
+        } else if let Some(value) = input.strip_suffix('m') {
+            (value, 60)
+        } else {
+            (input.strip_suffix('s').unwrap_or(input), 1)
+        };
+
+        let value = value
+            .trim()
+            .parse::<u64>()
+            .with_context(|| format!("Invalid duration: '{input}'"))?;
+
+        let secs = value
+            .checked_mul(multiplier)
+            .with_context(|| format!("Duration is too long: '{input}'"))?;
+
+        Ok(Self(Duration::from_secs(secs)))
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct SinceArg(pub SystemTime);
+
+impl FromStr for SinceArg {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        if let Ok(date) = OffsetDateTime::parse(input, &Rfc3339) {
+            return Ok(Self(date.into()));
+        }
+
+        let DurationArg(duration) = input
+            .parse()
+            .with_context(|| format!("Invalid date or duration: '{input}'"))?;
+
+        SystemTime::now()
+            .checked_sub(duration)
+            .map(Self)
+            .context("Provided duration is too long")
+    }
+}
+
+// Subdirectory of a slot, with components separated by '/'
+#[derive(Clone)]
+pub struct SlotSubpath(pub String);
+
+impl FromStr for SlotSubpath {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let components = input
+            .split(['/', '\\'])
+            .filter(|component| !component.is_empty() && *component != ".")
+            .collect::<Vec<_>>();
+
+        if components.is_empty() {
+            bail!("Subdirectory must not be empty (got '{input}')");
+        }
+
+        if components.contains(&"..") {
+            bail!("Subdirectory must not contain '..' components (got '{input}')");
+        }
+
+        Ok(Self(components.join("/")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{DurationArg, SinceArg};
+
+    #[test]
+    fn parses_durations() {
+        for (input, secs) in [
+            ("30", 30),
+            ("30s", 30),
+            ("5m", 300),
+            ("2h", 7200),
+            ("1d", 86400),
+        ] {
+            let DurationArg(duration) = input.parse().unwrap();
+            assert_eq!(duration, Duration::from_secs(secs), "{input}");
+        }
+    }
+
+    #[test]
+    fn rejects_overflowing_durations() {
+        assert!("999999999999999999d".parse::<DurationArg>().is_err());
+        assert!("999999999999999999d".parse::<SinceArg>().is_err());
+        assert!(format!("{}s", u64::MAX).parse::<DurationArg>().is_ok());
+    }
 }
@@ -0,0 +1,77 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use harmony_differ::protocol::{device_challenge_message, encode_hex};
+use ring::{
+    rand::SystemRandom,
+    signature::{Ed25519KeyPair, KeyPair},
+};
+use tokio::{fs, io::AsyncWriteExt};
+
+// Key pair authenticating this device once enrolled on a server, without sending the secret password
+pub struct DeviceKey {
+    key_pair: Ed25519KeyPair,
+}
+
+impl DeviceKey {
+    // Returns the key and whether it was just generated (in which case it can't have been enrolled yet)
+    pub async fn load_or_generate(path: &Path) -> Result<(Self, bool)> {
+        if path.is_file() {
+            let pkcs8 = fs::read(path)
+                .await
+                .context("Failed to read the device key")?;
+
+            let key_pair = Ed25519KeyPair::from_pkcs8(&pkcs8)
+                .ok()
+                .context("Failed to parse the device key")?;
+
+            return Ok((Self { key_pair }, false));
+        }
+
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new())
+            .ok()
+            .context("Failed to generate a device key")?;
+
+        let key_pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .context("Failed to create the device key's directory")?;
+        }
+
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+
+        // The private key must only be readable by its owner
+        #[cfg(unix)]
+        options.mode(0o600);
+
+        let mut file = options
+            .open(path)
+            .await
+            .context("Failed to create the device key file")?;
+
+        file.write_all(pkcs8.as_ref())
+            .await
+            .context("Failed to write the device key")?;
+
+        file.sync_all()
+            .await
+            .context("Failed to write the device key")?;
+
+        Ok((Self { key_pair }, true))
+    }
+
+    pub fn public_key(&self) -> String {
+        encode_hex(self.key_pair.public_key().as_ref())
+    }
+
+    pub fn sign_challenge(&self, challenge: &str) -> String {
+        encode_hex(
+            self.key_pair
+                .sign(device_challenge_message(challenge).as_bytes())
+                .as_ref(),
+        )
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+// Exit codes, so scripts can react to each kind of failure (listed in the client's help)
+pub const EXIT_CODE_SUCCESS: i32 = 0;
+pub const EXIT_CODE_FAILURE: i32 = 1;
+pub const EXIT_CODE_TRANSFERS_FAILED: i32 = 2;
+pub const EXIT_CODE_AUTH_FAILED: i32 = 3;
+pub const EXIT_CODE_SERVER_UNREACHABLE: i32 = 4;
+pub const EXIT_CODE_DECLINED: i32 = 5;
+pub const EXIT_CODE_MASS_DELETION_REJECTED: i32 = 6;
+pub const EXIT_CODE_CONTENTS_DIFFER: i32 = 7;
+pub const EXIT_CODE_NOTHING_TO_DO: i32 = 8;
+pub const EXIT_CODE_INTERRUPTED: i32 = 130;
+
+// Set with '--detailed-exit-codes', runs with nothing to do exit with a dedicated code instead of 0
+pub static DETAILED_EXIT_CODES: AtomicBool = AtomicBool::new(false);
+
+// How a run which didn't fail ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Completed,
+    NothingToDo,
+}
+
+impl Outcome {
+    pub fn exit_code(self) -> i32 {
+        match self {
+            Outcome::Completed => EXIT_CODE_SUCCESS,
+            Outcome::NothingToDo if DETAILED_EXIT_CODES.load(Ordering::SeqCst) => {
+                EXIT_CODE_NOTHING_TO_DO
+            }
+            Outcome::NothingToDo => EXIT_CODE_SUCCESS,
+        }
+    }
+}
+
+// Failures which have their own exit code
+#[derive(Debug)]
+pub enum ClientError {
+    AuthFailed,
+    ServerUnreachable,
+    Declined,
+    MassDeletionRejected { message: String },
+    TransfersFailed { failed: usize },
+    ContentsDiffer,
+    Interrupted,
+}
+
+impl ClientError {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ClientError::AuthFailed => EXIT_CODE_AUTH_FAILED,
+            ClientError::ServerUnreachable => EXIT_CODE_SERVER_UNREACHABLE,
+            ClientError::Declined => EXIT_CODE_DECLINED,
+            ClientError::MassDeletionRejected { message: _ } => EXIT_CODE_MASS_DELETION_REJECTED,
+            ClientError::TransfersFailed { failed: _ } => EXIT_CODE_TRANSFERS_FAILED,
+            ClientError::ContentsDiffer => EXIT_CODE_CONTENTS_DIFFER,
+            ClientError::Interrupted => EXIT_CODE_INTERRUPTED,
+        }
+    }
+
+    // Cancellations asked by the user aren't errors as such
+    pub fn is_cancellation(&self) -> bool {
+        matches!(self, ClientError::Declined | ClientError::Interrupted)
+    }
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientError::AuthFailed => write!(f, "Server refused the provided credentials"),
+            ClientError::ServerUnreachable => write!(f, "Server is not reachable"),
+            ClientError::Declined => write!(f, "Synchronization was cancelled."),
+            ClientError::MassDeletionRejected { message } => {
+                write!(f, "Server refused the synchronization: {message}")
+            }
+            ClientError::TransfersFailed { failed } => {
+                write!(f, "Failed to transfer {failed} file(s) (see above).")
+            }
+            ClientError::ContentsDiffer => {
+                write!(f, "Local and remote contents differ (see above).")
+            }
+            ClientError::Interrupted => write!(f, "Snapshot was cancelled."),
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
@@ -2,101 +2,710 @@
 #![forbid(unused_must_use)]
 #![warn(unused_crate_dependencies)]
 
+mod abort;
 mod cmd;
+mod device_key;
+mod exit;
 mod logging;
+mod progress;
+mod summary;
+mod throttle;
+mod throughput;
+mod tls;
+mod transport;
+mod upload;
 
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap, HashSet},
     future::Future,
-    path::Path,
-    sync::{atomic::Ordering, Arc},
-    time::Duration,
+    hash::Hash,
+    io::IsTerminal,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{bail, Context, Result};
 use clap::Parser;
-use cmd::{Args, SyncArgs};
+use cmd::{
+    Args, Command, DurationArg, FilesOrder, ProgressFormat, SinceArg, SlotSubpath, SyncArgs,
+    TimeoutArgs, TlsArgs,
+};
 use colored::Colorize;
 use dialoguer::Confirm;
-use futures_util::TryStreamExt;
 use gethostname::gethostname;
 use harmony_differ::{
-    diffing::{Diff, DiffItemModified},
-    snapshot::{make_snapshot, SnapshotItemMetadata, SnapshotOptions, SnapshotResult},
+    acl::ACLS_SUPPORTED,
+    cache::SnapshotCache,
+    crypto::{encrypted_size, EncryptionKey, SlotEncryption},
+    diffing::{Diff, DiffApplyOps, DiffItemModified, DEFAULT_TIME_GRANULARITY},
+    protocol::{
+        ServerVersion, ACCESS_TOKEN_EXPIRED_HEADER, CAPABILITY_ABORT_FILE, CAPABILITY_ACLS,
+        CAPABILITY_CRC_FRAMING, CAPABILITY_DELTA, CAPABILITY_DEVICE_KEYS, CAPABILITY_DRY_RUN_BEGIN,
+        CAPABILITY_ENCRYPTION, CAPABILITY_LIST_EXCLUDED, CAPABILITY_LIST_SLOT,
+        CAPABILITY_MODIFIED_SINCE, CAPABILITY_OPENING_KEY, CAPABILITY_PRESENT_FILES,
+        CAPABILITY_REMOTE_CHECK, CAPABILITY_REPAIR, CAPABILITY_SNAPSHOT_CACHE, CAPABILITY_SPARSE,
+        CAPABILITY_STREAMED_SNAPSHOT, CAPABILITY_TOUCH, CAPABILITY_TWO_PHASE_FINALIZE,
+        CAPABILITY_XATTRS, MASS_DELETION_HEADER, PROTOCOL_VERSION, REMOTE_CHANGED_HEADER,
+        UNKNOWN_DEVICE_KEY_HEADER,
+    },
+    snapshot::{
+        content_hash, make_snapshot, native_path, SnapshotCancelled, SnapshotItem,
+        SnapshotItemMetadata, SnapshotOptions, SnapshotResult, SnapshotSkipped,
+        SnapshotStreamReader,
+    },
+    xattrs::XATTRS_SUPPORTED,
+};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use rand::{thread_rng, Rng};
+use reqwest::{Client, Method, RequestBuilder, Response, StatusCode, Url};
+use serde::{
+    de::{DeserializeOwned, IgnoredAny},
+    Deserialize,
 };
-use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
-use reqwest::{Body, Client, Method, RequestBuilder, Url};
-use serde::{de::DeserializeOwned, Deserialize};
 use serde_json::json;
-use time::OffsetDateTime;
-use tokio::{fs::File, sync::Mutex, task::JoinSet, try_join};
-use tokio_util::codec::{BytesCodec, Decoder};
-
-use crate::logging::PRINT_DEBUG_MESSAGES;
+use sha2::{Digest, Sha256};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tokio::{fs, sync::watch, try_join};
+
+use crate::{
+    abort::{abort_files, confirm_abort_files},
+    device_key::DeviceKey,
+    exit::{ClientError, Outcome, DETAILED_EXIT_CODES, EXIT_CODE_FAILURE, EXIT_CODE_INTERRUPTED},
+    logging::PRINT_DEBUG_MESSAGES,
+    progress::{
+        draw_target, emit, enable_progress_events, EventThrottle, ProgressEvent,
+        SNAPSHOT_PROGRESS_INTERVAL,
+    },
+    summary::{DeletedItems, SyncSummary},
+    throttle::{ByteRate, RateLimiter},
+    throughput::{ThroughputHistory, MIN_SAMPLE_SIZE},
+    tls::configure_tls,
+    transport::{BodyStream, HttpClient, Transport},
+    upload::{FileTimeout, TransferError, Upload, UploadReport},
+};
 
 #[tokio::main]
 async fn main() {
-    if let Err(err) = inner_main().await {
-        error!("{err:?}");
-        std::process::exit(1);
+    let err = match run(Args::parse(), None).await {
+        Ok(outcome) => std::process::exit(outcome.exit_code()),
+        Err(err) => err,
+    };
+
+    match err.downcast_ref::<ClientError>() {
+        Some(client_err) if client_err.is_cancellation() => warn!("{client_err}"),
+        _ => error!("{err:?}"),
+    }
+
+    std::process::exit(exit_code(&err));
+}
+
+// Requests go through the provided transport if any, or through the network otherwise
+async fn run(args: Args, transport: Option<Arc<dyn Transport>>) -> Result<Outcome> {
+    let mut result = inner_main(args.clone(), transport.clone()).await;
+
+    // The access token may expire during a (very long) synchronization, in which case a new one is requested
+    if result
+        .as_ref()
+        .is_err_and(|err| err.is::<AccessTokenExpired>())
+    {
+        warn!("Access token has expired, requesting a new one...");
+        result = inner_main(args.clone(), transport.clone()).await;
+    }
+
+    // Another device synchronized in the meantime, so the diff must be built again
+    if result.as_ref().is_err_and(|err| err.is::<RemoteChanged>()) {
+        warn!("Slot's content changed on the server since it was snapshotted, starting over...");
+        result = inner_main(args, transport).await;
+    }
+
+    result
+}
+
+// Network failures are reported separately so scripts can retry later
+fn exit_code(err: &anyhow::Error) -> i32 {
+    match err.downcast_ref::<ClientError>() {
+        Some(err) => err.exit_code(),
+        None if is_transient_error(err) => ClientError::ServerUnreachable.exit_code(),
+        None => EXIT_CODE_FAILURE,
     }
 }
 
-async fn inner_main() -> Result<()> {
+async fn inner_main(args: Args, transport: Option<Arc<dyn Transport>>) -> Result<Outcome> {
+    let started_at = Instant::now();
+
     let Args {
         source_dir,
         address,
         secret,
         device_name,
+        device_key,
         slot,
+        command,
         verbose,
         max_parallel_transfers,
-        sync_args,
-    } = Args::parse();
+        no_delta,
+        crc_frames,
+        skip_present,
+        max_upload_rate,
+        jitter,
+        encryption_passphrase,
+        progress_format,
+        progress_output,
+        detailed_exit_codes,
+        timeout_args,
+        tls_args,
+        mut sync_args,
+    } = args;
 
     if verbose {
         PRINT_DEBUG_MESSAGES.store(true, Ordering::SeqCst);
     }
 
+    DETAILED_EXIT_CODES.store(detailed_exit_codes, Ordering::SeqCst);
+
+    match progress_format {
+        ProgressFormat::Human => {
+            if progress_output.is_some() {
+                bail!("Option '--progress-output' requires '--progress-format json'");
+            }
+        }
+
+        ProgressFormat::Json => enable_progress_events(progress_output.as_deref())?,
+    }
+
     debug!("Started.");
 
-    if !source_dir.is_dir() {
-        bail!("Provided data directory was not found");
+    // Listened to from the start, as the signal would otherwise terminate the process
+    let mut pause = listen_pause_signal()?;
+
+    let check = matches!(command, Some(Command::Check { .. }));
+    let remote_ls = matches!(command, Some(Command::RemoteLs { .. }));
+
+    // Subcommands only talk to the server, so the source directory (and the slot when checking)
+    // are left empty as they aren't used
+    let (source_dir, address, slot) = match command {
+        Some(Command::Check { address }) => (None, address, String::new()),
+        Some(Command::RemoteLs { address, slot }) => (None, address, slot),
+        None => (
+            source_dir,
+            address.context("No server address provided")?,
+            slot.context("No slot name provided")?,
+        ),
+    };
+
+    // Without a way to authenticate, the local snapshot can only be exported or diffed against an exported one
+    if secret.is_none()
+        && device_key.is_none()
+        && (check
+            || remote_ls
+            || (sync_args.export_snapshot.is_none() && sync_args.remote_snapshot.is_none()))
+    {
+        bail!("Option '--secret' or '--device-key' is required to authenticate to the server");
     }
 
+    let source_dir = match source_dir {
+        None => PathBuf::new(),
+
+        // A single file is synchronized as the only item of its parent directory
+        Some(source_dir) if source_dir.is_file() => {
+            if !sync_args.only.is_empty() {
+                bail!("Option '--only' can't be used when synchronizing a single file");
+            }
+
+            let file_name = source_dir
+                .file_name()
+                .and_then(|file_name| file_name.to_str())
+                .context("Provided file's name contains invalid UTF-8 characters")?;
+
+            sync_args.only.push(escape_glob(file_name));
+
+            match source_dir.parent() {
+                Some(parent) if !parent.as_os_str().is_empty() => parent.to_owned(),
+                _ => PathBuf::from("."),
+            }
+        }
+
+        Some(source_dir) if source_dir.is_dir() => source_dir,
+
+        Some(_) => bail!("Provided data directory or file was not found"),
+    };
+
     let base_url = Url::parse(&address)?;
 
     if base_url.cannot_be_a_base() {
         bail!("Provided URL cannot be a base");
     }
 
+    // Avoid all machines of a fleet hitting the server at the exact same time
+    if let Some(DurationArg(max_jitter)) = jitter {
+        let delay = Duration::from_millis(
+            thread_rng().gen_range(0..=u64::try_from(max_jitter.as_millis()).unwrap_or(u64::MAX)),
+        );
+
+        info!("Waiting for {} before starting...", HumanDuration(delay));
+
+        tokio::time::sleep(delay).await;
+    }
+
+    let TimeoutArgs {
+        connect_timeout,
+        transfer_timeout,
+        min_transfer_rate,
+        file_timeout,
+        snapshot_timeout,
+    } = timeout_args;
+
+    let TlsArgs {
+        ca_cert,
+        cert_fingerprint,
+    } = tls_args;
+
+    let client = Client::builder()
+        .connect_timeout(Duration::from_secs(connect_timeout))
+        .timeout(Duration::from_secs(transfer_timeout))
+        // Detect half-open connections instead of waiting on them forever
+        .tcp_keepalive(Duration::from_secs(30));
+
+    let client = configure_tls(client, ca_cert.as_deref(), cert_fingerprint.as_deref())?
+        .build()
+        .context("Failed to build the HTTP client")?;
+
+    let client = match transport {
+        Some(transport) => HttpClient::with_transport(client, transport),
+        None => HttpClient::new(client),
+    };
+
+    // ======================================================= //
+    // =
+    // = Export or diff against snapshot files without the server
+    // =
+    // ======================================================= //
+
+    // Without a secret password, the local snapshot is only exported (see the arguments' requirements)
+    if secret.is_none() && device_key.is_none() && sync_args.remote_snapshot.is_none() {
+        let export_path = sync_args.export_snapshot.as_ref().unwrap();
+
+        info!("Building local snapshot...");
+
+        let local = make_snapshot(
+            source_dir,
+            |_| {},
+            &build_snapshot_options(&sync_args),
+            None,
+            None,
+        )
+        .await?;
+
+        export_local_snapshot(export_path, &local).await?;
+
+        success!("Exported local snapshot to '{}'.", export_path.display());
+
+        return Ok(Outcome::Completed);
+    }
+
+    if sync_args.remote_snapshot.is_some() {
+        let dry_run = !sync_args.verify;
+
+        let outcome = open_sync(
+            &client,
+            false,
+            Duration::from_secs(snapshot_timeout),
+            &base_url,
+            &slot,
+            "-",
+            &source_dir,
+            false,
+            false,
+            false,
+            false,
+            SyncArgs {
+                dry_run,
+                ..sync_args
+            },
+        )
+        .await?;
+
+        // Verifications fail when there is something to do
+        return Ok(match outcome {
+            SyncOpening::NothingToDo => Outcome::NothingToDo,
+            SyncOpening::DryRun | SyncOpening::Opened(_, _) => Outcome::Completed,
+        });
+    }
+
+    // ======================================================= //
+    // =
+    // = Check the server's compatibility
+    // =
+    // ======================================================= //
+
+    if check {
+        let latency = check_server_reachability(&client, &base_url).await?;
+
+        success!(
+            "Server is reachable (latency: {} ms)",
+            latency.as_millis().to_string().bright_yellow()
+        );
+    }
+
+    debug!("Checking the server's version...");
+
+    let server_version = fetch_server_version(&client, &base_url).await?;
+
+    if check {
+        if let Some(ServerVersion {
+            version,
+            protocol_version,
+            capabilities,
+        }) = &server_version
+        {
+            success!(
+                "Server is running version {} (protocol version {protocol_version}, capabilities: {})",
+                version.bright_yellow(),
+                capabilities.join(", ")
+            );
+        }
+    }
+
+    // Servers which don't report their version predate capabilities, and support all of the base ones
+    let server_supports = |capability: &str| {
+        server_version
+            .as_ref()
+            .is_none_or(|version| version.supports(capability))
+    };
+
+    if encryption_passphrase.is_some() && !server_supports(CAPABILITY_ENCRYPTION) {
+        bail!("Server does not support encryption");
+    }
+
+    let no_delta = no_delta || !server_supports(CAPABILITY_DELTA);
+
+    // Not a base capability, so servers which don't report their version don't support it
+    let server_reports = |capability: &str| {
+        server_version
+            .as_ref()
+            .is_some_and(|version| version.supports(capability))
+    };
+
+    if crc_frames && !server_reports(CAPABILITY_CRC_FRAMING) {
+        bail!("Server does not support CRC-checked transfers");
+    }
+
+    if skip_present && !server_reports(CAPABILITY_PRESENT_FILES) {
+        bail!("Server does not support checking which files it already has");
+    }
+
+    if sync_args.delete_excluded && !server_reports(CAPABILITY_LIST_EXCLUDED) {
+        bail!("Server does not support deleting excluded items");
+    }
+
+    // Servers ignoring the cutoff date would make all older files look like they were deleted
+    if sync_args.since.is_some() && !server_reports(CAPABILITY_MODIFIED_SINCE) {
+        bail!("Server does not support filtering files by modification date");
+    }
+
+    if sync_args.rescan_remote && !server_reports(CAPABILITY_SNAPSHOT_CACHE) {
+        bail!("Server does not support caching snapshots");
+    }
+
+    if sync_args.mtime_only && !server_reports(CAPABILITY_TOUCH) {
+        bail!("Server does not support updating modification times only");
+    }
+
+    // Files' hashes were introduced alongside modification time updates
+    if sync_args.ignore_mtime_check_hash && !server_reports(CAPABILITY_TOUCH) {
+        bail!("Server does not support comparing files' hashes");
+    }
+
+    if remote_ls && !server_reports(CAPABILITY_LIST_SLOT) {
+        bail!("Server does not support listing a slot's content");
+    }
+
+    if sync_args.repair && !server_reports(CAPABILITY_REPAIR) {
+        bail!("Server does not support repairing files");
+    }
+
+    // ACLs are only synchronized when both sides support them
+    if sync_args.acls && !ACLS_SUPPORTED {
+        warn!("ACLs are not supported on this platform, they won't be synchronized");
+        sync_args.acls = false;
+    } else if sync_args.acls && !server_reports(CAPABILITY_ACLS) {
+        warn!("Server does not support ACLs, they won't be synchronized");
+        sync_args.acls = false;
+    }
+
+    // Same goes for extended attributes
+    if sync_args.xattrs && !XATTRS_SUPPORTED {
+        warn!("Extended attributes are not supported on this platform, they won't be synchronized");
+        sync_args.xattrs = false;
+    } else if sync_args.xattrs && !server_reports(CAPABILITY_XATTRS) {
+        warn!("Server does not support extended attributes, they won't be synchronized");
+        sync_args.xattrs = false;
+    }
+
+    // The server only has the encrypted content
+    if (sync_args.mtime_only_check_hash || sync_args.ignore_mtime_check_hash || sync_args.repair)
+        && encryption_passphrase.is_some()
+    {
+        bail!("Files' hashes can't be compared on encrypted slots");
+    }
+
+    if sync_args.max_files_per_sync == Some(0) {
+        bail!("At least one file must be transferred per synchronization");
+    }
+
+    let two_phase_finalize = server_reports(CAPABILITY_TWO_PHASE_FINALIZE);
+
+    // Dry runs are checked against the operations the server would perform
+    let server_dry_run = server_reports(CAPABILITY_DRY_RUN_BEGIN);
+
+    // The server ensures the slot didn't change between the snapshot and the synchronization's opening
+    let remote_check = server_reports(CAPABILITY_REMOTE_CHECK);
+
+    // Snapshots of large slots would otherwise be serialized as a whole in the server's memory
+    let streamed_snapshot = server_reports(CAPABILITY_STREAMED_SNAPSHOT);
+
+    // Lets retries of the synchronization's opening find the one they may have opened already
+    let opening_key = server_reports(CAPABILITY_OPENING_KEY);
+
+    let sparse_transfers = server_reports(CAPABILITY_SPARSE);
+
+    let can_abort_files = server_reports(CAPABILITY_ABORT_FILE);
+    let abort_failed_files = sync_args.abort_failed_files;
+
+    let locked_files_retries = sync_args.locked_files_retries;
+
+    // Files which disappear before being transferred can only be given up on if the server supports it
+    let skip_vanished_files = can_abort_files && !sync_args.fail_on_vanished_files;
+
+    if abort_failed_files && !can_abort_files {
+        bail!("Server does not support aborting the transfer of individual files");
+    }
+
+    if device_key.is_some() && !server_reports(CAPABILITY_DEVICE_KEYS) {
+        bail!("Server does not support authenticating with a device key");
+    }
+
     // ======================================================= //
     // =
     // = Request an access token
     // =
     // ======================================================= //
 
-    // TODO: store the access token
     debug!("Requesting access token...");
 
     let device_name = device_name.unwrap_or_else(|| gethostname().to_string_lossy().into_owned());
 
-    let access_token = request_url::<String>(
-        Method::POST,
-        "/request-access-token",
+    let device_key = match &device_key {
+        Some(path) => Some(
+            DeviceKey::load_or_generate(path)
+                .await
+                .with_context(|| format!("Failed to load device key at '{}'", path.display()))?,
+        ),
+        None => None,
+    };
+
+    // Keys which were just generated can't be enrolled yet
+    let access_token = match &device_key {
+        Some((device_key, false)) => {
+            match request_device_access_token(&client, &base_url, device_key).await {
+                Ok(access_token) => Some(access_token),
+                Err(err) if err.is::<UnknownDeviceKey>() && secret.is_some() => {
+                    warn!("Device key is not enrolled on the server, enrolling it...");
+                    None
+                }
+                Err(err) => {
+                    return Err(auth_error(
+                        err.context("Failed to authenticate with the device key"),
+                    ))
+                }
+            }
+        }
+        Some((_, true)) | None => None,
+    };
+
+    let access_token = match access_token {
+        Some(access_token) => access_token,
+        None => {
+            let secret = secret
+                .context("Please provide the server's secret password to enroll the device key")?;
+
+            let device_public_key = device_key.as_ref().map(|(key, _)| key.public_key());
+
+            let access_token = request_url::<String>(
+                &client,
+                Method::POST,
+                "/request-access-token",
+                &base_url,
+                "-",
+                |client| {
+                    let mut payload = json!({
+                        "secret_password": secret,
+                        "device_name": device_name
+                    });
+
+                    // Only sent when provided, as older servers reject unknown fields
+                    if let Some(device_public_key) = device_public_key {
+                        payload["device_public_key"] = device_public_key.into();
+                    }
+
+                    client.json(&payload)
+                },
+            )
+            .await
+            .context("Failed to request an access token")
+            .map_err(auth_error)?;
+
+            drop(secret);
+
+            access_token
+        }
+    };
+
+    // ======================================================= //
+    // =
+    // = Check the server's readiness without synchronizing
+    // =
+    // ======================================================= //
+
+    if check {
+        success!(
+            "Authenticated successfully as device '{}'",
+            device_name.bright_yellow()
+        );
+
+        let statuses = request_url::<BTreeMap<String, RemoteSlotStatus>>(
+            &client,
+            Method::GET,
+            "/status",
+            &base_url,
+            &access_token,
+            |client| client,
+        )
+        .await
+        .context("Failed to list the server's slots")?;
+
+        for (
+            slot_name,
+            RemoteSlotStatus {
+                free_space,
+                open_syncs,
+            },
+        ) in &statuses
+        {
+            info!(
+                "* Slot {} ({} free, {} open synchronization(s))",
+                slot_name.bright_cyan(),
+                HumanBytes(*free_space),
+                open_syncs.len()
+            );
+        }
+
+        success!("Server is ready to synchronize.");
+
+        return Ok(Outcome::Completed);
+    }
+
+    // ======================================================= //
+    // =
+    // = List the slot's content without synchronizing
+    // =
+    // ======================================================= //
+
+    if remote_ls {
+        let snapshot_options = match &sync_args.subpath {
+            Some(SlotSubpath(subpath)) => build_snapshot_options(&sync_args).scoped_to(subpath),
+            None => build_snapshot_options(&sync_args),
+        };
+
+        let pb = async_spinner().with_message("Listing the slot's content on server...");
+
+        pb.enable_steady_tick(Duration::from_millis(150));
+
+        let remote = async_with_spinner(pb, |_| {
+            request_url::<SnapshotResult>(
+                &client,
+                Method::POST,
+                "/slot/list",
+                &base_url,
+                &access_token,
+                |client| {
+                    client
+                        .timeout(Duration::from_secs(snapshot_timeout))
+                        .json(&json!({
+                            "slot_name": slot,
+                            "snapshot_options": snapshot_options
+                        }))
+                },
+            )
+        })
+        .await
+        .context("Failed to list the slot's content")?;
+
+        for warning in &remote.warnings {
+            warn!("On server: {warning}");
+        }
+
+        for SnapshotSkipped { path, reason } in &remote.skipped {
+            warn!("Skipped item '{}' on server: {reason}", path.bright_cyan());
+        }
+
+        print_slot_listing(remote.snapshot.items);
+
+        return Ok(Outcome::Completed);
+    }
+
+    // ======================================================= //
+    // =
+    // = Set up encryption
+    // =
+    // ======================================================= //
+
+    debug!("Checking the slot's encryption parameters...");
+
+    let encryption_key = setup_encryption(
+        &client,
         &base_url,
-        "-",
-        |client| {
-            client.json(&json!({
-                "secret_password": secret,
-                "device_name": device_name
-            }))
-        },
+        &slot,
+        &access_token,
+        encryption_passphrase.as_deref(),
     )
-    .await
-    .context("Failed to request an access token")?;
+    .await?;
+
+    drop(encryption_passphrase);
+
+    // ======================================================= //
+    // =
+    // = Verify contents without synchronizing
+    // =
+    // ======================================================= //
+
+    if sync_args.verify {
+        let outcome = open_sync(
+            &client,
+            encryption_key.is_some(),
+            Duration::from_secs(snapshot_timeout),
+            &base_url,
+            &slot,
+            &access_token,
+            &source_dir,
+            server_dry_run,
+            remote_check,
+            streamed_snapshot,
+            opening_key,
+            sync_args,
+        )
+        .await?;
 
-    drop(secret);
+        // Verifications fail when there is something to do
+        return Ok(match outcome {
+            SyncOpening::NothingToDo => Outcome::NothingToDo,
+            SyncOpening::DryRun | SyncOpening::Opened(_, _) => Outcome::Completed,
+        });
+    }
 
     // ======================================================= //
     // =
@@ -107,6 +716,7 @@ async fn inner_main() -> Result<()> {
     debug!("Checking if a sync is already open...");
 
     let is_sync_open = request_url::<bool>(
+        &client,
         Method::GET,
         "/sync/is-open",
         &base_url,
@@ -120,26 +730,24 @@ async fn inner_main() -> Result<()> {
     .await
     .context("Failed to check if a synchronization was already occurring for this slot")?;
 
+    let subpath = sync_args.subpath.clone();
+
     let sync_infos = if is_sync_open {
         warn!(
-            "A synchronization is already open for slot '{}'.",
+            "A synchronization opened by this device is already open for slot '{}'.",
             slot.bright_cyan()
         );
 
         warn!("Are you sure you want to continue?");
 
-        let confirm = Confirm::new()
-            .with_prompt("Continue?".bright_blue().to_string())
-            .interact()?;
-
-        if !confirm {
-            warn!("Process was cancelled.");
-            std::process::exit(1);
+        if !confirm(sync_args.yes)? {
+            return Err(ClientError::Declined.into());
         }
 
         debug!("Resuming open sync...");
 
-        request_url::<SyncInfos>(
+        let sync_infos = request_url::<SyncInfos>(
+            &client,
             Method::POST,
             "/sync/resume",
             &base_url,
@@ -151,205 +759,300 @@ async fn inner_main() -> Result<()> {
             },
         )
         .await
-        .context("Failed to resume open sync")?
+        .context("Failed to resume open sync")?;
+
+        // The synchronization may have been opened from another machine or directory
+        ensure_files_exist_locally(
+            &source_dir,
+            sync_args.subpath.as_ref(),
+            sync_infos.transfer_file_ids.keys(),
+        )?;
+
+        // Deletions were performed when the synchronization was opened
+        (sync_infos, None)
     } else {
-        let Some(sync_infos) =
-            open_sync(&base_url, &slot, &access_token, &source_dir, sync_args).await?
-        else {
-            return Ok(());
+        let opening = open_sync(
+            &client,
+            encryption_key.is_some(),
+            Duration::from_secs(snapshot_timeout),
+            &base_url,
+            &slot,
+            &access_token,
+            &source_dir,
+            server_dry_run,
+            remote_check,
+            streamed_snapshot,
+            opening_key,
+            sync_args,
+        )
+        .await?;
+
+        let (sync_infos, deleted) = match opening {
+            SyncOpening::NothingToDo => return Ok(Outcome::NothingToDo),
+            SyncOpening::DryRun => return Ok(Outcome::Completed),
+            SyncOpening::Opened(sync_infos, deleted) => (sync_infos, deleted),
         };
 
-        sync_infos
+        (sync_infos, Some(deleted))
     };
 
+    let (sync_infos, deleted) = sync_infos;
+
     let SyncInfos {
         sync_token,
         transfer_file_ids,
         transfer_size,
+        completed_files,
     } = sync_infos;
 
-    let mp = MultiProgress::new();
+    if !completed_files.is_empty() {
+        info!(
+            "Skipping {} file(s) which were already transferred.",
+            completed_files.len().to_string().bright_green()
+        );
+    }
 
-    let pb_msg = Arc::new(
-        mp.add(
-            ProgressBar::new(1)
-                .with_style(ProgressStyle::with_template("{msg}").unwrap())
-                .with_message("Running..."),
-        ),
-    );
+    // The server shouldn't ask for already-transferred files, but we ensure they are never sent twice
+    let completed_files = completed_files.into_iter().collect::<HashSet<_>>();
 
-    let transfer_pb = Arc::new(
-        mp.add(
-            ProgressBar::new(transfer_file_ids.len() as u64).with_style(
-                ProgressStyle::with_template(
-                    "Transferring : [{elapsed_precise}] {prefix} {bar:40} {pos}/{len} files",
-                )
-                .unwrap(),
-            ),
-        ),
-    );
+    // Including the files already transferred before a resume
+    let mut synced_files = transfer_file_ids.len();
 
-    let transfer_size_pb = Arc::new(
-        mp.add(
-            ProgressBar::new(transfer_size).with_style(
-                ProgressStyle::with_template(
-                    "Transfer size: [{elapsed_precise}] {prefix} {bar:40} {bytes}/{total_bytes} ({binary_bytes_per_sec})",
-                )
-                .unwrap(),
-            ),
-        )
-    );
+    let mut transfer_file_ids = transfer_file_ids
+        .into_iter()
+        .filter(|(relative_path, _)| !completed_files.contains(relative_path))
+        .collect::<Vec<_>>();
 
-    let errors = Arc::new(Mutex::new(vec![]));
+    let mut transfer_size = transfer_size;
 
-    macro_rules! report_err {
-        ($err: expr, $errors: expr, $pb: expr) => {{
-            let mut errors = $errors.lock().await;
+    if skip_present && !transfer_file_ids.is_empty() {
+        let (present, present_size) = present_files(
+            &client,
+            &base_url,
+            &slot,
+            &access_token,
+            &sync_token,
+            &source_dir,
+            subpath.as_ref(),
+            &transfer_file_ids,
+        )
+        .await?;
 
-            errors.push($err);
+        if !present.is_empty() {
+            info!(
+                "Skipping {} file(s) which the server already has.",
+                present.len().to_string().bright_green()
+            );
+        }
 
-            $pb.println(format!("{}", $err).bright_red().to_string());
-            // $pb.set_message(format!(
-            //     "Running... (encountered {} error(s))",
-            //     errors.len(),
-            // ));
-        }};
+        transfer_file_ids.retain(|(relative_path, _)| !present.contains(relative_path));
+        transfer_size = transfer_size.saturating_sub(present_size);
     }
 
-    let mut task_pool = JoinSet::new();
+    emit(ProgressEvent::SyncOpened {
+        resumed: deleted.is_none(),
+        files_to_transfer: transfer_file_ids.len(),
+        bytes_to_transfer: transfer_size,
+        already_transferred: completed_files.len(),
+    });
 
     let max_parallel_transfers =
         max_parallel_transfers.unwrap_or_else(|| std::cmp::min(num_cpus::get(), 8));
 
-    for (relative_path, _) in transfer_file_ids {
-        let data_dir = source_dir.clone();
-
-        let errors = Arc::clone(&errors);
-        let pb_msg = Arc::clone(&pb_msg);
-        let transfer_size_pb = Arc::clone(&transfer_size_pb);
-
-        transfer_pb.inc(1);
-
-        match File::open(data_dir.join(&relative_path)).await {
-            Err(err) => {
-                report_err!(
-                    format!("Failed to open file '{relative_path}' for transfer: {err}"),
-                    errors,
-                    pb_msg
-                );
-            }
-
-            Ok(file) => {
-                let transfer_size_pb = transfer_size_pb.clone();
+    // Files can't be sent faster than their share of the upload rate limit
+    let min_transfer_rate = match max_upload_rate {
+        Some(ByteRate(max_rate)) => min_transfer_rate
+            .0
+            .min(max_rate / max_parallel_transfers as u64),
+        None => min_transfer_rate.0,
+    };
 
-                let stream = BytesCodec::new().framed(file).inspect_ok(move |chunk| {
-                    let size = chunk.len() as u64;
-                    let transfer_size_pb = Arc::clone(&transfer_size_pb);
+    let upload = Upload {
+        client: client.clone(),
+        base_url: base_url.clone(),
+        access_token: access_token.clone(),
+        slot: slot.clone(),
+        sync_token: sync_token.clone(),
+        source_dir: source_dir.clone(),
+        subpath,
+        // Deltas can't be computed against encrypted content
+        use_delta: !no_delta && encryption_key.is_none(),
+        crc_frames,
+        encryption_key,
+        rate_limiter: max_upload_rate.map(|rate| Arc::new(RateLimiter::new(rate))),
+        file_timeout: FileTimeout {
+            base: Duration::from_secs(transfer_timeout),
+            min_rate: min_transfer_rate.max(1),
+            fixed: file_timeout.map(Duration::from_secs),
+        },
+        sparse_transfers,
+        max_parallel_transfers,
+        locked_files_retries,
+        skip_vanished_files,
+    };
 
-                    tokio::spawn(async move {
-                        transfer_size_pb.inc(size);
-                    });
-                });
+    let UploadReport {
+        transferred_files,
+        transferred_bytes,
+        duration: transfer_duration,
+        errors,
+        vanished,
+    } = upload
+        .run(
+            transfer_file_ids
+                .into_iter()
+                .map(|(relative_path, _)| relative_path)
+                .collect(),
+            transfer_size,
+            &mut pause,
+        )
+        .await?;
 
-                // Prepare variables for task closure
-                let base_url = base_url.clone();
-                let access_token = access_token.clone();
-                let query = json!({
-                    "slot_name": slot,
-                    "sync_token": sync_token,
-                    "path": relative_path
-                });
-                let file_body = Body::wrap_stream(stream);
-                let relative_path = relative_path.clone();
+    // ======================================================= //
+    // =
+    // = Finalize synchronization
+    // =
+    // ======================================================= //
 
-                // Send file
-                while task_pool.len() >= max_parallel_transfers {
-                    task_pool.join_next().await.unwrap()?;
-                }
+    if !errors.is_empty() {
+        report_transfer_errors(&errors);
 
-                task_pool.spawn(async move {
-                    let req = request_url::<()>(
-                        Method::POST,
-                        "/sync/file",
-                        &base_url,
-                        &access_token,
-                        |client| client.query(&query).body(file_body),
-                    );
-
-                    if let Err(err) = req.await {
-                        report_err!(
-                            format!("Failed to transfer file '{relative_path}': {err}"),
-                            errors,
-                            pb_msg
-                        );
-                    }
-                });
+        if !can_abort_files || !confirm_abort_files(abort_failed_files, errors.len())? {
+            return Err(ClientError::TransfersFailed {
+                failed: errors.len(),
             }
+            .into());
         }
     }
 
-    while let Some(result) = task_pool.join_next().await {
-        result?;
-    }
-
-    transfer_pb.finish_and_clear();
-    transfer_size_pb.finish_and_clear();
-
-    // ======================================================= //
-    // =
-    // = Finalize synchronization
-    // =
-    // ======================================================= //
-
-    let errors = errors.lock().await;
-
-    if !errors.is_empty() {
-        // for error in errors.as_slice() {
-        //     error!("* {error}");
-        // }
+    // Vanished files must be removed from the synchronization for it to be finalized
+    if !errors.is_empty() || !vanished.is_empty() {
+        let relative_paths = errors
+            .iter()
+            .map(|TransferError { relative_path, .. }| relative_path)
+            .chain(vanished.iter());
 
-        bail!("{} error(s) occurred (see above).", errors.len());
+        synced_files -= abort_files(
+            &client,
+            &base_url,
+            &access_token,
+            &slot,
+            &sync_token,
+            relative_paths,
+        )
+        .await?;
     }
 
-    info!("Finalization synchronization on the server...");
+    // Finalization may take a while on large synchronizations, so it must not look like a hang
+    let finalize_pb = async_spinner().with_message(format!(
+        "Finalizing synchronization on the server ({} file(s) to move into place)...",
+        synced_files
+    ));
 
-    request_url::<()>(
-        Method::POST,
-        "/sync/finalize",
-        &base_url,
-        &access_token,
-        |client| {
-            client.json(&json!({
-                "slot_name": slot,
-                "sync_token": sync_token
-            }))
-        },
-    )
+    finalize_pb.enable_steady_tick(Duration::from_millis(150));
+
+    async_with_spinner(finalize_pb, |pb| {
+        finalize_sync(
+            &client,
+            &base_url,
+            &access_token,
+            &slot,
+            &sync_token,
+            two_phase_finalize,
+            pb,
+        )
+    })
     .await
     .context("Failed to finalize synchronization")?;
 
+    if transferred_bytes >= MIN_SAMPLE_SIZE {
+        if let Err(err) =
+            record_throughput(base_url.as_str(), transferred_bytes, transfer_duration).await
+        {
+            warn!("Failed to record the transfer's throughput: {err:?}");
+        }
+    }
+
     // ======================================================= //
     // =
     // = Done!
     // =
     // ======================================================= //
 
-    success!("Synchronized successfully.");
+    SyncSummary {
+        transferred_files,
+        transferred_bytes,
+        transfer_duration,
+        deleted,
+        elapsed: started_at.elapsed(),
+    }
+    .report();
 
-    Ok(())
+    Ok(Outcome::Completed)
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn open_sync(
+    client: &HttpClient,
+    encrypted: bool,
+    snapshot_timeout: Duration,
     base_url: &Url,
     slot_name: &str,
     access_token: &str,
     data_dir: &Path,
+    server_dry_run: bool,
+    remote_check: bool,
+    streamed_snapshot: bool,
+    opening_key: bool,
     args: SyncArgs,
-) -> Result<Option<SyncInfos>> {
+) -> Result<SyncOpening> {
+    if !args.ignore_items.is_empty() {
+        warn!("Option '--ignore-items' is deprecated, use '--ignore-name' or '--ignore-path' instead.");
+    }
+
+    if let Some(SinceArg(since)) = args.since {
+        warn!(
+            "Only files modified since {} are considered, deletions won't be synchronized.",
+            OffsetDateTime::from(since)
+        );
+    }
+
+    let snapshot_options = build_snapshot_options(&args);
+
     let SyncArgs {
-        ignore_items,
-        ignore_exts,
+        ignore_name: _,
+        ignore_path: _,
+        ignore_ext: _,
+        ignore_items: _,
+        only: _,
+        subpath,
+        max_depth: _,
+        since: _,
+        skip_errors: _,
+        strict_special_files: _,
+        follow_symlinks: _,
+        acls: _,
+        xattrs: _,
+        delete_excluded,
+        prune_empty_dirs,
+        mtime_only,
+        mtime_only_check_hash,
+        ignore_mtime,
+        ignore_mtime_check_hash,
+        repair,
+        incremental,
+        export_snapshot,
+        remote_snapshot,
+        rescan_remote,
         dry_run,
+        verify,
+        yes,
+        force,
+        abort_failed_files: _,
+        fail_on_vanished_files: _,
+        locked_files_retries: _,
+        max_files_per_sync,
+        files_order,
     } = args;
 
     // ======================================================= //
@@ -360,47 +1063,201 @@ async fn open_sync(
 
     info!("Building snapshots...");
 
-    let snapshot_options = SnapshotOptions {
-        ignore_paths: ignore_items
-            .iter()
-            .filter(|item| Path::new(item).is_absolute())
-            .map(|item| item.strip_prefix('/').unwrap().to_string())
-            .collect(),
-
-        ignore_names: ignore_items
-            .into_iter()
-            .filter(|item| !Path::new(item).is_absolute())
-            .collect(),
+    let snapshot_cache_path = if incremental {
+        Some(snapshot_cache_path(data_dir)?)
+    } else {
+        None
+    };
 
-        ignore_exts,
+    let snapshot_cache = match &snapshot_cache_path {
+        Some(path) => load_snapshot_cache(path).await,
+        None => None,
     };
 
-    let multi_progress = MultiProgress::new();
+    let multi_progress = MultiProgress::with_draw_target(draw_target());
 
     let local_pb = multi_progress.add(async_spinner());
     let remote_pb =
-        multi_progress.add(async_spinner().with_message("Building snapshot on server..."));
+        multi_progress.add(async_spinner().with_message(if remote_snapshot.is_some() {
+            "Loading remote snapshot..."
+        } else {
+            "Building snapshot on server..."
+        }));
 
     local_pb.enable_steady_tick(Duration::from_millis(150));
     remote_pb.enable_steady_tick(Duration::from_millis(150));
 
-    let (local, remote) = try_join!(
+    // Pressing Ctrl-C cancels the local snapshot, pressing it again (or after the snapshot) exits immediately
+    let cancel_snapshot = Arc::new(AtomicBool::new(false));
+
+    tokio::spawn({
+        let cancel_snapshot = Arc::clone(&cancel_snapshot);
+
+        async move {
+            while tokio::signal::ctrl_c().await.is_ok() {
+                if cancel_snapshot.swap(true, Ordering::Relaxed) {
+                    std::process::exit(EXIT_CODE_INTERRUPTED);
+                }
+            }
+        }
+    });
+
+    // The server only needs to snapshot the subdirectory (and the directories leading to it)
+    let remote_snapshot_options = match &subpath {
+        Some(SlotSubpath(subpath)) => snapshot_options.scoped_to(subpath),
+        None => snapshot_options.clone(),
+    };
+
+    let remote_snapshot_path = remote_snapshot.as_deref();
+    let remote_snapshot_options = &remote_snapshot_options;
+
+    emit(ProgressEvent::SnapshotStarted);
+
+    let progress_throttle = EventThrottle::new(SNAPSHOT_PROGRESS_INTERVAL);
+
+    #[cfg(test)]
+    transport::tests::count_local_snapshot();
+
+    let snapshots = try_join!(
         async_with_spinner(local_pb, |pb| make_snapshot(
             data_dir.to_owned(),
-            pb,
-            &snapshot_options
+            move |progress| {
+                if progress_throttle.ready() {
+                    emit(ProgressEvent::SnapshotProgress {
+                        items: progress.items,
+                        bytes: progress.bytes,
+                        current_path: &progress.current_path,
+                    });
+                }
+
+                pb.set_message(format!(
+                    "Analyzed {} item(s) ({}): {}",
+                    progress.items,
+                    HumanBytes(progress.bytes),
+                    progress.current_path
+                ))
+            },
+            &snapshot_options,
+            Some(&cancel_snapshot),
+            snapshot_cache.as_ref()
         )),
-        async_with_spinner(remote_pb, |_| request_url::<SnapshotResult>(
-            Method::POST,
-            "/snapshot",
-            base_url,
-            access_token,
-            |client| client.json(&json!({
-                "slot_name": slot_name,
-                "snapshot_options": snapshot_options,
-            }))
-        ))
-    )?;
+        async_with_spinner(remote_pb, |pb| async move {
+            match remote_snapshot_path {
+                Some(path) => import_snapshot(path).await,
+                // Only the server's snapshot is requested again, the local one being kept as is
+                None => with_retries(
+                    |message| pb.println(message.bright_yellow().to_string()),
+                    |_| async move {
+                        let mut params = json!({
+                            "slot_name": slot_name,
+                            "snapshot_options": remote_snapshot_options,
+                            "encrypted": encrypted,
+                            "list_excluded": delete_excluded,
+                        });
+
+                        if rescan_remote {
+                            params["rescan"] = json!(true);
+                        }
+
+                        if !streamed_snapshot {
+                            return request_url::<SnapshotResult>(
+                                client,
+                                Method::POST,
+                                "/snapshot",
+                                base_url,
+                                access_token,
+                                |client| client.timeout(snapshot_timeout).json(&params),
+                            )
+                            .await;
+                        }
+
+                        params["stream"] = json!(true);
+
+                        request_streamed_snapshot(client, base_url, access_token, |client| {
+                            client.timeout(snapshot_timeout).json(&params)
+                        })
+                        .await
+                    },
+                )
+                .await
+                .context("Failed to build the snapshot on the server"),
+            }
+        })
+    );
+
+    cancel_snapshot.store(true, Ordering::Relaxed);
+
+    let (mut local, mut remote) = match snapshots {
+        Ok(snapshots) => snapshots,
+
+        Err(err) if err.is::<SnapshotCancelled>() => return Err(ClientError::Interrupted.into()),
+
+        Err(err) => return Err(err),
+    };
+
+    // Computed before the remote snapshot is altered in any way, as the server will compute it the same way
+    let remote_fingerprint =
+        (remote_check && remote_snapshot.is_none()).then(|| remote.snapshot.fingerprint());
+
+    emit(ProgressEvent::SnapshotDone {
+        local_items: local.snapshot.items.len(),
+        remote_items: remote.snapshot.items.len(),
+    });
+
+    if let Some(snapshot_cache_path) = &snapshot_cache_path {
+        if let Err(err) = save_snapshot_cache(snapshot_cache_path, &local).await {
+            warn!("Failed to save the snapshot cache: {err:?}");
+        }
+    }
+
+    if let Some(path) = &export_snapshot {
+        export_local_snapshot(path, &local).await?;
+
+        info!("Exported local snapshot to '{}'.", path.display());
+    }
+
+    if let Some(SlotSubpath(subpath)) = &subpath {
+        scope_to_subpath(subpath, &mut local, &mut remote);
+    }
+
+    // Apply the rules the server may have added so both snapshots are built the same way
+    remote.options.filter_snapshot(&mut local.snapshot)?;
+
+    // Empty directories are then absent locally, so they will be considered as deleted
+    if prune_empty_dirs {
+        local.snapshot.prune_empty_dirs();
+    }
+
+    // Excluded items are absent from the local snapshot, so they will be considered as deleted
+    if delete_excluded {
+        let excluded = std::mem::take(&mut remote.excluded);
+        remote.snapshot.items.extend(excluded);
+    }
+
+    for warning in &local.warnings {
+        warn!("{warning}");
+    }
+
+    for warning in &remote.warnings {
+        warn!("On server: {warning}");
+    }
+
+    for SnapshotSkipped { path, reason } in &local.skipped {
+        warn!("Skipped local item '{}': {reason}", path.bright_cyan());
+    }
+
+    for SnapshotSkipped { path, reason } in &remote.skipped {
+        warn!("Skipped item '{}' on server: {reason}", path.bright_cyan());
+    }
+
+    // The server only knows about the size of encrypted files
+    if encrypted {
+        for item in &mut local.snapshot.items {
+            if let SnapshotItemMetadata::File(mt) = &mut item.metadata {
+                mt.size = encrypted_size(mt.size);
+            }
+        }
+    }
 
     // ======================================================= //
     // =
@@ -410,19 +1267,232 @@ async fn open_sync(
 
     info!("Diffing...");
 
-    let diff = Diff::build(&local.snapshot, &remote.snapshot)
-        .apply_time_granularity(Duration::from_secs(1));
+    let mut diff = Diff::build(&local.snapshot, &remote.snapshot)
+        .apply_time_granularity(DEFAULT_TIME_GRANULARITY);
+
+    // Items which couldn't be analyzed locally must not be deleted from the server
+    diff.deleted.retain(|(path, _)| {
+        !local
+            .skipped
+            .iter()
+            .any(|skipped| Path::new(path).starts_with(Path::new(&skipped.path)))
+    });
+
+    // Files which were modified before the cutoff date on one side only would look deleted
+    if snapshot_options.modified_since.is_some() {
+        diff.deleted.clear();
+    }
+
+    if mtime_only {
+        let candidates = diff
+            .modified
+            .iter()
+            .filter(|(_, DiffItemModified { prev, new })| prev.size == new.size)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+
+        diff.touched = if mtime_only_check_hash && !candidates.is_empty() {
+            if remote_snapshot.is_some() {
+                bail!("Files' hashes can't be compared with an exported snapshot");
+            }
+
+            unchanged_files(
+                client,
+                base_url,
+                slot_name,
+                access_token,
+                data_dir,
+                subpath.as_ref(),
+                candidates,
+                false,
+            )
+            .await?
+        } else {
+            candidates
+        };
+    }
+
+    // Files which only had their modification time changed are not considered as modified
+    if ignore_mtime {
+        let candidates = diff
+            .modified
+            .iter()
+            .filter(|(_, DiffItemModified { prev, new })| prev.size == new.size)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+
+        let unchanged = if ignore_mtime_check_hash && !candidates.is_empty() {
+            if remote_snapshot.is_some() {
+                bail!("Files' hashes can't be compared with an exported snapshot");
+            }
+
+            unchanged_files(
+                client,
+                base_url,
+                slot_name,
+                access_token,
+                data_dir,
+                subpath.as_ref(),
+                candidates,
+                false,
+            )
+            .await?
+        } else {
+            candidates
+        };
+
+        let unchanged = unchanged.into_iter().collect::<HashSet<_>>();
+
+        diff.modified.retain(|(path, _)| !unchanged.contains(path));
+    }
+
+    // Files which look unchanged are compared by content, as the server's copy may have been corrupted
+    if repair {
+        if remote_snapshot.is_some() {
+            bail!("Files can't be repaired with an exported snapshot");
+        }
+
+        let changed = diff
+            .added
+            .iter()
+            .map(|(path, _)| path)
+            .chain(diff.modified.iter().map(|(path, _)| path))
+            .chain(diff.type_changed.iter().map(|(path, _)| path))
+            .collect::<HashSet<_>>();
+
+        let remote_files = remote
+            .snapshot
+            .items
+            .iter()
+            .filter_map(|item| match item.metadata {
+                SnapshotItemMetadata::File(mt) => Some((&item.relative_path, mt)),
+                SnapshotItemMetadata::Directory => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        let candidates = local
+            .snapshot
+            .items
+            .iter()
+            .filter(|item| {
+                matches!(item.metadata, SnapshotItemMetadata::File(_))
+                    && remote_files.contains_key(&item.relative_path)
+                    && !changed.contains(&item.relative_path)
+            })
+            .map(|item| item.relative_path.clone())
+            .collect::<Vec<_>>();
+
+        let intact = unchanged_files(
+            client,
+            base_url,
+            slot_name,
+            access_token,
+            data_dir,
+            subpath.as_ref(),
+            candidates.clone(),
+            true,
+        )
+        .await?
+        .into_iter()
+        .collect::<HashSet<_>>();
+
+        let local_files = local
+            .snapshot
+            .items
+            .iter()
+            .filter_map(|item| match item.metadata {
+                SnapshotItemMetadata::File(mt) => Some((&item.relative_path, mt)),
+                SnapshotItemMetadata::Directory => None,
+            })
+            .collect::<HashMap<_, _>>();
+
+        let corrupted = candidates
+            .into_iter()
+            .filter(|path| !intact.contains(path))
+            .collect::<Vec<_>>();
+
+        if !corrupted.is_empty() {
+            warn!(
+                "{} file(s) differ from their copy on the server and will be transferred again.",
+                corrupted.len()
+            );
+        }
+
+        for path in corrupted {
+            let prev = remote_files[&path];
+            let new = local_files[&path];
+
+            diff.modified.push((path, DiffItemModified { prev, new }));
+        }
+
+        diff.modified.sort_by(|(a, _), (b, _)| a.cmp(b));
+    }
+
+    if let Some(max_files) = max_files_per_sync {
+        let mut send_files = diff.ops().send_files;
+
+        if send_files.len() > max_files {
+            match files_order {
+                FilesOrder::Path => send_files.sort_by(|(a, _), (b, _)| a.cmp(b)),
+                FilesOrder::Size => send_files.sort_by_key(|(_, mt)| mt.size),
+            }
+
+            let postponed = send_files
+                .split_off(max_files)
+                .into_iter()
+                .map(|(path, _)| path)
+                .collect::<HashSet<_>>();
+
+            warn!(
+                "{} file(s) are left for the next synchronizations, as at most {max_files} are transferred at once.",
+                postponed.len()
+            );
+
+            diff.postpone_files(&postponed);
+        }
+    }
 
     let Diff {
+        version: _,
         added,
         modified,
         type_changed,
         deleted,
+        hardlinks: _,
+        touched,
+        acls,
+        xattrs,
     } = &diff;
 
-    if added.is_empty() && modified.is_empty() && type_changed.is_empty() && deleted.is_empty() {
-        success!("Nothing to do!");
-        return Ok(None);
+    let diff_ops = diff.ops();
+
+    let transfer_size = diff_ops.send_files.iter().map(|(_, mt)| mt.size).sum();
+
+    emit(ProgressEvent::DiffSummary {
+        added: added.len(),
+        modified: modified.len(),
+        type_changed: type_changed.len(),
+        deleted: deleted.len(),
+        files_to_transfer: diff_ops.send_files.len(),
+        bytes_to_transfer: transfer_size,
+        files_to_delete: diff_ops.delete_files.len(),
+        dirs_to_delete: diff_ops.delete_empty_dirs.len() + diff_ops.replace_dirs.len(),
+    });
+
+    if added.is_empty()
+        && modified.is_empty()
+        && type_changed.is_empty()
+        && deleted.is_empty()
+        && acls.is_empty()
+        && xattrs.is_empty()
+    {
+        if verify {
+            success!("Local and remote contents are identical.");
+        } else {
+            success!("Nothing to do!");
+        }
+
+        return Ok(SyncOpening::NothingToDo);
     }
 
     if !added.is_empty() {
@@ -463,9 +1533,14 @@ async fn open_sync(
                         .unwrap()
                         + Duration::from_nanos(new.last_modif_date_ns.into());
 
-                format!("({prev} => {new})")
+                if touched.contains(path) {
+                    format!("({prev} => {new}, modification time only)")
+                } else {
+                    format!("({prev} => {new})")
+                }
             } else {
-                unreachable!();
+                // Only happens when repairing
+                "(content differs from the server's)".to_owned()
             };
 
             println!("{} {}", path.bright_yellow(), how.bright_yellow());
@@ -520,15 +1595,13 @@ async fn open_sync(
         info!("");
     }
 
-    let diff_ops = diff.ops();
-
-    let transfer_size = diff_ops.send_files.iter().map(|(_, mt)| mt.size).sum();
-
     info!(
         "Found a total of {} files to transfer, {} files and {} directories to delete for a total of {}",
         diff_ops.send_files.len().to_string().bright_green(),
         diff_ops.delete_files.len().to_string().bright_red(),
-        diff_ops.delete_empty_dirs.len().to_string().bright_red(),
+        (diff_ops.delete_empty_dirs.len() + diff_ops.replace_dirs.len())
+            .to_string()
+            .bright_red(),
         format!(
             "{}",
             HumanBytes(transfer_size)
@@ -536,18 +1609,126 @@ async fn open_sync(
         .bright_yellow()
     );
 
+    // Encrypted content has no holes
+    if !encrypted {
+        let sparse_files = local
+            .snapshot
+            .items
+            .iter()
+            .filter(|item| item.sparse)
+            .map(|item| item.relative_path.as_str())
+            .collect::<HashSet<_>>();
+
+        let sparse_transfers = diff_ops
+            .send_files
+            .iter()
+            .filter(|(path, _)| sparse_files.contains(path.as_str()))
+            .count();
+
+        if sparse_transfers > 0 {
+            info!(
+                "{} files to transfer are sparse, their holes won't be sent if the server supports it",
+                sparse_transfers.to_string().bright_green()
+            );
+        }
+    }
+
+    if !diff_ops.create_hardlinks.is_empty() {
+        info!(
+            "{} files will be recreated as hard links instead of being transferred",
+            diff_ops.create_hardlinks.len().to_string().bright_green()
+        );
+    }
+
+    if !diff_ops.touch_files.is_empty() {
+        info!(
+            "{} files will only have their modification time updated instead of being transferred",
+            diff_ops.touch_files.len().to_string().bright_green()
+        );
+    }
+
+    if !diff_ops.set_acls.is_empty() {
+        info!(
+            "{} items will have their ACL set on the server",
+            diff_ops.set_acls.len().to_string().bright_green()
+        );
+    }
+
+    if !diff_ops.set_xattrs.is_empty() {
+        info!(
+            "{} items will have their extended attributes set on the server",
+            diff_ops.set_xattrs.len().to_string().bright_green()
+        );
+    }
+
+    if !diff_ops.move_dirs.is_empty() {
+        info!(
+            "{} directories will be moved on the server instead of being transferred again:",
+            diff_ops.move_dirs.len().to_string().bright_green()
+        );
+
+        for (from, to) in &diff_ops.move_dirs {
+            info!(
+                " {} -> {}",
+                format!("{from}/").bright_red(),
+                format!("{to}/").bright_green()
+            );
+        }
+    }
+
+    if dry_run && server_dry_run {
+        let server_ops = request_url::<DiffApplyOps>(
+            client,
+            Method::POST,
+            "/sync/begin",
+            base_url,
+            access_token,
+            |client| {
+                client.json(&json!({
+                    "slot_name": slot_name,
+                    "diff": diff,
+                    "encrypted": encrypted,
+                    "force": force,
+                    "dry_run": true
+                }))
+            },
+        )
+        .await
+        .context("Failed to check the synchronization with the server")?;
+
+        let mismatches = count_ops_mismatches(&diff_ops, &server_ops);
+
+        if mismatches > 0 {
+            bail!("Server would perform {mismatches} operation(s) differently (see above)");
+        }
+
+        info!("Server would perform the exact same operations.");
+    }
+
     if dry_run {
+        match ThroughputHistory::load().await {
+            Ok(history) => match history.average(base_url.as_str()) {
+                Some(rate) if transfer_size > 0 => info!(
+                    "Estimated transfer duration: ~{} at the recently observed rate of {}/s",
+                    HumanDuration(Duration::from_secs(transfer_size.div_ceil(rate))),
+                    HumanBytes(rate)
+                ),
+                _ => {}
+            },
+            Err(err) => warn!("Failed to load the throughput history: {err:?}"),
+        }
+
         info!("Dry run completed.");
-        std::process::exit(0);
+        return Ok(SyncOpening::DryRun);
     }
 
-    let confirm = Confirm::new()
-        .with_prompt("Continue?".bright_blue().to_string())
-        .interact()?;
+    // Nothing must be opened on the server when verifying
+    if verify {
+        return Err(ClientError::ContentsDiffer.into());
+    }
 
-    if !confirm {
-        warn!("Transfer was cancelled.");
-        std::process::exit(1);
+    if !confirm(yes)? {
+        return Err(ClientError::Declined.into());
     }
 
     // ======================================================= //
@@ -558,61 +1739,1107 @@ async fn open_sync(
 
     debug!("Sending diff to server...");
 
-    let sync_infos = request_url::<SyncInfos>(
-        Method::POST,
-        "/sync/begin",
-        base_url,
-        access_token,
-        |client| {
-            client.json(&json!({
-                "slot_name": slot_name,
-                "diff": diff
-            }))
-        },
-    )
-    .await
-    .context("Failed to begin synchronization")?;
+    let mut params = json!({
+        "slot_name": slot_name,
+        "diff": diff,
+        "encrypted": encrypted
+    });
 
-    Ok(Some(sync_infos))
-}
+    // Only sent when required, as older servers don't know about it
+    if force {
+        params["force"] = json!(true);
+    }
 
-#[derive(Deserialize)]
-#[serde(deny_unknown_fields)]
-struct SyncInfos {
-    sync_token: String,
-    transfer_file_ids: HashMap<String, String>,
-    transfer_size: u64,
-}
+    if let Some(fingerprint) = remote_fingerprint {
+        params["expected_remote"] = json!({
+            "snapshot_options": remote_snapshot_options,
+            "fingerprint": fingerprint
+        });
+    }
 
-async fn request_url<T: DeserializeOwned>(
-    method: Method,
-    join_url: &str,
-    base_url: &Url,
-    access_token: &str,
-    with_client: impl FnOnce(RequestBuilder) -> RequestBuilder,
-) -> Result<T> {
-    let req = Client::new()
-        .request(method, base_url.join(join_url)?)
-        .bearer_auth(access_token);
+    let opening_key = opening_key.then(|| format!("{:032x}", thread_rng().gen::<u128>()));
 
-    let res = with_client(req)
-        .send()
-        .await
-        .context("HTTP request failed")?;
+    if let Some(opening_key) = &opening_key {
+        params["opening_key"] = json!(opening_key);
+    }
 
-    if let Err(err) = res.error_for_status_ref() {
-        let res_text = res
-            .text()
-            .await
-            .unwrap_or_else(|_| "<failed to get response body as text>".to_string());
+    let params = &params;
+    let opening_key = opening_key.as_deref();
+
+    let sync_infos = with_retries(
+        |message| warn!("{message}"),
+        |attempt| async move {
+            // The synchronization may have been opened even though the server's response was lost
+            // Without a key, another synchronization opened by this device could be resumed instead,
+            // so the opening is just attempted again (which fails if the first one went through)
+            if let Some(opening_key) = opening_key.filter(|_| attempt > 1) {
+                let lookup = json!({
+                    "slot_name": slot_name,
+                    "opening_key": opening_key
+                });
 
-        return Err(
-            anyhow!("{err}").context(format!("Server responded: {}", res_text.bright_yellow()))
-        );
-    }
+                let is_sync_open = request_url::<bool>(
+                    client,
+                    Method::GET,
+                    "/sync/is-open",
+                    base_url,
+                    access_token,
+                    |client| client.json(&lookup),
+                )
+                .await?;
 
-    let text = res
-        .text()
+                if is_sync_open {
+                    return request_url::<SyncInfos>(
+                        client,
+                        Method::POST,
+                        "/sync/resume",
+                        base_url,
+                        access_token,
+                        |client| client.json(&lookup),
+                    )
+                    .await;
+                }
+            }
+
+            request_url::<SyncInfos>(
+                client,
+                Method::POST,
+                "/sync/begin",
+                base_url,
+                access_token,
+                |client| client.json(&params),
+            )
+            .await
+        },
+    )
+    .await;
+
+    let sync_infos = match sync_infos {
+        Ok(sync_infos) => sync_infos,
+
+        Err(err)
+            if matches!(
+                err.downcast_ref::<ClientError>(),
+                Some(ClientError::MassDeletionRejected { .. })
+            ) =>
+        {
+            error!("!!! The server refused this synchronization as it would delete a large part of the slot !!!");
+            error!("!!! Please ensure the source directory is the right one, then run again with '--force' if this is intended !!!");
+
+            return Err(err);
+        }
+
+        Err(err) if err.is::<RemoteChanged>() => return Err(err),
+
+        Err(err) => return Err(err.context("Failed to begin synchronization")),
+    };
+
+    let deleted = DeletedItems {
+        files: diff_ops.delete_files.len(),
+        dirs: diff_ops.delete_empty_dirs.len() + diff_ops.replace_dirs.len(),
+    };
+
+    Ok(SyncOpening::Opened(sync_infos, deleted))
+}
+
+// How opening a synchronization ended
+enum SyncOpening {
+    // Local and remote contents are identical
+    NothingToDo,
+    // Differences were found, but only a dry run was performed
+    DryRun,
+    Opened(SyncInfos, DeletedItems),
+}
+
+// Report the operations which are only planned on one side
+fn count_ops_mismatches(local: &DiffApplyOps, server: &DiffApplyOps) -> usize {
+    let DiffApplyOps {
+        create_dirs,
+        send_files,
+        delete_files,
+        delete_empty_dirs,
+        delta_files,
+        create_hardlinks,
+        move_dirs,
+        touch_files,
+        replace_dirs,
+        set_acls,
+        set_xattrs,
+    } = server;
+
+    count_mismatches("create directory", &local.create_dirs, create_dirs)
+        + count_mismatches("send file", &local.send_files, send_files)
+        + count_mismatches("delete file", &local.delete_files, delete_files)
+        + count_mismatches(
+            "delete directory",
+            &local.delete_empty_dirs,
+            delete_empty_dirs,
+        )
+        + count_mismatches("send delta", &local.delta_files, delta_files)
+        + count_mismatches(
+            "create hard link",
+            &local.create_hardlinks,
+            create_hardlinks,
+        )
+        + count_mismatches("move directory", &local.move_dirs, move_dirs)
+        + count_mismatches("update modification time", &local.touch_files, touch_files)
+        + count_mismatches("replace directory", &local.replace_dirs, replace_dirs)
+        + count_mismatches("set ACL", &local.set_acls, set_acls)
+        + count_mismatches("set extended attributes", &local.set_xattrs, set_xattrs)
+}
+
+fn count_mismatches<T: Eq + Hash + std::fmt::Debug>(
+    operation: &str,
+    local: &[T],
+    server: &[T],
+) -> usize {
+    let local = local.iter().collect::<HashSet<_>>();
+    let server = server.iter().collect::<HashSet<_>>();
+
+    for item in local.difference(&server) {
+        warn!("Operation '{operation}' is only planned locally: {item:?}");
+    }
+
+    for item in server.difference(&local) {
+        warn!("Operation '{operation}' is only planned by the server: {item:?}");
+    }
+
+    local.symmetric_difference(&server).count()
+}
+
+fn snapshot_cache_path(data_dir: &Path) -> Result<PathBuf> {
+    let data_dir = data_dir
+        .canonicalize()
+        .context("Failed to canonicalize the data directory's path")?;
+
+    let hash = Sha256::digest(data_dir.to_string_lossy().as_bytes());
+
+    Ok(dirs::cache_dir()
+        .context("Failed to find the user's cache directory")?
+        .join("harmony")
+        .join(format!("snapshot-{hash:x}.json")))
+}
+
+async fn load_snapshot_cache(path: &Path) -> Option<SnapshotCache> {
+    if !path.is_file() {
+        debug!("No snapshot cache found, building a full snapshot.");
+        return None;
+    }
+
+    let cache = fs::read_to_string(path)
+        .await
+        .context("Failed to read the snapshot cache")
+        .and_then(|json| serde_json::from_str(&json).context("Failed to parse the snapshot cache"));
+
+    match cache {
+        Ok(cache) => Some(cache),
+        Err(err) => {
+            warn!("Ignoring snapshot cache: {err:?}");
+            None
+        }
+    }
+}
+
+async fn export_local_snapshot(path: &Path, snapshot: &SnapshotResult) -> Result<()> {
+    let json = serde_json::to_string(snapshot).context("Failed to serialize the local snapshot")?;
+
+    fs::write(path, json)
+        .await
+        .with_context(|| format!("Failed to write the snapshot to '{}'", path.display()))
+}
+
+async fn import_snapshot(path: &Path) -> Result<SnapshotResult> {
+    let json = fs::read_to_string(path)
+        .await
+        .with_context(|| format!("Failed to read the snapshot from '{}'", path.display()))?;
+
+    serde_json::from_str(&json).context("Failed to parse the imported snapshot")
+}
+
+async fn record_throughput(server: &str, bytes: u64, elapsed: Duration) -> Result<()> {
+    let mut history = ThroughputHistory::load().await?;
+    history.record(server, bytes, elapsed);
+    history.save().await
+}
+
+async fn save_snapshot_cache(path: &Path, snapshot: &SnapshotResult) -> Result<()> {
+    fs::create_dir_all(path.parent().unwrap())
+        .await
+        .context("Failed to create the cache directory")?;
+
+    let json = serde_json::to_string(&SnapshotCache::new(snapshot))
+        .context("Failed to serialize the snapshot cache")?;
+
+    fs::write(path, json)
+        .await
+        .context("Failed to write the snapshot cache")
+}
+
+// Only the fields displayed when checking the server
+#[derive(Deserialize)]
+struct RemoteSlotStatus {
+    free_space: u64,
+    open_syncs: Vec<IgnoredAny>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SyncInfos {
+    sync_token: String,
+    transfer_file_ids: HashMap<String, String>,
+    transfer_size: u64,
+    completed_files: Vec<String>,
+}
+
+// Display items as a tree, with the size and modification time of files
+fn print_slot_listing(mut items: Vec<SnapshotItem>) {
+    items.sort_by(|a, b| Path::new(&a.relative_path).cmp(Path::new(&b.relative_path)));
+
+    let mut files = 0;
+    let mut dirs = 0;
+    let mut total_size = 0;
+
+    for item in &items {
+        let indent = "  ".repeat(item.relative_path.matches('/').count());
+        let name = item.relative_path.rsplit('/').next().unwrap();
+
+        match item.metadata {
+            SnapshotItemMetadata::Directory => {
+                dirs += 1;
+
+                println!("{indent}{}", format!("{name}/").bright_blue());
+            }
+
+            SnapshotItemMetadata::File(mt) => {
+                files += 1;
+                total_size += mt.size;
+
+                let modified = i64::try_from(mt.last_modif_date_s)
+                    .ok()
+                    .and_then(|date| OffsetDateTime::from_unix_timestamp(date).ok())
+                    .and_then(|date| date.format(&Rfc3339).ok())
+                    .unwrap_or_else(|| "<invalid date>".to_owned());
+
+                println!(
+                    "{indent}{} {} {}",
+                    name,
+                    format!("({})", HumanBytes(mt.size)).bright_yellow(),
+                    modified.bright_black()
+                );
+            }
+        }
+    }
+
+    info!(
+        "Found {} file(s) and {} directory(ies) for a total of {}",
+        files.to_string().bright_green(),
+        dirs.to_string().bright_green(),
+        HumanBytes(total_size).to_string().bright_yellow()
+    );
+}
+
+// Items' paths are relative to the slot's root, which is a parent of the local directory when using a subpath
+fn local_path(source_dir: &Path, subpath: Option<&SlotSubpath>, relative_path: &str) -> PathBuf {
+    let relative_path = match subpath {
+        Some(SlotSubpath(subpath)) => relative_path
+            .strip_prefix(subpath.as_str())
+            .and_then(|relative_path| relative_path.strip_prefix('/'))
+            .unwrap_or(relative_path),
+        None => relative_path,
+    };
+
+    source_dir.join(native_path(relative_path))
+}
+
+// Move the local items into the slot's subdirectory, and leave the rest of the slot out of the diff
+fn scope_to_subpath(subpath: &str, local: &mut SnapshotResult, remote: &mut SnapshotResult) {
+    for item in &mut local.snapshot.items {
+        item.relative_path = format!("{subpath}/{}", item.relative_path);
+    }
+
+    for skipped in &mut local.skipped {
+        skipped.path = format!("{subpath}/{}", skipped.path);
+    }
+
+    // The subdirectory and its parents must exist on the server too
+    let mut dir = String::new();
+
+    let dirs = subpath
+        .split('/')
+        .map(|component| {
+            if !dir.is_empty() {
+                dir.push('/');
+            }
+
+            dir.push_str(component);
+
+            SnapshotItem {
+                relative_path: dir.clone(),
+                metadata: SnapshotItemMetadata::Directory,
+                hardlink: None,
+                sparse: false,
+                acl: None,
+                xattrs: None,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    local.snapshot.items.splice(0..0, dirs);
+
+    // Servers ignoring the snapshot's restrictions would otherwise make the rest of the slot look deleted
+    remote.snapshot.items.retain(|item| {
+        let path = Path::new(&item.relative_path);
+        path.starts_with(subpath) || Path::new(subpath).starts_with(path)
+    });
+}
+
+fn ensure_files_exist_locally<'a>(
+    source_dir: &Path,
+    subpath: Option<&SlotSubpath>,
+    relative_paths: impl Iterator<Item = &'a String>,
+) -> Result<()> {
+    let mut missing = relative_paths
+        .filter(|relative_path| !local_path(source_dir, subpath, relative_path).is_file())
+        .collect::<Vec<_>>();
+
+    if missing.is_empty() {
+        return Ok(());
+    }
+
+    missing.sort();
+
+    for relative_path in missing.iter().take(MAX_DISPLAYED_ERROR_PATHS) {
+        error!("* Missing file: {}", relative_path.bright_cyan());
+    }
+
+    if missing.len() > MAX_DISPLAYED_ERROR_PATHS {
+        error!("...and {} more", missing.len() - MAX_DISPLAYED_ERROR_PATHS);
+    }
+
+    bail!(
+        "{} file(s) remaining to transfer were not found in the local directory, was the synchronization opened from another directory?",
+        missing.len()
+    );
+}
+
+#[derive(Debug)]
+struct AccessTokenExpired;
+
+impl std::fmt::Display for AccessTokenExpired {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Access token has expired")
+    }
+}
+
+impl std::error::Error for AccessTokenExpired {}
+
+#[derive(Debug)]
+struct UnknownDeviceKey;
+
+impl std::fmt::Display for UnknownDeviceKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Device key is not enrolled on the server")
+    }
+}
+
+impl std::error::Error for UnknownDeviceKey {}
+
+#[derive(Debug)]
+struct RemoteChanged;
+
+impl std::fmt::Display for RemoteChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Slot's content changed on the server since it was snapshotted"
+        )
+    }
+}
+
+impl std::error::Error for RemoteChanged {}
+
+// Maximum number of affected files displayed for each error
+const MAX_DISPLAYED_ERROR_PATHS: usize = 10;
+
+// Display errors grouped by message, as a single failure cause (e.g. a full disk) usually affects many files
+fn report_transfer_errors(errors: &[TransferError]) {
+    let mut by_message = HashMap::<&str, Vec<&str>>::new();
+
+    for TransferError {
+        relative_path,
+        message,
+    } in errors
+    {
+        by_message.entry(message).or_default().push(relative_path);
+    }
+
+    let mut by_message = by_message.into_iter().collect::<Vec<_>>();
+
+    // Most frequent errors come first
+    by_message.sort_by(|(a_msg, a_paths), (b_msg, b_paths)| {
+        b_paths.len().cmp(&a_paths.len()).then(a_msg.cmp(b_msg))
+    });
+
+    error!("Summary of transfer errors:");
+
+    for (message, mut paths) in by_message {
+        paths.sort();
+
+        error!("");
+        error!("* {message} ({} file(s))", paths.len());
+
+        for path in paths.iter().take(MAX_DISPLAYED_ERROR_PATHS) {
+            error!("  - {}", path.bright_cyan());
+        }
+
+        if paths.len() > MAX_DISPLAYED_ERROR_PATHS {
+            error!("  ...and {} more", paths.len() - MAX_DISPLAYED_ERROR_PATHS);
+        }
+    }
+
+    error!("");
+}
+
+const MAX_TRANSFER_ATTEMPTS: usize = 3;
+
+async fn setup_encryption(
+    client: &HttpClient,
+    base_url: &Url,
+    slot_name: &str,
+    access_token: &str,
+    passphrase: Option<&str>,
+) -> Result<Option<EncryptionKey>> {
+    let slot_encryption = request_url::<Option<SlotEncryption>>(
+        client,
+        Method::POST,
+        "/slot/encryption",
+        base_url,
+        access_token,
+        |client| {
+            client.json(&json!({
+                "slot_name": slot_name
+            }))
+        },
+    )
+    .await
+    .context("Failed to get the slot's encryption parameters")?;
+
+    match (slot_encryption, passphrase) {
+        (None, None) => Ok(None),
+
+        (Some(_), None) => bail!(
+            "Slot '{}' is encrypted, please provide its passphrase with '--encryption-passphrase'",
+            slot_name.bright_cyan()
+        ),
+
+        (Some(slot_encryption), Some(passphrase)) => slot_encryption.unlock(passphrase).map(Some),
+
+        (None, Some(passphrase)) => {
+            info!(
+                "Setting up encryption for slot '{}'...",
+                slot_name.bright_cyan()
+            );
+
+            let (slot_encryption, key) = SlotEncryption::generate(passphrase)?;
+
+            request_url::<()>(
+                client,
+                Method::POST,
+                "/slot/init-encryption",
+                base_url,
+                access_token,
+                |client| {
+                    client.json(&json!({
+                        "slot_name": slot_name,
+                        "encryption": slot_encryption
+                    }))
+                },
+            )
+            .await
+            .context("Failed to set up encryption for this slot")?;
+
+            Ok(Some(key))
+        }
+    }
+}
+
+// Find the files the server already has, returning their paths and total size
+// Files which can't be read are left to the transfer, which reports them properly
+#[allow(clippy::too_many_arguments)]
+async fn present_files(
+    client: &HttpClient,
+    base_url: &Url,
+    slot_name: &str,
+    access_token: &str,
+    sync_token: &str,
+    data_dir: &Path,
+    subpath: Option<&SlotSubpath>,
+    transfer_file_ids: &[(String, String)],
+) -> Result<(HashSet<String>, u64)> {
+    let pb = async_spinner().with_message(format!(
+        "Checking which of the {} file(s) to transfer the server already has...",
+        transfer_file_ids.len()
+    ));
+
+    pb.enable_steady_tick(Duration::from_millis(150));
+
+    async_with_spinner(pb, |_| async {
+        let data_dir = data_dir.to_owned();
+        let subpath = subpath.cloned();
+        let transfer_file_ids = transfer_file_ids.to_vec();
+
+        // Transfer ID, path, size and hash of each file
+        let hashed = tokio::task::spawn_blocking(move || {
+            transfer_file_ids
+                .into_iter()
+                .filter_map(|(relative_path, id)| {
+                    let path = local_path(&data_dir, subpath.as_ref(), &relative_path);
+
+                    let hashed = path
+                        .metadata()
+                        .map_err(anyhow::Error::from)
+                        .and_then(|mt| content_hash(&path).map(|hash| (mt.len(), hash)));
+
+                    match hashed {
+                        Ok((size, hash)) => Some((id, relative_path, size, hash)),
+                        Err(err) => {
+                            debug!("Failed to hash file '{relative_path}': {err:?}");
+                            None
+                        }
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .context("Failed to run the hashing task")?;
+
+        let mut present = HashSet::new();
+        let mut present_size = 0;
+
+        for chunk in hashed.chunks(PRESENT_FILES_CHUNK_LEN) {
+            let files = chunk
+                .iter()
+                .map(|(id, _, _, hash)| (id, hash))
+                .collect::<HashMap<_, _>>();
+
+            let present_ids = request_url::<HashSet<String>>(
+                client,
+                Method::POST,
+                "/sync/present",
+                base_url,
+                access_token,
+                |client| {
+                    client.json(&json!({
+                        "slot_name": slot_name,
+                        "sync_token": sync_token,
+                        "files": files
+                    }))
+                },
+            )
+            .await
+            .context("Failed to check which files the server already has")?;
+
+            for (id, relative_path, size, _) in chunk {
+                if present_ids.contains(id) {
+                    present.insert(relative_path.clone());
+                    present_size += size;
+                }
+            }
+        }
+
+        Ok((present, present_size))
+    })
+    .await
+}
+
+// Keeps the requests' body small enough for the server
+const PRESENT_FILES_CHUNK_LEN: usize = 1000;
+
+// Keep the files whose content is identical on both sides
+#[allow(clippy::too_many_arguments)]
+async fn unchanged_files(
+    client: &HttpClient,
+    base_url: &Url,
+    slot_name: &str,
+    access_token: &str,
+    data_dir: &Path,
+    subpath: Option<&SlotSubpath>,
+    paths: Vec<String>,
+    verify: bool,
+) -> Result<Vec<String>> {
+    let pb =
+        async_spinner().with_message(format!("Comparing the hash of {} file(s)...", paths.len()));
+
+    pb.enable_steady_tick(Duration::from_millis(150));
+
+    async_with_spinner(pb, |_| async {
+        let mut params = json!({
+            "slot_name": slot_name,
+            "paths": paths
+        });
+
+        // Only sent when required, as older servers don't know about it
+        if verify {
+            params["verify"] = json!(true);
+        }
+
+        let remote_hashes = request_url::<HashMap<String, String>>(
+            client,
+            Method::POST,
+            "/slot/file-hashes",
+            base_url,
+            access_token,
+            |client| client.json(&params),
+        )
+        .await
+        .context("Failed to get the files' hashes from the server")?;
+
+        let data_dir = data_dir.to_owned();
+        let subpath = subpath.cloned();
+
+        tokio::task::spawn_blocking(move || {
+            let mut unchanged = vec![];
+
+            for path in paths {
+                let Some(remote_hash) = remote_hashes.get(&path) else {
+                    continue;
+                };
+
+                let hash = content_hash(&local_path(&data_dir, subpath.as_ref(), &path))
+                    .with_context(|| format!("Failed to hash file '{path}'"))?;
+
+                if &hash == remote_hash {
+                    unchanged.push(path);
+                }
+            }
+
+            Ok(unchanged)
+        })
+        .await
+        .context("Failed to run the hashing task")?
+    })
+    .await
+}
+
+fn build_snapshot_options(args: &SyncArgs) -> SnapshotOptions {
+    SnapshotOptions {
+        ignore_paths: args
+            .ignore_path
+            .iter()
+            .filter(|item| !is_glob(item))
+            .map(|item| item.trim_start_matches('/').to_string())
+            // Legacy format: items starting with a '/' are root-relative paths
+            .chain(
+                args.ignore_items
+                    .iter()
+                    .filter_map(|item| item.strip_prefix('/'))
+                    .map(str::to_string),
+            )
+            .collect(),
+
+        ignore_names: args
+            .ignore_name
+            .iter()
+            .cloned()
+            .chain(
+                args.ignore_items
+                    .iter()
+                    .filter(|item| !item.starts_with('/'))
+                    .cloned(),
+            )
+            .collect(),
+
+        ignore_exts: args
+            .ignore_ext
+            .iter()
+            .map(|ext| ext.trim_start_matches('.').to_string())
+            .collect(),
+
+        ignore_globs: args
+            .ignore_path
+            .iter()
+            .filter(|item| is_glob(item))
+            .map(|item| item.trim_start_matches('/').to_string())
+            .collect(),
+
+        max_depth: args.max_depth,
+        modified_since: args.since.map(|SinceArg(since)| {
+            since
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        }),
+        skip_errors: args.skip_errors,
+        strict_special_files: args.strict_special_files,
+        follow_symlinks: args.follow_symlinks,
+        acls: args.acls,
+        xattrs: args.xattrs,
+
+        include_paths: args
+            .only
+            .iter()
+            .filter(|item| !is_glob(item))
+            .map(|item| item.trim_start_matches('/').to_string())
+            .collect(),
+
+        include_globs: args
+            .only
+            .iter()
+            .filter(|item| is_glob(item))
+            .map(|item| item.trim_start_matches('/').to_string())
+            .collect(),
+    }
+}
+
+fn is_glob(pattern: &str) -> bool {
+    pattern.contains(['*', '?', '[', '{'])
+}
+
+// Make a path match itself only, even if it contains characters with a special meaning in globs
+fn escape_glob(path: &str) -> String {
+    if !is_glob(path) {
+        return path.to_owned();
+    }
+
+    path.chars()
+        .map(|c| match c {
+            '*' | '?' | '[' | ']' | '{' | '}' => format!("[{c}]"),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+// Credentials refused by the server are reported with their own exit code
+// (invalid secret passwords are answered with a "bad request" status)
+fn auth_error(err: anyhow::Error) -> anyhow::Error {
+    let refused = err.is::<UnknownDeviceKey>()
+        || err.chain().any(|err| {
+            err.downcast_ref::<reqwest::Error>()
+                .and_then(reqwest::Error::status)
+                .is_some_and(|status| {
+                    matches!(status, StatusCode::BAD_REQUEST | StatusCode::FORBIDDEN)
+                })
+        });
+
+    if refused {
+        err.context(ClientError::AuthFailed)
+    } else {
+        err
+    }
+}
+
+// Network failures and gateway errors (e.g. a proxy giving up on a slow request) are usually transient
+fn is_transient_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        err.downcast_ref::<reqwest::Error>().is_some_and(|err| {
+            err.is_timeout()
+                || err.is_connect()
+                || err.status().is_some_and(|status| {
+                    matches!(
+                        status,
+                        StatusCode::BAD_GATEWAY
+                            | StatusCode::SERVICE_UNAVAILABLE
+                            | StatusCode::GATEWAY_TIMEOUT
+                    )
+                })
+        })
+    })
+}
+
+// Delay before retrying a request for the first time, doubled after each attempt
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(2);
+
+// Issue a request again when it fails because of a transient error, with the same number of attempts as file transfers
+async fn with_retries<T, F: Future<Output = Result<T>>>(
+    report: impl Fn(String),
+    mut request: impl FnMut(usize) -> F,
+) -> Result<T> {
+    let mut attempt = 1;
+
+    loop {
+        match request(attempt).await {
+            Ok(value) => return Ok(value),
+
+            Err(err) if is_transient_error(&err) && attempt < MAX_TRANSFER_ATTEMPTS => {
+                let delay = RETRY_BASE_DELAY * 2_u32.pow(attempt as u32 - 1);
+
+                report(format!(
+                    "Request failed, retrying in {} (attempt {}/{MAX_TRANSFER_ATTEMPTS}): {err:#}",
+                    HumanDuration(delay),
+                    attempt + 1
+                ));
+
+                tokio::time::sleep(delay).await;
+
+                attempt += 1;
+            }
+
+            Err(err) => return Err(err),
+        }
+    }
+}
+
+// Returns the time the server took to answer
+async fn check_server_reachability(client: &HttpClient, base_url: &Url) -> Result<Duration> {
+    let started_at = Instant::now();
+
+    client
+        .send(client.get(base_url.join("/healthcheck")?), None)
+        .await
+        .and_then(|res| Ok(res.error_for_status()?))
+        .context(ClientError::ServerUnreachable)?;
+
+    Ok(started_at.elapsed())
+}
+
+// Each SIGUSR1 toggles whether transfers are paused
+#[cfg(unix)]
+fn listen_pause_signal() -> Result<watch::Receiver<bool>> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signal =
+        signal(SignalKind::user_defined1()).context("Failed to listen to the pause signal")?;
+
+    let (sender, receiver) = watch::channel(false);
+
+    tokio::spawn(async move {
+        while signal.recv().await.is_some() {
+            sender.send_modify(|paused| *paused = !*paused);
+        }
+    });
+
+    Ok(receiver)
+}
+
+// There is no equivalent signal, so transfers can't be paused
+#[cfg(not(unix))]
+fn listen_pause_signal() -> Result<watch::Receiver<bool>> {
+    Ok(watch::channel(false).1)
+}
+
+// Returns `None` for servers which are too old to report their version
+async fn fetch_server_version(
+    client: &HttpClient,
+    base_url: &Url,
+) -> Result<Option<ServerVersion>> {
+    let res = client
+        .send(client.get(base_url.join("/version")?), None)
+        .await
+        .context("Failed to get the server's version")?;
+
+    if res.status() == StatusCode::NOT_FOUND {
+        warn!("Server does not report its version, it may be too old for this client.");
+        return Ok(None);
+    }
+
+    let server_version = res
+        .error_for_status()
+        .context("Failed to get the server's version")?
+        .json::<ServerVersion>()
+        .await
+        .context("Failed to parse the server's version")?;
+
+    debug!(
+        "Server is running version {} (protocol version {}, capabilities: {})",
+        server_version.version,
+        server_version.protocol_version,
+        server_version.capabilities.join(", ")
+    );
+
+    match server_version.protocol_version.cmp(&PROTOCOL_VERSION) {
+        std::cmp::Ordering::Equal => Ok(Some(server_version)),
+
+        std::cmp::Ordering::Less => bail!(
+            "Server (version {}) uses protocol version {} which is older than this client's ({PROTOCOL_VERSION}), please update the server",
+            server_version.version,
+            server_version.protocol_version
+        ),
+
+        std::cmp::Ordering::Greater => bail!(
+            "Server (version {}) uses protocol version {} which is newer than this client's ({PROTOCOL_VERSION}), please update the client",
+            server_version.version,
+            server_version.protocol_version
+        ),
+    }
+}
+
+// Number of attempts to commit a prepared synchronization before giving up
+const MAX_COMMIT_ATTEMPTS: usize = 3;
+
+// Get an access token by signing a challenge sent by the server
+async fn request_device_access_token(
+    client: &HttpClient,
+    base_url: &Url,
+    device_key: &DeviceKey,
+) -> Result<String> {
+    let public_key = device_key.public_key();
+
+    let challenge = request_url::<String>(
+        client,
+        Method::POST,
+        "/device/challenge",
+        base_url,
+        "-",
+        |client| client.json(&json!({ "public_key": public_key })),
+    )
+    .await?;
+
+    request_url::<String>(
+        client,
+        Method::POST,
+        "/device/request-access-token",
+        base_url,
+        "-",
+        |client| {
+            client.json(&json!({
+                "public_key": public_key,
+                "signature": device_key.sign_challenge(&challenge),
+                "challenge": challenge
+            }))
+        },
+    )
+    .await
+}
+
+async fn finalize_sync(
+    client: &HttpClient,
+    base_url: &Url,
+    access_token: &str,
+    slot: &str,
+    sync_token: &str,
+    two_phase: bool,
+    pb: ProgressBar,
+) -> Result<()> {
+    if !two_phase {
+        return request_url::<()>(
+            client,
+            Method::POST,
+            "/sync/finalize",
+            base_url,
+            access_token,
+            |client| {
+                client.json(&json!({
+                    "slot_name": slot,
+                    "sync_token": sync_token
+                }))
+            },
+        )
+        .await;
+    }
+
+    let commit_token = request_url::<String>(
+        client,
+        Method::POST,
+        "/sync/finalize/prepare",
+        base_url,
+        access_token,
+        |client| {
+            client.json(&json!({
+                "slot_name": slot,
+                "sync_token": sync_token
+            }))
+        },
+    )
+    .await
+    .context("Failed to prepare the synchronization's commit")?;
+
+    // Committing is idempotent, so it can safely be retried
+    let mut attempt = 1;
+
+    loop {
+        let result = request_url::<()>(
+            client,
+            Method::POST,
+            "/sync/finalize/commit",
+            base_url,
+            access_token,
+            |client| {
+                client.json(&json!({
+                    "slot_name": slot,
+                    "sync_token": sync_token,
+                    "commit_token": commit_token
+                }))
+            },
+        )
+        .await;
+
+        match result {
+            Ok(()) => return Ok(()),
+
+            Err(err) if attempt < MAX_COMMIT_ATTEMPTS => {
+                pb.println(
+                    format!("Failed to commit the synchronization, retrying: {err:?}")
+                        .bright_yellow()
+                        .to_string(),
+                );
+
+                attempt += 1;
+
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+
+            Err(err) => return Err(err.context("Failed to commit the synchronization")),
+        }
+    }
+}
+
+fn confirm(yes: bool) -> Result<bool> {
+    if yes {
+        return Ok(true);
+    }
+
+    // Prompting without a terminal would either fail obscurely or block forever (e.g. under cron)
+    if !std::io::stdin().is_terminal() {
+        bail!("Cannot ask for confirmation as the standard input is not a terminal, use '--yes' to continue without confirmation");
+    }
+
+    Confirm::new()
+        .with_prompt("Continue?".bright_blue().to_string())
+        .interact()
+        .context("Failed to ask for confirmation")
+}
+
+async fn request_url<T: DeserializeOwned>(
+    client: &HttpClient,
+    method: Method,
+    join_url: &str,
+    base_url: &Url,
+    access_token: &str,
+    with_client: impl FnOnce(RequestBuilder) -> RequestBuilder,
+) -> Result<T> {
+    request_url_with_body(
+        client,
+        method,
+        join_url,
+        base_url,
+        access_token,
+        with_client,
+        None,
+    )
+    .await
+}
+
+async fn request_url_with_body<T: DeserializeOwned>(
+    client: &HttpClient,
+    method: Method,
+    join_url: &str,
+    base_url: &Url,
+    access_token: &str,
+    with_client: impl FnOnce(RequestBuilder) -> RequestBuilder,
+    body: Option<BodyStream>,
+) -> Result<T> {
+    let res = send_request(
+        client,
+        method,
+        join_url,
+        base_url,
+        access_token,
+        with_client,
+        body,
+    )
+    .await?;
+
+    let text = res
+        .text()
         .await
         .context("Failed to get HTTP response body as text")?;
 
@@ -626,18 +2853,122 @@ async fn request_url<T: DeserializeOwned>(
     Ok(res)
 }
 
+// Request a snapshot sent as newline-delimited JSON, which is parsed as it is received
+async fn request_streamed_snapshot(
+    client: &HttpClient,
+    base_url: &Url,
+    access_token: &str,
+    with_client: impl FnOnce(RequestBuilder) -> RequestBuilder,
+) -> Result<SnapshotResult> {
+    let mut res = send_request(
+        client,
+        Method::POST,
+        "/snapshot",
+        base_url,
+        access_token,
+        with_client,
+        None,
+    )
+    .await?;
+
+    let mut reader = SnapshotStreamReader::default();
+    let mut pending = vec![];
+
+    while let Some(chunk) = res
+        .chunk()
+        .await
+        .context("Failed to receive the snapshot from the server")?
+    {
+        pending.extend_from_slice(&chunk);
+
+        let mut parsed = 0;
+
+        while let Some(len) = pending[parsed..].iter().position(|byte| *byte == b'\n') {
+            let line = &pending[parsed..parsed + len];
+
+            reader.push(serde_json::from_slice(line).with_context(|| {
+                format!(
+                    "Failed to parse server's snapshot line: {}",
+                    String::from_utf8_lossy(line).bright_yellow()
+                )
+            })?)?;
+
+            parsed += len + 1;
+        }
+
+        pending.drain(..parsed);
+    }
+
+    if !pending.is_empty() {
+        bail!("Snapshot ended with an incomplete line");
+    }
+
+    reader.finish()
+}
+
+async fn send_request(
+    client: &HttpClient,
+    method: Method,
+    join_url: &str,
+    base_url: &Url,
+    access_token: &str,
+    with_client: impl FnOnce(RequestBuilder) -> RequestBuilder,
+    body: Option<BodyStream>,
+) -> Result<Response> {
+    let req = client
+        .request(method, base_url.join(join_url)?)
+        .bearer_auth(access_token);
+
+    let res = client.send(with_client(req), body).await?;
+
+    if res.headers().contains_key(ACCESS_TOKEN_EXPIRED_HEADER) {
+        return Err(AccessTokenExpired.into());
+    }
+
+    if res.headers().contains_key(UNKNOWN_DEVICE_KEY_HEADER) {
+        return Err(UnknownDeviceKey.into());
+    }
+
+    if res.headers().contains_key(REMOTE_CHANGED_HEADER) {
+        return Err(RemoteChanged.into());
+    }
+
+    if res.headers().contains_key(MASS_DELETION_HEADER) {
+        let message = res
+            .text()
+            .await
+            .unwrap_or_else(|_| "<failed to get response body as text>".to_string());
+
+        return Err(ClientError::MassDeletionRejected { message }.into());
+    }
+
+    if let Err(err) = res.error_for_status_ref() {
+        let res_text = res
+            .text()
+            .await
+            .unwrap_or_else(|_| "<failed to get response body as text>".to_string());
+
+        return Err(anyhow::Error::new(err)
+            .context(format!("Server responded: {}", res_text.bright_yellow())));
+    }
+
+    Ok(res)
+}
+
 fn async_spinner() -> ProgressBar {
-    ProgressBar::new_spinner()
-        .with_style(ProgressStyle::with_template("{spinner} [{elapsed_precise}] {msg}").unwrap())
+    let pb = ProgressBar::new_spinner().with_style(
+        ProgressStyle::with_template("{spinner} [{elapsed_precise}] {wide_msg}").unwrap(),
+    );
+
+    pb.set_draw_target(draw_target());
+    pb
 }
 
 async fn async_with_spinner<F: Future<Output = Result<T, E>>, T, E>(
     pb: ProgressBar,
-    task: impl FnOnce(Box<dyn Fn(String) + Send + Sync>) -> F,
+    task: impl FnOnce(ProgressBar) -> F,
 ) -> Result<T, E> {
-    let pb_closure = pb.clone();
-
-    let result = task(Box::new(move |msg| pb_closure.set_message(msg))).await;
+    let result = task(pb.clone()).await;
 
     pb.set_style(pb.style().tick_chars(&format!(
         " {}",
@@ -0,0 +1,175 @@
+// Machine-readable progress events, meant for wrappers (e.g. GUIs) which render their own progress
+//
+// When enabled with '--progress-format json', each event is written as a single line of JSON,
+// with an "event" field holding its name:
+//
+// * `snapshot_started`: snapshots are being built
+// * `snapshot_progress` (`items`, `bytes`, `current_path`): the local snapshot analyzed more items
+// * `snapshot_done` (`local_items`, `remote_items`): both snapshots were built
+// * `diff_summary` (`added`, `modified`, `type_changed`, `deleted`, `files_to_transfer`,
+//   `bytes_to_transfer`, `files_to_delete`, `dirs_to_delete`): result of the diffing
+// * `sync_opened` (`resumed`, `files_to_transfer`, `bytes_to_transfer`, `already_transferred`):
+//   synchronization was opened (or resumed) on the server, transfers are about to start
+// * `transfer_started` (`path`, `size`): a file started being transferred
+// * `transfer_completed` (`path`, `files_done`, `files_total`, `bytes_done`, `bytes_total`):
+//   a file was transferred, with the overall progress of the transfers
+// * `transfer_failed` (`path`, `error`): a file failed to transfer (after all retries)
+// * `sync_finalized` (`transferred_files`, `transferred_bytes`, `deleted_files`, `deleted_dirs`,
+//   `duration_ms`): synchronization was committed on the server
+//
+// Sizes are in bytes, and paths are relative to the synchronized directory.
+// Wrappers should ignore unknown events and fields, as new ones may be added in the future.
+
+use std::{
+    fs::File,
+    io::Write,
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use indicatif::ProgressDrawTarget;
+use serde::Serialize;
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum ProgressEvent<'a> {
+    SnapshotStarted,
+    SnapshotProgress {
+        items: usize,
+        bytes: u64,
+        current_path: &'a str,
+    },
+    SnapshotDone {
+        local_items: usize,
+        remote_items: usize,
+    },
+    DiffSummary {
+        added: usize,
+        modified: usize,
+        type_changed: usize,
+        deleted: usize,
+        files_to_transfer: usize,
+        bytes_to_transfer: u64,
+        files_to_delete: usize,
+        dirs_to_delete: usize,
+    },
+    SyncOpened {
+        resumed: bool,
+        files_to_transfer: usize,
+        bytes_to_transfer: u64,
+        already_transferred: usize,
+    },
+    TransferStarted {
+        path: &'a str,
+        size: u64,
+    },
+    TransferCompleted {
+        path: &'a str,
+        files_done: u64,
+        files_total: u64,
+        bytes_done: u64,
+        bytes_total: u64,
+    },
+    TransferFailed {
+        path: &'a str,
+        error: &'a str,
+    },
+    // No new transfer starts until transfers are resumed
+    TransfersPaused,
+    TransfersResumed,
+    // File disappeared from the local directory before being transferred
+    TransferSkipped {
+        path: &'a str,
+    },
+    SyncFinalized {
+        transferred_files: u64,
+        transferred_bytes: u64,
+        deleted_files: usize,
+        deleted_dirs: usize,
+        duration_ms: u128,
+    },
+}
+
+// Minimum delay between two snapshot progress events, as the local snapshot reports every single item
+pub const SNAPSHOT_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+static EVENTS_OUTPUT: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+// Start emitting events to the provided file, or to STDERR if none is provided
+pub fn enable_progress_events(output: Option<&Path>) -> Result<()> {
+    // Events may already be enabled if the process was restarted (e.g. after an access token expired)
+    if progress_events_enabled() {
+        return Ok(());
+    }
+
+    let output: Box<dyn Write + Send> = match output {
+        None => Box::new(std::io::stderr()),
+        Some(path) => Box::new(File::create(path).with_context(|| {
+            format!(
+                "Failed to open progress events output at '{}'",
+                path.display()
+            )
+        })?),
+    };
+
+    let _ = EVENTS_OUTPUT.set(Mutex::new(output));
+
+    Ok(())
+}
+
+pub fn progress_events_enabled() -> bool {
+    EVENTS_OUTPUT.get().is_some()
+}
+
+pub fn emit(event: ProgressEvent) {
+    let Some(output) = EVENTS_OUTPUT.get() else {
+        return;
+    };
+
+    let mut line = serde_json::to_string(&event).unwrap();
+    line.push('\n');
+
+    let mut output = output.lock().unwrap();
+
+    // A wrapper which stopped listening must not make the synchronization fail
+    let _ = output
+        .write_all(line.as_bytes())
+        .and_then(|()| output.flush());
+}
+
+// Human-readable progress bars are hidden when events are emitted, as they would be interleaved with them
+pub fn draw_target() -> ProgressDrawTarget {
+    if progress_events_enabled() {
+        ProgressDrawTarget::hidden()
+    } else {
+        ProgressDrawTarget::stderr()
+    }
+}
+
+// Rate-limit events which may be emitted very frequently
+pub struct EventThrottle {
+    interval: Duration,
+    last: Mutex<Option<Instant>>,
+}
+
+impl EventThrottle {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Mutex::new(None),
+        }
+    }
+
+    pub fn ready(&self) -> bool {
+        let mut last = self.last.lock().unwrap();
+
+        if last.is_some_and(|last| last.elapsed() < self.interval) {
+            return false;
+        }
+
+        *last = Some(Instant::now());
+        true
+    }
+}
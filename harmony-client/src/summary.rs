@@ -0,0 +1,81 @@
+use std::time::Duration;
+
+use indicatif::{HumanBytes, HumanDuration};
+
+use crate::{
+    info,
+    progress::{emit, ProgressEvent},
+    success,
+};
+
+// Items removed from the server when the synchronization was opened
+pub struct DeletedItems {
+    pub files: usize,
+    pub dirs: usize,
+}
+
+// What a finalized synchronization did, reported to the user once it's done
+pub struct SyncSummary {
+    pub transferred_files: u64,
+    pub transferred_bytes: u64,
+    pub transfer_duration: Duration,
+    // Nothing was deleted by a resumed synchronization
+    pub deleted: Option<DeletedItems>,
+    pub elapsed: Duration,
+}
+
+impl SyncSummary {
+    pub fn report(self) {
+        let Self {
+            transferred_files,
+            transferred_bytes,
+            transfer_duration,
+            deleted,
+            elapsed,
+        } = self;
+
+        info!(
+            "Transferred {} file(s) for a total of {} in {} ({}/s on average)",
+            transferred_files.to_string().bright_green(),
+            HumanBytes(transferred_bytes).to_string().bright_yellow(),
+            HumanDuration(transfer_duration),
+            HumanBytes(average_rate(transferred_bytes, transfer_duration))
+        );
+
+        emit(ProgressEvent::SyncFinalized {
+            transferred_files,
+            transferred_bytes,
+            deleted_files: deleted.as_ref().map_or(0, |deleted| deleted.files),
+            deleted_dirs: deleted.as_ref().map_or(0, |deleted| deleted.dirs),
+            duration_ms: elapsed.as_millis(),
+        });
+
+        if let Some(DeletedItems { files, dirs }) = deleted {
+            info!(
+                "Deleted {} file(s) and {} directory(ies)",
+                files.to_string().bright_red(),
+                dirs.to_string().bright_red()
+            );
+        }
+
+        success!("Synchronized successfully in {}.", HumanDuration(elapsed));
+    }
+}
+
+// Transfers which took no measurable time don't make the rate infinite
+fn average_rate(bytes: u64, duration: Duration) -> u64 {
+    (bytes as f64 / duration.as_secs_f64().max(0.001)) as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::average_rate;
+
+    #[test]
+    fn computes_average_rate() {
+        assert_eq!(average_rate(10_000, Duration::from_secs(4)), 2_500);
+        assert_eq!(average_rate(5, Duration::ZERO), 5_000);
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context};
+
+#[derive(Clone, Copy)]
+pub struct ByteRate(pub u64);
+
+impl FromStr for ByteRate {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        let input = input.trim();
+        let input = input.strip_suffix("/s").unwrap_or(input);
+
+        let unit_start = input
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(input.len());
+
+        let (value, unit) = input.split_at(unit_start);
+
+        let value = value
+            .parse::<f64>()
+            .with_context(|| format!("Invalid rate value: '{value}'"))?;
+
+        let multiplier: u64 = match unit.trim() {
+            "" | "B" => 1,
+            "KB" | "K" => 1000,
+            "MB" | "M" => 1000 * 1000,
+            "GB" | "G" => 1000 * 1000 * 1000,
+            "KiB" => 1024,
+            "MiB" => 1024 * 1024,
+            "GiB" => 1024 * 1024 * 1024,
+            unit => bail!("Unknown rate unit: '{unit}'"),
+        };
+
+        let rate = (value * multiplier as f64) as u64;
+
+        if rate == 0 {
+            bail!("Rate must be greater than zero");
+        }
+
+        Ok(Self(rate))
+    }
+}
+
+// Token bucket shared between all transfers, allowing bursts of up to one second of data
+pub struct RateLimiter {
+    rate: f64,
+    state: Mutex<RateLimiterState>,
+}
+
+struct RateLimiterState {
+    available: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(ByteRate(rate): ByteRate) -> Self {
+        Self {
+            rate: rate as f64,
+            state: Mutex::new(RateLimiterState {
+                available: rate as f64,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    pub async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+
+            state.available = (state.available + elapsed * self.rate).min(self.rate);
+            state.last_refill = now;
+
+            // Going into debt makes concurrent transfers wait for each other's share
+            state.available -= bytes as f64;
+
+            if state.available < 0.0 {
+                Duration::from_secs_f64(-state.available / self.rate)
+            } else {
+                Duration::ZERO
+            }
+        };
+
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
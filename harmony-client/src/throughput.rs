@@ -0,0 +1,82 @@
+use std::{collections::HashMap, path::PathBuf, time::Duration};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+// Number of previous synchronizations the average throughput is computed from
+const MAX_SAMPLES: usize = 5;
+
+// Transfers smaller than this are too short to give a meaningful throughput
+pub const MIN_SAMPLE_SIZE: u64 = 1024 * 1024;
+
+// Throughput (in bytes per second) observed during the last synchronizations, for each server
+#[derive(Serialize, Deserialize, Default)]
+pub struct ThroughputHistory {
+    servers: HashMap<String, Vec<u64>>,
+}
+
+impl ThroughputHistory {
+    pub async fn load() -> Result<Self> {
+        let path = history_path()?;
+
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        let json = fs::read_to_string(&path)
+            .await
+            .context("Failed to read the throughput history")?;
+
+        serde_json::from_str(&json).context("Failed to parse the throughput history")
+    }
+
+    pub async fn save(&self) -> Result<()> {
+        let path = history_path()?;
+
+        fs::create_dir_all(path.parent().unwrap())
+            .await
+            .context("Failed to create the cache directory")?;
+
+        let json =
+            serde_json::to_string(self).context("Failed to serialize the throughput history")?;
+
+        fs::write(path, json)
+            .await
+            .context("Failed to write the throughput history")
+    }
+
+    pub fn record(&mut self, server: &str, bytes: u64, elapsed: Duration) {
+        let rate = (bytes as f64 / elapsed.as_secs_f64()) as u64;
+
+        if rate == 0 {
+            return;
+        }
+
+        let samples = self.servers.entry(server.to_owned()).or_default();
+
+        samples.push(rate);
+
+        if samples.len() > MAX_SAMPLES {
+            samples.remove(0);
+        }
+    }
+
+    // Average throughput observed with a server, if any synchronization was recorded yet
+    pub fn average(&self, server: &str) -> Option<u64> {
+        let samples = self.servers.get(server)?;
+
+        if samples.is_empty() {
+            return None;
+        }
+
+        Some(samples.iter().sum::<u64>() / samples.len() as u64)
+    }
+}
+
+fn history_path() -> Result<PathBuf> {
+    Ok(dirs::cache_dir()
+        .context("Failed to find the user's cache directory")?
+        .join("harmony")
+        .join("throughput.json"))
+}
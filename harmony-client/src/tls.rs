@@ -0,0 +1,128 @@
+use std::{fs, io::Cursor, path::Path, sync::Arc, time::SystemTime};
+
+use anyhow::{bail, Context, Result};
+use reqwest::ClientBuilder;
+use rustls::{
+    client::{ServerCertVerified, ServerCertVerifier, WebPkiVerifier},
+    Certificate, ClientConfig, Error as TlsError, RootCertStore, ServerName,
+};
+use sha2::{Digest, Sha256};
+
+pub fn configure_tls(
+    builder: ClientBuilder,
+    ca_cert: Option<&Path>,
+    cert_fingerprint: Option<&str>,
+) -> Result<ClientBuilder> {
+    let ca_cert = ca_cert
+        .map(|path| {
+            fs::read(path)
+                .with_context(|| format!("Failed to read CA certificate at '{}'", path.display()))
+        })
+        .transpose()?;
+
+    let Some(cert_fingerprint) = cert_fingerprint else {
+        return match ca_cert {
+            Some(ca_cert) => {
+                let cert = reqwest::Certificate::from_pem(&ca_cert)
+                    .context("Failed to parse CA certificate")?;
+
+                Ok(builder.add_root_certificate(cert))
+            }
+
+            None => Ok(builder),
+        };
+    };
+
+    let verifier = PinnedCertVerifier {
+        fingerprint: parse_fingerprint(cert_fingerprint)?,
+        ca_verifier: ca_cert
+            .map(|ca_cert| {
+                let mut roots = RootCertStore::empty();
+
+                let certs = rustls_pemfile::certs(&mut Cursor::new(ca_cert))
+                    .context("Failed to parse CA certificate")?;
+
+                for cert in certs {
+                    roots
+                        .add(&Certificate(cert))
+                        .context("Failed to add CA certificate")?;
+                }
+
+                Ok::<_, anyhow::Error>(WebPkiVerifier::new(roots, None))
+            })
+            .transpose()?,
+    };
+
+    let config = ClientConfig::builder()
+        .with_safe_defaults()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(builder.use_preconfigured_tls(config))
+}
+
+fn parse_fingerprint(input: &str) -> Result<[u8; 32]> {
+    let hex = input.replace(':', "");
+
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Certificate fingerprint must be a SHA-256 hash in hexadecimal (got '{input}')");
+    }
+
+    let mut fingerprint = [0; 32];
+
+    for (i, byte) in fingerprint.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+    }
+
+    Ok(fingerprint)
+}
+
+fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint
+        .iter()
+        .map(|byte| format!("{byte:02X}"))
+        .collect::<Vec<_>>()
+        .join(":")
+}
+
+// Accepts the server's certificate only if its SHA-256 fingerprint matches the pinned one
+// If a CA certificate was provided, the certificate must also be signed by it
+struct PinnedCertVerifier {
+    fingerprint: [u8; 32],
+    ca_verifier: Option<WebPkiVerifier>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        intermediates: &[Certificate],
+        server_name: &ServerName,
+        scts: &mut dyn Iterator<Item = &[u8]>,
+        ocsp_response: &[u8],
+        now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        if let Some(ca_verifier) = &self.ca_verifier {
+            ca_verifier.verify_server_cert(
+                end_entity,
+                intermediates,
+                server_name,
+                scts,
+                ocsp_response,
+                now,
+            )?;
+        }
+
+        let fingerprint = Sha256::digest(&end_entity.0);
+
+        if fingerprint.as_slice() != self.fingerprint {
+            return Err(TlsError::General(format!(
+                "Server certificate's fingerprint ({}) does not match the pinned one ({})",
+                format_fingerprint(&fingerprint),
+                format_fingerprint(&self.fingerprint)
+            )));
+        }
+
+        Ok(ServerCertVerified::assertion())
+    }
+}
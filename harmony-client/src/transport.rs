@@ -0,0 +1,638 @@
+use std::{future::Future, pin::Pin, sync::Arc};
+
+use anyhow::{Context, Result};
+use futures_util::Stream;
+use reqwest::{Body, Client, Method, Request, RequestBuilder, Response, Url};
+use tokio_util::bytes::Bytes;
+
+// Streamed request body, provided separately as reqwest's streamed bodies can't be read back
+pub type BodyStream = Pin<Box<dyn Stream<Item = std::io::Result<Bytes>> + Send + Sync>>;
+
+pub type ResponseFuture<'a> = Pin<Box<dyn Future<Output = Result<Response>> + Send + 'a>>;
+
+// How requests reach the server, so the client can be driven without going through the network
+pub trait Transport: Send + Sync {
+    fn execute(&self, request: Request, body: Option<BodyStream>) -> ResponseFuture<'_>;
+}
+
+impl Transport for Client {
+    fn execute(&self, mut request: Request, body: Option<BodyStream>) -> ResponseFuture<'_> {
+        if let Some(body) = body {
+            *request.body_mut() = Some(Body::wrap_stream(body));
+        }
+
+        Box::pin(async move {
+            Client::execute(self, request)
+                .await
+                .context("HTTP request failed")
+        })
+    }
+}
+
+// Builds requests with the configured client (timeouts, TLS...) and sends them through a transport
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client,
+    transport: Arc<dyn Transport>,
+}
+
+impl HttpClient {
+    pub fn new(client: Client) -> Self {
+        Self {
+            transport: Arc::new(client.clone()),
+            client,
+        }
+    }
+
+    pub fn with_transport(client: Client, transport: Arc<dyn Transport>) -> Self {
+        Self { client, transport }
+    }
+
+    pub fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        self.client.request(method, url)
+    }
+
+    pub fn get(&self, url: Url) -> RequestBuilder {
+        self.request(Method::GET, url)
+    }
+
+    pub async fn send(&self, req: RequestBuilder, body: Option<BodyStream>) -> Result<Response> {
+        let request = req.build().context("Failed to build HTTP request")?;
+
+        self.transport.execute(request, body).await
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{
+        cell::Cell,
+        ffi::{OsStr, OsString},
+        fs::{self, File},
+        path::{Path, PathBuf},
+        sync::{Arc, Mutex},
+        time::{Duration, SystemTime},
+    };
+
+    use anyhow::Context;
+    use axum::{http, Router};
+    use clap::Parser;
+    use harmony_server::Server;
+    use reqwest::{Request, StatusCode, Url};
+    use tempfile::TempDir;
+    use tower::ServiceExt;
+
+    use super::{BodyStream, Bytes, ResponseFuture, Transport};
+    use crate::{
+        cmd::Args,
+        exit::{ClientError, Outcome},
+        run,
+    };
+
+    tokio::task_local! {
+        // Number of local snapshots built by the run being tested
+        static LOCAL_SNAPSHOTS: Cell<usize>;
+    }
+
+    pub(crate) fn count_local_snapshot() {
+        // Runs happening outside of a counting scope aren't tracked
+        let _ = LOCAL_SNAPSHOTS.try_with(|count| count.set(count.get() + 1));
+    }
+
+    // A failure of the first request to a route
+    struct Fault {
+        route: &'static str,
+        status: StatusCode,
+        // Whether the server still handles the request, only its response being lost (e.g. by a proxy giving up)
+        handled: bool,
+    }
+
+    // Hands the requests to the server's router without going through the network
+    // (the router isn't `Sync`, so it is cloned for each request)
+    struct InProcessTransport {
+        router: Mutex<Router>,
+        faults: Mutex<Vec<Fault>>,
+        requests: Mutex<Vec<Url>>,
+    }
+
+    impl InProcessTransport {
+        fn requested(&self, route: &str) -> bool {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|url| url.path() == route)
+        }
+
+        // Paths of the files which were sent to the server
+        fn sent_files(&self) -> Vec<String> {
+            self.requests
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|url| url.path() == "/sync/file")
+                .filter_map(|url| {
+                    url.query_pairs()
+                        .find(|(name, _)| name == "path")
+                        .map(|(_, path)| path.into_owned())
+                })
+                .collect()
+        }
+    }
+
+    impl Transport for InProcessTransport {
+        fn execute(&self, request: Request, body: Option<BodyStream>) -> ResponseFuture<'_> {
+            let router = self.router.lock().unwrap().clone();
+
+            self.requests.lock().unwrap().push(request.url().clone());
+
+            let fault = {
+                let mut faults = self.faults.lock().unwrap();
+
+                faults
+                    .iter()
+                    .position(|fault| fault.route == request.url().path())
+                    .map(|pos| faults.remove(pos))
+            };
+
+            Box::pin(async move {
+                let body = match body {
+                    Some(body) => hyper::Body::wrap_stream(body),
+                    None => hyper::Body::from(
+                        request
+                            .body()
+                            .and_then(|body| body.as_bytes())
+                            .unwrap_or_default()
+                            .to_vec(),
+                    ),
+                };
+
+                let mut req = http::Request::builder()
+                    .method(request.method().clone())
+                    .uri(request.url().as_str())
+                    .body(body)?;
+
+                *req.headers_mut() = request.headers().clone();
+
+                let res = match fault {
+                    Some(Fault {
+                        route: _,
+                        status,
+                        handled,
+                    }) => {
+                        if handled {
+                            router.oneshot(req).await?;
+                        }
+
+                        http::Response::builder()
+                            .status(status)
+                            .body(Bytes::new())?
+                    }
+
+                    None => {
+                        let (parts, body) = router.oneshot(req).await?.into_parts();
+
+                        let body = hyper::body::to_bytes(body)
+                            .await
+                            .context("Failed to read the server's response")?;
+
+                        http::Response::from_parts(parts, body)
+                    }
+                };
+
+                Ok(res.into())
+            })
+        }
+    }
+
+    fn sorted_names(dir: &Path) -> Vec<OsString> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect::<Vec<_>>();
+
+        names.sort();
+        names
+    }
+
+    fn assert_same_content(expected: &Path, actual: &Path) {
+        let names = sorted_names(expected);
+        assert_eq!(names, sorted_names(actual), "in '{}'", actual.display());
+
+        for name in names {
+            let (expected, actual) = (expected.join(&name), actual.join(&name));
+
+            if expected.is_dir() {
+                assert!(actual.is_dir(), "'{}' is not a directory", actual.display());
+                assert_same_content(&expected, &actual);
+            } else {
+                assert_eq!(fs::read(&expected).unwrap(), fs::read(&actual).unwrap());
+            }
+        }
+    }
+
+    // A server with a single slot, and a directory to synchronize into it
+    struct Fixture {
+        source_dir: TempDir,
+        slot_dir: TempDir,
+        server: Server,
+        // Removed last, as the server keeps a lock file in it
+        _data_dir: TempDir,
+    }
+
+    impl Fixture {
+        async fn new() -> Self {
+            let data_dir = TempDir::new().unwrap();
+            let slot_dir = TempDir::new().unwrap();
+
+            let server = harmony_server::setup(harmony_server::cmd::Args::parse_from([
+                "harmony-server".as_ref(),
+                data_dir.path().as_os_str(),
+                "--slots".as_ref(),
+                format!("s1:{}", slot_dir.path().display()).as_ref(),
+                "--secret".as_ref(),
+                "pw".as_ref(),
+            ]))
+            .await
+            .unwrap();
+
+            Self {
+                source_dir: TempDir::new().unwrap(),
+                slot_dir,
+                server,
+                _data_dir: data_dir,
+            }
+        }
+
+        fn source(&self, path: &str) -> PathBuf {
+            self.source_dir.path().join(path)
+        }
+
+        fn transport(&self, faults: Vec<Fault>) -> Arc<InProcessTransport> {
+            Arc::new(InProcessTransport {
+                router: Mutex::new(self.server.router()),
+                faults: Mutex::new(faults),
+                requests: Mutex::new(vec![]),
+            })
+        }
+
+        async fn run(
+            &self,
+            transport: Arc<InProcessTransport>,
+            extra_args: &[&OsStr],
+        ) -> anyhow::Result<Outcome> {
+            let mut args = vec![
+                "harmony-client".as_ref(),
+                self.source_dir.path().as_os_str(),
+                "http://harmony.test".as_ref(),
+                "s1".as_ref(),
+                "--secret".as_ref(),
+                "pw".as_ref(),
+                "--yes".as_ref(),
+            ];
+
+            args.extend(extra_args);
+
+            run(Args::parse_from(args), Some(transport)).await
+        }
+
+        async fn sync(&self) -> Outcome {
+            self.run(self.transport(vec![]), &[]).await.unwrap()
+        }
+
+        fn assert_synced(&self) {
+            assert_same_content(self.source_dir.path(), self.slot_dir.path());
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn syncs_a_directory_through_the_server() {
+        let fixture = Fixture::new().await;
+
+        fs::create_dir_all(fixture.source("a/b")).unwrap();
+        fs::write(fixture.source("file.txt"), "Hello world!").unwrap();
+        fs::write(
+            fixture.source("a/b/data.bin"),
+            (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        assert_eq!(fixture.sync().await, Outcome::Completed);
+        fixture.assert_synced();
+
+        fs::write(
+            fixture.source("file.txt"),
+            "Hello again, with a different size!",
+        )
+        .unwrap();
+        fs::remove_dir_all(fixture.source("a")).unwrap();
+
+        assert_eq!(fixture.sync().await, Outcome::Completed);
+        fixture.assert_synced();
+
+        assert_eq!(fixture.sync().await, Outcome::NothingToDo);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn syncs_empty_dirs() {
+        let fixture = Fixture::new().await;
+
+        fs::create_dir(fixture.source("logs")).unwrap();
+        fs::create_dir_all(fixture.source("deep/x/y")).unwrap();
+        fs::write(fixture.source("file.txt"), "Hello world!").unwrap();
+
+        assert_eq!(fixture.sync().await, Outcome::Completed);
+        fixture.assert_synced();
+        assert!(fixture.slot_dir.path().join("deep/x/y").is_dir());
+
+        fs::remove_dir(fixture.source("logs")).unwrap();
+        fs::remove_dir_all(fixture.source("deep/x")).unwrap();
+
+        assert_eq!(fixture.sync().await, Outcome::Completed);
+        fixture.assert_synced();
+        assert!(!fixture.slot_dir.path().join("logs").exists());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn skips_transferred_files_when_resuming() {
+        let fixture = Fixture::new().await;
+
+        fs::write(fixture.source("a.txt"), "Hello").unwrap();
+        fs::write(fixture.source("b.txt"), "world!").unwrap();
+
+        // Interrupted once all files were transferred
+        let transport = fixture.transport(vec![Fault {
+            route: "/sync/finalize/prepare",
+            status: StatusCode::INTERNAL_SERVER_ERROR,
+            handled: false,
+        }]);
+
+        fixture.run(transport.clone(), &[]).await.unwrap_err();
+
+        let mut sent_files = transport.sent_files();
+        sent_files.sort();
+        assert_eq!(sent_files, ["a.txt", "b.txt"]);
+
+        let transport = fixture.transport(vec![]);
+
+        assert_eq!(
+            fixture.run(transport.clone(), &[]).await.unwrap(),
+            Outcome::Completed
+        );
+
+        assert!(transport.requested("/sync/resume"));
+        assert!(transport.sent_files().is_empty());
+        fixture.assert_synced();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn diffs_against_exported_snapshots() {
+        let fixture = Fixture::new().await;
+        let export_dir = TempDir::new().unwrap();
+        let snapshot_path = export_dir.path().join("snapshot.json");
+
+        fs::create_dir(fixture.source("dir")).unwrap();
+        fs::write(fixture.source("dir/file.txt"), "Hello world!").unwrap();
+
+        let outcome = fixture
+            .run(
+                fixture.transport(vec![]),
+                &["--export-snapshot".as_ref(), snapshot_path.as_os_str()],
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(outcome, Outcome::Completed);
+        fixture.assert_synced();
+
+        let verify_args = [
+            "--remote-snapshot".as_ref(),
+            snapshot_path.as_os_str(),
+            "--verify".as_ref(),
+        ];
+
+        let transport = fixture.transport(vec![]);
+
+        assert_eq!(
+            fixture.run(transport.clone(), &verify_args).await.unwrap(),
+            Outcome::NothingToDo
+        );
+
+        fs::write(fixture.source("dir/file.txt"), "Hello again!!").unwrap();
+
+        let err = fixture
+            .run(transport.clone(), &verify_args)
+            .await
+            .unwrap_err();
+
+        assert!(
+            matches!(err.downcast_ref(), Some(ClientError::ContentsDiffer)),
+            "{err:?}"
+        );
+
+        // The server is never contacted
+        assert!(transport.requests.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn retries_snapshots_and_sync_openings() {
+        let fixture = Fixture::new().await;
+
+        fs::write(fixture.source("file.txt"), "Hello world!").unwrap();
+
+        let transport = fixture.transport(vec![
+            Fault {
+                route: "/snapshot",
+                status: StatusCode::BAD_GATEWAY,
+                handled: false,
+            },
+            // The synchronization is opened, but the client doesn't know about it
+            Fault {
+                route: "/sync/begin",
+                status: StatusCode::GATEWAY_TIMEOUT,
+                handled: true,
+            },
+        ]);
+
+        let (outcome, local_snapshots) = LOCAL_SNAPSHOTS
+            .scope(Cell::new(0), async {
+                let outcome = fixture.run(transport.clone(), &[]).await.unwrap();
+                (outcome, LOCAL_SNAPSHOTS.with(Cell::get))
+            })
+            .await;
+
+        assert_eq!(outcome, Outcome::Completed);
+        assert_eq!(local_snapshots, 1);
+
+        assert!(transport.faults.lock().unwrap().is_empty());
+        assert!(transport.requested("/sync/resume"));
+        fixture.assert_synced();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn ignores_modification_times() {
+        let fixture = Fixture::new().await;
+        let path = fixture.source("file.txt");
+
+        let set_old_mtime = || {
+            File::options()
+                .write(true)
+                .open(&path)
+                .unwrap()
+                .set_modified(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+                .unwrap();
+        };
+
+        fs::write(&path, "Hello world!").unwrap();
+        assert_eq!(fixture.sync().await, Outcome::Completed);
+
+        set_old_mtime();
+
+        let ignore_mtime = ["--ignore-mtime".as_ref()];
+        let check_hash = [
+            "--ignore-mtime".as_ref(),
+            "--ignore-mtime-check-hash".as_ref(),
+        ];
+
+        for args in [&ignore_mtime[..], &check_hash] {
+            let outcome = fixture.run(fixture.transport(vec![]), args).await.unwrap();
+            assert_eq!(outcome, Outcome::NothingToDo);
+        }
+
+        // Same size, but a different content
+        fs::write(&path, "Hello World?").unwrap();
+        set_old_mtime();
+
+        let outcome = fixture
+            .run(fixture.transport(vec![]), &ignore_mtime)
+            .await
+            .unwrap();
+
+        assert_eq!(outcome, Outcome::NothingToDo);
+
+        let transport = fixture.transport(vec![]);
+
+        assert_eq!(
+            fixture.run(transport.clone(), &check_hash).await.unwrap(),
+            Outcome::Completed
+        );
+
+        assert_eq!(transport.sent_files(), ["file.txt"]);
+        fixture.assert_synced();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn prunes_empty_dirs() {
+        let fixture = Fixture::new().await;
+        let slot_dir = fixture.slot_dir.path();
+
+        for dir in ["a/b/c", "docs/empty", "empty"] {
+            fs::create_dir_all(fixture.source(dir)).unwrap();
+        }
+
+        fs::write(fixture.source("a/b/c/file.txt"), "Hello world!").unwrap();
+        fs::write(fixture.source("docs/readme.txt"), "Hello world!").unwrap();
+
+        assert_eq!(fixture.sync().await, Outcome::Completed);
+        fixture.assert_synced();
+
+        fs::remove_file(fixture.source("a/b/c/file.txt")).unwrap();
+
+        // Without pruning, directories left empty are kept
+        assert_eq!(fixture.sync().await, Outcome::Completed);
+        fixture.assert_synced();
+
+        let prune = ["--prune-empty-dirs".as_ref()];
+
+        let outcome = fixture
+            .run(fixture.transport(vec![]), &prune)
+            .await
+            .unwrap();
+        assert_eq!(outcome, Outcome::Completed);
+
+        assert_eq!(sorted_names(slot_dir), ["docs"]);
+        assert_eq!(sorted_names(&slot_dir.join("docs")), ["readme.txt"]);
+
+        let outcome = fixture
+            .run(fixture.transport(vec![]), &prune)
+            .await
+            .unwrap();
+        assert_eq!(outcome, Outcome::NothingToDo);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reports_dry_runs_outcome() {
+        let fixture = Fixture::new().await;
+        let dry_run = ["--dry-run".as_ref()];
+
+        fs::write(fixture.source("file.txt"), "Hello world!").unwrap();
+
+        let transport = fixture.transport(vec![]);
+
+        assert_eq!(
+            fixture.run(transport.clone(), &dry_run).await.unwrap(),
+            Outcome::Completed
+        );
+
+        assert!(transport.sent_files().is_empty());
+        assert!(sorted_names(fixture.slot_dir.path()).is_empty());
+
+        assert_eq!(fixture.sync().await, Outcome::Completed);
+
+        let outcome = fixture
+            .run(fixture.transport(vec![]), &dry_run)
+            .await
+            .unwrap();
+
+        assert_eq!(outcome, Outcome::NothingToDo);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn checks_servers_without_source_dir() {
+        let fixture = Fixture::new().await;
+        let transport = fixture.transport(vec![]);
+
+        let args = Args::parse_from([
+            "harmony-client",
+            "check",
+            "http://harmony.test",
+            "--secret",
+            "pw",
+        ]);
+
+        assert_eq!(
+            run(args, Some(transport.clone())).await.unwrap(),
+            Outcome::Completed
+        );
+
+        assert!(transport.requested("/status"));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn lists_slots_without_source_dir() {
+        let fixture = Fixture::new().await;
+
+        fs::write(fixture.source("file.txt"), "Hello world!").unwrap();
+        assert_eq!(fixture.sync().await, Outcome::Completed);
+
+        let transport = fixture.transport(vec![]);
+
+        let args = Args::parse_from([
+            "harmony-client",
+            "remote-ls",
+            "http://harmony.test",
+            "s1",
+            "--secret",
+            "pw",
+            "--ignore-ext",
+            "txt",
+        ]);
+
+        assert_eq!(
+            run(args, Some(transport.clone())).await.unwrap(),
+            Outcome::Completed
+        );
+
+        assert!(transport.requested("/slot/list"));
+    }
+}
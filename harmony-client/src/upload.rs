@@ -0,0 +1,764 @@
+use std::{
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use colored::Colorize;
+use futures_util::{future, stream, Stream, StreamExt, TryStreamExt};
+use harmony_differ::{
+    crypto::{encrypted_size, EncryptionKey, FileEncryptor, CHUNK_SIZE},
+    delta::{compute_delta, FileSignature},
+    framing::{encode_frame, CRC_FRAMING_HEADER},
+    sparse::{is_sparse, SparseEncoder, SPARSE_ENCODING_HEADER},
+};
+use indicatif::{HumanDuration, MultiProgress, ProgressBar, ProgressStyle};
+use reqwest::{Method, RequestBuilder, Url};
+use serde_json::json;
+use tokio::{
+    fs::File,
+    io::AsyncReadExt,
+    sync::{watch, Mutex},
+    task::JoinSet,
+};
+use tokio_util::{
+    bytes::Bytes,
+    codec::{BytesCodec, Decoder},
+};
+
+use crate::{
+    cmd::SlotSubpath,
+    local_path,
+    progress::{draw_target, emit, ProgressEvent},
+    request_url, request_url_with_body,
+    throttle::RateLimiter,
+    transport::HttpClient,
+    MAX_TRANSFER_ATTEMPTS,
+};
+
+// Files of an open synchronization to upload, and how to upload them
+pub struct Upload {
+    pub client: HttpClient,
+    pub base_url: Url,
+    pub access_token: String,
+    pub slot: String,
+    pub sync_token: String,
+    pub source_dir: PathBuf,
+    pub subpath: Option<SlotSubpath>,
+    pub use_delta: bool,
+    pub crc_frames: bool,
+    pub encryption_key: Option<EncryptionKey>,
+    pub rate_limiter: Option<Arc<RateLimiter>>,
+    pub file_timeout: FileTimeout,
+    pub sparse_transfers: bool,
+    pub max_parallel_transfers: usize,
+    pub locked_files_retries: usize,
+    pub skip_vanished_files: bool,
+}
+
+// Failed and vanished files must be aborted for the synchronization to be finalized
+pub struct UploadReport {
+    pub transferred_files: u64,
+    pub transferred_bytes: u64,
+    pub duration: Duration,
+    pub errors: Vec<TransferError>,
+    pub vanished: Vec<String>,
+}
+
+impl Upload {
+    pub async fn run(
+        self,
+        files: Vec<String>,
+        transfer_size: u64,
+        pause: &mut watch::Receiver<bool>,
+    ) -> Result<UploadReport> {
+        let Self {
+            client,
+            base_url,
+            access_token,
+            slot,
+            sync_token,
+            source_dir,
+            subpath,
+            use_delta,
+            crc_frames,
+            encryption_key,
+            rate_limiter,
+            file_timeout,
+            sparse_transfers,
+            max_parallel_transfers,
+            locked_files_retries,
+            skip_vanished_files,
+        } = self;
+
+        let mp = MultiProgress::with_draw_target(draw_target());
+
+        let pb_msg = Arc::new(
+            mp.add(
+                ProgressBar::new(1)
+                    .with_style(ProgressStyle::with_template("{msg}").unwrap())
+                    .with_message("Running..."),
+            ),
+        );
+
+        let transfer_pb = Arc::new(
+            mp.add(
+                ProgressBar::new(files.len() as u64).with_style(
+                    ProgressStyle::with_template(
+                        "Transferring : [{elapsed_precise}] {prefix} {bar:40} {pos}/{len} files",
+                    )
+                    .unwrap(),
+                ),
+            ),
+        );
+
+        let transfer_size_pb = Arc::new(
+            mp.add(
+                ProgressBar::new(transfer_size).with_style(
+                    ProgressStyle::with_template(
+                        "Transfer size: [{elapsed_precise}] {prefix} {bar:40} {bytes}/{total_bytes} ({binary_bytes_per_sec}, ETA: {eta})",
+                    )
+                    .unwrap(),
+                ),
+            )
+        );
+
+        let errors = Arc::new(Mutex::new(vec![]));
+        let vanished = Arc::new(Mutex::new(vec![]));
+
+        macro_rules! report_err {
+            ($relative_path: expr, $err: expr, $errors: expr, $pb: expr) => {{
+                let mut errors = $errors.lock().await;
+
+                $pb.println(
+                    format!("Failed to transfer file '{}': {}", $relative_path, $err)
+                        .bright_red()
+                        .to_string(),
+                );
+
+                errors.push(TransferError {
+                    relative_path: $relative_path,
+                    message: $err,
+                });
+
+                $pb.set_message(format!(
+                    "Running... (encountered {} error(s))",
+                    errors.len(),
+                ));
+            }};
+        }
+
+        let mut task_pool = JoinSet::new();
+
+        let transfer_ctx = TransferContext {
+            client,
+            base_url,
+            access_token,
+            multi_progress: mp.clone(),
+            transfer_size_pb: Arc::clone(&transfer_size_pb),
+            use_delta,
+            crc_frames,
+            encryption_key,
+            rate_limiter,
+            file_timeout,
+            sparse_transfers,
+        };
+
+        let transfer_started_at = Instant::now();
+
+        let files_total = files.len() as u64;
+        let files_done = Arc::new(AtomicU64::new(0));
+
+        let locked = Arc::new(Mutex::new(vec![]));
+
+        let mut pending = files;
+
+        // Files which were locked are transferred again in additional passes
+        let mut pass = 0;
+
+        loop {
+            for relative_path in pending {
+                let data_dir = source_dir.clone();
+                let subpath = subpath.clone();
+
+                let errors = Arc::clone(&errors);
+                let vanished = Arc::clone(&vanished);
+                let locked = Arc::clone(&locked);
+                let pb_msg = Arc::clone(&pb_msg);
+                let transfer_pb = Arc::clone(&transfer_pb);
+                let files_done = Arc::clone(&files_done);
+
+                // Prepare variables for task closure
+                let transfer_ctx = transfer_ctx.clone();
+                let query = json!({
+                    "slot_name": slot,
+                    "sync_token": sync_token,
+                    "path": relative_path
+                });
+
+                // Send file
+                while task_pool.len() >= max_parallel_transfers {
+                    task_pool.join_next().await.unwrap()?;
+                }
+
+                // Transfers in progress are completed, but no new one starts while paused
+                if *pause.borrow() {
+                    pb_msg.println(
+                        format!(
+                            "Transfers paused, send SIGUSR1 again to resume (e.g. 'kill -USR1 {}')",
+                            std::process::id()
+                        )
+                        .bright_yellow()
+                        .to_string(),
+                    );
+
+                    transfer_pb.set_prefix("(paused)");
+                    transfer_size_pb.set_prefix("(paused)");
+
+                    emit(ProgressEvent::TransfersPaused);
+
+                    // The sender is never dropped on platforms which support pausing
+                    let _ = pause.wait_for(|paused| !paused).await;
+
+                    transfer_pb.set_prefix("");
+                    transfer_size_pb.set_prefix("");
+
+                    emit(ProgressEvent::TransfersResumed);
+
+                    pb_msg.println("Transfers resumed".bright_yellow().to_string());
+                }
+
+                task_pool.spawn(async move {
+                    let mut attempt = 1;
+
+                    loop {
+                        let result = transfer_file(
+                            &transfer_ctx,
+                            &query,
+                            &local_path(&data_dir, subpath.as_ref(), &relative_path),
+                            &relative_path,
+                        )
+                        .await;
+
+                        match result {
+                            Ok(()) => {
+                                transfer_pb.inc(1);
+
+                                emit(ProgressEvent::TransferCompleted {
+                                    path: &relative_path,
+                                    files_done: files_done.fetch_add(1, Ordering::Relaxed) + 1,
+                                    files_total,
+                                    bytes_done: transfer_ctx.transfer_size_pb.position(),
+                                    bytes_total: transfer_size,
+                                });
+
+                                break;
+                            }
+
+                            // Timeouts are usually caused by a transient network problem, so they are worth retrying
+                            Err(err) if is_timeout_error(&err) && attempt < MAX_TRANSFER_ATTEMPTS => {
+                                pb_msg.println(
+                                    format!(
+                                        "Transfer of file '{relative_path}' timed out, retrying (attempt {}/{MAX_TRANSFER_ATTEMPTS})...",
+                                        attempt + 1
+                                    )
+                                    .bright_yellow()
+                                    .to_string(),
+                                );
+
+                                attempt += 1;
+                            }
+
+                            // Transient files are common in active directories
+                            Err(err) if err.is::<FileVanished>() && skip_vanished_files => {
+                                pb_msg.println(
+                                    format!("File '{relative_path}' disappeared before being transferred, skipping it")
+                                        .bright_yellow()
+                                        .to_string(),
+                                );
+
+                                transfer_pb.inc(1);
+
+                                emit(ProgressEvent::TransferSkipped {
+                                    path: &relative_path,
+                                });
+
+                                vanished.lock().await.push(relative_path);
+
+                                break;
+                            }
+
+                            // Locked files are usually released quickly, so they are retried after all other files
+                            Err(err) if is_locked_error(&err) && pass < locked_files_retries => {
+                                pb_msg.println(
+                                    format!("File '{relative_path}' is locked, it will be retried later")
+                                        .bright_yellow()
+                                        .to_string(),
+                                );
+
+                                locked.lock().await.push(relative_path);
+
+                                break;
+                            }
+
+                            Err(err) => {
+                                let message = format!("{err:#}");
+
+                                transfer_pb.inc(1);
+
+                                emit(ProgressEvent::TransferFailed {
+                                    path: &relative_path,
+                                    error: &message,
+                                });
+
+                                report_err!(relative_path, message, errors, pb_msg);
+
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+
+            while let Some(result) = task_pool.join_next().await {
+                result?;
+            }
+
+            pending = std::mem::take(&mut *locked.lock().await);
+
+            if pending.is_empty() {
+                break;
+            }
+
+            pass += 1;
+
+            pb_msg.println(
+                format!(
+                    "Retrying {} locked file(s) in {} (pass {pass}/{locked_files_retries})...",
+                    pending.len(),
+                    HumanDuration(LOCKED_FILES_RETRY_DELAY)
+                )
+                .bright_yellow()
+                .to_string(),
+            );
+
+            tokio::time::sleep(LOCKED_FILES_RETRY_DELAY).await;
+        }
+
+        pb_msg.finish_and_clear();
+        transfer_pb.finish_and_clear();
+        transfer_size_pb.finish_and_clear();
+
+        let errors = std::mem::take(&mut *errors.lock().await);
+        let vanished = std::mem::take(&mut *vanished.lock().await);
+
+        // Files which failed or vanished were not transferred
+        Ok(UploadReport {
+            transferred_files: files_done.load(Ordering::Relaxed),
+            transferred_bytes: transfer_size_pb.position(),
+            duration: transfer_started_at.elapsed(),
+            errors,
+            vanished,
+        })
+    }
+}
+
+pub struct TransferError {
+    pub relative_path: String,
+    pub message: String,
+}
+
+#[derive(Debug)]
+pub struct FileVanished;
+
+impl std::fmt::Display for FileVanished {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "File disappeared from the local directory")
+    }
+}
+
+impl std::error::Error for FileVanished {}
+
+// Delay before each new attempt to transfer the files which were locked
+const LOCKED_FILES_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+// Files smaller than this are always transferred entirely
+const DELTA_MIN_FILE_SIZE: u64 = 1024 * 1024;
+
+// Files larger than this get their own progress bar during transfer
+const FILE_PROGRESS_MIN_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Clone)]
+struct TransferContext {
+    client: HttpClient,
+    base_url: Url,
+    access_token: String,
+    multi_progress: MultiProgress,
+    transfer_size_pb: Arc<ProgressBar>,
+    use_delta: bool,
+    crc_frames: bool,
+    encryption_key: Option<EncryptionKey>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    file_timeout: FileTimeout,
+    sparse_transfers: bool,
+}
+
+// Stalled transfers are aborted after this timeout, so they don't hold a transfer slot forever
+#[derive(Clone, Copy)]
+pub struct FileTimeout {
+    pub base: Duration,
+    pub min_rate: u64,
+    pub fixed: Option<Duration>,
+}
+
+impl FileTimeout {
+    fn for_size(&self, size: u64) -> Duration {
+        self.fixed
+            .unwrap_or_else(|| self.base + Duration::from_secs(size / self.min_rate))
+    }
+}
+
+async fn transfer_file(
+    ctx: &TransferContext,
+    query: &serde_json::Value,
+    path: &Path,
+    relative_path: &str,
+) -> Result<()> {
+    let TransferContext {
+        client,
+        base_url,
+        access_token,
+        multi_progress,
+        transfer_size_pb,
+        use_delta,
+        crc_frames,
+        encryption_key,
+        rate_limiter,
+        file_timeout,
+        sparse_transfers,
+    } = ctx;
+
+    let file = match File::open(path).await {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Err(FileVanished.into()),
+        result => result.context("Failed to open file for transfer")?,
+    };
+
+    let metadata = file
+        .metadata()
+        .await
+        .context("Failed to get file's metadata")?;
+
+    let size = metadata.len();
+
+    // Encrypted content has no holes
+    let sparse = *sparse_transfers && encryption_key.is_none() && is_sparse(&metadata);
+
+    emit(ProgressEvent::TransferStarted {
+        path: relative_path,
+        size,
+    });
+
+    let timeout = file_timeout.for_size(size);
+
+    if *use_delta
+        && size >= DELTA_MIN_FILE_SIZE
+        && transfer_file_delta(ctx, query, path, size).await?
+    {
+        return Ok(());
+    }
+
+    let sent = Arc::new(AtomicU64::new(0));
+
+    let stream: ChunkStream = match encryption_key {
+        None if sparse => Box::pin(sparse_file_stream(file)),
+        None => Box::pin(
+            BytesCodec::new()
+                .framed(file)
+                .map_ok(|chunk| with_chunk_len(chunk.freeze())),
+        ),
+        Some(encryption_key) => {
+            Box::pin(encrypted_file_stream(file, size, encryption_key).map_ok(with_chunk_len))
+        }
+    };
+
+    let file_pb = if size >= FILE_PROGRESS_MIN_SIZE {
+        let transferred_size = match encryption_key {
+            None => size,
+            Some(_) => encrypted_size(size),
+        };
+
+        Some(
+            multi_progress.add(
+                ProgressBar::new(transferred_size)
+                    .with_style(
+                        ProgressStyle::with_template(
+                            "{msg} {bar:40} {bytes}/{total_bytes} ({binary_bytes_per_sec}, ETA: {eta})",
+                        )
+                        .unwrap(),
+                    )
+                    .with_message(relative_path.to_owned()),
+            ),
+        )
+    } else {
+        None
+    };
+
+    let stream = stream
+        .and_then({
+            let rate_limiter = rate_limiter.clone();
+
+            move |(chunk, content_len)| {
+                let rate_limiter = rate_limiter.clone();
+
+                async move {
+                    if let Some(rate_limiter) = rate_limiter {
+                        rate_limiter.acquire(chunk.len() as u64).await;
+                    }
+
+                    Ok((chunk, content_len))
+                }
+            }
+        })
+        .inspect_ok({
+            let sent = Arc::clone(&sent);
+            let transfer_size_pb = Arc::clone(transfer_size_pb);
+            let file_pb = file_pb.clone();
+
+            move |(_, content_len)| {
+                let size = *content_len;
+
+                sent.fetch_add(size, Ordering::Relaxed);
+                transfer_size_pb.inc(size);
+
+                if let Some(file_pb) = &file_pb {
+                    file_pb.inc(size);
+                }
+            }
+        })
+        .map_ok(|(chunk, _)| chunk);
+
+    let result = if *crc_frames {
+        request_url_with_body::<()>(
+            client,
+            Method::POST,
+            "/sync/file",
+            base_url,
+            access_token,
+            |client| {
+                with_sparse_header(client.timeout(timeout).query(query), sparse)
+                    .header(CRC_FRAMING_HEADER, "1")
+            },
+            Some(Box::pin(
+                stream.map_ok(|chunk| Bytes::from(encode_frame(&chunk))),
+            )),
+        )
+        .await
+    } else {
+        request_url_with_body::<()>(
+            client,
+            Method::POST,
+            "/sync/file",
+            base_url,
+            access_token,
+            |client| with_sparse_header(client.timeout(timeout).query(query), sparse),
+            Some(Box::pin(stream)),
+        )
+        .await
+    };
+
+    if let Some(file_pb) = file_pb {
+        file_pb.finish_and_clear();
+    }
+
+    if result.is_err() {
+        // Don't count bytes from a failed transfer as they will be sent again if it is retried
+        let sent = sent.load(Ordering::Relaxed);
+        transfer_size_pb.set_position(transfer_size_pb.position().saturating_sub(sent));
+    }
+
+    result
+}
+
+// Try to only send the parts of the file which changed since its previous version on the server
+// Returns `false` if a full transfer is required instead
+async fn transfer_file_delta(
+    ctx: &TransferContext,
+    query: &serde_json::Value,
+    path: &Path,
+    size: u64,
+) -> Result<bool> {
+    let TransferContext {
+        client,
+        base_url,
+        access_token,
+        multi_progress: _,
+        transfer_size_pb,
+        use_delta: _,
+        crc_frames: _,
+        encryption_key: _,
+        rate_limiter,
+        file_timeout,
+        sparse_transfers: _,
+    } = ctx;
+
+    let signature = request_url::<Option<FileSignature>>(
+        client,
+        Method::POST,
+        "/sync/signature",
+        base_url,
+        access_token,
+        |client| client.json(query),
+    )
+    .await
+    .context("Failed to get the signature of the file's previous version")?;
+
+    let Some(signature) = signature else {
+        return Ok(false);
+    };
+
+    let path = path.to_owned();
+
+    let delta = tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&path).context("Failed to open file for transfer")?;
+        let mut delta = vec![];
+
+        // Deltas larger than half the file's size aren't worth it
+        let stats = compute_delta(&signature, file, &mut delta, size / 2)?;
+
+        Ok::<_, anyhow::Error>(stats.map(|_| delta))
+    })
+    .await
+    .context("Failed to run delta computation")?
+    .context("Failed to compute delta")?;
+
+    let Some(delta) = delta else {
+        return Ok(false);
+    };
+
+    if let Some(rate_limiter) = rate_limiter {
+        rate_limiter.acquire(delta.len() as u64).await;
+    }
+
+    request_url::<()>(
+        client,
+        Method::POST,
+        "/sync/delta",
+        base_url,
+        access_token,
+        |client| {
+            client
+                .timeout(file_timeout.for_size(delta.len() as u64))
+                .query(query)
+                .body(delta)
+        },
+    )
+    .await?;
+
+    transfer_size_pb.inc(size);
+
+    Ok(true)
+}
+
+// Chunks to send, along with the amount of the file's content they represent
+type ChunkStream = Pin<Box<dyn Stream<Item = std::io::Result<(Bytes, u64)>> + Send + Sync>>;
+
+fn with_chunk_len(chunk: Bytes) -> (Bytes, u64) {
+    let len = chunk.len() as u64;
+    (chunk, len)
+}
+
+fn with_sparse_header(client: RequestBuilder, sparse: bool) -> RequestBuilder {
+    if sparse {
+        client.header(SPARSE_ENCODING_HEADER, "1")
+    } else {
+        client
+    }
+}
+
+// Size of the chunks sparse files are read by, which are then split in blocks to find holes
+const SPARSE_READ_SIZE: u64 = 1024 * 1024;
+
+// Zero-filled parts of the file are sent as holes, which the server doesn't write
+fn sparse_file_stream(file: File) -> impl Stream<Item = std::io::Result<(Bytes, u64)>> {
+    stream::try_unfold(Some((file, SparseEncoder::default())), |state| async move {
+        let Some((mut file, mut encoder)) = state else {
+            return Ok(None);
+        };
+
+        let mut chunk = vec![];
+        (&mut file)
+            .take(SPARSE_READ_SIZE)
+            .read_to_end(&mut chunk)
+            .await?;
+
+        if chunk.is_empty() {
+            let trailing_hole = encoder.finish();
+
+            return Ok((!trailing_hole.is_empty()).then(|| ((Bytes::from(trailing_hole), 0), None)));
+        }
+
+        let encoded = encoder.push(&chunk);
+
+        Ok(Some((
+            (Bytes::from(encoded), chunk.len() as u64),
+            Some((file, encoder)),
+        )))
+    })
+}
+
+fn encrypted_file_stream(
+    file: File,
+    size: u64,
+    encryption_key: &EncryptionKey,
+) -> impl Stream<Item = std::io::Result<Bytes>> {
+    let encryptor = FileEncryptor::new(encryption_key);
+    let header = Bytes::from(encryptor.header());
+
+    let chunks = stream::try_unfold(Some((file, encryptor, size)), |state| async move {
+        let Some((mut file, mut encryptor, remaining)) = state else {
+            return Ok(None);
+        };
+
+        let len = std::cmp::min(remaining, CHUNK_SIZE as u64);
+        let mut chunk = vec![0; usize::try_from(len).unwrap()];
+
+        file.read_exact(&mut chunk).await?;
+
+        let remaining = remaining - len;
+        let last = remaining == 0;
+
+        let encrypted = encryptor
+            .encrypt_chunk(&chunk, last)
+            .map_err(std::io::Error::other)?;
+
+        Ok(Some((
+            Bytes::from(encrypted),
+            (!last).then_some((file, encryptor, remaining)),
+        )))
+    });
+
+    stream::once(future::ready(Ok(header))).chain(chunks)
+}
+
+fn is_timeout_error(err: &anyhow::Error) -> bool {
+    err.chain().any(|err| {
+        err.downcast_ref::<reqwest::Error>()
+            .is_some_and(reqwest::Error::is_timeout)
+    })
+}
+
+// Files opened exclusively by another process (e.g. a live database) can't be read on Windows,
+// while locks are only advisory on other platforms
+fn is_locked_error(err: &anyhow::Error) -> bool {
+    // ERROR_SHARING_VIOLATION and ERROR_LOCK_VIOLATION
+    cfg!(windows)
+        && err.chain().any(|err| {
+            err.downcast_ref::<std::io::Error>()
+                .is_some_and(|err| matches!(err.raw_os_error(), Some(32 | 33)))
+        })
+}
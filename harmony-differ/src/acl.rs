@@ -0,0 +1,125 @@
+use std::path::Path;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+// POSIX ACLs of an item, as the raw content of the extended attributes storing them (in hexadecimal)
+// Entries reference users and groups by their numeric ID, which are restored as-is
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct ItemAcl {
+    #[serde(default)]
+    pub access: Option<String>,
+    // Only directories have a default ACL, which is inherited by the items created inside them
+    #[serde(default)]
+    pub default: Option<String>,
+}
+
+impl ItemAcl {
+    // Items without ACL only rely on their mode bits
+    pub fn is_empty(&self) -> bool {
+        self.access.is_none() && self.default.is_none()
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{io::ErrorKind, path::Path};
+
+    use anyhow::{Context, Result};
+
+    use crate::protocol::{decode_hex, encode_hex};
+
+    use super::ItemAcl;
+
+    const ACCESS_ACL_ATTR: &str = "system.posix_acl_access";
+    const DEFAULT_ACL_ATTR: &str = "system.posix_acl_default";
+
+    pub const SUPPORTED: bool = true;
+
+    pub fn read_acl(path: &Path) -> Result<Option<ItemAcl>> {
+        let read = |name| match xattr::get(path, name) {
+            Ok(value) => Ok(Some(value.map(|value| encode_hex(&value)))),
+            Err(err) if err.kind() == ErrorKind::Unsupported => Ok(None),
+            Err(err) => Err(err).with_context(|| format!("Failed to read ACL '{name}'")),
+        };
+
+        let Some(access) = read(ACCESS_ACL_ATTR)? else {
+            return Ok(None);
+        };
+
+        let default = if path.is_dir() {
+            read(DEFAULT_ACL_ATTR)?.flatten()
+        } else {
+            None
+        };
+
+        Ok(Some(ItemAcl { access, default }))
+    }
+
+    pub fn write_acl(path: &Path, acl: &ItemAcl) -> Result<()> {
+        let ItemAcl { access, default } = acl;
+
+        let is_dir = path.is_dir();
+
+        for (name, value) in [(ACCESS_ACL_ATTR, access), (DEFAULT_ACL_ATTR, default)] {
+            // Files can't have a default ACL
+            if name == DEFAULT_ACL_ATTR && !is_dir {
+                continue;
+            }
+
+            match value {
+                Some(value) => {
+                    let value = decode_hex(value).context("Invalid ACL content")?;
+
+                    xattr::set(path, name, &value)
+                        .with_context(|| format!("Failed to set ACL '{name}'"))?;
+                }
+
+                None => {
+                    let exists = xattr::get(path, name)
+                        .with_context(|| format!("Failed to read ACL '{name}'"))?
+                        .is_some();
+
+                    if exists {
+                        xattr::remove(path, name)
+                            .with_context(|| format!("Failed to remove ACL '{name}'"))?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::path::Path;
+
+    use anyhow::Result;
+
+    use super::ItemAcl;
+
+    pub const SUPPORTED: bool = false;
+
+    pub fn read_acl(_: &Path) -> Result<Option<ItemAcl>> {
+        Ok(None)
+    }
+
+    pub fn write_acl(_: &Path, _: &ItemAcl) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Whether ACLs can be handled on this platform at all
+pub const ACLS_SUPPORTED: bool = imp::SUPPORTED;
+
+// Returns `None` if the filesystem doesn't support ACLs
+pub fn read_acl(path: &Path) -> Result<Option<ItemAcl>> {
+    imp::read_acl(path)
+}
+
+// Absent ACLs are removed from the item
+pub fn write_acl(path: &Path, acl: &ItemAcl) -> Result<()> {
+    imp::write_acl(path, acl)
+}
@@ -0,0 +1,66 @@
+use std::{collections::HashMap, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::snapshot::{
+    portable_path, SnapshotItem, SnapshotItemMetadata, SnapshotOptions, SnapshotResult,
+};
+
+// Modification time of a directory, as seconds and nanoseconds since the Unix epoch
+pub type DirModificationTime = (u64, u32);
+
+// Cache of a previous snapshot, allowing to reuse the entries of directories which didn't change
+//
+// A directory's modification time only changes when items are added, removed or renamed inside it,
+// so files which are modified in place inside an unchanged directory can't be detected this way.
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotCache {
+    from_dir: String,
+    options: SnapshotOptions,
+    dirs_mtime: HashMap<String, DirModificationTime>,
+    items: HashMap<String, SnapshotItem>,
+}
+
+impl SnapshotCache {
+    pub fn new(result: &SnapshotResult) -> Self {
+        Self {
+            from_dir: result.snapshot.from_dir.clone(),
+            options: result.options.clone(),
+            dirs_mtime: result.dirs_mtime.clone(),
+            items: result
+                .snapshot
+                .items
+                .iter()
+                .map(|item| (item.relative_path.clone(), item.clone()))
+                .collect(),
+        }
+    }
+
+    // Check if the cache can be used for a new snapshot
+    pub fn is_valid_for(&self, from_dir: &str, options: &SnapshotOptions) -> bool {
+        // The cutoff date doesn't change how cached items were analyzed, only which ones are kept
+        let comparable = |options: &SnapshotOptions| SnapshotOptions {
+            modified_since: None,
+            ..options.clone()
+        };
+
+        self.from_dir == from_dir && comparable(&self.options) == comparable(options)
+    }
+
+    // Get a file's cached entry if its parent directory didn't change since the cache was built
+    pub(crate) fn reusable_file(
+        &self,
+        relative_path: &Path,
+        dirs_mtime: &HashMap<String, DirModificationTime>,
+    ) -> Option<&SnapshotItem> {
+        let parent = portable_path(relative_path.parent()?)?;
+
+        if self.dirs_mtime.get(&parent)? != dirs_mtime.get(&parent)? {
+            return None;
+        }
+
+        self.items
+            .get(&portable_path(relative_path)?)
+            .filter(|item| matches!(item.metadata, SnapshotItemMetadata::File(_)))
+    }
+}
@@ -0,0 +1,189 @@
+use std::io::{BufRead, BufReader, Read, Write};
+
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::Argon2;
+use chacha20poly1305::{
+    aead::{Aead, OsRng, Payload},
+    AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
+};
+use serde::{Deserialize, Serialize};
+
+// Files are encrypted by chunks of this size, each one being authenticated separately
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+const MAGIC: &[u8; 4] = b"HMC1";
+const NONCE_PREFIX_SIZE: usize = 16;
+const HEADER_SIZE: u64 = (MAGIC.len() + NONCE_PREFIX_SIZE) as u64;
+const TAG_SIZE: u64 = 16;
+
+// Associated data marking the last chunk, which prevents truncation attacks
+const AD_LAST_CHUNK: &[u8] = b"last";
+const AD_OTHER_CHUNK: &[u8] = b"more";
+
+// Stored on the server so all clients derive the same key, and can check they use the right passphrase
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SlotEncryption {
+    pub salt: [u8; 16],
+    pub key_check: [u8; 32],
+}
+
+impl SlotEncryption {
+    pub fn generate(passphrase: &str) -> Result<(Self, EncryptionKey)> {
+        let salt: [u8; 16] = XChaCha20Poly1305::generate_nonce(&mut OsRng)[..16]
+            .try_into()
+            .unwrap();
+
+        let key = EncryptionKey::derive(passphrase, &salt)?;
+
+        Ok((
+            Self {
+                salt,
+                key_check: key.key_check(),
+            },
+            key,
+        ))
+    }
+
+    pub fn unlock(&self, passphrase: &str) -> Result<EncryptionKey> {
+        let key = EncryptionKey::derive(passphrase, &self.salt)?;
+
+        if key.key_check() != self.key_check {
+            bail!("Provided encryption passphrase is incorrect");
+        }
+
+        Ok(key)
+    }
+}
+
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    fn derive(passphrase: &str, salt: &[u8; 16]) -> Result<Self> {
+        let mut key = [0; 32];
+
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+            .map_err(|err| anyhow!("Failed to derive encryption key: {err}"))?;
+
+        Ok(Self(key))
+    }
+
+    fn key_check(&self) -> [u8; 32] {
+        blake3::keyed_hash(&self.0, b"harmony encryption key check").into()
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(&self.0.into())
+    }
+}
+
+pub fn encrypted_size(plain_size: u64) -> u64 {
+    let chunks = std::cmp::max(1, plain_size.div_ceil(CHUNK_SIZE as u64));
+    HEADER_SIZE + plain_size + chunks * TAG_SIZE
+}
+
+pub struct FileEncryptor {
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_SIZE],
+    counter: u64,
+}
+
+impl FileEncryptor {
+    pub fn new(key: &EncryptionKey) -> Self {
+        Self {
+            cipher: key.cipher(),
+            nonce_prefix: XChaCha20Poly1305::generate_nonce(&mut OsRng)[..NONCE_PREFIX_SIZE]
+                .try_into()
+                .unwrap(),
+            counter: 0,
+        }
+    }
+
+    pub fn header(&self) -> Vec<u8> {
+        let mut header = MAGIC.to_vec();
+        header.extend(self.nonce_prefix);
+        header
+    }
+
+    // All chunks except the last one must be exactly `CHUNK_SIZE` bytes long
+    pub fn encrypt_chunk(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter);
+        self.counter += 1;
+
+        self.cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: chunk,
+                    aad: if last { AD_LAST_CHUNK } else { AD_OTHER_CHUNK },
+                },
+            )
+            .map_err(|err| anyhow!("Failed to encrypt chunk: {err}"))
+    }
+}
+
+pub fn decrypt_file(key: &EncryptionKey, reader: impl Read, mut writer: impl Write) -> Result<u64> {
+    let mut reader = BufReader::new(reader);
+
+    let mut header = [0; HEADER_SIZE as usize];
+
+    reader
+        .read_exact(&mut header)
+        .context("Failed to read encryption header")?;
+
+    if &header[..MAGIC.len()] != MAGIC {
+        bail!("File is not encrypted or uses an unsupported format");
+    }
+
+    let nonce_prefix: [u8; NONCE_PREFIX_SIZE] = header[MAGIC.len()..].try_into().unwrap();
+
+    let cipher = key.cipher();
+    let mut counter = 0;
+    let mut written = 0;
+
+    loop {
+        let mut chunk = vec![];
+
+        reader
+            .by_ref()
+            .take(CHUNK_SIZE as u64 + TAG_SIZE)
+            .read_to_end(&mut chunk)
+            .context("Failed to read encrypted chunk")?;
+
+        let last = reader
+            .fill_buf()
+            .context("Failed to read encrypted file")?
+            .is_empty();
+
+        let plain = cipher
+            .decrypt(
+                &chunk_nonce(&nonce_prefix, counter),
+                Payload {
+                    msg: &chunk,
+                    aad: if last { AD_LAST_CHUNK } else { AD_OTHER_CHUNK },
+                },
+            )
+            .map_err(|_| anyhow!("Failed to decrypt chunk {counter}: file is corrupted, truncated or the key is incorrect"))?;
+
+        writer
+            .write_all(&plain)
+            .context("Failed to write decrypted content")?;
+
+        written += plain.len() as u64;
+        counter += 1;
+
+        if last {
+            break;
+        }
+    }
+
+    Ok(written)
+}
+
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_SIZE], counter: u64) -> XNonce {
+    let mut nonce = [0; 24];
+    nonce[..NONCE_PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[NONCE_PREFIX_SIZE..].copy_from_slice(&counter.to_be_bytes());
+    nonce.into()
+}
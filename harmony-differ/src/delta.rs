@@ -0,0 +1,357 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    io::{BufReader, ErrorKind, Read, Seek, SeekFrom, Write},
+};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+const MIN_BLOCK_SIZE: u64 = 2 * 1024;
+const MAX_BLOCK_SIZE: u64 = 1024 * 1024;
+
+// Literal data is flushed by chunks of this size to avoid keeping it all in memory
+const MAX_LITERAL_CHUNK: usize = 1024 * 1024;
+
+const OP_COPY: u8 = 0;
+const OP_DATA: u8 = 1;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FileSignature {
+    pub file_size: u64,
+    pub block_size: u64,
+    pub blocks: Vec<BlockSignature>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct BlockSignature {
+    pub weak: u32,
+    pub strong: u128,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeltaStats {
+    pub copied_bytes: u64,
+    pub literal_bytes: u64,
+}
+
+// Use blocks of roughly the square root of the file's size, like rsync does
+pub fn block_size_for(file_size: u64) -> u64 {
+    ((file_size as f64).sqrt() as u64).clamp(MIN_BLOCK_SIZE, MAX_BLOCK_SIZE)
+}
+
+pub fn compute_signature(reader: impl Read, file_size: u64) -> Result<FileSignature> {
+    let block_size = block_size_for(file_size);
+
+    let mut reader = BufReader::new(reader);
+    let mut blocks = vec![];
+    let mut read_size = 0;
+
+    loop {
+        let mut block = vec![];
+
+        reader
+            .by_ref()
+            .take(block_size)
+            .read_to_end(&mut block)
+            .context("Failed to read file")?;
+
+        if block.is_empty() {
+            break;
+        }
+
+        read_size += block.len() as u64;
+
+        blocks.push(BlockSignature {
+            weak: RollingChecksum::new(&block).digest(),
+            strong: strong_hash(&block),
+        });
+    }
+
+    if read_size != file_size {
+        bail!("File's size changed while computing its signature");
+    }
+
+    Ok(FileSignature {
+        file_size,
+        block_size,
+        blocks,
+    })
+}
+
+// Compute the delta between the file described by the signature and the provided content,
+// and write it to the provided writer
+//
+// If the encoded delta would be larger than the provided maximum, `None` is returned instead
+// so the caller can fall back to a full transfer.
+pub fn compute_delta(
+    signature: &FileSignature,
+    reader: impl Read,
+    mut writer: impl Write,
+    max_delta_size: u64,
+) -> Result<Option<DeltaStats>> {
+    let block_size = usize::try_from(signature.block_size).unwrap();
+
+    let mut weak_index = HashMap::<u32, Vec<usize>>::new();
+
+    for (i, block) in signature.blocks.iter().enumerate() {
+        weak_index.entry(block.weak).or_default().push(i);
+    }
+
+    let block_len = |i: usize| -> usize {
+        if i + 1 == signature.blocks.len() {
+            usize::try_from(signature.file_size - signature.block_size * i as u64).unwrap()
+        } else {
+            block_size
+        }
+    };
+
+    let mut bytes = BufReader::new(reader).bytes();
+
+    let mut encoder = DeltaEncoder {
+        writer: &mut writer,
+        written: 0,
+        max_size: max_delta_size,
+        stats: DeltaStats {
+            copied_bytes: 0,
+            literal_bytes: 0,
+        },
+    };
+
+    let mut window = VecDeque::with_capacity(block_size);
+    let mut literal = vec![];
+    let mut eof = false;
+
+    fill_window(&mut bytes, &mut window, block_size, &mut eof)?;
+
+    let mut checksum = RollingChecksum::new(window.make_contiguous());
+
+    while !window.is_empty() {
+        let matching_block = weak_index.get(&checksum.digest()).and_then(|candidates| {
+            let strong = strong_hash(window.make_contiguous());
+
+            candidates
+                .iter()
+                .copied()
+                .find(|i| block_len(*i) == window.len() && signature.blocks[*i].strong == strong)
+        });
+
+        if let Some(i) = matching_block {
+            if !encoder.data(&literal)? {
+                return Ok(None);
+            }
+
+            literal.clear();
+
+            if !encoder.copy(signature.block_size * i as u64, window.len() as u64)? {
+                return Ok(None);
+            }
+
+            window.clear();
+            fill_window(&mut bytes, &mut window, block_size, &mut eof)?;
+            checksum = RollingChecksum::new(window.make_contiguous());
+
+            continue;
+        }
+
+        let removed = window.pop_front().unwrap();
+        literal.push(removed);
+
+        if eof {
+            checksum.remove(removed, window.len() + 1);
+        } else {
+            match next_byte(&mut bytes)? {
+                Some(byte) => {
+                    window.push_back(byte);
+                    checksum.roll(removed, byte, window.len());
+                }
+                None => {
+                    eof = true;
+                    checksum.remove(removed, window.len() + 1);
+                }
+            }
+        }
+
+        if literal.len() >= MAX_LITERAL_CHUNK {
+            if !encoder.data(&literal)? {
+                return Ok(None);
+            }
+
+            literal.clear();
+        }
+    }
+
+    if !encoder.data(&literal)? {
+        return Ok(None);
+    }
+
+    Ok(Some(encoder.stats))
+}
+
+// Rebuild a file from its previous version and a delta computed with `compute_delta`
+pub fn apply_delta(
+    mut old: impl Read + Seek,
+    delta: impl Read,
+    mut writer: impl Write,
+) -> Result<u64> {
+    let mut delta = BufReader::new(delta);
+    let mut written = 0;
+
+    loop {
+        let mut tag = [0];
+
+        match delta.read_exact(&mut tag) {
+            Ok(()) => {}
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err).context("Failed to read delta"),
+        }
+
+        match tag[0] {
+            OP_COPY => {
+                let offset = read_u64(&mut delta)?;
+                let len = read_u64(&mut delta)?;
+
+                old.seek(SeekFrom::Start(offset))
+                    .context("Failed to seek in previous version of the file")?;
+
+                let copied = std::io::copy(&mut old.by_ref().take(len), &mut writer)
+                    .context("Failed to copy block from previous version of the file")?;
+
+                if copied != len {
+                    bail!("Delta references a block outside of the previous version of the file");
+                }
+
+                written += len;
+            }
+
+            OP_DATA => {
+                let len = read_u64(&mut delta)?;
+
+                let copied = std::io::copy(&mut delta.by_ref().take(len), &mut writer)
+                    .context("Failed to write literal data")?;
+
+                if copied != len {
+                    bail!("Delta is truncated");
+                }
+
+                written += len;
+            }
+
+            tag => bail!("Invalid delta operation tag: {tag}"),
+        }
+    }
+
+    Ok(written)
+}
+
+struct DeltaEncoder<W: Write> {
+    writer: W,
+    written: u64,
+    max_size: u64,
+    stats: DeltaStats,
+}
+
+impl<W: Write> DeltaEncoder<W> {
+    fn copy(&mut self, offset: u64, len: u64) -> Result<bool> {
+        self.stats.copied_bytes += len;
+        self.write(&[&[OP_COPY], &offset.to_le_bytes(), &len.to_le_bytes()])
+    }
+
+    fn data(&mut self, data: &[u8]) -> Result<bool> {
+        if data.is_empty() {
+            return Ok(true);
+        }
+
+        self.stats.literal_bytes += data.len() as u64;
+        self.write(&[&[OP_DATA], &(data.len() as u64).to_le_bytes(), data])
+    }
+
+    fn write(&mut self, parts: &[&[u8]]) -> Result<bool> {
+        for part in parts {
+            self.written += part.len() as u64;
+
+            if self.written > self.max_size {
+                return Ok(false);
+            }
+
+            self.writer
+                .write_all(part)
+                .context("Failed to write delta")?;
+        }
+
+        Ok(true)
+    }
+}
+
+fn next_byte(bytes: &mut impl Iterator<Item = std::io::Result<u8>>) -> Result<Option<u8>> {
+    bytes.next().transpose().context("Failed to read file")
+}
+
+fn fill_window(
+    bytes: &mut impl Iterator<Item = std::io::Result<u8>>,
+    window: &mut VecDeque<u8>,
+    block_size: usize,
+    eof: &mut bool,
+) -> Result<()> {
+    while !*eof && window.len() < block_size {
+        match next_byte(bytes)? {
+            Some(byte) => window.push_back(byte),
+            None => *eof = true,
+        }
+    }
+
+    Ok(())
+}
+
+fn read_u64(reader: &mut impl Read) -> Result<u64> {
+    let mut bytes = [0; 8];
+
+    reader
+        .read_exact(&mut bytes)
+        .context("Delta is truncated")?;
+
+    Ok(u64::from_le_bytes(bytes))
+}
+
+fn strong_hash(data: &[u8]) -> u128 {
+    let hash = blake3::hash(data);
+    u128::from_le_bytes(hash.as_bytes()[..16].try_into().unwrap())
+}
+
+// Adler-32-like checksum which can be updated in constant time when the window moves
+struct RollingChecksum {
+    a: u32,
+    b: u32,
+}
+
+impl RollingChecksum {
+    fn new(data: &[u8]) -> Self {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+
+        for (i, byte) in data.iter().enumerate() {
+            a = a.wrapping_add(u32::from(*byte));
+            b = b.wrapping_add((data.len() - i) as u32 * u32::from(*byte));
+        }
+
+        Self { a, b }
+    }
+
+    // Remove the first byte of a window of length `len`
+    fn remove(&mut self, removed: u8, len: usize) {
+        self.a = self.a.wrapping_sub(u32::from(removed));
+        self.b = self
+            .b
+            .wrapping_sub((len as u32).wrapping_mul(u32::from(removed)));
+    }
+
+    // Move a window of length `len` by one byte
+    fn roll(&mut self, removed: u8, added: u8, len: usize) {
+        self.remove(removed, len);
+        self.a = self.a.wrapping_add(u32::from(added));
+        self.b = self.b.wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | ((self.b & 0xffff) << 16)
+    }
+}
@@ -1,18 +1,44 @@
-use crate::snapshot::{Snapshot, SnapshotFileMetadata, SnapshotItem, SnapshotItemMetadata};
+use crate::{
+    acl::ItemAcl,
+    snapshot::{
+        make_snapshot, HardlinkId, SchemaVersion, Snapshot, SnapshotFileMetadata, SnapshotItem,
+        SnapshotItemMetadata, SnapshotOptions,
+    },
+    xattrs::ItemXattrs,
+};
 
 use std::{
     collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
     time::Duration,
 };
 
+use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
+// Modification times closer than this are considered equal, as some filesystems don't store them more precisely
+pub const DEFAULT_TIME_GRANULARITY: Duration = Duration::from_secs(1);
+
 #[derive(Serialize, Deserialize)]
 pub struct Diff {
+    #[serde(default = "SchemaVersion::unversioned")]
+    pub version: SchemaVersion,
     pub added: Vec<(String, DiffItemAdded)>,
     pub modified: Vec<(String, DiffItemModified)>,
     pub type_changed: Vec<(String, DiffItemTypeChanged)>,
     pub deleted: Vec<(String, DiffItemDeleted)>,
+    // Files sharing their content with another one through a hard link, associated to said other file
+    #[serde(default)]
+    pub hardlinks: Vec<(String, String)>,
+    // Modified files whose content is known to be unchanged, which only need their modification time to be updated
+    #[serde(default)]
+    pub touched: Vec<String>,
+    // Items whose ACL must be set once their content is in place
+    #[serde(default)]
+    pub acls: Vec<(String, ItemAcl)>,
+    // Items whose extended attributes must be set once their content is in place
+    #[serde(default)]
+    pub xattrs: Vec<(String, ItemXattrs)>,
 }
 
 impl Diff {
@@ -32,10 +58,15 @@ impl Diff {
         }
 
         Self {
+            version: SchemaVersion::default(),
             added,
             modified,
             type_changed,
             deleted,
+            hardlinks: vec![],
+            touched: vec![],
+            acls: vec![],
+            xattrs: vec![],
         }
     }
 
@@ -43,128 +74,167 @@ impl Diff {
         let source_items = build_item_names_hashmap(local);
         let backed_up_items = build_item_names_hashmap(remote);
 
-        let source_items_paths: HashSet<_> = source_items.keys().collect();
-        let backed_up_items_paths: HashSet<_> = backed_up_items.keys().collect();
-
-        let mut diff = Vec::with_capacity(source_items.len());
-
-        // debug!("> Building list of new items...");
-
-        diff.extend(
-            source_items_paths
-                .difference(&backed_up_items_paths)
-                .map(|item| DiffItem {
-                    path: item.to_string(),
+        let added_or_changed = local.items.iter().filter_map(|source_item| {
+            let Some(backed_up_item) = backed_up_items.get(source_item.relative_path.as_str())
+            else {
+                return Some(DiffItem {
+                    path: source_item.relative_path.clone(),
                     status: DiffType::Added(DiffItemAdded {
-                        new: source_items.get(*item).unwrap().metadata,
+                        new: source_item.metadata,
                     }),
-                }),
-        );
+                });
+            };
+
+            match (source_item.metadata, backed_up_item.metadata) {
+                // Both directories = no change
+                (SnapshotItemMetadata::Directory, SnapshotItemMetadata::Directory) => None,
+                // Source item is directory and backed up item is file or the opposite = type changed
+                (SnapshotItemMetadata::Directory, SnapshotItemMetadata::File { .. })
+                | (SnapshotItemMetadata::File { .. }, SnapshotItemMetadata::Directory) => {
+                    Some(DiffItem {
+                        path: source_item.relative_path.clone(),
+                        status: DiffType::TypeChanged(DiffItemTypeChanged {
+                            prev: backed_up_item.metadata,
+                            new: source_item.metadata,
+                        }),
+                    })
+                }
+                // Otherwise, compare their metadata to see if something changed
+                (
+                    SnapshotItemMetadata::File(source_data),
+                    SnapshotItemMetadata::File(backed_up_data),
+                ) => {
+                    if source_data == backed_up_data {
+                        None
+                    } else {
+                        Some(DiffItem {
+                            path: source_item.relative_path.clone(),
+                            status: DiffType::Modified(DiffItemModified {
+                                prev: backed_up_data,
+                                new: source_data,
+                            }),
+                        })
+                    }
+                }
+            }
+        });
 
-        // debug!("> Building list of deleted items...");
+        let deleted = remote.items.iter().filter_map(|backed_up_item| {
+            if source_items.contains_key(backed_up_item.relative_path.as_str()) {
+                return None;
+            }
 
-        diff.extend(
-            backed_up_items_paths
-                .difference(&source_items_paths)
-                .map(|item| DiffItem {
-                    path: item.to_string(),
-                    status: DiffType::Deleted(DiffItemDeleted {
-                        prev: backed_up_items.get(*item).unwrap().metadata,
-                    }),
+            Some(DiffItem {
+                path: backed_up_item.relative_path.clone(),
+                status: DiffType::Deleted(DiffItemDeleted {
+                    prev: backed_up_item.metadata,
                 }),
-        );
-
-        // debug!("> Building list of modified items...");
+            })
+        });
 
-        diff.extend(
-            local
-                .items
-                .iter()
-                .filter(|item| backed_up_items_paths.contains(&item.relative_path.as_str()))
-                .filter_map(|source_item| {
-                    let backed_up_item = backed_up_items
-                        .get(&source_item.relative_path.as_str())
-                        .unwrap();
-
-                    match (source_item.metadata, backed_up_item.metadata) {
-                        // Both directories = no change
-                        (SnapshotItemMetadata::Directory, SnapshotItemMetadata::Directory) => None,
-                        // Source item is directory and backed up item is file or the opposite = type changed
-                        (SnapshotItemMetadata::Directory, SnapshotItemMetadata::File { .. })
-                        | (SnapshotItemMetadata::File { .. }, SnapshotItemMetadata::Directory) => {
-                            Some(DiffItem {
-                                path: source_item.relative_path.clone(),
-                                status: DiffType::TypeChanged(DiffItemTypeChanged {
-                                    prev: backed_up_item.metadata,
-                                    new: source_item.metadata,
-                                }),
-                            })
-                        }
-                        // Otherwise, compare their metadata to see if something changed
-                        (
-                            SnapshotItemMetadata::File(source_data),
-                            SnapshotItemMetadata::File(backed_up_data),
-                        ) => {
-                            if source_data == backed_up_data {
-                                None
-                            } else {
-                                Some(DiffItem {
-                                    path: source_item.relative_path.clone(),
-                                    status: DiffType::Modified(DiffItemModified {
-                                        prev: backed_up_data,
-                                        new: source_data,
-                                    }),
-                                })
-                            }
-                        }
-                    }
-                }),
-        );
+        let mut diff = added_or_changed.chain(deleted).collect::<Vec<_>>();
 
         diff.sort_by(|a, b| a.path.cmp(&b.path));
 
-        Self::new(diff)
+        let mut diff = Self::new(diff);
+        diff.hardlinks = build_hardlinks(local, &diff);
+        diff.acls = build_item_attrs(
+            local,
+            remote,
+            &diff,
+            |item| item.acl.as_ref(),
+            ItemAcl::is_empty,
+        );
+        diff.xattrs = build_item_attrs(
+            local,
+            remote,
+            &diff,
+            |item| item.xattrs.as_ref(),
+            ItemXattrs::is_empty,
+        );
+        diff
     }
 
     pub fn apply_time_granularity(mut self, time_granularity: Duration) -> Self {
-        self.modified = self
-            .modified
-            .into_iter()
-            .filter(|(_, DiffItemModified { prev, new })| {
-                // Destructuring isn't necessary, but it allows us to ensure we are correctly using every single field of the metadata
-                let SnapshotFileMetadata {
-                    size,
-                    last_modif_date_s,
-                    last_modif_date_ns,
-                } = new;
-
-                if *size != prev.size {
-                    return true;
-                }
+        self.modified.retain(|(_, DiffItemModified { prev, new })| {
+            // Destructuring isn't necessary, but it allows us to ensure we are correctly using every single field of the metadata
+            let SnapshotFileMetadata {
+                size,
+                last_modif_date_s,
+                last_modif_date_ns,
+            } = new;
+
+            if *size != prev.size {
+                return true;
+            }
 
-                let new_modified_at = Duration::from_secs(*last_modif_date_s)
-                    + Duration::from_nanos((*last_modif_date_ns).into());
+            let new_modified_at = Duration::from_secs(*last_modif_date_s)
+                + Duration::from_nanos((*last_modif_date_ns).into());
 
-                let prev_modified_at = Duration::from_secs(prev.last_modif_date_s)
-                    + Duration::from_nanos(prev.last_modif_date_ns.into());
+            let prev_modified_at = Duration::from_secs(prev.last_modif_date_s)
+                + Duration::from_nanos(prev.last_modif_date_ns.into());
 
-                let diff_abs = new_modified_at
-                    .checked_sub(prev_modified_at)
-                    .or_else(|| prev_modified_at.checked_sub(new_modified_at))
-                    .unwrap();
+            let diff_abs = new_modified_at
+                .checked_sub(prev_modified_at)
+                .or_else(|| prev_modified_at.checked_sub(new_modified_at))
+                .unwrap();
 
-                diff_abs >= time_granularity
-            })
-            .collect();
+            diff_abs >= time_granularity
+        });
 
         self
     }
 
+    // Leave out files to send so they are transferred by a later synchronization instead,
+    // along with the files which would have been created as hard links to them
+    // Their previous version (if any) is left untouched on the remote, and deletions are not affected
+    pub fn postpone_files(&mut self, postponed: &HashSet<String>) {
+        let postponed = postponed
+            .iter()
+            .map(String::as_str)
+            .chain(
+                self.hardlinks
+                    .iter()
+                    .filter(|(_, target)| postponed.contains(target))
+                    .map(|(path, _)| path.as_str()),
+            )
+            .map(str::to_owned)
+            .collect::<HashSet<_>>();
+
+        let is_postponed_file = |path: &String, new: &SnapshotItemMetadata| {
+            matches!(new, SnapshotItemMetadata::File(_)) && postponed.contains(path)
+        };
+
+        self.added
+            .retain(|(path, DiffItemAdded { new })| !is_postponed_file(path, new));
+
+        self.modified.retain(|(path, _)| !postponed.contains(path));
+
+        self.type_changed
+            .retain(|(path, DiffItemTypeChanged { prev: _, new })| !is_postponed_file(path, new));
+
+        self.hardlinks.retain(|(path, _)| !postponed.contains(path));
+        self.acls.retain(|(path, _)| !postponed.contains(path));
+        self.xattrs.retain(|(path, _)| !postponed.contains(path));
+    }
+
     pub fn ops(&self) -> DiffApplyOps {
         DiffApplyOps::new(self)
     }
 }
 
+// Build a snapshot of a local directory and compare it against an existing (remote) one,
+// without any progress report, cancellation or cache (modification times are compared like the client does)
+pub async fn snapshot_and_diff(
+    local_dir: impl Into<PathBuf>,
+    remote: &Snapshot,
+    options: &SnapshotOptions,
+) -> Result<Diff> {
+    let local = make_snapshot(local_dir.into(), |_| {}, options, None, None).await?;
+
+    Ok(Diff::build(&local.snapshot, remote).apply_time_granularity(DEFAULT_TIME_GRANULARITY))
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct DiffItem {
     pub status: DiffType,
@@ -201,6 +271,85 @@ pub struct DiffItemDeleted {
     pub prev: SnapshotItemMetadata,
 }
 
+// For each group of files sharing the same inode, associate every file which needs to be sent
+// to the first one of the group (which is then either sent too or already present on the remote)
+fn build_hardlinks(local: &Snapshot, diff: &Diff) -> Vec<(String, String)> {
+    let mut groups = HashMap::<HardlinkId, Vec<&str>>::new();
+
+    for item in &local.items {
+        if let (SnapshotItemMetadata::File(_), Some(hardlink)) = (item.metadata, item.hardlink) {
+            groups
+                .entry(hardlink)
+                .or_default()
+                .push(item.relative_path.as_str());
+        }
+    }
+
+    let to_send = diff
+        .ops()
+        .send_files
+        .into_iter()
+        .map(|(path, _)| path)
+        .collect::<HashSet<_>>();
+
+    let mut hardlinks = vec![];
+
+    for mut paths in groups.into_values() {
+        paths.sort();
+
+        let (target, others) = paths.split_first().unwrap();
+
+        hardlinks.extend(
+            others
+                .iter()
+                .filter(|path| to_send.contains(**path))
+                .map(|path| (path.to_string(), target.to_string())),
+        );
+    }
+
+    hardlinks.sort();
+    hardlinks
+}
+
+// New and replaced items don't keep any previous ACL or extended attributes, so theirs must be set if they have any,
+// while existing items only need them when they changed (and are known on both sides)
+fn build_item_attrs<T: Clone + PartialEq>(
+    local: &Snapshot,
+    remote: &Snapshot,
+    diff: &Diff,
+    attrs: impl Fn(&SnapshotItem) -> Option<&T>,
+    is_empty: impl Fn(&T) -> bool,
+) -> Vec<(String, T)> {
+    let remote_items = build_item_names_hashmap(remote);
+
+    let recreated = diff
+        .added
+        .iter()
+        .map(|(path, _)| path)
+        .chain(diff.modified.iter().map(|(path, _)| path))
+        .chain(diff.type_changed.iter().map(|(path, _)| path))
+        .collect::<HashSet<_>>();
+
+    local
+        .items
+        .iter()
+        .filter_map(|item| {
+            let item_attrs = attrs(item)?;
+
+            let changed = if recreated.contains(&item.relative_path) {
+                !is_empty(item_attrs)
+            } else {
+                remote_items
+                    .get(item.relative_path.as_str())
+                    .and_then(|remote_item| attrs(remote_item))
+                    .is_some_and(|remote_attrs| remote_attrs != item_attrs)
+            };
+
+            changed.then(|| (item.relative_path.clone(), item_attrs.clone()))
+        })
+        .collect()
+}
+
 fn build_item_names_hashmap(snapshot: &Snapshot) -> HashMap<&str, &SnapshotItem> {
     snapshot
         .items
@@ -215,17 +364,173 @@ pub struct DiffApplyOps {
     pub send_files: Vec<(String, SnapshotFileMetadata)>,
     pub delete_files: Vec<String>,
     pub delete_empty_dirs: Vec<String>,
+    pub delta_files: Vec<String>,
+    pub create_hardlinks: Vec<(String, String)>,
+    // Directories which were renamed without any change to their content
+    #[serde(default)]
+    pub move_dirs: Vec<(String, String)>,
+    // Files which only need their modification time to be updated
+    #[serde(default)]
+    pub touch_files: Vec<(String, SnapshotFileMetadata)>,
+    // Directories replaced by a file, which must be removed with all their content before anything else
+    // so the path is free when the file is moved into place (their content isn't part of the other deletions)
+    #[serde(default)]
+    pub replace_dirs: Vec<String>,
+    // Items whose ACL must be set, after every other operation
+    #[serde(default)]
+    pub set_acls: Vec<(String, ItemAcl)>,
+    // Items whose extended attributes must be set, after every other operation
+    #[serde(default)]
+    pub set_xattrs: Vec<(String, ItemXattrs)>,
 }
 
 impl DiffApplyOps {
     pub fn new(diff: &Diff) -> Self {
+        let mut ops = Self::new_without_moves(diff);
+        ops.collapse_moved_dirs(diff);
+        ops
+    }
+
+    // Check if an item is part of a directory which was moved
+    pub fn is_moved(&self, path: &str) -> bool {
+        self.move_dirs
+            .iter()
+            .any(|(from, to)| Path::new(path).starts_with(from) || Path::new(path).starts_with(to))
+    }
+
+    // Replace deleted directories which have been added back elsewhere with the exact same content by a single move
+    fn collapse_moved_dirs(&mut self, diff: &Diff) {
+        let deleted_dirs = dirs_content(
+            diff.deleted
+                .iter()
+                .map(|(path, DiffItemDeleted { prev })| (path, prev)),
+        );
+
+        let added_dirs = dirs_content(
+            diff.added
+                .iter()
+                .map(|(path, DiffItemAdded { new })| (path, new)),
+        );
+
+        let overlaps =
+            |a: &str, b: &str| Path::new(a).starts_with(b) || Path::new(b).starts_with(a);
+
+        // Parent directories come first, so the largest possible moves are found
+        for (deleted_dir, content) in &deleted_dirs {
+            // Moving empty directories wouldn't save anything
+            if !content
+                .iter()
+                .any(|(_, mt)| matches!(mt, SnapshotItemMetadata::File(_)))
+            {
+                continue;
+            }
+
+            if self
+                .move_dirs
+                .iter()
+                .any(|(from, _)| Path::new(deleted_dir).starts_with(from))
+            {
+                continue;
+            }
+
+            let added_dir = added_dirs.iter().find(|(added_dir, added_content)| {
+                added_content == content
+                    && !self.move_dirs.iter().any(|(_, to)| overlaps(to, added_dir))
+            });
+
+            if let Some((added_dir, _)) = added_dir {
+                self.move_dirs
+                    .push((deleted_dir.to_string(), added_dir.to_string()));
+            }
+        }
+
+        if self.move_dirs.is_empty() {
+            return;
+        }
+
+        let move_dirs = std::mem::take(&mut self.move_dirs);
+
+        let is_moved = |path: &String| {
+            move_dirs.iter().any(|(from, to)| {
+                Path::new(path).starts_with(from) || Path::new(path).starts_with(to)
+            })
+        };
+
+        self.create_dirs.retain(|path| !is_moved(path));
+        self.send_files.retain(|(path, _)| !is_moved(path));
+        self.delete_files.retain(|path| !is_moved(path));
+        self.delete_empty_dirs.retain(|path| !is_moved(path));
+        self.delta_files.retain(|path| !is_moved(path));
+        self.create_hardlinks.retain(|(path, _)| !is_moved(path));
+
+        self.move_dirs = move_dirs;
+    }
+
+    fn new_without_moves(diff: &Diff) -> Self {
         let Diff {
+            version: _,
             added,
             modified,
             type_changed,
             deleted,
+            hardlinks,
+            touched,
+            acls,
+            xattrs,
         } = diff;
 
+        // Files can't change size without their content changing too
+        let touched = touched.iter().map(String::as_str).collect::<HashSet<_>>();
+
+        let (touch_files, modified): (Vec<_>, Vec<_>) =
+            modified
+                .iter()
+                .partition(|(path, DiffItemModified { prev, new })| {
+                    touched.contains(path.as_str()) && prev.size == new.size
+                });
+
+        // Compute files to send
+        let files_to_send = added
+            .iter()
+            .filter_map(|(path, DiffItemAdded { new })| match new {
+                SnapshotItemMetadata::Directory => None,
+                SnapshotItemMetadata::File(mt) => Some((path.clone(), *mt)),
+            })
+            .chain(
+                modified
+                    .iter()
+                    .map(|(path, DiffItemModified { prev: _, new })| (path.clone(), *new)),
+            )
+            .chain(type_changed.iter().filter_map(
+                |(path, DiffItemTypeChanged { prev: _, new })| match new {
+                    SnapshotItemMetadata::Directory => None,
+                    SnapshotItemMetadata::File(mt) => Some((path.clone(), *mt)),
+                },
+            ));
+
+        // Files which are only a hard link to another one don't need to be sent
+        let hardlinks = hardlinks
+            .iter()
+            .map(|(path, target)| (path.as_str(), target.as_str()))
+            .collect::<HashMap<_, _>>();
+
+        let (create_hardlinks, send_files): (Vec<_>, Vec<_>) =
+            files_to_send.partition(|(path, _)| hardlinks.contains_key(path.as_str()));
+
+        let replace_dirs = type_changed
+            .iter()
+            .filter_map(|(path, DiffItemTypeChanged { prev, new: _ })| match prev {
+                SnapshotItemMetadata::Directory => Some(path.clone()),
+                SnapshotItemMetadata::File(_) => None,
+            })
+            .collect::<Vec<_>>();
+
+        let is_replaced = |path: &String| {
+            replace_dirs
+                .iter()
+                .any(|dir| Path::new(path).starts_with(dir))
+        };
+
         Self {
             // Compute directories to create
             create_dirs: sort_rev_in_place(
@@ -245,26 +550,17 @@ impl DiffApplyOps {
                     .collect(),
             ),
 
-            // Compute files to send
-            send_files: added
-                .iter()
-                .filter_map(|(path, DiffItemAdded { new })| match new {
-                    SnapshotItemMetadata::Directory => None,
-                    SnapshotItemMetadata::File(mt) => Some((path.clone(), *mt)),
+            // Compute hard links to create
+            create_hardlinks: create_hardlinks
+                .into_iter()
+                .map(|(path, _)| {
+                    let target = hardlinks.get(path.as_str()).unwrap().to_string();
+                    (path, target)
                 })
-                .chain(
-                    modified
-                        .iter()
-                        .map(|(path, DiffItemModified { prev: _, new })| (path.clone(), *new)),
-                )
-                .chain(type_changed.iter().filter_map(
-                    |(path, DiffItemTypeChanged { prev: _, new })| match new {
-                        SnapshotItemMetadata::Directory => None,
-                        SnapshotItemMetadata::File(mt) => Some((path.clone(), *mt)),
-                    },
-                ))
                 .collect(),
 
+            send_files,
+
             // Compute files to delete
             delete_files: deleted
                 .iter()
@@ -278,30 +574,212 @@ impl DiffApplyOps {
                     SnapshotItemMetadata::Directory => None,
                     SnapshotItemMetadata::File(_) => Some(path.clone()),
                 })
+                .filter(|path| !is_replaced(path))
+                .collect(),
+
+            // Compute files which already have a previous version, which can be transferred as a delta
+            delta_files: modified
+                .iter()
+                .map(|(path, _)| path)
+                .filter(|path| !hardlinks.contains_key(path.as_str()))
+                .cloned()
                 .collect(),
 
             // Compute directories to delete
             delete_empty_dirs: sort_rev_in_place(
                 deleted
                     .iter()
-                    .map(|(path, DiffItemDeleted { prev })| (path, prev))
-                    .chain(
-                        type_changed
-                            .iter()
-                            .map(|(path, DiffItemTypeChanged { prev, new: _ })| (path, prev)),
-                    )
                     .rev()
-                    .filter_map(|(path, mt)| match mt {
+                    .filter_map(|(path, DiffItemDeleted { prev })| match prev {
                         SnapshotItemMetadata::Directory => Some(path.clone()),
                         SnapshotItemMetadata::File(_) => None,
                     })
+                    .filter(|path| !is_replaced(path))
                     .collect(),
             ),
+
+            move_dirs: vec![],
+
+            touch_files: touch_files
+                .into_iter()
+                .map(|(path, DiffItemModified { prev: _, new })| (path.clone(), *new))
+                .collect(),
+
+            replace_dirs,
+
+            set_acls: acls.clone(),
+
+            set_xattrs: xattrs.clone(),
         }
     }
 }
 
+// Get the content of each directory from the provided list, with paths relative to the directory
+// Directories are sorted so that parents always come before their children
+fn dirs_content<'a>(
+    items: impl Iterator<Item = (&'a String, &'a SnapshotItemMetadata)> + Clone,
+) -> Vec<(&'a str, Vec<(&'a Path, SnapshotItemMetadata)>)> {
+    let mut dirs = items
+        .clone()
+        .filter(|(_, mt)| matches!(mt, SnapshotItemMetadata::Directory))
+        .map(|(path, _)| (path.as_str(), vec![]))
+        .collect::<HashMap<_, _>>();
+
+    for (path, mt) in items {
+        for ancestor in Path::new(path).ancestors().skip(1) {
+            if let Some(content) = dirs.get_mut(ancestor.to_str().unwrap()) {
+                content.push((Path::new(path).strip_prefix(ancestor).unwrap(), *mt));
+            }
+        }
+    }
+
+    let mut dirs = dirs.into_iter().collect::<Vec<_>>();
+
+    for (_, content) in &mut dirs {
+        content.sort_by_key(|(path, _)| *path);
+    }
+
+    dirs.sort_by_key(|(dir, _)| *dir);
+    dirs
+}
+
 fn sort_rev_in_place<T: Ord>(mut vec: Vec<T>) -> Vec<T> {
     vec.sort_by(|a, b| b.cmp(a));
     vec
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        snapshot::{
+            tests::{dir, file, snapshot},
+            SchemaVersion,
+        },
+        xattrs::ItemXattrs,
+    };
+
+    use super::Diff;
+
+    fn paths<T>(items: &[(String, T)]) -> Vec<&str> {
+        items.iter().map(|(path, _)| path.as_str()).collect()
+    }
+
+    #[test]
+    fn keeps_empty_dirs() {
+        let empty = snapshot(vec![]);
+        let with_dirs = snapshot(vec![
+            dir("logs"),
+            dir("deep"),
+            dir("deep/x"),
+            dir("deep/x/y"),
+        ]);
+
+        let sorted = |mut dirs: Vec<String>| {
+            dirs.sort();
+            dirs
+        };
+
+        let ops = Diff::build(&with_dirs, &empty).ops();
+
+        assert_eq!(
+            sorted(ops.create_dirs),
+            ["deep", "deep/x", "deep/x/y", "logs"]
+        );
+        assert!(ops.send_files.is_empty());
+
+        let ops = Diff::build(&empty, &with_dirs).ops();
+
+        assert_eq!(
+            sorted(ops.delete_empty_dirs),
+            ["deep", "deep/x", "deep/x/y", "logs"]
+        );
+        assert!(ops.delete_files.is_empty());
+    }
+
+    #[test]
+    fn moves_renamed_dirs() {
+        let remote = snapshot(vec![
+            dir("a"),
+            file("a/1", 1),
+            file("a/2", 2),
+            dir("a/sub"),
+            file("a/sub/3", 3),
+            file("kept", 1),
+        ]);
+
+        let local = snapshot(vec![
+            dir("b"),
+            file("b/1", 1),
+            file("b/2", 2),
+            dir("b/sub"),
+            file("b/sub/3", 3),
+            file("kept", 1),
+        ]);
+
+        let ops = Diff::build(&local, &remote).ops();
+
+        assert_eq!(ops.move_dirs, [("a".to_owned(), "b".to_owned())]);
+        assert!(ops.create_dirs.is_empty());
+        assert!(ops.send_files.is_empty());
+        assert!(ops.delete_files.is_empty());
+        assert!(ops.delete_empty_dirs.is_empty());
+
+        // Only the unchanged subdirectories of a directory whose content changed are moved
+        let local = snapshot(vec![
+            dir("b"),
+            file("b/1", 1),
+            file("b/2", 20),
+            dir("b/sub"),
+            file("b/sub/3", 3),
+            file("kept", 1),
+        ]);
+
+        let ops = Diff::build(&local, &remote).ops();
+
+        assert_eq!(ops.move_dirs, [("a/sub".to_owned(), "b/sub".to_owned())]);
+        assert_eq!(paths(&ops.send_files), ["b/1", "b/2"]);
+
+        let mut deleted = ops.delete_files;
+        deleted.sort();
+        assert_eq!(deleted, ["a/1", "a/2"]);
+    }
+
+    #[test]
+    fn reads_unversioned_diffs() {
+        let diff = serde_json::from_str::<Diff>(
+            r#"{ "added": [], "modified": [], "type_changed": [], "deleted": [] }"#,
+        )
+        .unwrap();
+
+        assert_eq!(diff.version, SchemaVersion::unversioned());
+    }
+
+    #[test]
+    fn detects_xattr_only_changes() {
+        let xattrs = |entries: &[(&str, &str)]| {
+            Some(
+                entries
+                    .iter()
+                    .map(|(name, value)| (name.to_string(), value.to_string()))
+                    .collect::<ItemXattrs>(),
+            )
+        };
+
+        let mut local = snapshot(vec![file("changed", 1), file("new", 1), file("same", 1)]);
+        let mut remote = snapshot(vec![file("changed", 1), file("same", 1)]);
+
+        local.items[0].xattrs = xattrs(&[("user.tag", "01")]);
+        local.items[1].xattrs = xattrs(&[]);
+        local.items[2].xattrs = xattrs(&[("user.tag", "02")]);
+
+        remote.items[0].xattrs = xattrs(&[]);
+        remote.items[1].xattrs = xattrs(&[("user.tag", "02")]);
+
+        let diff = Diff::build(&local, &remote);
+
+        assert_eq!(paths(&diff.added), ["new"]);
+        assert!(diff.modified.is_empty());
+        assert_eq!(paths(&diff.xattrs), ["changed"]);
+        assert_eq!(diff.ops().set_xattrs, diff.xattrs);
+    }
+}
@@ -1,6 +1,14 @@
-use anyhow::{Context, Result};
+use std::path::PathBuf;
+
+use anyhow::{anyhow, Result};
 use walkdir::{DirEntry, WalkDir};
 
+pub struct EntryError {
+    // Path of the entry which failed, if known
+    pub path: Option<PathBuf>,
+    pub error: anyhow::Error,
+}
+
 pub struct FallibleEntryFilter<'a> {
     iter: walkdir::IntoIter,
 
@@ -19,26 +27,28 @@ impl<'a> FallibleEntryFilter<'a> {
         }
     }
 
-    fn iter_next(&mut self) -> Result<Option<DirEntry>> {
+    fn iter_next(&mut self) -> Result<Option<DirEntry>, EntryError> {
         loop {
             let Some(entry) = self.iter.next() else {
                 return Ok(None);
             };
 
-            let entry = entry.context("Failed to read next directory entry")?;
+            let entry = entry.map_err(|err| EntryError {
+                path: err.path().map(ToOwned::to_owned),
+                error: anyhow!(err).context("Failed to read next directory entry"),
+            })?;
+
+            let entry_error = |error| EntryError {
+                path: Some(entry.path().to_owned()),
+                error,
+            };
 
-            if (self.filter)(&entry)? {
+            if (self.filter)(&entry).map_err(entry_error)? {
                 break Ok(Some(entry));
             }
 
-            let mt = entry.metadata().with_context(|| {
-                format!(
-                    "Failed to get metadata for filtered item '{}'",
-                    entry.path().display()
-                )
-            })?;
-
-            if mt.is_dir() {
+            // The entry's type is known without fetching its metadata (it follows symlinks if the walker does)
+            if entry.file_type().is_dir() {
                 self.iter.skip_current_dir();
             }
         }
@@ -46,7 +56,7 @@ impl<'a> FallibleEntryFilter<'a> {
 }
 
 impl<'a> Iterator for FallibleEntryFilter<'a> {
-    type Item = Result<DirEntry>;
+    type Item = Result<DirEntry, EntryError>;
 
     fn next(&mut self) -> Option<Self::Item> {
         self.iter_next().transpose()
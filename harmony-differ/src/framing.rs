@@ -0,0 +1,85 @@
+use anyhow::{bail, Result};
+
+// Header sent by clients which frame their upload streams
+// Each frame is made of the payload's length (u32 LE), the payload itself and its CRC32 (u32 LE)
+pub const CRC_FRAMING_HEADER: &str = "x-harmony-crc-framing";
+
+// Frames can't be larger than this, to avoid buffering huge amounts of data because of a corrupted length
+pub const MAX_FRAME_PAYLOAD_SIZE: usize = 16 * 1024 * 1024;
+
+const LENGTH_SIZE: usize = 4;
+const CRC_SIZE: usize = 4;
+
+pub fn encode_frame(payload: &[u8]) -> Vec<u8> {
+    assert!(payload.len() <= MAX_FRAME_PAYLOAD_SIZE);
+
+    let mut frame = Vec::with_capacity(LENGTH_SIZE + payload.len() + CRC_SIZE);
+
+    frame.extend_from_slice(&u32::try_from(payload.len()).unwrap().to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+
+    frame
+}
+
+// Extract payloads from a stream of frames, which may be split at any point
+#[derive(Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    // Returns the payloads of all the frames which were completed by this data
+    pub fn push(&mut self, data: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.buffer.extend_from_slice(data);
+
+        let mut payloads = vec![];
+        let mut consumed = 0;
+
+        loop {
+            let remaining = &self.buffer[consumed..];
+
+            if remaining.len() < LENGTH_SIZE {
+                break;
+            }
+
+            let len = u32::from_le_bytes(remaining[..LENGTH_SIZE].try_into().unwrap()) as usize;
+
+            if len > MAX_FRAME_PAYLOAD_SIZE {
+                bail!("Frame is too large ({len} bytes)");
+            }
+
+            if remaining.len() < LENGTH_SIZE + len + CRC_SIZE {
+                break;
+            }
+
+            let payload = &remaining[LENGTH_SIZE..LENGTH_SIZE + len];
+
+            let crc = u32::from_le_bytes(
+                remaining[LENGTH_SIZE + len..LENGTH_SIZE + len + CRC_SIZE]
+                    .try_into()
+                    .unwrap(),
+            );
+
+            if crc32fast::hash(payload) != crc {
+                bail!("Frame's CRC doesn't match its content, it was corrupted during transfer");
+            }
+
+            payloads.push(payload.to_vec());
+            consumed += LENGTH_SIZE + len + CRC_SIZE;
+        }
+
+        self.buffer.drain(..consumed);
+
+        Ok(payloads)
+    }
+
+    // Ensure the stream didn't end in the middle of a frame
+    pub fn finish(self) -> Result<()> {
+        if !self.buffer.is_empty() {
+            bail!("Stream ended in the middle of a frame");
+        }
+
+        Ok(())
+    }
+}
@@ -2,6 +2,15 @@
 #![forbid(unused_must_use)]
 #![warn(unused_crate_dependencies)]
 
+pub mod acl;
+pub mod cache;
+#[cfg(feature = "crypto")]
+pub mod crypto;
+pub mod delta;
 pub mod diffing;
 mod filter;
+pub mod framing;
+pub mod protocol;
 pub mod snapshot;
+pub mod sparse;
+pub mod xattrs;
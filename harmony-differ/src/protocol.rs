@@ -0,0 +1,74 @@
+use serde::{Deserialize, Serialize};
+
+// Must be incremented on every change which makes clients and servers incompatible
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Optional features a server may support
+pub const CAPABILITY_DELTA: &str = "delta";
+pub const CAPABILITY_ENCRYPTION: &str = "encryption";
+pub const CAPABILITY_HARDLINKS: &str = "hardlinks";
+pub const CAPABILITY_MOVE_DIRS: &str = "move-dirs";
+pub const CAPABILITY_CRC_FRAMING: &str = "crc-framing";
+pub const CAPABILITY_LIST_EXCLUDED: &str = "list-excluded";
+pub const CAPABILITY_MODIFIED_SINCE: &str = "modified-since";
+pub const CAPABILITY_TWO_PHASE_FINALIZE: &str = "two-phase-finalize";
+pub const CAPABILITY_TOUCH: &str = "touch";
+pub const CAPABILITY_ABORT_FILE: &str = "abort-file";
+pub const CAPABILITY_REPAIR: &str = "repair";
+pub const CAPABILITY_LIST_SLOT: &str = "list-slot";
+pub const CAPABILITY_SPARSE: &str = "sparse";
+pub const CAPABILITY_DEVICE_KEYS: &str = "device-keys";
+pub const CAPABILITY_DRY_RUN_BEGIN: &str = "dry-run-begin";
+pub const CAPABILITY_ACLS: &str = "acls";
+pub const CAPABILITY_REMOTE_CHECK: &str = "remote-check";
+pub const CAPABILITY_STREAMED_SNAPSHOT: &str = "streamed-snapshot";
+pub const CAPABILITY_PRESENT_FILES: &str = "present-files";
+pub const CAPABILITY_SNAPSHOT_CACHE: &str = "snapshot-cache";
+pub const CAPABILITY_XATTRS: &str = "xattrs";
+pub const CAPABILITY_OPENING_KEY: &str = "opening-key";
+
+// Header set by the server when rejecting an access token which expired
+pub const ACCESS_TOKEN_EXPIRED_HEADER: &str = "x-harmony-token-expired";
+
+// Header set by the server when rejecting a synchronization which would delete too many items
+pub const MASS_DELETION_HEADER: &str = "x-harmony-mass-deletion";
+
+// Header set by the server when a device key used to authenticate was never enrolled (or was removed)
+pub const UNKNOWN_DEVICE_KEY_HEADER: &str = "x-harmony-unknown-device-key";
+
+// Header set by the server when a slot's content changed since the snapshot a synchronization was diffed against
+pub const REMOTE_CHANGED_HEADER: &str = "x-harmony-remote-changed";
+
+// Devices authenticating with their key sign this message, which can't be mistaken for another protocol's one
+pub fn device_challenge_message(challenge: &str) -> String {
+    format!("harmony-device-challenge:{challenge}")
+}
+
+// Device keys and signatures are exchanged in hexadecimal
+pub fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+pub fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServerVersion {
+    pub version: String,
+    pub protocol_version: u32,
+    pub capabilities: Vec<String>,
+}
+
+impl ServerVersion {
+    pub fn supports(&self, capability: &str) -> bool {
+        self.capabilities.iter().any(|c| c == capability)
+    }
+}
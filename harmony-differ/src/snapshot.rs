@@ -1,55 +1,218 @@
 use std::{
+    collections::{HashMap, HashSet},
     ffi::OsStr,
-    path::{Path, PathBuf},
-    sync::{atomic::AtomicUsize, Arc},
+    fs::{FileType, Metadata},
+    path::{Component, Path, PathBuf},
+    sync::atomic::{AtomicBool, Ordering},
     time::SystemTime,
 };
 
 use anyhow::{bail, Context, Result};
-use serde::{Deserialize, Serialize};
-use tokio::sync::Mutex;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::{de::Error as _, Deserialize, Deserializer, Serialize};
 use walkdir::WalkDir;
 
-use crate::filter::FallibleEntryFilter;
+use crate::{
+    acl::{read_acl, ItemAcl},
+    cache::{DirModificationTime, SnapshotCache},
+    filter::{EntryError, FallibleEntryFilter},
+    sparse::is_sparse,
+    xattrs::{read_xattrs, ItemXattrs},
+};
+
+// Items Harmony creates in synchronized directories for its own needs (temporary files, probes, etc.)
+// have names starting with this prefix, and are never part of snapshots whatever the options are
+pub const HARMONY_ITEMS_PREFIX: &str = ".harmony-";
+
+fn is_harmony_item(name: &OsStr) -> bool {
+    name.as_encoded_bytes()
+        .starts_with(HARMONY_ITEMS_PREFIX.as_bytes())
+}
+
+// Version of the format of snapshots and diffs, to increase when older versions can't read them anymore
+pub const SCHEMA_VERSION: u32 = 1;
+
+// Payloads using a newer format than this one supports are rejected when deserialized
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct SchemaVersion(u32);
+
+impl SchemaVersion {
+    // Payloads without a version predate it, but use the first version's format
+    pub fn unversioned() -> Self {
+        Self(1)
+    }
+
+    pub fn get(self) -> u32 {
+        self.0
+    }
+}
+
+impl Default for SchemaVersion {
+    fn default() -> Self {
+        Self(SCHEMA_VERSION)
+    }
+}
+
+impl<'de> Deserialize<'de> for SchemaVersion {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let version = u32::deserialize(deserializer)?;
 
-#[derive(Serialize, Deserialize, Debug)]
+        if version > SCHEMA_VERSION {
+            return Err(D::Error::custom(format!(
+                "data uses format version {version} while this version of Harmony only supports up to {SCHEMA_VERSION}, please update it"
+            )));
+        }
+
+        Ok(Self(version))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Snapshot {
+    #[serde(default = "SchemaVersion::unversioned")]
+    pub version: SchemaVersion,
     pub from_dir: String,
     pub items: Vec<SnapshotItem>,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+impl Snapshot {
+    // Identity of the snapshot's content, which doesn't depend on the order items were found in
+    //
+    // It is the hash of the items sorted by path, each one being encoded as its path followed by
+    // a NUL byte (which paths can't contain) and its metadata: a 0 byte for directories, or a 1 byte
+    // followed by the size, the modification time's seconds and nanoseconds (little-endian) for files
+    pub fn fingerprint(&self) -> String {
+        let mut items = self.items.iter().collect::<Vec<_>>();
+        items.sort_by(|a, b| a.relative_path.cmp(&b.relative_path));
+
+        let mut hasher = blake3::Hasher::new();
+
+        for item in items {
+            hasher.update(item.relative_path.as_bytes());
+            hasher.update(&[0]);
+
+            match item.metadata {
+                SnapshotItemMetadata::Directory => {
+                    hasher.update(&[0]);
+                }
+
+                SnapshotItemMetadata::File(mt) => {
+                    hasher.update(&[1]);
+                    hasher.update(&mt.size.to_le_bytes());
+                    hasher.update(&mt.last_modif_date_s.to_le_bytes());
+                    hasher.update(&mt.last_modif_date_ns.to_le_bytes());
+                }
+            }
+        }
+
+        hasher.finalize().to_hex().to_string()
+    }
+
+    // Remove directories which don't contain any file, even through their subdirectories
+    pub fn prune_empty_dirs(&mut self) {
+        let non_empty = self
+            .items
+            .iter()
+            .filter(|item| matches!(item.metadata, SnapshotItemMetadata::File(_)))
+            .flat_map(|item| Path::new(&item.relative_path).ancestors().skip(1))
+            .map(Path::to_path_buf)
+            .collect::<HashSet<_>>();
+
+        self.items.retain(|item| match item.metadata {
+            SnapshotItemMetadata::Directory => non_empty.contains(Path::new(&item.relative_path)),
+            SnapshotItemMetadata::File(_) => true,
+        });
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SnapshotItem {
     pub relative_path: String,
     pub metadata: SnapshotItemMetadata,
+    // Only present for files which have multiple hard links, on platforms exposing this information
+    #[serde(default)]
+    pub hardlink: Option<HardlinkId>,
+    // Only set for files containing holes, on platforms exposing this information
+    #[serde(default)]
+    pub sparse: bool,
+    // Only present when ACLs are requested, on platforms and filesystems supporting them
+    #[serde(default)]
+    pub acl: Option<ItemAcl>,
+    // Only present when extended attributes are requested, on platforms and filesystems supporting them
+    #[serde(default)]
+    pub xattrs: Option<ItemXattrs>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct HardlinkId {
+    pub device: u64,
+    pub inode: u64,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SnapshotItemMetadata {
     Directory,
     File(SnapshotFileMetadata),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct SnapshotFileMetadata {
     pub size: u64,
     pub last_modif_date_s: u64,
     pub last_modif_date_ns: u32,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
 pub struct SnapshotOptions {
     pub ignore_paths: Vec<String>,
     pub ignore_names: Vec<String>,
     pub ignore_exts: Vec<String>,
+    #[serde(default)]
+    pub ignore_globs: Vec<String>,
+    #[serde(default)]
+    pub max_depth: Option<usize>,
+    // Only include files modified after this date (as a UNIX timestamp in seconds)
+    // Directories are still traversed, but deletions of older files can't be detected
+    #[serde(default)]
+    pub modified_since: Option<u64>,
+    // Skip items which fail to be analyzed instead of failing the whole snapshot
+    #[serde(default)]
+    pub skip_errors: bool,
+    // Fail on special files (FIFOs, sockets, devices) instead of skipping them
+    #[serde(default)]
+    pub strict_special_files: bool,
+    // Snapshot the targets of symbolic links as if they were real items
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    // When any of these is provided, only matching items (and the directories leading to them) are included
+    #[serde(default)]
+    pub include_paths: Vec<String>,
+    #[serde(default)]
+    pub include_globs: Vec<String>,
+    // Capture the POSIX ACLs of items
+    #[serde(default)]
+    pub acls: bool,
+    // Capture the extended attributes of items
+    #[serde(default)]
+    pub xattrs: bool,
 }
 
 impl SnapshotOptions {
+    pub fn builder() -> SnapshotOptionsBuilder {
+        SnapshotOptionsBuilder::default()
+    }
+
     pub fn validate(&self) -> Result<()> {
         for path in &self.ignore_paths {
             if Path::new(path).is_absolute() {
                 bail!("Paths to ignore must be relative (got '{path}')",);
             }
+
+            // Would otherwise ignore everything
+            if path_components(path).next().is_none() {
+                bail!("Paths to ignore must not be empty (got '{path}')");
+            }
         }
 
         for name in &self.ignore_names {
@@ -70,25 +233,208 @@ impl SnapshotOptions {
             }
         }
 
+        for glob in &self.ignore_globs {
+            Glob::new(glob).with_context(|| format!("Invalid glob to ignore: {glob}"))?;
+        }
+
+        for path in &self.include_paths {
+            if Path::new(path).is_absolute() {
+                bail!("Paths to include must be relative (got '{path}')");
+            }
+        }
+
+        if self.max_depth == Some(0) {
+            bail!("Maximum depth must be at least 1");
+        }
+
         Ok(())
     }
 
-    pub fn should_ignore(&self, path: &Path, from_dir: &Path) -> Result<bool> {
-        let relative_path = path.strip_prefix(from_dir).unwrap();
+    // Compile the ignore rules so items can be checked against them
+    pub fn ignore_matcher(&self) -> Result<IgnoreMatcher<'_>> {
+        let globs = if self.ignore_globs.is_empty() {
+            None
+        } else {
+            let mut builder = GlobSetBuilder::new();
 
-        if self
-            .ignore_paths
-            .iter()
-            .any(|c| relative_path.strip_prefix(c).is_ok())
-        {
-            return Ok(true);
+            for glob in &self.ignore_globs {
+                builder.add(
+                    Glob::new(glob).with_context(|| format!("Invalid glob to ignore: {glob}"))?,
+                );
+            }
+
+            Some(builder.build().context("Failed to build globs to ignore")?)
+        };
+
+        Ok(IgnoreMatcher {
+            options: self,
+            globs,
+        })
+    }
+
+    // Add ignore rules to the current ones
+    pub fn merge_ignore_rules(&mut self, other: &SnapshotOptions) {
+        for (rules, other_rules) in [
+            (&mut self.ignore_paths, &other.ignore_paths),
+            (&mut self.ignore_names, &other.ignore_names),
+            (&mut self.ignore_exts, &other.ignore_exts),
+            (&mut self.ignore_globs, &other.ignore_globs),
+        ] {
+            for rule in other_rules {
+                if !rules.contains(rule) {
+                    rules.push(rule.clone());
+                }
+            }
         }
+    }
 
-        if self.ignore_names.iter().any(|c| {
-            relative_path
-                .components()
-                .any(|component| component.as_os_str() == OsStr::new(c))
-        }) {
+    // Same options, without any rule excluding items
+    pub fn without_exclusion_rules(&self) -> Self {
+        Self {
+            ignore_paths: vec![],
+            ignore_names: vec![],
+            ignore_exts: vec![],
+            ignore_globs: vec![],
+            include_paths: vec![],
+            include_globs: vec![],
+            ..self.clone()
+        }
+    }
+
+    // Same options, for a snapshot of a parent directory restricted to the provided subdirectory
+    // Rules anchored at the root are moved under the subdirectory
+    pub fn scoped_to(&self, subpath: &str) -> Self {
+        let prefixed = |rules: &[String]| {
+            rules
+                .iter()
+                .map(|rule| format!("{subpath}/{rule}"))
+                .collect::<Vec<_>>()
+        };
+
+        let include_paths = if self.include_paths.is_empty() && self.include_globs.is_empty() {
+            vec![subpath.to_owned()]
+        } else {
+            prefixed(&self.include_paths)
+        };
+
+        Self {
+            ignore_paths: prefixed(&self.ignore_paths),
+            ignore_globs: prefixed(&self.ignore_globs),
+            include_paths,
+            include_globs: prefixed(&self.include_globs),
+            max_depth: self
+                .max_depth
+                .map(|max_depth| max_depth + path_components(subpath).count()),
+            ..self.clone()
+        }
+    }
+
+    // Remove items from an existing snapshot which would have been ignored with these options
+    pub fn filter_snapshot(&self, snapshot: &mut Snapshot) -> Result<()> {
+        let matcher = self.ignore_matcher()?;
+
+        snapshot.items.retain(|item| {
+            let relative_path = Path::new(&item.relative_path);
+
+            match item.metadata {
+                SnapshotItemMetadata::Directory => !matcher.ignores_path(relative_path),
+                SnapshotItemMetadata::File(_) => {
+                    !matcher.ignores_path(relative_path) && !matcher.ignores_ext(relative_path)
+                }
+            }
+        });
+
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+pub struct SnapshotOptionsBuilder {
+    options: SnapshotOptions,
+}
+
+impl SnapshotOptionsBuilder {
+    pub fn ignore_path(mut self, path: impl Into<String>) -> Self {
+        self.options.ignore_paths.push(path.into());
+        self
+    }
+
+    pub fn ignore_name(mut self, name: impl Into<String>) -> Self {
+        self.options.ignore_names.push(name.into());
+        self
+    }
+
+    pub fn ignore_ext(mut self, ext: impl Into<String>) -> Self {
+        self.options.ignore_exts.push(ext.into());
+        self
+    }
+
+    pub fn ignore_glob(mut self, glob: impl Into<String>) -> Self {
+        self.options.ignore_globs.push(glob.into());
+        self
+    }
+
+    pub fn include_path(mut self, path: impl Into<String>) -> Self {
+        self.options.include_paths.push(path.into());
+        self
+    }
+
+    pub fn include_glob(mut self, glob: impl Into<String>) -> Self {
+        self.options.include_globs.push(glob.into());
+        self
+    }
+
+    pub fn max_depth(mut self, max_depth: usize) -> Self {
+        self.options.max_depth = Some(max_depth);
+        self
+    }
+
+    pub fn modified_since(mut self, timestamp_s: u64) -> Self {
+        self.options.modified_since = Some(timestamp_s);
+        self
+    }
+
+    pub fn skip_errors(mut self, skip_errors: bool) -> Self {
+        self.options.skip_errors = skip_errors;
+        self
+    }
+
+    pub fn strict_special_files(mut self, strict_special_files: bool) -> Self {
+        self.options.strict_special_files = strict_special_files;
+        self
+    }
+
+    pub fn acls(mut self, acls: bool) -> Self {
+        self.options.acls = acls;
+        self
+    }
+
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.options.xattrs = xattrs;
+        self
+    }
+
+    pub fn follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.options.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    pub fn build(self) -> Result<SnapshotOptions> {
+        self.options.validate()?;
+        Ok(self.options)
+    }
+}
+
+pub struct IgnoreMatcher<'a> {
+    options: &'a SnapshotOptions,
+    globs: Option<GlobSet>,
+}
+
+impl<'a> IgnoreMatcher<'a> {
+    pub fn should_ignore(&self, path: &Path, from_dir: &Path) -> Result<bool> {
+        let relative_path = path.strip_prefix(from_dir).unwrap();
+
+        if self.ignores_path(relative_path) {
             return Ok(true);
         }
 
@@ -99,89 +445,653 @@ impl SnapshotOptions {
             )
         })?;
 
-        if mt.is_file() {
-            if let Some(ext) = relative_path.extension() {
-                if self.ignore_exts.iter().any(|c| OsStr::new(c) == ext) {
-                    return Ok(true);
-                }
-            }
+        Ok(mt.is_file() && self.ignores_ext(relative_path))
+    }
+
+    // Check if an item must be ignored based on its path only (regardless of its type)
+    // Items inside an ignored directory are ignored too, as the directory isn't walked through
+    pub fn ignores_path(&self, relative_path: &Path) -> bool {
+        if self
+            .options
+            .ignore_paths
+            .iter()
+            .any(|ignored| is_under_path(relative_path, ignored))
+        {
+            return true;
         }
 
-        Ok(false)
+        if self.globs.as_ref().is_some_and(|globs| {
+            relative_path
+                .ancestors()
+                .filter(|ancestor| !ancestor.as_os_str().is_empty())
+                .any(|ancestor| globs.is_match(ancestor))
+        }) {
+            return true;
+        }
+
+        self.options.ignore_names.iter().any(|c| {
+            relative_path
+                .components()
+                .any(|component| component.as_os_str() == OsStr::new(c))
+        })
+    }
+
+    // Check if a file must be ignored based on its extension
+    pub fn ignores_ext(&self, relative_path: &Path) -> bool {
+        match relative_path.extension() {
+            Some(ext) => self
+                .options
+                .ignore_exts
+                .iter()
+                .any(|c| OsStr::new(c) == ext),
+            None => false,
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct SnapshotResult {
     pub snapshot: Snapshot,
+    #[serde(default)]
+    pub warnings: Vec<String>,
+    #[serde(default)]
+    pub skipped: Vec<SnapshotSkipped>,
+    // Options the snapshot was effectively built with
+    #[serde(default)]
+    pub options: SnapshotOptions,
+    // Existing items which were excluded by the ignore rules, only listed on demand
+    #[serde(default)]
+    pub excluded: Vec<SnapshotItem>,
+    // Only used to build a cache of the snapshot
+    #[serde(skip)]
+    pub dirs_mtime: HashMap<String, DirModificationTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SnapshotSkipped {
+    pub path: String,
+    pub reason: String,
+}
+
+// Lines of a snapshot result sent as newline-delimited JSON, so its whole serialized form never has to be held in memory
+// Items come first, then excluded ones, then a summary which also marks the end of the stream
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SnapshotStreamLine {
+    Item(SnapshotItem),
+    Excluded(SnapshotItem),
+    End(SnapshotStreamEnd),
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SnapshotStreamEnd {
+    pub version: SchemaVersion,
+    pub from_dir: String,
+    // Number of items and excluded items which were sent, to ensure none was lost
+    pub items: usize,
+    pub excluded: usize,
+    pub warnings: Vec<String>,
+    pub skipped: Vec<SnapshotSkipped>,
+    pub options: SnapshotOptions,
+}
+
+impl SnapshotResult {
+    pub fn into_stream_lines(self) -> impl Iterator<Item = SnapshotStreamLine> {
+        let SnapshotResult {
+            snapshot:
+                Snapshot {
+                    version,
+                    from_dir,
+                    items,
+                },
+            warnings,
+            skipped,
+            options,
+            excluded,
+            dirs_mtime: _,
+        } = self;
+
+        let end = SnapshotStreamEnd {
+            version,
+            from_dir,
+            items: items.len(),
+            excluded: excluded.len(),
+            warnings,
+            skipped,
+            options,
+        };
+
+        items
+            .into_iter()
+            .map(SnapshotStreamLine::Item)
+            .chain(excluded.into_iter().map(SnapshotStreamLine::Excluded))
+            .chain(std::iter::once(SnapshotStreamLine::End(end)))
+    }
+}
+
+// Rebuild a snapshot result from its lines, as they are received
+#[derive(Default)]
+pub struct SnapshotStreamReader {
+    items: Vec<SnapshotItem>,
+    excluded: Vec<SnapshotItem>,
+    end: Option<SnapshotStreamEnd>,
+}
+
+impl SnapshotStreamReader {
+    pub fn push(&mut self, line: SnapshotStreamLine) -> Result<()> {
+        if self.end.is_some() {
+            bail!("Received data after the end of the snapshot");
+        }
+
+        match line {
+            SnapshotStreamLine::Item(item) => self.items.push(item),
+            SnapshotStreamLine::Excluded(item) => self.excluded.push(item),
+            SnapshotStreamLine::End(end) => self.end = Some(end),
+        }
+
+        Ok(())
+    }
+
+    pub fn finish(self) -> Result<SnapshotResult> {
+        let Self {
+            items,
+            excluded,
+            end,
+        } = self;
+
+        let SnapshotStreamEnd {
+            version,
+            from_dir,
+            items: items_count,
+            excluded: excluded_count,
+            warnings,
+            skipped,
+            options,
+        } = end.context("Snapshot ended prematurely")?;
+
+        if items.len() != items_count || excluded.len() != excluded_count {
+            bail!(
+                "Snapshot is incomplete: received {} item(s) and {} excluded item(s) instead of {items_count} and {excluded_count}",
+                items.len(),
+                excluded.len()
+            );
+        }
+
+        Ok(SnapshotResult {
+            snapshot: Snapshot {
+                version,
+                from_dir,
+                items,
+            },
+            warnings,
+            skipped,
+            options,
+            excluded,
+            dirs_mtime: HashMap::new(),
+        })
+    }
 }
 
+// Reported after each analyzed item
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotProgress {
+    pub items: usize,
+    // Total size of the analyzed files
+    pub bytes: u64,
+    pub current_path: String,
+}
+
+#[derive(Debug)]
+pub struct SnapshotCancelled;
+
+impl std::fmt::Display for SnapshotCancelled {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Snapshot was cancelled")
+    }
+}
+
+impl std::error::Error for SnapshotCancelled {}
+
+// The snapshot stops as soon as possible after the `cancelled` flag (if any) is set
 pub async fn make_snapshot(
     from_dir: PathBuf,
-    progress: impl Fn(String) + Send + Sync + 'static,
+    progress: impl Fn(SnapshotProgress) + Send + Sync + 'static,
     options: &SnapshotOptions,
+    cancelled: Option<&AtomicBool>,
+    cache: Option<&SnapshotCache>,
 ) -> Result<SnapshotResult> {
     options.validate()?;
 
-    let total = Arc::new(Mutex::new(AtomicUsize::new(0)));
-    let progress = Arc::new(progress);
+    let from_dir_str = from_dir.to_str().with_context(|| {
+        format!(
+            "Provided path contains invalid UTF-8 characters: {}",
+            from_dir.display()
+        )
+    })?;
+
+    let cache = cache.filter(|cache| cache.is_valid_for(from_dir_str, options));
+
+    // Modification time of every analyzed directory, the root one being represented by an empty path
+    let mut dirs_mtime = HashMap::new();
+    let from_dir_metadata = from_dir.metadata().with_context(|| {
+        format!(
+            "Failed to get metadata of directory: {}",
+            from_dir.display()
+        )
+    })?;
+
+    dirs_mtime.insert(
+        String::new(),
+        dir_modification_time(&from_dir, &from_dir_metadata)?,
+    );
+
+    let mut scanned = SnapshotProgress::default();
 
     let mut items = Vec::new();
+    let mut warnings = Vec::new();
+    let mut skipped = Vec::new();
+
+    let mut acls_unsupported = false;
+    let mut xattrs_unsupported = false;
+
+    // Symbolic link loops are detected by the walker, which then yields an error
+    let mut walker = WalkDir::new(&from_dir)
+        .min_depth(1)
+        .follow_links(options.follow_symlinks);
+
+    if let Some(max_depth) = options.max_depth {
+        walker = walker.max_depth(max_depth);
+    }
+
+    let includes = IncludeRules::new(options)?;
+    let ignores = options.ignore_matcher()?;
+
+    // Directories which were traversed but not included yet, as they may lead to an included item
+    let mut pending_dirs = HashMap::<PathBuf, SnapshotItem>::new();
 
-    let walker = WalkDir::new(&from_dir).min_depth(1);
     let walker_with_ignores = FallibleEntryFilter::new(walker, |entry| {
-        options
-            .should_ignore(entry.path(), &from_dir)
-            .map(|ignore| !ignore)
+        let relative_path = entry.path().strip_prefix(&from_dir).unwrap();
+
+        if is_harmony_item(entry.file_name()) {
+            return Ok(false);
+        }
+
+        if entry.file_type().is_dir() && !includes.may_lead_to_include(relative_path) {
+            return Ok(false);
+        }
+
+        // Regular files don't require to fetch their metadata to know if they must be ignored
+        if entry.file_type().is_file() {
+            return Ok(!ignores.ignores_path(relative_path) && !ignores.ignores_ext(relative_path));
+        }
+
+        // Symbolic links which are not followed are ignored depending on their target's type
+        if entry.file_type().is_symlink() {
+            return ignores
+                .should_ignore(entry.path(), &from_dir)
+                .map(|ignore| !ignore);
+        }
+
+        Ok(!ignores.ignores_path(relative_path))
     });
 
     for item in walker_with_ignores {
-        let item = item.context("Failed to analyze directory entry")?;
+        if cancelled.is_some_and(|cancelled| cancelled.load(Ordering::Relaxed)) {
+            return Err(SnapshotCancelled.into());
+        }
 
-        let from = from_dir.clone();
+        let item = match item {
+            Ok(item) => item,
+
+            Err(EntryError {
+                path: Some(path),
+                error,
+            }) if options.skip_errors => {
+                skipped.push(SnapshotSkipped {
+                    path: relative_path_lossy(&path, &from_dir),
+                    reason: format!("{error:#}"),
+                });
+
+                continue;
+            }
 
-        let progress = Arc::clone(&progress);
-        let total = Arc::clone(&total);
+            Err(EntryError { path: _, error }) => {
+                return Err(error.context("Failed to analyze directory entry"))
+            }
+        };
+
+        let from = from_dir.clone();
 
         let path = item.path();
 
-        let item = snapshot_item(path, &from)
-            .await
-            .with_context(|| format!("Failed analysis on filesystem item: {}", path.display()))?;
+        // Content of directories at the maximum depth is not walked through, so we warn about it
+        // (it isn't part of the snapshot either way, so failing to read it isn't an error)
+        if options.max_depth == Some(item.depth()) && item.file_type().is_dir() {
+            match has_children(path) {
+                Ok(false) => {}
 
-        items.push(item);
+                Ok(true) => warnings.push(format!(
+                    "Skipped content of directory '{}' as it exceeds the maximum depth",
+                    path.strip_prefix(&from).unwrap().display()
+                )),
 
-        let total = total
-            .lock()
-            .await
-            .fetch_add(1, std::sync::atomic::Ordering::Release)
-            + 1;
+                Err(err) => warnings.push(format!(
+                    "Skipped content of directory '{}' as it exceeds the maximum depth: {err:#}",
+                    path.strip_prefix(&from).unwrap().display()
+                )),
+            }
+        }
+
+        let relative_path = path.strip_prefix(&from).unwrap();
+
+        if let Some(kind) = special_file_kind(&item.file_type()) {
+            if options.strict_special_files {
+                bail!(
+                    "Found unsupported item type ({kind}): {}",
+                    relative_path.display()
+                );
+            }
+
+            skipped.push(SnapshotSkipped {
+                path: relative_path_lossy(path, &from),
+                reason: format!("unsupported item type ({kind})"),
+            });
+
+            continue;
+        }
+
+        let cached = if item.file_type().is_file() {
+            cache.and_then(|cache| cache.reusable_file(relative_path, &dirs_mtime))
+        } else {
+            None
+        };
+
+        // Metadata are only fetched once per item, and not at all for cached files
+        let result = match cached {
+            Some(cached) => Ok(cached.clone()),
+            None => match item.metadata() {
+                Ok(metadata) => snapshot_item(path, &from, &metadata).and_then(|snapshot_item| {
+                    if metadata.is_dir() {
+                        dirs_mtime.insert(
+                            snapshot_item.relative_path.clone(),
+                            dir_modification_time(path, &metadata)?,
+                        );
+                    }
 
-        progress(format!("Analyzed {total} item(s)"));
+                    Ok(snapshot_item)
+                }),
+                Err(err) => Err(err.into()),
+            },
+        };
+
+        // Old files are excluded, but directories are still traversed
+        if let (
+            Some(since),
+            Ok(SnapshotItem {
+                metadata: SnapshotItemMetadata::File(file),
+                ..
+            }),
+        ) = (options.modified_since, &result)
+        {
+            if file.last_modif_date_s < since {
+                continue;
+            }
+        }
+
+        // Changing an item's ACL or extended attributes doesn't change its modification time,
+        // so cached items can't be trusted
+        let result = result.and_then(|mut item| {
+            if options.acls {
+                item.acl = read_acl(path)
+                    .with_context(|| format!("Failed to read ACL of item: {}", path.display()))?;
+
+                acls_unsupported |= item.acl.is_none();
+            }
+
+            if options.xattrs {
+                item.xattrs = read_xattrs(path).with_context(|| {
+                    format!(
+                        "Failed to read extended attributes of item: {}",
+                        path.display()
+                    )
+                })?;
+
+                xattrs_unsupported |= item.xattrs.is_none();
+            }
+
+            Ok(item)
+        });
+
+        match result {
+            Ok(item) if includes.includes(relative_path) => {
+                if let SnapshotItemMetadata::File(file) = &item.metadata {
+                    scanned.bytes += file.size;
+                }
+
+                for ancestor in relative_path
+                    .ancestors()
+                    .skip(1)
+                    .collect::<Vec<_>>()
+                    .iter()
+                    .rev()
+                {
+                    if let Some(dir) = pending_dirs.remove(*ancestor) {
+                        items.push(dir);
+                    }
+                }
+
+                items.push(item);
+            }
+
+            Ok(item) => {
+                if matches!(item.metadata, SnapshotItemMetadata::Directory) {
+                    pending_dirs.insert(relative_path.to_owned(), item);
+                }
+            }
+
+            Err(err) if options.skip_errors => {
+                skipped.push(SnapshotSkipped {
+                    path: relative_path_lossy(path, &from),
+                    reason: format!("{err:#}"),
+                });
+
+                continue;
+            }
+
+            Err(err) => {
+                return Err(err).with_context(|| {
+                    format!("Failed analysis on filesystem item: {}", path.display())
+                })
+            }
+        }
+
+        scanned.items += 1;
+        scanned.current_path = relative_path_lossy(path, &from);
+
+        progress(scanned.clone());
     }
 
-    let from_dir_str = from_dir.to_str().with_context(|| {
-        format!(
-            "Provided path contains invalid UTF-8 characters: {}",
-            from_dir.display()
-        )
-    })?;
+    if acls_unsupported {
+        warnings.push(
+            "ACLs are not supported by this platform or filesystem, they were ignored".to_owned(),
+        );
+    }
+
+    if xattrs_unsupported {
+        warnings.push(
+            "Extended attributes are not supported by this platform or filesystem, they were ignored"
+                .to_owned(),
+        );
+    }
 
     Ok(SnapshotResult {
         snapshot: Snapshot {
+            version: SchemaVersion::default(),
             from_dir: from_dir_str.to_string(),
             items,
         },
+        warnings,
+        skipped,
+        options: options.clone(),
+        excluded: vec![],
+        dirs_mtime,
     })
 }
 
-async fn snapshot_item(item: &Path, from: &Path) -> Result<SnapshotItem> {
-    let metadata = item.metadata()?;
+// Whitelist of items to include in a snapshot
+struct IncludeRules<'a> {
+    paths: &'a [String],
+    globs: Option<GlobSet>,
+}
 
+impl<'a> IncludeRules<'a> {
+    fn new(options: &'a SnapshotOptions) -> Result<Self> {
+        let globs = if options.include_globs.is_empty() {
+            None
+        } else {
+            let mut builder = GlobSetBuilder::new();
+
+            for glob in &options.include_globs {
+                builder.add(
+                    Glob::new(glob).with_context(|| format!("Invalid glob to include: {glob}"))?,
+                );
+            }
+
+            Some(
+                builder
+                    .build()
+                    .context("Failed to build globs to include")?,
+            )
+        };
+
+        Ok(Self {
+            paths: &options.include_paths,
+            globs,
+        })
+    }
+
+    fn is_whitelist(&self) -> bool {
+        !self.paths.is_empty() || self.globs.is_some()
+    }
+
+    fn includes(&self, relative_path: &Path) -> bool {
+        if !self.is_whitelist() {
+            return true;
+        }
+
+        self.paths
+            .iter()
+            .any(|path| relative_path.starts_with(path))
+            || self
+                .globs
+                .as_ref()
+                .is_some_and(|globs| globs.is_match(relative_path))
+    }
+
+    // Check if a directory needs to be traversed to find included items
+    fn may_lead_to_include(&self, relative_dir: &Path) -> bool {
+        // Globs may match anything, so we can't know in advance
+        if !self.is_whitelist() || self.globs.is_some() {
+            return true;
+        }
+
+        self.paths
+            .iter()
+            .any(|path| relative_dir.starts_with(path) || Path::new(path).starts_with(relative_dir))
+    }
+}
+
+// Ignored paths are anchored at the root and only match whole components, so 'build' matches 'build'
+// and 'build/x' but neither 'build-x' nor 'x/build' (which requires a glob like '**/build' or an ignored name)
+fn is_under_path(relative_path: &Path, ignored: &str) -> bool {
+    let mut components = relative_path.components();
+
+    path_components(ignored).all(|component| components.next() == Some(component))
+}
+
+// Leading or intermediate '.' components don't change the designated path
+fn path_components(path: &str) -> impl Iterator<Item = Component<'_>> {
+    Path::new(path)
+        .components()
+        .filter(|component| !matches!(component, Component::CurDir))
+}
+
+fn relative_path_lossy(path: &Path, from_dir: &Path) -> String {
+    path.strip_prefix(from_dir)
+        .unwrap_or(path)
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Paths in snapshots always use '/' as a separator, so they can be exchanged between platforms
+pub fn portable_path(relative_path: &Path) -> Option<String> {
+    let components = relative_path
+        .components()
+        .map(|component| component.as_os_str().to_str())
+        .collect::<Option<Vec<_>>>()?;
+
+    Some(components.join("/"))
+}
+
+// Convert a path from a snapshot to the current platform's format
+pub fn native_path(portable_path: &str) -> PathBuf {
+    portable_path.split('/').collect()
+}
+
+// Hash of a file's content, used to ensure two files are identical without transferring them
+pub fn content_hash(path: &Path) -> Result<String> {
+    let mut hasher = blake3::Hasher::new();
+
+    hasher
+        .update_reader(std::fs::File::open(path).context("Failed to open file")?)
+        .context("Failed to compute the file's hash")?;
+
+    Ok(hasher.finalize().to_hex().to_string())
+}
+
+fn dir_modification_time(dir: &Path, metadata: &Metadata) -> Result<DirModificationTime> {
+    let mtime = metadata
+        .modified()
+        .with_context(|| {
+            format!(
+                "Failed to get modification time of directory: {}",
+                dir.display()
+            )
+        })?
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .with_context(|| {
+            format!(
+                "Found invalid modification time for directory: {}",
+                dir.display()
+            )
+        })?;
+
+    Ok((mtime.as_secs(), mtime.subsec_nanos()))
+}
+
+fn has_children(dir: &Path) -> Result<bool> {
+    let mut entries = std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory: {}", dir.display()))?;
+
+    Ok(entries.next().is_some())
+}
+
+// Metadata must have been fetched following symbolic links only if they are followed by the snapshot
+fn snapshot_item(item: &Path, from: &Path, metadata: &Metadata) -> Result<SnapshotItem> {
     if metadata.is_symlink() {
-        bail!("Symbolc links are unsupported.");
+        bail!("Symbolic links are not followed unless explicitly requested");
     }
 
+    let hardlink = if metadata.is_file() {
+        hardlink_id(metadata)
+    } else {
+        None
+    };
+
+    let sparse = is_sparse(metadata);
+
     let metadata = if metadata.is_dir() {
         SnapshotItemMetadata::Directory
     } else if metadata.is_file() {
@@ -212,7 +1122,7 @@ async fn snapshot_item(item: &Path, from: &Path) -> Result<SnapshotItem> {
 
     let relative_path = item.strip_prefix(from).unwrap();
 
-    let relative_path_str = relative_path.to_str().with_context(|| {
+    let relative_path_str = portable_path(relative_path).with_context(|| {
         format!(
             "Relative path contains invalid UTF-8 characters: {}",
             relative_path.display()
@@ -220,7 +1130,466 @@ async fn snapshot_item(item: &Path, from: &Path) -> Result<SnapshotItem> {
     })?;
 
     Ok(SnapshotItem {
-        relative_path: relative_path_str.to_string(),
+        relative_path: relative_path_str,
         metadata,
+        hardlink,
+        sparse,
+        acl: None,
+        xattrs: None,
     })
 }
+
+#[cfg(unix)]
+fn hardlink_id(metadata: &Metadata) -> Option<HardlinkId> {
+    use std::os::unix::fs::MetadataExt;
+
+    if metadata.nlink() > 1 {
+        Some(HardlinkId {
+            device: metadata.dev(),
+            inode: metadata.ino(),
+        })
+    } else {
+        None
+    }
+}
+
+// Get the kind of an item which is neither a file, a directory nor a symbolic link
+#[cfg(unix)]
+fn special_file_kind(file_type: &FileType) -> Option<&'static str> {
+    use std::os::unix::fs::FileTypeExt;
+
+    if file_type.is_fifo() {
+        Some("FIFO")
+    } else if file_type.is_socket() {
+        Some("socket")
+    } else if file_type.is_block_device() {
+        Some("block device")
+    } else if file_type.is_char_device() {
+        Some("character device")
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn special_file_kind(file_type: &FileType) -> Option<&'static str> {
+    if file_type.is_file() || file_type.is_dir() || file_type.is_symlink() {
+        None
+    } else {
+        Some("special file")
+    }
+}
+
+// Hard links can't be detected on other platforms, so they will be transferred as distinct files
+#[cfg(not(unix))]
+fn hardlink_id(_: &Metadata) -> Option<HardlinkId> {
+    None
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{fs, path::Path, time::UNIX_EPOCH};
+
+    use anyhow::Result;
+    use tempfile::TempDir;
+
+    use super::{
+        make_snapshot, native_path, portable_path, SchemaVersion, Snapshot, SnapshotFileMetadata,
+        SnapshotItem, SnapshotItemMetadata, SnapshotOptions, SnapshotResult, SCHEMA_VERSION,
+    };
+
+    pub fn dir(path: &str) -> SnapshotItem {
+        item(path, SnapshotItemMetadata::Directory)
+    }
+
+    pub fn file(path: &str, size: u64) -> SnapshotItem {
+        item(
+            path,
+            SnapshotItemMetadata::File(SnapshotFileMetadata {
+                size,
+                last_modif_date_s: 1_700_000_000,
+                last_modif_date_ns: 0,
+            }),
+        )
+    }
+
+    fn item(path: &str, metadata: SnapshotItemMetadata) -> SnapshotItem {
+        SnapshotItem {
+            relative_path: path.to_owned(),
+            metadata,
+            hardlink: None,
+            sparse: false,
+            acl: None,
+            xattrs: None,
+        }
+    }
+
+    pub fn snapshot(items: Vec<SnapshotItem>) -> Snapshot {
+        Snapshot {
+            version: SchemaVersion::default(),
+            from_dir: String::new(),
+            items,
+        }
+    }
+
+    pub fn paths(snapshot: &Snapshot) -> Vec<&str> {
+        snapshot
+            .items
+            .iter()
+            .map(|item| item.relative_path.as_str())
+            .collect()
+    }
+
+    // Create a directory containing the provided items, directories' paths ending with a '/'
+    fn tree(paths: &[&str]) -> TempDir {
+        let dir = TempDir::new().unwrap();
+
+        for path in paths {
+            let path = dir.path().join(path);
+
+            if path.to_str().unwrap().ends_with('/') {
+                fs::create_dir_all(path).unwrap();
+            } else {
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, "content").unwrap();
+            }
+        }
+
+        dir
+    }
+
+    async fn snapshot_of(dir: &TempDir, options: &SnapshotOptions) -> Result<SnapshotResult> {
+        make_snapshot(dir.path().to_owned(), |_| {}, options, None, None).await
+    }
+
+    fn sorted_paths(result: &SnapshotResult) -> Vec<&str> {
+        let mut paths = paths(&result.snapshot);
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn ignored_paths_match_whole_components_from_the_root() {
+        let options = SnapshotOptions::builder()
+            .ignore_path("build")
+            .ignore_path("./a/out")
+            .build()
+            .unwrap();
+
+        let matcher = options.ignore_matcher().unwrap();
+
+        for path in ["build", "build/x", "a/out", "a/out/x"] {
+            assert!(matcher.ignores_path(Path::new(path)), "{path}");
+        }
+
+        for path in ["a/build", "build-x", "x/build/y", "a/outside", "out"] {
+            assert!(!matcher.ignores_path(Path::new(path)), "{path}");
+        }
+
+        // Nested matches require a glob
+        let options = SnapshotOptions::builder()
+            .ignore_glob("**/build")
+            .build()
+            .unwrap();
+
+        let matcher = options.ignore_matcher().unwrap();
+
+        for path in ["build", "a/build", "x/build/y"] {
+            assert!(matcher.ignores_path(Path::new(path)), "{path}");
+        }
+
+        assert!(!matcher.ignores_path(Path::new("build-x")));
+    }
+
+    #[test]
+    fn uses_forward_slashes_in_snapshot_paths() {
+        let path = Path::new("a").join("b").join("c.txt");
+
+        assert_eq!(portable_path(&path).unwrap(), "a/b/c.txt");
+        assert_eq!(native_path("a/b/c.txt"), path);
+        assert_eq!(native_path(&portable_path(&path).unwrap()), path);
+    }
+
+    #[test]
+    #[cfg(windows)]
+    fn converts_windows_separators() {
+        assert_eq!(portable_path(Path::new(r"a\b\c.txt")).unwrap(), "a/b/c.txt");
+        assert_eq!(native_path("a/b/c.txt"), Path::new(r"a\b\c.txt"));
+    }
+
+    #[tokio::test]
+    async fn only_includes_whitelisted_items() {
+        let dir = tree(&[
+            "Cargo.toml",
+            "README.md",
+            "src/main.rs",
+            "src/cmd/mod.rs",
+            "target/debug/app",
+            "docs/guide/intro.md",
+            "docs/guide/image.png",
+            "empty/",
+        ]);
+
+        let options = SnapshotOptions::builder()
+            .include_path("src")
+            .include_path("Cargo.toml")
+            .include_glob("docs/**/*.md")
+            .build()
+            .unwrap();
+
+        let result = snapshot_of(&dir, &options).await.unwrap();
+
+        // Directories leading to included items are included as well
+        assert_eq!(
+            sorted_paths(&result),
+            [
+                "Cargo.toml",
+                "docs",
+                "docs/guide",
+                "docs/guide/intro.md",
+                "src",
+                "src/cmd",
+                "src/cmd/mod.rs",
+                "src/main.rs"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn skips_special_files() {
+        let dir = tree(&["file"]);
+        let _socket = std::os::unix::net::UnixListener::bind(dir.path().join("app.sock")).unwrap();
+
+        let result = snapshot_of(&dir, &SnapshotOptions::default())
+            .await
+            .unwrap();
+
+        assert_eq!(sorted_paths(&result), ["file"]);
+        assert_eq!(result.skipped.len(), 1);
+        assert_eq!(result.skipped[0].path, "app.sock");
+        assert_eq!(result.skipped[0].reason, "unsupported item type (socket)");
+
+        let options = SnapshotOptions::builder()
+            .strict_special_files(true)
+            .build()
+            .unwrap();
+
+        assert!(snapshot_of(&dir, &options).await.is_err());
+    }
+
+    #[tokio::test]
+    #[cfg(unix)]
+    async fn follows_symlinks_when_requested() {
+        use std::os::unix::fs::symlink;
+
+        let target = tree(&["x", "sub/y"]);
+        let dir = tree(&["file"]);
+
+        symlink(target.path(), dir.path().join("link")).unwrap();
+
+        // Symbolic links aren't followed by default
+        assert!(snapshot_of(&dir, &SnapshotOptions::default())
+            .await
+            .is_err());
+
+        let options = SnapshotOptions::builder()
+            .follow_symlinks(true)
+            .build()
+            .unwrap();
+
+        let result = snapshot_of(&dir, &options).await.unwrap();
+
+        assert_eq!(
+            sorted_paths(&result),
+            ["file", "link", "link/sub", "link/sub/y", "link/x"]
+        );
+
+        // Followed links are snapshotted as their target
+        for item in &result.snapshot.items {
+            let is_dir = matches!(item.metadata, SnapshotItemMetadata::Directory);
+            assert_eq!(
+                is_dir,
+                ["link", "link/sub"].contains(&item.relative_path.as_str())
+            );
+        }
+
+        // Loops are detected instead of being walked through forever
+        symlink(dir.path(), dir.path().join("loop")).unwrap();
+
+        assert!(snapshot_of(&dir, &options).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn snapshots_metadata_fetched_during_the_walk() {
+        let dir = tree(&["a.txt", "b.log", "dir/c.txt"]);
+        fs::write(dir.path().join("dir/c.txt"), "longer content").unwrap();
+
+        // Extensions of symbolic links which aren't followed apply to their target
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.path().join("a.txt"), dir.path().join("link.log")).unwrap();
+
+        let options = SnapshotOptions::builder()
+            .ignore_ext("log")
+            .build()
+            .unwrap();
+        let result = snapshot_of(&dir, &options).await.unwrap();
+
+        assert_eq!(sorted_paths(&result), ["a.txt", "dir", "dir/c.txt"]);
+
+        for item in &result.snapshot.items {
+            let path = dir.path().join(native_path(&item.relative_path));
+            let metadata = fs::metadata(&path).unwrap();
+            let mtime = metadata
+                .modified()
+                .unwrap()
+                .duration_since(UNIX_EPOCH)
+                .unwrap();
+
+            match &item.metadata {
+                SnapshotItemMetadata::File(file) => {
+                    assert_eq!(file.size, metadata.len());
+                    assert_eq!(file.last_modif_date_s, mtime.as_secs());
+                    assert_eq!(file.last_modif_date_ns, mtime.subsec_nanos());
+                }
+
+                SnapshotItemMetadata::Directory => assert_eq!(
+                    result.dirs_mtime[&item.relative_path],
+                    (mtime.as_secs(), mtime.subsec_nanos())
+                ),
+            }
+        }
+
+        assert!(result.dirs_mtime.contains_key(""));
+    }
+
+    #[test]
+    fn reads_payloads_of_supported_versions() {
+        let parse = |json: &str| serde_json::from_str::<Snapshot>(json);
+
+        // Payloads predating versioning use the first version's format
+        let unversioned = parse(r#"{ "from_dir": "/data", "items": [] }"#).unwrap();
+        assert_eq!(unversioned.version, SchemaVersion::unversioned());
+        assert_eq!(unversioned.version.get(), 1);
+
+        let versioned = parse(r#"{ "version": 1, "from_dir": "/data", "items": [] }"#).unwrap();
+        assert_eq!(versioned.version.get(), 1);
+
+        let err = parse(r#"{ "version": 2, "from_dir": "/data", "items": [] }"#).unwrap_err();
+        assert!(err.to_string().contains("please update"), "{err}");
+
+        let json = serde_json::to_string(&snapshot(vec![])).unwrap();
+        assert!(
+            json.contains(&format!(r#""version":{SCHEMA_VERSION}"#)),
+            "{json}"
+        );
+    }
+
+    #[tokio::test]
+    async fn never_includes_harmony_items() {
+        let dir = tree(&[
+            ".harmony-trash/old",
+            "dir/.harmony-probe",
+            "dir/file",
+            "harmony-file",
+            ".harmony",
+        ]);
+
+        for options in [
+            SnapshotOptions::default(),
+            SnapshotOptions::builder()
+                .include_glob("**")
+                .build()
+                .unwrap(),
+        ] {
+            let result = snapshot_of(&dir, &options).await.unwrap();
+
+            assert_eq!(
+                sorted_paths(&result),
+                [".harmony", "dir", "dir/file", "harmony-file"]
+            );
+        }
+    }
+
+    #[test]
+    fn globs_ignore_the_content_of_matching_dirs() {
+        let options = SnapshotOptions::builder()
+            .ignore_glob("**/.secrets")
+            .build()
+            .unwrap();
+
+        let matcher = options.ignore_matcher().unwrap();
+
+        for path in [".secrets", ".secrets/key", "a/.secrets", "a/.secrets/b/key"] {
+            assert!(matcher.ignores_path(Path::new(path)), "{path}");
+        }
+
+        for path in ["secrets", "a/.secrets-old/key", "a/b"] {
+            assert!(!matcher.ignores_path(Path::new(path)), "{path}");
+        }
+    }
+
+    #[test]
+    fn filtering_removes_the_content_of_ignored_dirs() {
+        let options = SnapshotOptions::builder()
+            .ignore_glob("**/.secrets")
+            .build()
+            .unwrap();
+
+        let mut snapshot = snapshot(vec![
+            dir(".secrets"),
+            file(".secrets/key", 1),
+            dir("a"),
+            file("a/kept", 1),
+        ]);
+
+        options.filter_snapshot(&mut snapshot).unwrap();
+
+        assert_eq!(paths(&snapshot), ["a", "a/kept"]);
+    }
+
+    #[tokio::test]
+    async fn warns_about_content_beyond_the_max_depth() {
+        let dir = tree(&["a/b/file", "a/empty/", "a/locked/file", "top"]);
+
+        // Unreadable directories at the maximum depth don't fail the snapshot
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(
+                dir.path().join("a/locked"),
+                fs::Permissions::from_mode(0o000),
+            )
+            .unwrap();
+        }
+
+        let options = SnapshotOptions::builder().max_depth(2).build().unwrap();
+        let result = snapshot_of(&dir, &options).await;
+
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            fs::set_permissions(
+                dir.path().join("a/locked"),
+                fs::Permissions::from_mode(0o755),
+            )
+            .unwrap();
+        }
+
+        let result = result.unwrap();
+
+        assert_eq!(
+            sorted_paths(&result),
+            ["a", "a/b", "a/empty", "a/locked", "top"]
+        );
+
+        let mut warnings = result.warnings.clone();
+        warnings.sort();
+
+        assert_eq!(warnings.len(), 2, "{warnings:?}");
+        assert!(warnings[0].contains("'a/b'"), "{warnings:?}");
+        assert!(warnings[1].contains("'a/locked'"), "{warnings:?}");
+    }
+}
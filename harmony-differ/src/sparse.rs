@@ -0,0 +1,151 @@
+use std::fs::Metadata;
+
+use anyhow::{bail, Result};
+
+// Header sent by clients which encode their upload streams to skip the holes of sparse files
+// The stream is made of segments, each starting with a tag (u8) and a length (u64 LE):
+// data segments (tag 0) are followed by their content, holes (tag 1) are made of zeros and have no content
+pub const SPARSE_ENCODING_HEADER: &str = "x-harmony-sparse";
+
+// Zero-filled blocks of this size are sent as holes
+pub const SPARSE_BLOCK_SIZE: usize = 4096;
+
+const TAG_DATA: u8 = 0;
+const TAG_HOLE: u8 = 1;
+
+const SEGMENT_HEADER_SIZE: usize = 9;
+
+// Files with less allocated blocks than their size contain holes
+#[cfg(unix)]
+pub fn is_sparse(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+
+    // Blocks are always counted in 512-byte units, regardless of the filesystem's block size
+    metadata.is_file() && metadata.blocks().saturating_mul(512) < metadata.len()
+}
+
+#[cfg(not(unix))]
+pub fn is_sparse(_: &Metadata) -> bool {
+    false
+}
+
+// Encode the content of a file read chunk by chunk
+#[derive(Default)]
+pub struct SparseEncoder {
+    pending_hole: u64,
+}
+
+impl SparseEncoder {
+    // Returns the encoded segments, holes being only emitted once the next data segment is known
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let mut encoded = vec![];
+        let mut data_start = None;
+
+        for (i, block) in chunk.chunks(SPARSE_BLOCK_SIZE).enumerate() {
+            let offset = i * SPARSE_BLOCK_SIZE;
+
+            if block.iter().all(|byte| *byte == 0) {
+                if let Some(start) = data_start.take() {
+                    push_data_segment(&mut encoded, &chunk[start..offset]);
+                }
+
+                self.pending_hole += u64::try_from(block.len()).unwrap();
+            } else if data_start.is_none() {
+                self.flush_hole(&mut encoded);
+                data_start = Some(offset);
+            }
+        }
+
+        if let Some(start) = data_start {
+            push_data_segment(&mut encoded, &chunk[start..]);
+        }
+
+        encoded
+    }
+
+    // Returns the trailing hole, if any
+    pub fn finish(mut self) -> Vec<u8> {
+        let mut encoded = vec![];
+        self.flush_hole(&mut encoded);
+        encoded
+    }
+
+    fn flush_hole(&mut self, encoded: &mut Vec<u8>) {
+        if self.pending_hole > 0 {
+            encoded.push(TAG_HOLE);
+            encoded.extend_from_slice(&self.pending_hole.to_le_bytes());
+
+            self.pending_hole = 0;
+        }
+    }
+}
+
+fn push_data_segment(encoded: &mut Vec<u8>, data: &[u8]) {
+    encoded.push(TAG_DATA);
+    encoded.extend_from_slice(&u64::try_from(data.len()).unwrap().to_le_bytes());
+    encoded.extend_from_slice(data);
+}
+
+pub enum SparseSegment {
+    Data(Vec<u8>),
+    Hole(u64),
+}
+
+// Decode a stream of segments, which may be split at any point
+// Data segments are yielded as soon as their content is received, so they can be arbitrarily large
+#[derive(Default)]
+pub struct SparseDecoder {
+    header: Vec<u8>,
+    remaining_data: u64,
+}
+
+impl SparseDecoder {
+    pub fn push(&mut self, mut data: &[u8]) -> Result<Vec<SparseSegment>> {
+        let mut segments = vec![];
+
+        while !data.is_empty() {
+            if self.remaining_data > 0 {
+                let len = usize::try_from(self.remaining_data)
+                    .unwrap_or(usize::MAX)
+                    .min(data.len());
+
+                segments.push(SparseSegment::Data(data[..len].to_vec()));
+
+                self.remaining_data -= u64::try_from(len).unwrap();
+                data = &data[len..];
+
+                continue;
+            }
+
+            let missing = (SEGMENT_HEADER_SIZE - self.header.len()).min(data.len());
+
+            self.header.extend_from_slice(&data[..missing]);
+            data = &data[missing..];
+
+            if self.header.len() < SEGMENT_HEADER_SIZE {
+                break;
+            }
+
+            let len = u64::from_le_bytes(self.header[1..].try_into().unwrap());
+
+            match self.header[0] {
+                TAG_DATA => self.remaining_data = len,
+                TAG_HOLE => segments.push(SparseSegment::Hole(len)),
+                tag => bail!("Unknown sparse segment type: {tag}"),
+            }
+
+            self.header.clear();
+        }
+
+        Ok(segments)
+    }
+
+    // Ensure the stream didn't end in the middle of a segment
+    pub fn finish(self) -> Result<()> {
+        if !self.header.is_empty() || self.remaining_data > 0 {
+            bail!("Stream ended in the middle of a sparse segment");
+        }
+
+        Ok(())
+    }
+}
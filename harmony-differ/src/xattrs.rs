@@ -0,0 +1,154 @@
+use std::{collections::BTreeMap, path::Path};
+
+use anyhow::Result;
+
+// Extended attributes of an item, associating their name to their value (in hexadecimal)
+// Attributes of the 'system' namespace are managed by the filesystem itself (e.g. ACLs) and are left out
+pub type ItemXattrs = BTreeMap<String, String>;
+
+#[cfg(target_os = "linux")]
+mod imp {
+    use std::{io::ErrorKind, path::Path};
+
+    use anyhow::{anyhow, Context, Result};
+
+    use crate::protocol::{decode_hex, encode_hex};
+
+    use super::ItemXattrs;
+
+    const SYSTEM_NAMESPACE: &str = "system.";
+
+    pub const SUPPORTED: bool = true;
+
+    fn list_names(path: &Path) -> Result<Option<Vec<String>>> {
+        let names = match xattr::list(path) {
+            Ok(names) => names,
+            Err(err) if err.kind() == ErrorKind::Unsupported => return Ok(None),
+            Err(err) => return Err(err).context("Failed to list extended attributes"),
+        };
+
+        let mut xattr_names = vec![];
+
+        for name in names {
+            let name = name
+                .into_string()
+                .map_err(|name| anyhow!("Invalid extended attribute name: {name:?}"))?;
+
+            if !name.starts_with(SYSTEM_NAMESPACE) {
+                xattr_names.push(name);
+            }
+        }
+
+        Ok(Some(xattr_names))
+    }
+
+    pub fn read_xattrs(path: &Path) -> Result<Option<ItemXattrs>> {
+        let Some(names) = list_names(path)? else {
+            return Ok(None);
+        };
+
+        let mut xattrs = ItemXattrs::new();
+
+        for name in names {
+            // Attributes may be removed between listing and reading them
+            let value = xattr::get(path, &name)
+                .with_context(|| format!("Failed to read extended attribute '{name}'"))?;
+
+            if let Some(value) = value {
+                xattrs.insert(name, encode_hex(&value));
+            }
+        }
+
+        Ok(Some(xattrs))
+    }
+
+    pub fn write_xattrs(path: &Path, xattrs: &ItemXattrs) -> Result<()> {
+        for (name, value) in xattrs {
+            let value = decode_hex(value)
+                .with_context(|| format!("Invalid content for extended attribute '{name}'"))?;
+
+            xattr::set(path, name, &value)
+                .with_context(|| format!("Failed to set extended attribute '{name}'"))?;
+        }
+
+        for name in list_names(path)?.unwrap_or_default() {
+            if !xattrs.contains_key(&name) {
+                xattr::remove(path, &name)
+                    .with_context(|| format!("Failed to remove extended attribute '{name}'"))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod imp {
+    use std::path::Path;
+
+    use anyhow::Result;
+
+    use super::ItemXattrs;
+
+    pub const SUPPORTED: bool = false;
+
+    pub fn read_xattrs(_: &Path) -> Result<Option<ItemXattrs>> {
+        Ok(None)
+    }
+
+    pub fn write_xattrs(_: &Path, _: &ItemXattrs) -> Result<()> {
+        Ok(())
+    }
+}
+
+// Whether extended attributes can be handled on this platform at all
+pub const XATTRS_SUPPORTED: bool = imp::SUPPORTED;
+
+// Returns `None` if the filesystem doesn't support extended attributes
+pub fn read_xattrs(path: &Path) -> Result<Option<ItemXattrs>> {
+    imp::read_xattrs(path)
+}
+
+// Attributes which aren't provided are removed from the item
+pub fn write_xattrs(path: &Path, xattrs: &ItemXattrs) -> Result<()> {
+    imp::write_xattrs(path, xattrs)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use std::fs;
+
+    use super::{read_xattrs, write_xattrs, ItemXattrs};
+
+    #[test]
+    fn writes_and_reads_back_xattrs() {
+        let path = std::env::temp_dir().join(format!("harmony-xattrs-{}", std::process::id()));
+        fs::write(&path, b"content").unwrap();
+
+        // Not every filesystem used for temporary files supports user attributes
+        if read_xattrs(&path).unwrap().is_none() {
+            fs::remove_file(&path).unwrap();
+            return;
+        }
+
+        let xattrs = ItemXattrs::from([
+            ("user.a".to_owned(), "0102".to_owned()),
+            ("user.b".to_owned(), String::new()),
+        ]);
+
+        let result = write_xattrs(&path, &xattrs).and_then(|()| {
+            let written = read_xattrs(&path)?;
+
+            write_xattrs(&path, &ItemXattrs::new())?;
+
+            Ok((written, read_xattrs(&path)?))
+        });
+
+        fs::remove_file(&path).unwrap();
+
+        let (written, cleared) = result.unwrap();
+
+        assert_eq!(written, Some(xattrs));
+        assert_eq!(cleared, Some(ItemXattrs::new()));
+    }
+}
@@ -0,0 +1,81 @@
+use std::{path::Path, time::SystemTime};
+
+use anyhow::{Context, Result};
+use harmony_differ::diffing::Diff;
+use serde::Serialize;
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+
+// Audit logs are rotated once they go over this size
+const MAX_AUDIT_LOG_SIZE: u64 = 10 * 1024 * 1024;
+
+#[derive(Serialize)]
+pub struct AuditRecord<'a> {
+    pub timestamp: SystemTime,
+    pub device_name: &'a str,
+    pub slot_name: &'a str,
+    pub operation: AuditOperation,
+    pub added: usize,
+    pub modified: usize,
+    pub type_changed: usize,
+    pub deleted: usize,
+    pub bytes: u64,
+    // For operations on a single item
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<&'a str>,
+}
+
+#[derive(Serialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditOperation {
+    BeginSync,
+    FinalizeSync,
+    ResetSlot,
+    AbortFile,
+}
+
+impl<'a> AuditRecord<'a> {
+    pub fn new(
+        device_name: &'a str,
+        slot_name: &'a str,
+        operation: AuditOperation,
+        diff: &Diff,
+        bytes: u64,
+    ) -> Self {
+        Self {
+            timestamp: SystemTime::now(),
+            device_name,
+            slot_name,
+            operation,
+            added: diff.added.len(),
+            modified: diff.modified.len(),
+            type_changed: diff.type_changed.len(),
+            deleted: diff.deleted.len(),
+            bytes,
+            path: None,
+        }
+    }
+
+    pub async fn append_to(&self, path: &Path) -> Result<()> {
+        if let Ok(mt) = path.metadata() {
+            if mt.len() >= MAX_AUDIT_LOG_SIZE {
+                tokio::fs::rename(path, path.with_extension("log.1"))
+                    .await
+                    .context("Failed to rotate audit log")?;
+            }
+        }
+
+        let mut line = serde_json::to_string(self).context("Failed to serialize audit record")?;
+        line.push('\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .context("Failed to open audit log")?;
+
+        file.write_all(line.as_bytes())
+            .await
+            .context("Failed to write to audit log")
+    }
+}
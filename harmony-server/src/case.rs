@@ -0,0 +1,156 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    io::ErrorKind,
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use harmony_differ::snapshot::{native_path, HARMONY_ITEMS_PREFIX};
+
+use crate::data::generate_id;
+
+// Case-insensitive filesystems (default ones on macOS and Windows) consider items whose names only
+// differ by case as the same one, so writing one of them would silently replace the other
+
+// Check if the filesystem of a directory doesn't distinguish items whose names only differ by case
+pub fn is_case_insensitive(dir: &Path) -> Result<bool> {
+    let probe_name = format!(
+        "{HARMONY_ITEMS_PREFIX}case-probe-{}",
+        generate_id().to_lowercase()
+    );
+    let probe_path = dir.join(&probe_name);
+
+    fs::write(&probe_path, []).context("Failed to create the case probe file")?;
+
+    let result = match fs::symlink_metadata(dir.join(probe_name.to_uppercase())) {
+        Ok(_) => Ok(true),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+        Err(err) => Err(err).context("Failed to check the case probe file"),
+    };
+
+    fs::remove_file(&probe_path).context("Failed to remove the case probe file")?;
+
+    result
+}
+
+// Find an item to create which would collide with another one to create or with an existing item,
+// ignoring existing items which are removed beforehand (so items can be renamed to change their case)
+// Returns the colliding item, then the item to create
+pub fn find_case_collision(
+    content_dir: &Path,
+    created: &[&str],
+    removed: &[&str],
+) -> Result<Option<(String, String)>> {
+    let mut folded = HashMap::<String, &str>::new();
+
+    for path in created {
+        if let Some(other) = folded.insert(path.to_lowercase(), path) {
+            if other != *path {
+                return Ok(Some((other.to_owned(), path.to_string())));
+            }
+        }
+    }
+
+    let is_removed = |path: &str| {
+        removed
+            .iter()
+            .any(|removed| Path::new(path).starts_with(removed))
+    };
+
+    // Names of the items in each (existing) parent directory
+    let mut dirs_entries = HashMap::<&str, Option<Vec<String>>>::new();
+
+    for path in created {
+        let (parent, name) = path.rsplit_once('/').unwrap_or(("", path));
+
+        let entries = match dirs_entries.entry(parent) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(
+                read_dir_names(&content_dir.join(native_path(parent))).with_context(|| {
+                    format!("Failed to read directory '{parent}' in the slot's content")
+                })?,
+            ),
+        };
+
+        let folded_name = name.to_lowercase();
+
+        for entry in entries.iter().flatten() {
+            if entry == name || entry.to_lowercase() != folded_name {
+                continue;
+            }
+
+            let existing = if parent.is_empty() {
+                entry.clone()
+            } else {
+                format!("{parent}/{entry}")
+            };
+
+            if !is_removed(&existing) {
+                return Ok(Some((existing, path.to_string())));
+            }
+        }
+    }
+
+    Ok(None)
+}
+
+// Returns `None` if the directory doesn't exist (yet), e.g. if it replaces a file
+fn read_dir_names(dir: &Path) -> Result<Option<Vec<String>>> {
+    if !dir.is_dir() {
+        return Ok(None);
+    }
+
+    fs::read_dir(dir)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<Result<_>>()
+        .map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::{find_case_collision, is_case_insensitive};
+
+    #[test]
+    fn finds_case_collisions() {
+        let content_dir = TempDir::new().unwrap();
+        fs::create_dir(content_dir.path().join("docs")).unwrap();
+        fs::write(content_dir.path().join("docs/README.md"), "").unwrap();
+
+        let collision = |created: &[&str], removed: &[&str]| {
+            find_case_collision(content_dir.path(), created, removed).unwrap()
+        };
+
+        // Between items to create
+        assert_eq!(
+            collision(&["a/file.txt", "a/FILE.txt"], &[]),
+            Some(("a/file.txt".to_owned(), "a/FILE.txt".to_owned()))
+        );
+
+        // With an existing item
+        assert_eq!(
+            collision(&["docs/readme.md"], &[]),
+            Some(("docs/README.md".to_owned(), "docs/readme.md".to_owned()))
+        );
+
+        // Existing items can be renamed to change their case
+        assert_eq!(collision(&["docs/readme.md"], &["docs/README.md"]), None);
+        assert_eq!(collision(&["DOCS"], &["docs"]), None);
+
+        assert_eq!(collision(&["docs/other.md", "new/README.md"], &[]), None);
+    }
+
+    #[test]
+    fn probes_case_sensitivity() {
+        let dir = TempDir::new().unwrap();
+
+        // Both results are valid depending on the filesystem, but the probe must not be left behind
+        is_case_insensitive(dir.path()).unwrap();
+
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+    }
+}
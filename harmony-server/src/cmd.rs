@@ -2,8 +2,9 @@ use std::{net::IpAddr, path::PathBuf};
 
 use clap::Parser;
 use log::LevelFilter;
+use reqwest::Url;
 
-use crate::paths::SlotInfos;
+use crate::paths::{CompletionTracking, SlotInfos};
 
 #[derive(Parser)]
 pub struct Args {
@@ -27,6 +28,19 @@ pub struct HttpArgs {
 
     #[clap(short, long, help = "Port to listen on", default_value = "9423")]
     pub port: u16,
+
+    #[clap(
+        long,
+        help = "Maximum size of a request's body, in bytes",
+        default_value = "17179869184"
+    )]
+    pub max_body_size: usize,
+
+    #[clap(
+        long,
+        help = "Maximum number of synchronizations open at the same time across all slots (unlimited by default)"
+    )]
+    pub max_open_syncs: Option<usize>,
 }
 
 #[derive(clap::Args)]
@@ -37,6 +51,124 @@ pub struct BackupArgs {
     )]
     pub slots: Vec<SlotInfos>,
 
-    #[clap(long, help = "The secret password")]
-    pub secret: String,
+    #[clap(
+        long,
+        help = "The secret password, stored hashed so it only needs to be provided once. Providing a different one replaces it and revokes all access tokens and device keys (don't keep providing an old one after rotating it)"
+    )]
+    pub secret: Option<String>,
+
+    #[clap(
+        long,
+        conflicts_with = "secret",
+        help = "Argon2 hash of the secret password (PHC string, e.g. '$argon2id$v=19$...'), to use instead of '--secret' so it is never provided in plain text"
+    )]
+    pub secret_hash: Option<String>,
+
+    #[clap(
+        long,
+        help = "Secret password for administrative operations (e.g. resetting a slot), which are disabled if not provided"
+    )]
+    pub admin_secret: Option<String>,
+
+    #[clap(
+        long,
+        help = "Length of the generated access tokens (at least 32)",
+        default_value = "48"
+    )]
+    pub access_token_length: usize,
+
+    #[clap(
+        long,
+        help = "Number of days after which access tokens expire and clients have to request a new one (never by default)"
+    )]
+    pub access_token_max_age: Option<u64>,
+
+    #[clap(
+        long,
+        help = "Maximum number of access tokens a single device name can have, the least recently used ones being revoked when a new one is requested (1 to replace the previous token every time, which prevents several clients from sharing a device name)",
+        default_value = "10"
+    )]
+    pub max_tokens_per_device: usize,
+
+    #[clap(
+        long,
+        help = "Reject synchronizations which would delete more than this percentage of a slot's items, unless the client forces them (can be overridden in each slot's 'settings.json')"
+    )]
+    pub max_deletion_percent: Option<f64>,
+
+    #[clap(
+        long,
+        help = "Keep the items deleted or replaced by each synchronization in the slot's 'history' directory, as hard links to their previous versions, for this number of synchronizations (disabled by default, can be overridden in each slot's 'settings.json')"
+    )]
+    pub history: Option<usize>,
+
+    #[clap(
+        long,
+        help = "URL to send a POST request to after a synchronization is finalized"
+    )]
+    pub on_finalize: Option<Url>,
+
+    #[clap(
+        long,
+        help = "Shell command to run after a synchronization is finalized"
+    )]
+    pub on_finalize_exec: Option<String>,
+
+    #[clap(
+        long,
+        help = "Time (in seconds) after which an inactive synchronization can be forcibly closed to let another one begin"
+    )]
+    pub sync_lock_timeout: Option<u64>,
+
+    #[clap(
+        long,
+        help = "Flush every transferred file to the disk before considering it complete. This protects against corrupted files after a power loss, but makes synchronizations of many small files noticeably slower."
+    )]
+    pub durable: bool,
+
+    #[clap(
+        long,
+        help = "Size of the buffer transferred files are written through, in bytes, so small chunks of data are written to the disk together (0 to write each chunk as soon as it is received)",
+        default_value = "262144"
+    )]
+    pub write_buffer_size: usize,
+
+    #[clap(
+        long,
+        help = "Maximum number of filesystem operations (creating directories, moving files, ...) run simultaneously when finalizing a synchronization. Higher values (e.g. 16) speed up finalizations on networked or other high-latency disks, but slow them down on local ones.",
+        default_value = "1"
+    )]
+    pub finalize_concurrency: usize,
+
+    #[clap(
+        long,
+        value_enum,
+        help = "How fully transferred files are tracked: 'dir' moves each of them to a dedicated directory, 'journal' records them in a single append-only file, which requires less filesystem operations (faster on slow or networked disks)",
+        default_value = "dir"
+    )]
+    pub completion_tracking: CompletionTracking,
+
+    #[clap(
+        long,
+        help = "Directory to store files being transferred in instead of each slot's directory (e.g. on a faster volume). Transferred files are moved into place atomically when it is on the same filesystem as the slots' content, otherwise they are first copied next to their destination, which is slower and may leave temporary copies behind after a crash."
+    )]
+    pub transfer_dir: Option<PathBuf>,
+
+    #[clap(
+        long,
+        help = "Keep the open synchronizations of slots whose linked directory changed since the server's last start instead of refusing to start. This is only safe if the directory's content was moved as-is."
+    )]
+    pub keep_relinked_syncs: bool,
+
+    #[clap(
+        long,
+        help = "Keep the latest snapshot of each slot (in its data directory) up to date with the synchronizations, so it can be sent to clients without walking through the slot's content again. Changes made to the content outside of synchronizations are then only detected when a client requests a full walk ('--rescan-remote')."
+    )]
+    pub cache_snapshot: bool,
+
+    #[clap(
+        long,
+        help = "Store identical files only once across all slots, as hard links to a shared pool (Unix only, slots' content must be on the same filesystem as the data directory)"
+    )]
+    pub dedup: bool,
 }
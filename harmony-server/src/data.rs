@@ -1,6 +1,14 @@
-use std::{path::Path, time::SystemTime};
+use std::{
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
 
-use anyhow::{bail, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng as SaltRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use harmony_differ::snapshot::SnapshotOptions;
 use rand::{rngs::OsRng, Rng};
 use serde::{Deserialize, Serialize};
 use tokio::fs;
@@ -9,12 +17,24 @@ use tokio::fs;
 #[serde(deny_unknown_fields)]
 pub struct AppData {
     access_tokens: Vec<AccessToken>,
+    // Public keys of the devices allowed to get access tokens without the secret password
+    #[serde(default)]
+    device_keys: Vec<DeviceKey>,
+    // Tokens which were revoked to make room for newer ones of the same device, to explain why they are rejected
+    #[serde(default)]
+    replaced_tokens: Vec<ReplacedToken>,
+    // Hash of the secret password, only known in plain text by the devices
+    #[serde(default)]
+    secret_hash: Option<String>,
 }
 
 impl AppData {
     pub fn empty() -> Self {
         Self {
             access_tokens: vec![],
+            device_keys: vec![],
+            replaced_tokens: vec![],
+            secret_hash: None,
         }
     }
 
@@ -37,16 +57,225 @@ impl AppData {
             .context("Failed to write app data to file")
     }
 
-    pub fn create_access_token(&mut self, device_name: String) -> &AccessToken {
-        self.access_tokens.push(AccessToken::new(device_name));
+    // Devices can't have more than `max_per_device` tokens, the least recently used ones being replaced first
+    pub fn create_access_token(
+        &mut self,
+        device_name: String,
+        length: usize,
+        max_per_device: usize,
+    ) -> &AccessToken {
+        let mut device_tokens = self
+            .access_tokens
+            .iter()
+            .filter(|token| token.device_name == device_name)
+            .map(|token| (token.last_use, token.token.clone()))
+            .collect::<Vec<_>>();
+
+        if device_tokens.len() >= max_per_device {
+            device_tokens.sort();
+
+            let now = SystemTime::now();
+
+            for (_, token) in device_tokens.drain(..=device_tokens.len() - max_per_device) {
+                self.revoke_access_token(&token);
+
+                self.replaced_tokens.push(ReplacedToken {
+                    device_name: device_name.clone(),
+                    token,
+                    replaced_at: now,
+                });
+            }
+
+            let excess = self
+                .replaced_tokens
+                .len()
+                .saturating_sub(MAX_REPLACED_TOKENS);
+
+            self.replaced_tokens.drain(..excess);
+        }
+
+        self.access_tokens
+            .push(AccessToken::new(device_name, length));
         self.access_tokens.last().unwrap()
     }
 
+    pub fn get_replaced_token(&self, token: &str) -> Option<&ReplacedToken> {
+        self.replaced_tokens.iter().find(|c| c.token == token)
+    }
+
+    pub fn revoke_access_token(&mut self, token: &str) {
+        self.access_tokens.retain(|c| c.token != token);
+    }
+
     pub fn get_access_token(&mut self, token: &str) -> Option<&AccessToken> {
         let access_token = self.access_tokens.iter_mut().find(|c| c.token == token)?;
         access_token.last_use = SystemTime::now();
         Some(access_token)
     }
+
+    // Enrolling a key again only updates its device's name
+    pub fn enroll_device_key(&mut self, device_name: String, public_key: String) {
+        self.device_keys.retain(|key| key.public_key != public_key);
+        self.device_keys.push(DeviceKey {
+            device_name,
+            public_key,
+            enrolled_at: SystemTime::now(),
+        });
+    }
+
+    pub fn get_device_key(&self, public_key: &str) -> Option<&DeviceKey> {
+        self.device_keys
+            .iter()
+            .find(|key| key.public_key == public_key)
+    }
+
+    pub fn secret_hash(&self) -> Option<&str> {
+        self.secret_hash.as_deref()
+    }
+
+    pub fn set_secret_hash(&mut self, secret_hash: String) {
+        self.secret_hash = Some(secret_hash);
+    }
+
+    // Every device then has to request a new access token with the secret password
+    // Returns the number of revoked access tokens and device keys
+    pub fn revoke_all_access(&mut self) -> (usize, usize) {
+        let revoked = (self.access_tokens.len(), self.device_keys.len());
+
+        self.access_tokens.clear();
+        self.device_keys.clear();
+        self.replaced_tokens.clear();
+
+        revoked
+    }
+}
+
+// Hashing is purposely slow, so it should not be run on asynchronous tasks
+pub fn hash_secret(secret: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut SaltRng);
+
+    Argon2::default()
+        .hash_password(secret.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| anyhow!("Failed to hash the secret password: {err}"))
+}
+
+pub fn is_valid_secret_hash(secret_hash: &str) -> bool {
+    PasswordHash::new(secret_hash)
+        .is_ok_and(|hash| argon2::Algorithm::try_from(hash.algorithm).is_ok())
+}
+
+// Hashes are compared in constant time
+pub fn verify_secret(secret_hash: &str, secret: &str) -> bool {
+    PasswordHash::new(secret_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(secret.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+// Ignore rules enforced by the server for a slot, whatever the client's own rules are
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SlotIgnoreRules {
+    #[serde(default)]
+    ignore_paths: Vec<String>,
+    #[serde(default)]
+    ignore_names: Vec<String>,
+    #[serde(default)]
+    ignore_exts: Vec<String>,
+}
+
+impl SlotIgnoreRules {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .await
+            .context("Failed to read slot ignore rules file")?;
+
+        serde_json::from_str(&json).context("Failed to parse slot ignore rules file")
+    }
+
+    pub fn into_snapshot_options(self) -> Result<SnapshotOptions> {
+        let Self {
+            ignore_paths,
+            ignore_names,
+            ignore_exts,
+        } = self;
+
+        let options = SnapshotOptions {
+            ignore_paths,
+            ignore_names,
+            ignore_exts,
+            ..Default::default()
+        };
+
+        options
+            .validate()
+            .context("Invalid rule in slot ignore rules file")?;
+
+        Ok(options)
+    }
+}
+
+// Settings of a slot, overriding the server's ones
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct SlotSettings {
+    #[serde(default)]
+    max_deletion_percent: Option<f64>,
+    // Disable the deletion limit, even if the server has one
+    #[serde(default)]
+    allow_mass_deletions: bool,
+    // Number of history points to keep, 0 disabling history even if the server has it enabled
+    #[serde(default)]
+    history: Option<usize>,
+}
+
+impl SlotSettings {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .await
+            .context("Failed to read slot settings file")?;
+
+        serde_json::from_str(&json).context("Failed to parse slot settings file")
+    }
+
+    pub fn max_deletion_percent(&self, server_default: Option<f64>) -> Option<f64> {
+        if self.allow_mass_deletions {
+            None
+        } else {
+            self.max_deletion_percent.or(server_default)
+        }
+    }
+
+    pub fn history(&self, server_default: Option<usize>) -> Option<usize> {
+        self.history.or(server_default).filter(|keep| *keep > 0)
+    }
+}
+
+// Directory a slot's content was linked to when the server last started
+#[derive(Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SlotLink {
+    pub linked: Option<PathBuf>,
+}
+
+impl SlotLink {
+    pub async fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .await
+            .context("Failed to read slot link file")?;
+
+        serde_json::from_str(&json).context("Failed to parse slot link file")
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string(self).context("Failed to serialize slot link")?;
+
+        fs::write(path, json)
+            .await
+            .context("Failed to write slot link file")
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -59,34 +288,150 @@ pub struct AccessToken {
 }
 
 impl AccessToken {
-    pub fn new(device_name: String) -> Self {
+    pub fn new(device_name: String, length: usize) -> Self {
         let now = SystemTime::now();
 
         Self {
             device_name,
-            token: generate_id(),
+            token: generate_access_token(length),
             created_at: now,
             last_use: now,
         }
     }
 
-    // pub fn device_name(&self) -> &str {
-    //     &self.device_name
-    // }
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
 
     pub fn token(&self) -> &str {
         &self.token
     }
 
-    // pub fn created_at(&self) -> &SystemTime {
-    //     &self.created_at
-    // }
+    pub fn is_older_than(&self, max_age: Duration) -> bool {
+        // Tokens created in the future (clock changes) are considered as fresh
+        self.created_at
+            .elapsed()
+            .is_ok_and(|elapsed| elapsed > max_age)
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceKey {
+    device_name: String,
+    // Ed25519 public key, in hexadecimal
+    public_key: String,
+    enrolled_at: SystemTime,
 }
 
+impl DeviceKey {
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ReplacedToken {
+    device_name: String,
+    token: String,
+    replaced_at: SystemTime,
+}
+
+impl ReplacedToken {
+    pub fn device_name(&self) -> &str {
+        &self.device_name
+    }
+}
+
+// Number of replaced tokens which are remembered, the oldest ones being forgotten first
+const MAX_REPLACED_TOKENS: usize = 100;
+
+// Access tokens must not be shorter than this
+pub const MIN_ACCESS_TOKEN_LENGTH: usize = 32;
+
+const ID_CHARSET: &[u8] = b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+
+// All characters are valid in bearer tokens, and each one provides 6 bits of entropy
 const ACCESS_TOKEN_CHARSET: &[u8] =
-    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789";
+    b"abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ0123456789-_";
 
 pub fn generate_id() -> String {
-    let one_char = || ACCESS_TOKEN_CHARSET[OsRng.gen_range(0..ACCESS_TOKEN_CHARSET.len())] as char;
-    (0..32).map(|_| one_char()).collect()
+    random_string(ID_CHARSET, 32)
+}
+
+fn generate_access_token(length: usize) -> String {
+    assert!(length >= MIN_ACCESS_TOKEN_LENGTH);
+    random_string(ACCESS_TOKEN_CHARSET, length)
+}
+
+fn random_string(charset: &[u8], length: usize) -> String {
+    (0..length)
+        .map(|_| charset[OsRng.gen_range(0..charset.len())] as char)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread::sleep, time::Duration};
+
+    use super::{hash_secret, is_valid_secret_hash, verify_secret, AppData};
+
+    #[test]
+    fn stores_secrets_as_salted_hashes() {
+        let hash = hash_secret("pw").unwrap();
+
+        assert!(hash.starts_with("$argon2"));
+        assert!(!hash.contains("pw"));
+        assert!(is_valid_secret_hash(&hash));
+        assert_ne!(hash, hash_secret("pw").unwrap());
+
+        assert!(verify_secret(&hash, "pw"));
+        assert!(!verify_secret(&hash, "pw2"));
+        assert!(!verify_secret(&hash, ""));
+        assert!(!verify_secret("pw", "pw"));
+
+        for invalid in [
+            "pw",
+            "",
+            "$2b$12$R9h/cIPz0gi.URNNX3kh2OPST9/PgBkqquzi.Ss7KIUgO2t0jWMUW",
+        ] {
+            assert!(!is_valid_secret_hash(invalid), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn replaces_least_recently_used_tokens_of_a_device() {
+        let mut app_data = AppData::empty();
+
+        let create = |app_data: &mut AppData, device_name: &str| {
+            // Ensures tokens don't share the same last use date
+            sleep(Duration::from_millis(5));
+
+            app_data
+                .create_access_token(device_name.to_owned(), 32, 2)
+                .token()
+                .to_owned()
+        };
+
+        let first = create(&mut app_data, "laptop");
+        let second = create(&mut app_data, "laptop");
+        let other_device = create(&mut app_data, "desktop");
+
+        sleep(Duration::from_millis(5));
+        assert!(app_data.get_access_token(&first).is_some());
+
+        let third = create(&mut app_data, "laptop");
+
+        assert!(app_data.get_access_token(&second).is_none());
+        assert_eq!(
+            app_data.get_replaced_token(&second).unwrap().device_name(),
+            "laptop"
+        );
+
+        for token in [&first, &third, &other_device] {
+            assert!(app_data.get_access_token(token).is_some());
+            assert!(app_data.get_replaced_token(token).is_none());
+        }
+    }
 }
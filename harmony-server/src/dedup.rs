@@ -0,0 +1,140 @@
+use std::{
+    fs::{self, File, Metadata},
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{Context, Result};
+
+// Content-addressed storage shared by all slots
+//
+// Each stored file is a hard link to an object of the pool, named after its content's hash.
+// As a hard link shares its modification time with all the other ones, it's part of the object's
+// name too, so files with the same content but different modification times are stored separately.
+//
+// The filesystem's link count acts as the reference count of each object: when it drops to 1,
+// the object is only referenced by the pool itself and can be collected.
+
+// Replace a file by a link to an identical object if there is one, otherwise add it to the pool
+pub fn deduplicate_file(objects_dir: &Path, path: &Path, mtime: (u64, u32)) -> Result<()> {
+    let mut hasher = blake3::Hasher::new();
+
+    hasher
+        .update_reader(File::open(path).context("Failed to open file")?)
+        .context("Failed to compute the file's hash")?;
+
+    let hash = hasher.finalize().to_hex();
+
+    let object = object_path(objects_dir, &hash, mtime);
+
+    if object.is_file() {
+        // Renaming a link over another one of the same file does nothing, which would leave the link behind
+        if is_same_file(&object, path)? {
+            return Ok(());
+        }
+
+        let tmp_path = path.with_extension("dedup");
+
+        match fs::hard_link(&object, &tmp_path) {
+            Ok(()) => {
+                return fs::rename(&tmp_path, path)
+                    .context("Failed to replace the file with a link to the existing object");
+            }
+
+            // The object was collected in the meantime
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+
+            Err(err) => return Err(err).context("Failed to link the existing object"),
+        }
+    }
+
+    fs::create_dir_all(object.parent().unwrap())
+        .context("Failed to create the object's directory")?;
+
+    match fs::hard_link(path, &object) {
+        Ok(()) => Ok(()),
+
+        // An identical file was stored concurrently, this one just won't be deduplicated
+        Err(err) if err.kind() == ErrorKind::AlreadyExists => Ok(()),
+
+        Err(err) => Err(err).context("Failed to add the file to the objects pool"),
+    }
+}
+
+// Path of the object holding a content with the provided hash and modification time
+pub fn object_path(objects_dir: &Path, hash: &str, mtime: (u64, u32)) -> PathBuf {
+    let (mtime_s, mtime_ns) = mtime;
+
+    objects_dir
+        .join(&hash[..2])
+        .join(format!("{hash}-{mtime_s}-{mtime_ns}"))
+}
+
+// Remove objects which aren't referenced by any slot anymore, returning how many were removed
+pub fn collect_garbage(objects_dir: &Path) -> Result<usize> {
+    if !objects_dir.is_dir() {
+        return Ok(0);
+    }
+
+    let mut removed = 0;
+
+    for shard in fs::read_dir(objects_dir).context("Failed to read the objects directory")? {
+        let shard = shard.context("Failed to read the objects directory")?;
+
+        for object in fs::read_dir(shard.path()).context("Failed to read an objects directory")? {
+            let object = object.context("Failed to read an objects directory")?;
+
+            let metadata = object
+                .metadata()
+                .context("Failed to get an object's metadata")?;
+
+            if link_count(&metadata) > 1 {
+                continue;
+            }
+
+            match fs::remove_file(object.path()) {
+                Ok(()) => removed += 1,
+
+                // Collected concurrently
+                Err(err) if err.kind() == ErrorKind::NotFound => {}
+
+                Err(err) => {
+                    return Err(err).with_context(|| {
+                        format!("Failed to remove object '{}'", object.path().display())
+                    })
+                }
+            }
+        }
+    }
+
+    Ok(removed)
+}
+
+#[cfg(unix)]
+fn link_count(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.nlink()
+}
+
+// Link counts aren't available, so objects are never considered as unused
+#[cfg(not(unix))]
+fn link_count(_: &Metadata) -> u64 {
+    u64::MAX
+}
+
+#[cfg(unix)]
+fn is_same_file(a: &Path, b: &Path) -> Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+
+    let a = fs::metadata(a).context("Failed to get the object's metadata")?;
+    let b = fs::metadata(b).context("Failed to get the file's metadata")?;
+
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+// Files can't be identified, so they are never considered as the same
+#[cfg(not(unix))]
+fn is_same_file(_: &Path, _: &Path) -> Result<bool> {
+    Ok(false)
+}
@@ -0,0 +1,92 @@
+use std::{collections::HashMap, path::Path, time::SystemTime};
+
+use anyhow::{Context, Result};
+use harmony_differ::snapshot::content_hash;
+use serde::{Deserialize, Serialize};
+
+// Content hashes of a slot's files, which are only computed again when a file's size or modification time changes
+// Operations are blocking, as hashing requires reading whole files anyway
+#[derive(Default, Serialize, Deserialize)]
+pub struct HashCache {
+    files: HashMap<String, CachedHash>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct CachedHash {
+    size: u64,
+    modified: SystemTime,
+    hash: String,
+}
+
+pub struct FileHash {
+    pub hash: String,
+    // Set when the file's content changed since it was last hashed, without its metadata changing
+    pub corrupted: bool,
+}
+
+impl HashCache {
+    pub fn load(path: &Path) -> Result<Self> {
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+
+        let json = std::fs::read_to_string(path).context("Failed to read hash cache file")?;
+
+        serde_json::from_str(&json).context("Failed to parse hash cache file")
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string(self).context("Failed to serialize hash cache")?;
+
+        std::fs::write(path, json).context("Failed to write hash cache file")
+    }
+
+    // Get a file's hash, from the cache if its metadata didn't change since it was computed
+    // When `verify` is set, the hash is always computed again to detect corruptions
+    pub fn hash(&mut self, relative_path: &str, path: &Path, verify: bool) -> Result<FileHash> {
+        let mt = path.metadata().context("Failed to get file's metadata")?;
+
+        let size = mt.len();
+        let modified = mt
+            .modified()
+            .context("Failed to get file's modification time")?;
+
+        let cached = self
+            .files
+            .get(relative_path)
+            .filter(|cached| cached.size == size && cached.modified == modified);
+
+        if let Some(cached) = cached {
+            if !verify {
+                return Ok(FileHash {
+                    hash: cached.hash.clone(),
+                    corrupted: false,
+                });
+            }
+        }
+
+        let hash = content_hash(path)?;
+        let corrupted = cached.is_some_and(|cached| cached.hash != hash);
+
+        self.files.insert(
+            relative_path.to_owned(),
+            CachedHash {
+                size,
+                modified,
+                hash: hash.clone(),
+            },
+        );
+
+        Ok(FileHash { hash, corrupted })
+    }
+
+    // Forget about the provided items (and their content for directories), which were changed by a synchronization
+    // This is required as files may be replaced by others with the exact same size and modification time
+    pub fn invalidate(&mut self, paths: &[String]) {
+        self.files.retain(|relative_path, _| {
+            !paths
+                .iter()
+                .any(|path| Path::new(relative_path).starts_with(path))
+        });
+    }
+}
@@ -0,0 +1,126 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use harmony_differ::snapshot::native_path;
+
+// Previous versions of a slot's items, kept as of each synchronization
+//
+// Each history point is a directory named after the time its synchronization was opened, holding
+// the items it deleted or replaced at their original location. Files are hard links to the previous
+// versions, so they don't use any additional space as long as they are shared with other points.
+// This relies on files always being replaced by new ones instead of being modified in place.
+
+// Name of the history point of a synchronization opened at the provided time
+pub fn history_point_name(opened_at: SystemTime) -> String {
+    opened_at
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .to_string()
+}
+
+// Keep the current version of the provided items (files or directories) in a history point
+// Items which are already in it were kept by a previous attempt, which had the older version
+pub fn preserve_items<'a>(
+    content_dir: &Path,
+    point_dir: &Path,
+    relative_paths: impl IntoIterator<Item = &'a str>,
+) -> Result<()> {
+    for relative_path in relative_paths {
+        let relative_path = native_path(relative_path);
+
+        preserve_item(
+            &content_dir.join(&relative_path),
+            &point_dir.join(&relative_path),
+        )
+        .with_context(|| {
+            format!(
+                "Failed to keep the previous version of '{}' in history",
+                relative_path.display()
+            )
+        })?;
+    }
+
+    Ok(())
+}
+
+fn preserve_item(path: &Path, kept_path: &Path) -> Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).context("Failed to get item's metadata"),
+    };
+
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path).context("Failed to read directory")? {
+            let entry = entry.context("Failed to read directory")?;
+
+            preserve_item(&entry.path(), &kept_path.join(entry.file_name()))?;
+        }
+
+        return Ok(());
+    }
+
+    if !metadata.is_file() {
+        return Ok(());
+    }
+
+    fs::create_dir_all(kept_path.parent().unwrap())
+        .context("Failed to create directory in history")?;
+
+    let Err(err) = fs::hard_link(path, kept_path) else {
+        return Ok(());
+    };
+
+    match err.kind() {
+        ErrorKind::AlreadyExists => Ok(()),
+
+        // History may not be on the same filesystem as the slot's content (e.g. for linked slots)
+        _ => {
+            fs::copy(path, kept_path).context("Failed to copy file to history")?;
+
+            filetime::set_file_mtime(kept_path, FileTime::from_last_modification_time(&metadata))
+                .context("Failed to set modification time of file in history")
+        }
+    }
+}
+
+// Remove the oldest history points so only the provided number of them remain
+// Returns how many were removed
+pub fn prune_history(history_dir: &Path, keep: usize) -> Result<usize> {
+    if !history_dir.is_dir() {
+        return Ok(0);
+    }
+
+    let mut points = vec![];
+
+    for entry in fs::read_dir(history_dir).context("Failed to read the history directory")? {
+        let entry = entry.context("Failed to read the history directory")?;
+
+        // Other items were not created by the server
+        if let Some(time) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<u64>().ok())
+        {
+            points.push((time, entry.path()));
+        }
+    }
+
+    points.sort();
+
+    let to_remove = points.len().saturating_sub(keep);
+
+    for (_, path) in &points[..to_remove] {
+        fs::remove_dir_all(path)
+            .with_context(|| format!("Failed to remove history point '{}'", path.display()))?;
+    }
+
+    Ok(to_remove)
+}
@@ -0,0 +1,90 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, error};
+use reqwest::{Client, Url};
+use serde::Serialize;
+use tokio::process::Command;
+
+use crate::cmd::BackupArgs;
+
+#[derive(Serialize, Clone)]
+pub struct FinalizeHookPayload {
+    pub slot_name: String,
+    pub sync_id: String,
+    pub files_changed: usize,
+    pub bytes_transferred: u64,
+}
+
+pub fn trigger_finalize_hooks(args: &BackupArgs, payload: FinalizeHookPayload) {
+    // Hooks are run in the background and their failures are only logged,
+    // as they must never make a synchronization fail
+    if let Some(url) = &args.on_finalize {
+        let url = url.clone();
+        let payload = payload.clone();
+
+        tokio::spawn(async move {
+            match send_webhook(url, &payload).await {
+                Ok(()) => debug!("Finalization webhook was sent successfully"),
+                Err(err) => error!("Failed to send finalization webhook: {err:?}"),
+            }
+        });
+    }
+
+    if let Some(command) = &args.on_finalize_exec {
+        let command = command.clone();
+
+        tokio::spawn(async move {
+            match run_command(&command, &payload).await {
+                Ok(()) => debug!("Finalization command was run successfully"),
+                Err(err) => error!("Failed to run finalization command: {err:?}"),
+            }
+        });
+    }
+}
+
+async fn send_webhook(url: Url, payload: &FinalizeHookPayload) -> Result<()> {
+    Client::new()
+        .post(url)
+        .json(payload)
+        .send()
+        .await
+        .context("HTTP request failed")?
+        .error_for_status()
+        .context("Webhook responded with an error")?;
+
+    Ok(())
+}
+
+async fn run_command(command: &str, payload: &FinalizeHookPayload) -> Result<()> {
+    let FinalizeHookPayload {
+        slot_name,
+        sync_id,
+        files_changed,
+        bytes_transferred,
+    } = payload;
+
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
+    };
+
+    let status = cmd
+        .arg(command)
+        .env("HARMONY_SLOT_NAME", slot_name)
+        .env("HARMONY_SYNC_ID", sync_id)
+        .env("HARMONY_FILES_CHANGED", files_changed.to_string())
+        .env("HARMONY_BYTES_TRANSFERRED", bytes_transferred.to_string())
+        .status()
+        .await
+        .context("Failed to spawn command")?;
+
+    if !status.success() {
+        bail!("Command failed with status: {status}");
+    }
+
+    Ok(())
+}
@@ -1,32 +1,86 @@
+use std::time::Duration;
+
 use axum::{
     extract::State,
     headers::{authorization::Bearer, Authorization},
-    http::Request,
+    http::{HeaderName, HeaderValue, Request},
     middleware::Next,
     response::Response,
     TypedHeader,
 };
 
-use crate::throw_err;
+use harmony_differ::protocol::ACCESS_TOKEN_EXPIRED_HEADER;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+
+use crate::{server_err, throw_err};
 
 use super::{errors::HttpError, state::HttpState};
 
 pub async fn auth_middleware<B>(
     TypedHeader(Authorization(bearer_token)): TypedHeader<Authorization<Bearer>>,
     State(state): State<HttpState>,
-    request: Request<B>,
+    mut request: Request<B>,
     next: Next<B>,
 ) -> Result<Response, HttpError> {
-    authenticate(bearer_token.token(), &state).await?;
+    let device = authenticate(bearer_token.token(), &state).await?;
+    request.extensions_mut().insert(device);
     Ok(next.run(request).await)
 }
 
-async fn authenticate(bearer_token: &str, state: &HttpState) -> Result<(), HttpError> {
-    let mut state = state.app_data.write().await;
+async fn authenticate(
+    bearer_token: &str,
+    state: &HttpState,
+) -> Result<AuthenticatedDevice, HttpError> {
+    let mut app_data = state.app_data.write().await;
+
+    let Some(access_token) = app_data.get_access_token(bearer_token) else {
+        // Multiple clients using the same device name may replace each other's token
+        if let Some(replaced) = app_data.get_replaced_token(bearer_token) {
+            throw_err!(
+                FORBIDDEN,
+                format!(
+                    "Access token was replaced by a newer one requested with the same device name ('{}'), are multiple clients using it?",
+                    replaced.device_name()
+                )
+            );
+        }
 
-    if state.get_access_token(bearer_token).is_none() {
         throw_err!(FORBIDDEN, "Invalid access token provided");
+    };
+
+    // Expired tokens are revoked, clients then have to request a new one
+    if let Some(max_age_days) = state.backup_args.access_token_max_age {
+        if access_token.is_older_than(Duration::from_secs(max_age_days * 24 * 3600)) {
+            info!(
+                "Access token of device '{}' expired and was revoked",
+                access_token.device_name()
+            );
+
+            app_data.revoke_access_token(bearer_token);
+
+            if let Err(err) = app_data.save(&state.paths.app_data_file()).await {
+                error!("Failed to save data file: {err:?}");
+            }
+
+            return Err(
+                server_err!(FORBIDDEN, "Provided access token has expired").with_header(
+                    HeaderName::from_static(ACCESS_TOKEN_EXPIRED_HEADER),
+                    HeaderValue::from_static("1"),
+                ),
+            );
+        }
     }
 
-    Ok(())
+    Ok(AuthenticatedDevice {
+        device_name: access_token.device_name().to_owned(),
+        token: access_token.token().to_owned(),
+    })
+}
+
+// Injected into the request's extensions by the authentication middleware
+#[derive(Clone, Serialize, Deserialize)]
+pub struct AuthenticatedDevice {
+    pub device_name: String,
+    pub token: String,
 }
@@ -1,5 +1,5 @@
 use axum::{
-    http::StatusCode,
+    http::{header::RETRY_AFTER, HeaderMap, HeaderName, HeaderValue, StatusCode},
     response::{IntoResponse, Response},
 };
 use colored::Colorize;
@@ -15,6 +15,8 @@ pub struct HttpError {
     message: String,
     #[serde(skip)]
     code: StatusCode,
+    #[serde(skip)]
+    headers: Vec<(HeaderName, HeaderValue)>,
 }
 
 impl HttpError {
@@ -28,13 +30,24 @@ impl HttpError {
             http_name: code.to_string(),
             code,
             message,
+            headers: vec![],
         }
     }
+
+    pub fn with_header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.push((name, value));
+        self
+    }
+
+    // Number of seconds the client should wait before retrying
+    pub fn with_retry_after(self, secs: u64) -> Self {
+        self.with_header(RETRY_AFTER, HeaderValue::from(secs))
+    }
 }
 
 impl IntoResponse for HttpError {
     fn into_response(self) -> Response {
-        (self.code, self.message).into_response()
+        (self.code, HeaderMap::from_iter(self.headers), self.message).into_response()
     }
 }
 
@@ -9,20 +9,31 @@ use axum::{
     Router, Server,
 };
 use colored::Colorize;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
+use tokio::fs;
+use tower_http::limit::RequestBodyLimitLayer;
 
 use crate::{
+    case::is_case_insensitive,
     cmd::{BackupArgs, HttpArgs},
     data::AppData,
     http::{
         auth::auth_middleware,
-        routes::{is_sync_open, resume_open_sync},
+        routes::{
+            abort_file, commit_sync, file_hashes, file_signature, init_slot_encryption,
+            is_sync_open, prepare_sync_commit, present_files, reset_slot, resume_open_sync,
+            rotate_secret, send_file_delta, slot_encryption, slot_list, status,
+        },
     },
     paths::Paths,
+    snapshot_cache::{CachedSnapshot, SlotSnapshotCache},
 };
 
 use self::{
-    routes::{begin_sync, finalize_sync, healthcheck, request_access_token, send_file, snapshot},
+    routes::{
+        begin_sync, finalize_sync, healthcheck, request_access_token, request_device_access_token,
+        request_device_challenge, send_file, snapshot, version,
+    },
     state::HttpState,
 };
 
@@ -31,39 +42,121 @@ mod errors;
 mod routes;
 mod state;
 
-pub async fn launch(
+pub async fn build_router(
     http_args: HttpArgs,
     backup_args: BackupArgs,
     app_data: AppData,
     paths: Paths,
-) -> Result<()> {
-    let HttpArgs { addr, port } = http_args;
+) -> Result<Router> {
+    let HttpArgs {
+        addr: _,
+        port: _,
+        max_body_size,
+        max_open_syncs,
+    } = http_args;
+
+    let state = HttpState::new(backup_args, app_data, paths, max_open_syncs);
+
+    for slot in state.slots.values() {
+        let mut slot = slot.write().await;
+
+        slot.restore_open_syncs(&state).await.with_context(|| {
+            format!(
+                "Failed to restore open synchronizations of slot '{}'",
+                slot.infos.name()
+            )
+        })?;
 
-    let state = HttpState::new(backup_args, app_data, paths);
+        slot.case_insensitive = is_case_insensitive(&state.paths.slot_content_dir(&slot.infos))
+            .with_context(|| {
+                format!(
+                    "Failed to check if the filesystem of slot '{}' is case-insensitive",
+                    slot.infos.name()
+                )
+            })?;
+
+        if slot.case_insensitive {
+            info!(
+                "Slot {} is on a case-insensitive filesystem, items whose names only differ by case will be refused",
+                slot.infos.name().bright_blue()
+            );
+        }
+
+        let snapshot_cache_file = state.paths.slot_snapshot_cache_file(&slot.infos);
+
+        // Without caching, synchronizations don't update the cached snapshot, so it can't be used anymore
+        if snapshot_cache_file.is_file() {
+            if state.backup_args.cache_snapshot {
+                match CachedSnapshot::load(&snapshot_cache_file).await {
+                    Ok(cached) => slot.snapshot_cache = SlotSnapshotCache::new(Some(cached)),
+                    Err(err) => warn!(
+                        "Ignoring the snapshot cache of slot '{}': {err:?}",
+                        slot.infos.name()
+                    ),
+                }
+            } else {
+                fs::remove_file(&snapshot_cache_file)
+                    .await
+                    .with_context(|| {
+                        format!(
+                            "Failed to remove the snapshot cache of slot '{}'",
+                            slot.infos.name()
+                        )
+                    })?;
+            }
+        }
+    }
 
-    let app = Router::new()
+    Ok(router(state, max_body_size))
+}
+
+pub async fn listen(app: Router, addr: SocketAddr) -> Result<()> {
+    info!("Listening on {addr}...");
+
+    Server::bind(&addr)
+        .serve(app.into_make_service())
+        .await
+        .context("HTTP server crashed")
+}
+
+// Independent from the transport, so it can be driven without binding a socket
+pub fn router(state: HttpState, max_body_size: usize) -> Router {
+    Router::new()
         .route("/snapshot", post(snapshot))
+        .route("/slot/encryption", post(slot_encryption))
+        .route("/slot/init-encryption", post(init_slot_encryption))
+        .route("/slot/file-hashes", post(file_hashes))
+        .route("/slot/list", post(slot_list))
+        .route("/slots/reset", post(reset_slot))
+        .route("/secret/rotate", post(rotate_secret))
+        .route("/status", get(status))
         .route("/sync/is-open", get(is_sync_open))
         .route("/sync/begin", post(begin_sync))
         .route("/sync/resume", post(resume_open_sync))
         .route("/sync/finalize", post(finalize_sync))
+        .route("/sync/finalize/prepare", post(prepare_sync_commit))
+        .route("/sync/finalize/commit", post(commit_sync))
         .route("/sync/file", post(send_file))
+        .route("/sync/abort-file", post(abort_file))
+        .route("/sync/present", post(present_files))
+        .route("/sync/signature", post(file_signature))
+        .route("/sync/delta", post(send_file_delta))
         .layer(middleware::from_fn_with_state(
             state.clone(),
             auth_middleware,
         ))
         // Routes below can be accessed without authentication
         .route("/request-access-token", post(request_access_token))
+        .route("/device/challenge", post(request_device_challenge))
+        .route(
+            "/device/request-access-token",
+            post(request_device_access_token),
+        )
         .route("/healthcheck", get(healthcheck))
+        .route("/version", get(version))
         .layer(middleware::from_fn(log_errors))
-        .with_state(state);
-
-    info!("Listening on {addr}:{port}...");
-
-    Server::bind(&SocketAddr::from((addr, port)))
-        .serve(app.into_make_service())
-        .await
-        .context("HTTP server crashed")
+        .layer(RequestBodyLimitLayer::new(max_body_size))
+        .with_state(state)
 }
 
 async fn log_errors<B>(request: Request<B>, next: Next<B>) -> Response {
@@ -87,3 +180,408 @@ async fn log_errors<B>(request: Request<B>, next: Next<B>) -> Response {
 
     res
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use axum::{
+        body::Body,
+        http::{header, Method, Request, StatusCode},
+    };
+    use clap::Parser;
+    use harmony_differ::{
+        diffing::{Diff, DiffItem, DiffItemAdded, DiffItemDeleted, DiffItemModified, DiffType},
+        snapshot::{SnapshotFileMetadata, SnapshotItemMetadata},
+    };
+    use serde_json::{json, Value};
+    use tempfile::TempDir;
+    use tower::ServiceExt;
+
+    use crate::{cmd::Args, setup, Server};
+
+    // A server with a single slot, driven without going through the network
+    struct TestServer {
+        server: Server,
+        access_token: String,
+        data_dir: TempDir,
+    }
+
+    impl TestServer {
+        async fn new() -> Self {
+            let data_dir = TempDir::new().unwrap();
+
+            let server = setup(Args::parse_from([
+                "harmony-server".as_ref(),
+                data_dir.path().as_os_str(),
+                "--slots".as_ref(),
+                "s1".as_ref(),
+                "--secret".as_ref(),
+                "pw".as_ref(),
+            ]))
+            .await
+            .unwrap();
+
+            let mut server = Self {
+                server,
+                access_token: String::new(),
+                data_dir,
+            };
+
+            let (status, token) = server
+                .post_json(
+                    "/request-access-token",
+                    json!({ "secret_password": "pw", "device_name": "test" }),
+                )
+                .await;
+
+            assert_eq!(status, StatusCode::OK);
+            server.access_token = serde_json::from_str(&token).unwrap();
+            server
+        }
+
+        async fn post(&self, uri: &str, content_type: &str, body: Body) -> (StatusCode, String) {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(
+                    header::AUTHORIZATION,
+                    format!("Bearer {}", self.access_token),
+                )
+                .body(body)
+                .unwrap();
+
+            let res = self.server.router().oneshot(req).await.unwrap();
+            let status = res.status();
+            let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+
+            (status, String::from_utf8(body.to_vec()).unwrap())
+        }
+
+        async fn post_json(&self, uri: &str, payload: Value) -> (StatusCode, String) {
+            self.post(uri, "application/json", Body::from(payload.to_string()))
+                .await
+        }
+
+        // Returns the synchronization's token
+        async fn begin_sync(&self, items: Vec<DiffItem>) -> String {
+            let (status, body) = self.try_begin_sync(Diff::new(items)).await;
+            assert_eq!(status, StatusCode::OK, "{body}");
+
+            let infos = serde_json::from_str::<Value>(&body).unwrap();
+            infos["sync_token"].as_str().unwrap().to_owned()
+        }
+
+        async fn try_begin_sync(&self, diff: Diff) -> (StatusCode, String) {
+            self.post_json("/sync/begin", json!({ "slot_name": "s1", "diff": diff }))
+                .await
+        }
+
+        async fn send_file(&self, sync_token: &str, path: &str, content: &[u8]) -> StatusCode {
+            let (status, _) = self
+                .post(
+                    &format!("/sync/file?slot_name=s1&sync_token={sync_token}&path={path}"),
+                    "application/octet-stream",
+                    Body::from(content.to_vec()),
+                )
+                .await;
+
+            status
+        }
+
+        async fn finalize(&self, sync_token: &str) -> (StatusCode, String) {
+            self.post_json(
+                "/sync/finalize",
+                json!({ "slot_name": "s1", "sync_token": sync_token }),
+            )
+            .await
+        }
+
+        fn slot_dir(&self) -> PathBuf {
+            self.data_dir.path().join("slots").join("s1")
+        }
+
+        // Directories of the slot's open synchronizations
+        fn sync_dirs(&self) -> Vec<PathBuf> {
+            fs::read_dir(self.slot_dir())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .filter(|path| {
+                    path.file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .starts_with("open-sync-")
+                })
+                .collect()
+        }
+    }
+
+    fn file_metadata(size: u64) -> SnapshotFileMetadata {
+        SnapshotFileMetadata {
+            last_modif_date_s: 1_700_000_000,
+            last_modif_date_ns: 0,
+            size,
+        }
+    }
+
+    fn added(path: &str, new: SnapshotItemMetadata) -> DiffItem {
+        DiffItem {
+            path: path.to_owned(),
+            status: DiffType::Added(DiffItemAdded { new }),
+        }
+    }
+
+    fn added_file(path: &str, size: u64) -> DiffItem {
+        added(path, SnapshotItemMetadata::File(file_metadata(size)))
+    }
+
+    fn deleted(path: &str, prev: SnapshotItemMetadata) -> DiffItem {
+        DiffItem {
+            path: path.to_owned(),
+            status: DiffType::Deleted(DiffItemDeleted { prev }),
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_content_larger_than_announced() {
+        let server = TestServer::new().await;
+        let sync_token = server.begin_sync(vec![added_file("a.txt", 5)]).await;
+
+        assert_eq!(
+            server.send_file(&sync_token, "a.txt", b"too long").await,
+            StatusCode::BAD_REQUEST
+        );
+
+        // The rejected content isn't kept around
+        let [sync_dir] = server.sync_dirs().try_into().unwrap();
+        assert_eq!(fs::read_dir(sync_dir.join("pending")).unwrap().count(), 0);
+
+        assert_eq!(
+            server.send_file(&sync_token, "a.txt", b"hello").await,
+            StatusCode::OK
+        );
+
+        assert_eq!(server.finalize(&sync_token).await.0, StatusCode::OK);
+        assert_eq!(
+            fs::read(server.slot_dir().join("content/a.txt")).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn accepts_uploads_of_already_transferred_files() {
+        let server = TestServer::new().await;
+        let sync_token = server.begin_sync(vec![added_file("a.txt", 5)]).await;
+
+        for _ in 0..2 {
+            assert_eq!(
+                server.send_file(&sync_token, "a.txt", b"hello").await,
+                StatusCode::OK
+            );
+        }
+
+        assert_eq!(server.finalize(&sync_token).await.0, StatusCode::OK);
+        assert_eq!(
+            fs::read(server.slot_dir().join("content/a.txt")).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[tokio::test]
+    async fn closes_syncs_whose_dir_was_removed() {
+        let server = TestServer::new().await;
+        let sync_token = server.begin_sync(vec![added_file("a.txt", 5)]).await;
+
+        let [sync_dir] = server.sync_dirs().try_into().unwrap();
+        fs::remove_dir_all(sync_dir.join("pending")).unwrap();
+
+        assert_eq!(
+            server.send_file(&sync_token, "a.txt", b"hello").await,
+            StatusCode::CONFLICT
+        );
+
+        assert!(server.sync_dirs().is_empty());
+
+        let (status, body) = server.finalize(&sync_token).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{body}");
+
+        // A new synchronization can be opened right away
+        let sync_token = server.begin_sync(vec![added_file("a.txt", 5)]).await;
+
+        assert_eq!(
+            server.send_file(&sync_token, "a.txt", b"hello").await,
+            StatusCode::OK
+        );
+
+        assert_eq!(server.finalize(&sync_token).await.0, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn refuses_moves_and_touches_to_excluded_items() {
+        let server = TestServer::new().await;
+
+        let sync_token = server
+            .begin_sync(vec![
+                added("dir", SnapshotItemMetadata::Directory),
+                added_file("dir/a.txt", 5),
+                added_file("b.tmp", 5),
+            ])
+            .await;
+
+        for (path, content) in [("dir/a.txt", b"hello"), ("b.tmp", b"world")] {
+            assert_eq!(
+                server.send_file(&sync_token, path, content).await,
+                StatusCode::OK
+            );
+        }
+
+        assert_eq!(server.finalize(&sync_token).await.0, StatusCode::OK);
+
+        fs::write(
+            server.slot_dir().join("ignore.json"),
+            json!({ "ignore_paths": ["secret"], "ignore_exts": ["tmp"] }).to_string(),
+        )
+        .unwrap();
+
+        // Renaming the directory is sent as a single move, whose destination is excluded
+        let (status, body) = server
+            .try_begin_sync(Diff::new(vec![
+                deleted("dir", SnapshotItemMetadata::Directory),
+                deleted("dir/a.txt", SnapshotItemMetadata::File(file_metadata(5))),
+                added("secret", SnapshotItemMetadata::Directory),
+                added_file("secret/a.txt", 5),
+            ]))
+            .await;
+
+        assert_eq!(status, StatusCode::FORBIDDEN, "{body}");
+        assert!(body.contains("'secret'"), "{body}");
+
+        // Only the modification time of this file would be updated
+        let mut diff = Diff::new(vec![DiffItem {
+            path: "b.tmp".to_owned(),
+            status: DiffType::Modified(DiffItemModified {
+                prev: file_metadata(5),
+                new: SnapshotFileMetadata {
+                    last_modif_date_s: 1_800_000_000,
+                    ..file_metadata(5)
+                },
+            }),
+        }]);
+
+        diff.touched = vec!["b.tmp".to_owned()];
+
+        let (status, body) = server.try_begin_sync(diff).await;
+        assert_eq!(status, StatusCode::FORBIDDEN, "{body}");
+        assert!(body.contains("'b.tmp'"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn refuses_overlapping_syncs() {
+        let server = TestServer::new().await;
+
+        let sync_token = server
+            .begin_sync(vec![
+                added("old", SnapshotItemMetadata::Directory),
+                added_file("old/f.txt", 5),
+            ])
+            .await;
+
+        assert_eq!(
+            server.send_file(&sync_token, "old/f.txt", b"hello").await,
+            StatusCode::OK
+        );
+
+        assert_eq!(server.finalize(&sync_token).await.0, StatusCode::OK);
+
+        let delete_old = || {
+            Diff::new(vec![
+                deleted("old", SnapshotItemMetadata::Directory),
+                deleted("old/f.txt", SnapshotItemMetadata::File(file_metadata(5))),
+            ])
+        };
+
+        let added_token = server.begin_sync(vec![added_file("old/new.txt", 5)]).await;
+
+        // Same path, and a directory containing it
+        for diff in [Diff::new(vec![added_file("old/new.txt", 5)]), delete_old()] {
+            let (status, body) = server.try_begin_sync(diff).await;
+            assert_eq!(status, StatusCode::CONFLICT, "{body}");
+            assert!(body.contains("'old/new.txt'"), "{body}");
+        }
+
+        // Disjoint items can be synchronized concurrently
+        let other_token = server.begin_sync(vec![added_file("other.txt", 5)]).await;
+
+        for (sync_token, path) in [(&added_token, "old/new.txt"), (&other_token, "other.txt")] {
+            assert_eq!(
+                server.send_file(sync_token, path, b"hello").await,
+                StatusCode::OK
+            );
+
+            assert_eq!(server.finalize(sync_token).await.0, StatusCode::OK);
+        }
+
+        // An item inside a directory another synchronization touches
+        let mut diff = delete_old();
+
+        diff.deleted.push((
+            "old/new.txt".to_owned(),
+            DiffItemDeleted {
+                prev: SnapshotItemMetadata::File(file_metadata(5)),
+            },
+        ));
+
+        assert_eq!(server.try_begin_sync(diff).await.0, StatusCode::OK);
+
+        let (status, body) = server
+            .try_begin_sync(Diff::new(vec![added_file("old/again.txt", 5)]))
+            .await;
+
+        assert_eq!(status, StatusCode::CONFLICT, "{body}");
+        assert!(body.contains("'old'"), "{body}");
+    }
+
+    #[tokio::test]
+    async fn resumes_syncs_by_opening_key() {
+        let server = TestServer::new().await;
+
+        for (path, opening_key) in [("a.txt", "key-a"), ("b.txt", "key-b")] {
+            let (status, body) = server
+                .post_json(
+                    "/sync/begin",
+                    json!({
+                        "slot_name": "s1",
+                        "diff": Diff::new(vec![added_file(path, 5)]),
+                        "opening_key": opening_key
+                    }),
+                )
+                .await;
+
+            assert_eq!(status, StatusCode::OK, "{body}");
+        }
+
+        // The least recently active synchronization is picked, as it matches the key
+        let (status, body) = server
+            .post_json(
+                "/sync/resume",
+                json!({ "slot_name": "s1", "opening_key": "key-a" }),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::OK, "{body}");
+
+        let infos = serde_json::from_str::<Value>(&body).unwrap();
+        let files = infos["transfer_file_ids"].as_object().unwrap();
+        assert_eq!(files.keys().collect::<Vec<_>>(), ["a.txt"]);
+
+        let (status, body) = server
+            .post_json(
+                "/sync/resume",
+                json!({ "slot_name": "s1", "opening_key": "key-c" }),
+            )
+            .await;
+
+        assert_eq!(status, StatusCode::CONFLICT, "{body}");
+    }
+}
@@ -1,57 +1,258 @@
-use std::collections::HashMap;
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    future::Future,
+    io::{BufWriter, ErrorKind, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
 use anyhow::Context;
 use axum::{
+    body::{Bytes, StreamBody},
     extract::{BodyStream, Query, State},
-    Json,
+    http::{header, HeaderMap, HeaderName, HeaderValue},
+    response::{IntoResponse, Response},
+    Extension, Json,
 };
 use filetime::FileTime;
 use futures_util::StreamExt;
 use harmony_differ::{
-    diffing::Diff,
-    snapshot::{make_snapshot, SnapshotFileMetadata, SnapshotOptions, SnapshotResult},
+    acl::{write_acl, ACLS_SUPPORTED},
+    crypto::SlotEncryption,
+    delta::{apply_delta, compute_signature, FileSignature},
+    diffing::{Diff, DiffApplyOps, DiffItemDeleted, DiffItemTypeChanged},
+    framing::{FrameDecoder, CRC_FRAMING_HEADER},
+    protocol::{
+        decode_hex, device_challenge_message, ServerVersion, CAPABILITY_ABORT_FILE,
+        CAPABILITY_ACLS, CAPABILITY_CRC_FRAMING, CAPABILITY_DELTA, CAPABILITY_DEVICE_KEYS,
+        CAPABILITY_DRY_RUN_BEGIN, CAPABILITY_ENCRYPTION, CAPABILITY_HARDLINKS,
+        CAPABILITY_LIST_EXCLUDED, CAPABILITY_LIST_SLOT, CAPABILITY_MODIFIED_SINCE,
+        CAPABILITY_MOVE_DIRS, CAPABILITY_OPENING_KEY, CAPABILITY_PRESENT_FILES,
+        CAPABILITY_REMOTE_CHECK, CAPABILITY_REPAIR, CAPABILITY_SNAPSHOT_CACHE, CAPABILITY_SPARSE,
+        CAPABILITY_STREAMED_SNAPSHOT, CAPABILITY_TOUCH, CAPABILITY_TWO_PHASE_FINALIZE,
+        CAPABILITY_XATTRS, MASS_DELETION_HEADER, PROTOCOL_VERSION, REMOTE_CHANGED_HEADER,
+        UNKNOWN_DEVICE_KEY_HEADER,
+    },
+    snapshot::{
+        content_hash, make_snapshot, native_path, SnapshotCancelled, SnapshotFileMetadata,
+        SnapshotItemMetadata, SnapshotOptions, SnapshotResult, HARMONY_ITEMS_PREFIX,
+    },
+    sparse::{SparseDecoder, SparseSegment, SPARSE_ENCODING_HEADER},
+    xattrs::{write_xattrs, XATTRS_SUPPORTED},
 };
-use log::error;
+use log::{debug, error, info, warn};
+use ring::signature::{UnparsedPublicKey, ED25519, ED25519_PUBLIC_KEY_LEN};
 use serde::{Deserialize, Serialize};
 use tokio::{
     fs::{self, File},
-    io::AsyncWriteExt,
+    io::{AsyncSeekExt, AsyncWriteExt},
+    sync::RwLockWriteGuard,
+    task::JoinSet,
 };
 
-use crate::{handle_err, throw_err};
+use crate::{
+    audit::{AuditOperation, AuditRecord},
+    case::find_case_collision,
+    data::{generate_id, hash_secret, verify_secret, AppData, SlotIgnoreRules, SlotSettings},
+    dedup::{collect_garbage, deduplicate_file, object_path},
+    handle_err,
+    hashes::HashCache,
+    history::{history_point_name, preserve_items, prune_history},
+    hooks::{trigger_finalize_hooks, FinalizeHookPayload},
+    paths::{is_relative_linear_path, CompletionTracking, SlotInfos, SyncId},
+    server_err,
+    snapshot_cache::{CachedSnapshot, SlotSnapshotCache},
+    throw_err,
+};
 
 use super::{
-    errors::HttpResult,
-    state::{HttpState, OpenSync},
+    auth::AuthenticatedDevice,
+    errors::{HttpError, HttpResult},
+    state::{DeviceChallenge, HttpState, OpenSync, SlotSync, DEVICE_CHALLENGE_VALIDITY},
 };
 
 pub async fn healthcheck() -> &'static str {
     "OK"
 }
 
+pub async fn version() -> Json<ServerVersion> {
+    Json(ServerVersion {
+        version: env!("CARGO_PKG_VERSION").to_owned(),
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: [
+            CAPABILITY_DELTA,
+            CAPABILITY_ENCRYPTION,
+            CAPABILITY_HARDLINKS,
+            CAPABILITY_MOVE_DIRS,
+            CAPABILITY_CRC_FRAMING,
+            CAPABILITY_LIST_EXCLUDED,
+            CAPABILITY_MODIFIED_SINCE,
+            CAPABILITY_TWO_PHASE_FINALIZE,
+            CAPABILITY_TOUCH,
+            CAPABILITY_ABORT_FILE,
+            CAPABILITY_REPAIR,
+            CAPABILITY_LIST_SLOT,
+            CAPABILITY_SPARSE,
+            CAPABILITY_DEVICE_KEYS,
+            CAPABILITY_DRY_RUN_BEGIN,
+            CAPABILITY_REMOTE_CHECK,
+            CAPABILITY_STREAMED_SNAPSHOT,
+            CAPABILITY_PRESENT_FILES,
+            CAPABILITY_SNAPSHOT_CACHE,
+            CAPABILITY_OPENING_KEY,
+        ]
+        .into_iter()
+        // ACLs can only be stored on platforms which support them
+        .chain(ACLS_SUPPORTED.then_some(CAPABILITY_ACLS))
+        .chain(XATTRS_SUPPORTED.then_some(CAPABILITY_XATTRS))
+        .map(str::to_owned)
+        .collect(),
+    })
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct RequestAccessTokenPayload {
     secret_password: String,
     device_name: String,
+    // Enrolled so the device can later get access tokens without the secret password
+    #[serde(default)]
+    device_public_key: Option<String>,
 }
 
 pub async fn request_access_token(
     State(state): State<HttpState>,
     Json(payload): Json<RequestAccessTokenPayload>,
 ) -> HttpResult<Json<String>> {
-    let mut app_data = state.app_data.write().await;
-
     let RequestAccessTokenPayload {
         secret_password,
         device_name,
+        device_public_key,
     } = payload;
 
-    if secret_password != state.backup_args.secret {
+    if !is_secret_password(&state, secret_password).await? {
         throw_err!(BAD_REQUEST, "Invalid secret password provided");
     }
 
-    let access_token = app_data.create_access_token(device_name).clone();
+    let mut app_data = state.app_data.write().await;
+
+    if let Some(public_key) = device_public_key {
+        if !is_valid_device_key(&public_key) {
+            throw_err!(BAD_REQUEST, "Invalid device public key provided");
+        }
+
+        info!("Enrolled the key of device '{device_name}'");
+
+        app_data.enroll_device_key(device_name.clone(), public_key);
+    }
+
+    create_access_token(&state, &mut app_data, device_name).await
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceChallengePayload {
+    public_key: String,
+}
+
+pub async fn request_device_challenge(
+    State(state): State<HttpState>,
+    Json(payload): Json<DeviceChallengePayload>,
+) -> HttpResult<Json<String>> {
+    let DeviceChallengePayload { public_key } = payload;
+
+    if state
+        .app_data
+        .read()
+        .await
+        .get_device_key(&public_key)
+        .is_none()
+    {
+        return Err(unknown_device_key());
+    }
+
+    let challenge = generate_id();
+
+    let mut challenges = state.device_challenges.lock().unwrap();
+
+    challenges.retain(|_, challenge| challenge.issued_at.elapsed() < DEVICE_CHALLENGE_VALIDITY);
+    challenges.insert(
+        challenge.clone(),
+        DeviceChallenge {
+            public_key,
+            issued_at: Instant::now(),
+        },
+    );
+
+    Ok(Json(challenge))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DeviceAccessTokenPayload {
+    public_key: String,
+    challenge: String,
+    signature: String,
+}
+
+pub async fn request_device_access_token(
+    State(state): State<HttpState>,
+    Json(payload): Json<DeviceAccessTokenPayload>,
+) -> HttpResult<Json<String>> {
+    let DeviceAccessTokenPayload {
+        public_key,
+        challenge,
+        signature,
+    } = payload;
+
+    // Challenges are consumed even if the signature is invalid, so they can't be brute-forced
+    let issued = state.device_challenges.lock().unwrap().remove(&challenge);
+
+    let Some(issued) = issued.filter(|issued| {
+        issued.public_key == public_key && issued.issued_at.elapsed() < DEVICE_CHALLENGE_VALIDITY
+    }) else {
+        throw_err!(FORBIDDEN, "Unknown or expired challenge provided");
+    };
+
+    let is_signed = match (decode_hex(&issued.public_key), decode_hex(&signature)) {
+        (Some(public_key), Some(signature)) => UnparsedPublicKey::new(&ED25519, public_key)
+            .verify(device_challenge_message(&challenge).as_bytes(), &signature)
+            .is_ok(),
+        _ => false,
+    };
+
+    if !is_signed {
+        throw_err!(FORBIDDEN, "Invalid challenge signature provided");
+    }
+
+    let mut app_data = state.app_data.write().await;
+
+    // The key may have been removed since the challenge was issued
+    let Some(device_key) = app_data.get_device_key(&public_key) else {
+        return Err(unknown_device_key());
+    };
+
+    let device_name = device_key.device_name().to_owned();
+
+    create_access_token(&state, &mut app_data, device_name).await
+}
+
+async fn create_access_token(
+    state: &HttpState,
+    app_data: &mut AppData,
+    device_name: String,
+) -> HttpResult<Json<String>> {
+    let access_token = app_data
+        .create_access_token(
+            device_name,
+            state.backup_args.access_token_length,
+            state.backup_args.max_tokens_per_device,
+        )
+        .clone();
 
     if let Err(err) = app_data.save(&state.paths.app_data_file()).await {
         error!("Failed to save data file: {err:?}");
@@ -61,151 +262,303 @@ pub async fn request_access_token(
     Ok(Json(access_token.token().to_owned()))
 }
 
+async fn is_secret_password(state: &HttpState, secret_password: String) -> HttpResult<bool> {
+    let Some(secret_hash) = state.app_data.read().await.secret_hash().map(str::to_owned) else {
+        return Ok(false);
+    };
+
+    tokio::task::spawn_blocking(move || verify_secret(&secret_hash, &secret_password))
+        .await
+        .context("Failed to run the secret password check")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))
+}
+
+fn is_valid_device_key(public_key: &str) -> bool {
+    decode_hex(public_key).is_some_and(|bytes| bytes.len() == ED25519_PUBLIC_KEY_LEN)
+}
+
+fn unknown_device_key() -> HttpError {
+    server_err!(
+        FORBIDDEN,
+        "Unknown device key, it must be enrolled with the secret password first"
+    )
+    .with_header(
+        HeaderName::from_static(UNKNOWN_DEVICE_KEY_HEADER),
+        HeaderValue::from_static("1"),
+    )
+}
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct SnapshotParams {
     slot_name: String,
     snapshot_options: SnapshotOptions,
+    #[serde(default)]
+    encrypted: bool,
+    // List the items excluded by the client's ignore rules
+    #[serde(default)]
+    list_excluded: bool,
+    // Send the result as newline-delimited JSON
+    #[serde(default)]
+    stream: bool,
+    // Walk through the slot's content even if its snapshot is cached
+    #[serde(default)]
+    rescan: bool,
 }
 
 pub async fn snapshot(
     State(state): State<HttpState>,
     Json(payload): Json<SnapshotParams>,
-) -> HttpResult<Json<SnapshotResult>> {
+) -> HttpResult<Response> {
     let SnapshotParams {
         slot_name,
-        snapshot_options,
+        mut snapshot_options,
+        encrypted,
+        list_excluded,
+        stream,
+        rescan,
     } = payload;
 
     // This block contains quick, locking computing
     // After this block we can do the actual transfer without worrying about locking a concurrent request
-    let path = {
-        let slot = state
+    let (path, excluded_options, cached, content_version) = {
+        let mut slot = state
             .slots
             .get(&slot_name)
             .context("Provided slot was not found")
             .map_err(handle_err!(NOT_FOUND))?
-            .read()
+            .write()
             .await;
 
-        if slot.open_sync.is_some() {
-            throw_err!(
-                FORBIDDEN,
-                "A synchronization is already opened for the provided slot"
-            );
+        reclaim_stale_syncs(&state, &mut slot).await?;
+
+        ensure_encryption_mode(&state, &slot.infos, encrypted)?;
+
+        let server_rules = read_slot_ignore_rules(&state, &slot.infos).await?;
+
+        // Items excluded by the server's own rules are never listed, as they must not be deleted
+        let excluded_options = list_excluded.then(|| {
+            let mut options = snapshot_options.without_exclusion_rules();
+            options.merge_ignore_rules(&server_rules);
+            options
+        });
+
+        snapshot_options.merge_ignore_rules(&server_rules);
+
+        let cacheable =
+            state.backup_args.cache_snapshot && CachedSnapshot::is_cacheable(&snapshot_options);
+
+        let cached = if cacheable && !rescan {
+            slot.snapshot_cache
+                .get(&snapshot_options, excluded_options.as_ref())
+        } else {
+            None
+        };
+
+        (
+            state.paths.slot_content_dir(&slot.infos),
+            excluded_options,
+            cached,
+            cacheable.then(|| slot.snapshot_cache.content_version()),
+        )
+    };
+
+    let result = match cached {
+        Some(result) => {
+            debug!("Sending the cached snapshot of slot '{slot_name}'");
+            result
         }
 
-        state.paths.slot_content_dir(&slot.infos)
+        None => {
+            let result = run_snapshot(
+                slot_name.clone(),
+                path,
+                snapshot_options.clone(),
+                excluded_options.clone(),
+            )
+            .await?;
+
+            // The snapshot isn't cached if the slot's content changed while it was being built
+            if let Some(content_version) = content_version {
+                let mut slot = state.slots.get(&slot_name).unwrap().write().await;
+                let snapshot_cache_file = state.paths.slot_snapshot_cache_file(&slot.infos);
+
+                if let Err(err) = slot
+                    .snapshot_cache
+                    .store(
+                        &snapshot_cache_file,
+                        CachedSnapshot::new(snapshot_options, excluded_options, &result),
+                        content_version,
+                    )
+                    .await
+                {
+                    error!("Failed to save the snapshot cache of slot '{slot_name}': {err:?}");
+                }
+            }
+
+            result
+        }
     };
 
-    make_snapshot(path, |_| {}, &snapshot_options)
-        .await
-        .map(Json)
-        .map_err(handle_err!(INTERNAL_SERVER_ERROR))
+    if !stream {
+        return Ok(Json(result).into_response());
+    }
+
+    // The snapshot is still built (and cached) as a whole, only its serialized form isn't buffered
+    let mut lines = result.into_stream_lines().peekable();
+
+    // Lines are only serialized as they are sent, in chunks to avoid sending many tiny ones
+    let chunks = std::iter::from_fn(move || {
+        lines.peek()?;
+
+        let mut chunk = vec![];
+
+        for line in lines.by_ref().take(SNAPSHOT_STREAM_CHUNK_LINES) {
+            if let Err(err) = serde_json::to_writer(&mut chunk, &line) {
+                return Some(Err(err));
+            }
+
+            chunk.push(b'\n');
+        }
+
+        Some(Ok(Bytes::from(chunk)))
+    });
+
+    Ok((
+        [(header::CONTENT_TYPE, "application/x-ndjson")],
+        StreamBody::new(futures_util::stream::iter(chunks)),
+    )
+        .into_response())
 }
 
+// Number of lines sent at once when streaming a snapshot
+const SNAPSHOT_STREAM_CHUNK_LINES: usize = 1000;
+
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct BeginSyncParams {
+pub struct SlotListParams {
     slot_name: String,
-    diff: Diff,
-}
-
-#[derive(Serialize)]
-pub struct SyncInfos {
-    sync_token: String,
-    transfer_file_ids: HashMap<String, String>,
-    transfer_size: u64,
+    snapshot_options: SnapshotOptions,
 }
 
-pub async fn begin_sync(
+// Read-only counterpart of the snapshot route, which doesn't interfere with synchronizations
+pub async fn slot_list(
     State(state): State<HttpState>,
-    Json(begin_sync_params): Json<BeginSyncParams>,
-) -> HttpResult<Json<SyncInfos>> {
-    let BeginSyncParams { slot_name, diff } = begin_sync_params;
+    Json(payload): Json<SlotListParams>,
+) -> HttpResult<Json<SnapshotResult>> {
+    let SlotListParams {
+        slot_name,
+        mut snapshot_options,
+    } = payload;
 
-    let mut slot = state
-        .slots
-        .get(&slot_name)
-        .context("Provided slot was not found")
-        .map_err(handle_err!(NOT_FOUND))?
-        .write()
-        .await;
+    let path = {
+        let slot = state
+            .slots
+            .get(&slot_name)
+            .context("Provided slot was not found")
+            .map_err(handle_err!(NOT_FOUND))?
+            .read()
+            .await;
 
-    if slot.open_sync.is_some() {
-        throw_err!(
-            FORBIDDEN,
-            "A synchronization is already opened for the provided slot"
-        );
-    }
+        snapshot_options.merge_ignore_rules(&read_slot_ignore_rules(&state, &slot.infos).await?);
 
-    let open_sync = OpenSync::new(diff)?;
+        if let Some(cached) = slot.snapshot_cache.get(&snapshot_options, None) {
+            return Ok(Json(cached));
+        }
 
-    fs::create_dir(state.paths.slot_transfer_dir(&slot.infos, open_sync.id))
-        .await
-        .context("Failed to create the synchronization directory")
-        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+        state.paths.slot_content_dir(&slot.infos)
+    };
 
-    fs::create_dir(state.paths.slot_pending_dir(&slot.infos, open_sync.id))
+    run_snapshot(slot_name, path, snapshot_options, None)
         .await
-        .context("Failed to create the pending transfers directory")
-        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+        .map(Json)
+}
 
-    fs::create_dir(state.paths.slot_completion_dir(&slot.infos, open_sync.id))
-        .await
-        .context("Failed to create the complete transfers directory")
-        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+// The snapshot runs in its own task so the request can be dropped while it is running,
+// in which case the snapshot is cancelled
+async fn run_snapshot(
+    slot_name: String,
+    path: PathBuf,
+    snapshot_options: SnapshotOptions,
+    excluded_options: Option<SnapshotOptions>,
+) -> HttpResult<SnapshotResult> {
+    let cancelled = Arc::new(AtomicBool::new(false));
+    let _cancel_guard = CancelOnDrop(Arc::clone(&cancelled));
+
+    tokio::spawn(async move {
+        let result = snapshot_with_excluded(
+            path,
+            &snapshot_options,
+            excluded_options.as_ref(),
+            &cancelled,
+        )
+        .await;
 
-    let slot_files_dir = state.paths.slot_content_dir(&slot.infos);
+        if let Err(err) = &result {
+            if err.is::<SnapshotCancelled>() {
+                info!("Snapshot of slot '{slot_name}' was cancelled as the request was dropped");
+            }
+        }
 
-    for relative_path in &open_sync.diff_ops.delete_files {
-        fs::remove_file(slot_files_dir.join(relative_path))
-            .await
-            .with_context(|| format!("Failed to remove file at '{relative_path}'"))
-            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
-    }
+        result
+    })
+    .await
+    .context("Failed to run the snapshot task")
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))
+}
 
-    for relative_path in &open_sync.diff_ops.delete_empty_dirs {
-        fs::remove_dir(slot_files_dir.join(relative_path))
-            .await
-            .with_context(|| format!("Failed to remove directory at '{relative_path}'"))
-            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
-    }
+// Build a snapshot and, if options without the client's exclusion rules are provided, list the excluded items
+async fn snapshot_with_excluded(
+    path: PathBuf,
+    options: &SnapshotOptions,
+    excluded_options: Option<&SnapshotOptions>,
+    cancelled: &AtomicBool,
+) -> anyhow::Result<SnapshotResult> {
+    let mut result = make_snapshot(path.clone(), |_| {}, options, Some(cancelled), None).await?;
+
+    if let Some(excluded_options) = excluded_options {
+        let full = make_snapshot(path, |_| {}, excluded_options, Some(cancelled), None).await?;
+
+        let included = result
+            .snapshot
+            .items
+            .iter()
+            .map(|item| item.relative_path.as_str())
+            .collect::<HashSet<_>>();
 
-    let sync_infos = SyncInfos {
-        sync_token: open_sync.token.to_owned(),
+        let excluded = full
+            .snapshot
+            .items
+            .into_iter()
+            .filter(|item| !included.contains(item.relative_path.as_str()))
+            .collect();
 
-        transfer_file_ids: open_sync
-            .files
-            .iter()
-            .map(|(id, (relative_path, _))| (id.clone(), relative_path.clone()))
-            .collect(),
+        result.excluded = excluded;
+    }
 
-        transfer_size: open_sync
-            .diff_ops
-            .send_files
-            .iter()
-            .map(|(_, mt)| mt.size)
-            .sum(),
-    };
+    Ok(result)
+}
 
-    // This must come last, otherwise we have a begin synchronization even if we didn't go to the end of its preparation
-    slot.open_sync = Some(open_sync);
+struct CancelOnDrop(Arc<AtomicBool>);
 
-    Ok(Json(sync_infos))
+impl Drop for CancelOnDrop {
+    fn drop(&mut self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct IsSyncOpenParams {
+pub struct SlotEncryptionParams {
     slot_name: String,
 }
 
-pub async fn is_sync_open(
+pub async fn slot_encryption(
     State(state): State<HttpState>,
-    Json(payload): Json<IsSyncOpenParams>,
-) -> HttpResult<Json<bool>> {
-    let IsSyncOpenParams { slot_name } = payload;
+    Json(payload): Json<SlotEncryptionParams>,
+) -> HttpResult<Json<Option<SlotEncryption>>> {
+    let SlotEncryptionParams { slot_name } = payload;
 
     let slot = state
         .slots
@@ -215,323 +568,2866 @@ pub async fn is_sync_open(
         .read()
         .await;
 
-    Ok(Json(slot.open_sync.is_some()))
+    read_slot_encryption(&state.paths.slot_encryption_file(&slot.infos)).map(Json)
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct ResumeOpenSyncParams {
+pub struct FileHashesParams {
     slot_name: String,
+    paths: Vec<String>,
+    // Compute the hashes again instead of using the cached ones, to detect corrupted files
+    #[serde(default)]
+    verify: bool,
 }
 
-pub async fn resume_open_sync(
+// Compute the content hash of existing files, so clients can check if they changed without sending them
+// Files which don't exist are omitted
+pub async fn file_hashes(
     State(state): State<HttpState>,
-    Json(payload): Json<ResumeOpenSyncParams>,
-) -> HttpResult<Json<SyncInfos>> {
-    let ResumeOpenSyncParams { slot_name } = payload;
+    Json(payload): Json<FileHashesParams>,
+) -> HttpResult<Json<HashMap<String, String>>> {
+    let FileHashesParams {
+        slot_name,
+        paths,
+        verify,
+    } = payload;
 
-    let mut slot = state
+    let slot = state
         .slots
         .get(&slot_name)
         .context("Provided slot was not found")
         .map_err(handle_err!(NOT_FOUND))?
-        .write()
+        .read()
         .await;
 
-    let slot_infos = slot.infos.clone();
+    for path in &paths {
+        if is_relative_linear_path(Path::new(path)) {
+            throw_err!(
+                BAD_REQUEST,
+                format!("Path is trying to escape or contains '.' / '..' components: {path}")
+            );
+        }
+    }
 
-    let Some(open_sync) = slot.open_sync.as_mut() else {
-        throw_err!(
-            CONFLICT,
-            "No synchronization is currently open for the provided slot"
-        )
-    };
+    let slot_files_dir = state.paths.slot_content_dir(&slot.infos);
+    let hash_cache_file = state.paths.slot_hash_cache_file(&slot.infos);
 
-    let sync_token = open_sync.regenerate_access_token();
+    let (hashes, corrupted) = tokio::task::spawn_blocking(move || {
+        let mut hash_cache = HashCache::load(&hash_cache_file)?;
 
-    let mut remaining_files = HashMap::new();
+        let mut hashes = HashMap::new();
+        let mut corrupted = vec![];
 
-    for (id, data) in &open_sync.files {
-        if !state
-            .paths
-            .slot_completion_dir(&slot_infos, open_sync.id)
-            .join(id)
-            .exists()
-        {
-            remaining_files.insert(id.clone(), data.clone());
-        }
+        for path in paths {
+            let file_path = slot_files_dir.join(native_path(&path));
 
-        let tmp_path = state
-            .paths
-            .slot_transfer_dir(&slot_infos, open_sync.id)
-            .join(id);
+            if !file_path.is_file() {
+                continue;
+            }
 
-        if tmp_path.exists() {
-            fs::remove_file(&tmp_path)
-                .await
-                .with_context(|| {
-                    format!(
-                        "Failed to remove partially transferred file at '{}'",
-                        tmp_path.display()
-                    )
-                })
-                .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+            let file_hash = hash_cache
+                .hash(&path, &file_path, verify)
+                .with_context(|| format!("Failed to hash file '{path}'"))?;
+
+            if file_hash.corrupted {
+                corrupted.push(path.clone());
+            }
+
+            hashes.insert(path, file_hash.hash);
         }
+
+        hash_cache.save(&hash_cache_file)?;
+
+        Ok::<_, anyhow::Error>((hashes, corrupted))
+    })
+    .await
+    .context("Failed to run the hashing task")
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    for path in corrupted {
+        warn!(
+            "!!! Content of file '{path}' in slot '{slot_name}' changed without its metadata changing, it may be corrupted !!!"
+        );
     }
 
-    Ok(Json(SyncInfos {
-        sync_token,
-        transfer_file_ids: remaining_files
-            .iter()
-            .map(|(id, (relative_path, _))| (id.clone(), relative_path.clone()))
-            .collect(),
-        transfer_size: open_sync
-            .diff_ops
-            .send_files
-            .iter()
-            .filter(|(id, _)| remaining_files.contains_key(id))
-            .map(|(_, mt)| mt.size)
-            .sum(),
-    }))
+    Ok(Json(hashes))
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct SyncFinalizationParams {
+pub struct InitSlotEncryptionParams {
     slot_name: String,
-    sync_token: String,
+    encryption: SlotEncryption,
 }
 
-pub async fn finalize_sync(
+pub async fn init_slot_encryption(
     State(state): State<HttpState>,
-    Json(payload): Json<SyncFinalizationParams>,
+    Json(payload): Json<InitSlotEncryptionParams>,
 ) -> HttpResult<Json<()>> {
-    let SyncFinalizationParams {
+    let InitSlotEncryptionParams {
         slot_name,
-        sync_token,
+        encryption,
     } = payload;
 
-    let mut slot = state
+    let slot = state
         .slots
         .get(&slot_name)
         .context("Provided slot was not found")
         .map_err(handle_err!(NOT_FOUND))?
-        // Getting an exclusive access right now is very important as it ensures that no
-        // other finalization process can happen simultaneously, which would be destructive
         .write()
         .await;
 
-    let open_sync = slot
-        .open_sync
-        .as_ref()
-        .context("No synchronization is currently open for this slot")
-        .map_err(handle_err!(NOT_FOUND))?;
-
-    if open_sync.token != sync_token {
+    if !slot.open_syncs.is_empty() {
         throw_err!(
-            BAD_REQUEST,
-            "Provided synchronization token does not match currently open sync."
+            FORBIDDEN,
+            "A synchronization is already opened for the provided slot"
         );
     }
 
-    let complete_dir = state.paths.slot_completion_dir(&slot.infos, open_sync.id);
+    let encryption_file = state.paths.slot_encryption_file(&slot.infos);
 
-    for (relative_path, (id, _)) in &open_sync.files {
-        let marker_path = complete_dir.join(id);
-
-        if !marker_path.is_file() {
-            throw_err!(
-                BAD_REQUEST,
-                format!("File '{relative_path}' has not been transferred yet!")
-            );
-        }
-
-        fs::remove_file(&marker_path)
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to remove marker file at '{}'",
-                    marker_path.display()
-                )
-            })
-            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    if encryption_file.exists() {
+        throw_err!(CONFLICT, "Encryption is already set up for this slot");
     }
 
-    let slot_files_dir = state.paths.slot_content_dir(&slot.infos);
+    // Encrypted and unencrypted files must never be mixed in the same slot
+    let has_content = std::fs::read_dir(state.paths.slot_content_dir(&slot.infos))
+        .context("Failed to read the slot's content directory")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+        .next()
+        .is_some();
 
-    for relative_path in &open_sync.diff_ops.create_dirs {
-        fs::create_dir(slot_files_dir.join(relative_path))
-            .await
-            .with_context(|| format!("Failed to create folder at '{relative_path}'"))
-            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    if has_content {
+        throw_err!(CONFLICT, "Encryption can only be set up on an empty slot");
     }
 
-    fs::remove_dir(state.paths.slot_pending_dir(&slot.infos, open_sync.id))
-        .await
-        .context("Failed to remove the pending transfers directory")
+    let json = serde_json::to_string(&encryption)
+        .context("Failed to serialize encryption parameters")
         .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
 
-    fs::remove_dir(&complete_dir)
+    fs::write(&encryption_file, json)
         .await
-        .context("Failed to remove the complete transfers directory")
+        .context("Failed to write encryption parameters")
         .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
 
-    fs::remove_dir(state.paths.slot_transfer_dir(&slot.infos, open_sync.id))
+    Ok(Json(()))
+}
+
+// Get the ignore rules the server enforces for a slot
+async fn read_slot_ignore_rules(
+    state: &HttpState,
+    slot_infos: &SlotInfos,
+) -> HttpResult<SnapshotOptions> {
+    let path = state.paths.slot_ignore_rules_file(slot_infos);
+
+    if !path.exists() {
+        return Ok(SnapshotOptions::default());
+    }
+
+    SlotIgnoreRules::load(&path)
         .await
-        .context("Failed to remove the slot directory")
+        .and_then(SlotIgnoreRules::into_snapshot_options)
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))
+}
+
+async fn read_slot_settings(state: &HttpState, slot_infos: &SlotInfos) -> HttpResult<SlotSettings> {
+    let path = state.paths.slot_settings_file(slot_infos);
+
+    if !path.exists() {
+        return Ok(SlotSettings::default());
+    }
+
+    SlotSettings::load(&path)
+        .await
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))
+}
+
+fn read_slot_encryption(path: &Path) -> HttpResult<Option<SlotEncryption>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let json = std::fs::read_to_string(path)
+        .context("Failed to read the slot's encryption parameters")
         .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
 
-    slot.open_sync = None;
+    serde_json::from_str(&json)
+        .context("Failed to parse the slot's encryption parameters")
+        .map(Some)
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))
+}
 
-    Ok(Json(()))
+fn ensure_encryption_mode(
+    state: &HttpState,
+    slot_infos: &SlotInfos,
+    encrypted: bool,
+) -> HttpResult<()> {
+    let slot_encrypted = state.paths.slot_encryption_file(slot_infos).exists();
+
+    if slot_encrypted && !encrypted {
+        throw_err!(
+            CONFLICT,
+            "This slot is encrypted, an encryption passphrase is required"
+        );
+    }
+
+    if !slot_encrypted && encrypted {
+        throw_err!(
+            CONFLICT,
+            "This slot is not encrypted, encrypted content cannot be stored in it"
+        );
+    }
+
+    Ok(())
 }
 
 #[derive(Deserialize)]
 #[serde(deny_unknown_fields)]
-pub struct SendFileParams {
+pub struct BeginSyncParams {
     slot_name: String,
+    diff: Diff,
+    #[serde(default)]
+    encrypted: bool,
+    // Bypass the deletion limit
+    #[serde(default)]
+    force: bool,
+    // Only check the synchronization could be opened and return the operations it would perform
+    #[serde(default)]
+    dry_run: bool,
+    // Snapshot of the slot the diff was built against, which must still match its content
+    #[serde(default)]
+    expected_remote: Option<ExpectedRemote>,
+    // Chosen by the client so it can find this synchronization again if the response is lost
+    #[serde(default)]
+    opening_key: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExpectedRemote {
+    snapshot_options: SnapshotOptions,
+    fingerprint: String,
+}
+
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum BeginSyncResult {
+    Opened(SyncInfos),
+    DryRun(DiffApplyOps),
+}
+
+#[derive(Serialize)]
+pub struct SyncInfos {
     sync_token: String,
-    path: String,
+    transfer_file_ids: HashMap<String, String>,
+    transfer_size: u64,
+    completed_files: Vec<String>,
 }
 
-pub async fn send_file(
-    Query(params): Query<SendFileParams>,
+pub async fn begin_sync(
     State(state): State<HttpState>,
-    mut stream: BodyStream,
-) -> HttpResult<Json<()>> {
-    let SendFileParams {
+    Extension(device): Extension<AuthenticatedDevice>,
+    Json(begin_sync_params): Json<BeginSyncParams>,
+) -> HttpResult<Json<BeginSyncResult>> {
+    let BeginSyncParams {
         slot_name,
-        sync_token,
-        path,
-    } = params;
+        diff,
+        encrypted,
+        force,
+        dry_run,
+        expected_remote,
+        opening_key,
+    } = begin_sync_params;
+
+    if dry_run {
+        info!(
+            "Device '{}' is checking a synchronization on slot '{slot_name}' (dry run)",
+            device.device_name
+        );
+    } else {
+        info!(
+            "Device '{}' is beginning a synchronization on slot '{slot_name}'",
+            device.device_name
+        );
+    }
 
-    // This block contains quick, locking computing
-    // After this block we can do the actual transfer without worrying about locking a concurrent request
-    let (tmp_path, sync_id, file_id, metadata, slot_infos) = {
-        let slot = state
-            .slots
-            .get(&slot_name)
-            .context("Provided slot was not found")
-            .map_err(handle_err!(NOT_FOUND))?
-            .read()
-            .await;
+    let mut slot = state
+        .slots
+        .get(&slot_name)
+        .context("Provided slot was not found")
+        .map_err(handle_err!(NOT_FOUND))?
+        .write()
+        .await;
 
-        let open_sync = slot
-            .open_sync
-            .as_ref()
-            .context("No synchronization is currently open for this slot")
-            .map_err(handle_err!(NOT_FOUND))?;
+    // Dry runs must not have any side effect, including on stale synchronizations
+    if !dry_run {
+        reclaim_stale_syncs(&state, &mut slot).await?;
+    }
+
+    ensure_encryption_mode(&state, &slot.infos, encrypted)?;
+
+    let permit = if dry_run {
+        Some(None)
+    } else {
+        state.try_acquire_open_sync_permit()
+    };
+
+    let Some(permit) = permit else {
+        return Err(server_err!(
+            SERVICE_UNAVAILABLE,
+            "Too many synchronizations are currently open on this server, please retry later"
+        )
+        .with_retry_after(OPEN_SYNCS_RETRY_AFTER_SECS));
+    };
+
+    let open_sync = OpenSync::new(
+        diff,
+        device.clone(),
+        opening_key,
+        state.backup_args.completion_tracking,
+        permit,
+    )?;
+
+    if let Some((other, path)) = slot.find_overlapping_sync(&open_sync) {
+        throw_err!(
+            CONFLICT,
+            format!(
+                "Item '{path}' is already part of a synchronization opened by device '{}' on the provided slot",
+                other.opened_by.device_name
+            )
+        );
+    }
+
+    let ignore_rules = read_slot_ignore_rules(&state, &slot.infos).await?;
+
+    let ignore_rules = ignore_rules
+        .ignore_matcher()
+        .context("Failed to compile the slot's ignore rules")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    // Prevent clients from sending items the server's rules exclude
+    let ignored_dir = open_sync
+        .diff_ops
+        .create_dirs
+        .iter()
+        .find(|path| ignore_rules.ignores_path(Path::new(path)));
+
+    let ignored_moved_dir = open_sync
+        .diff_ops
+        .move_dirs
+        .iter()
+        .map(|(_, to)| to)
+        .find(|path| ignore_rules.ignores_path(Path::new(path)));
+
+    let ignored_file = open_sync
+        .files
+        .keys()
+        .chain(
+            open_sync
+                .diff_ops
+                .create_hardlinks
+                .iter()
+                .map(|(path, _)| path),
+        )
+        .chain(open_sync.diff_ops.touch_files.iter().map(|(path, _)| path))
+        .find(|path| {
+            ignore_rules.ignores_path(Path::new(path)) || ignore_rules.ignores_ext(Path::new(path))
+        });
+
+    if let Some(path) = ignored_dir.or(ignored_moved_dir).or(ignored_file) {
+        throw_err!(
+            FORBIDDEN,
+            format!("Item '{path}' is excluded from synchronization by the server")
+        );
+    }
+
+    let slot_files_dir = state.paths.slot_content_dir(&slot.infos);
+
+    if slot.case_insensitive {
+        ensure_no_case_collision(&slot_files_dir, &open_sync).await?;
+    }
+
+    // The slot is locked, so its content can't change before the synchronization is opened
+    if let Some(expected_remote) = expected_remote {
+        ensure_remote_unchanged(&state, &slot_name, &slot, expected_remote).await?;
+    }
+
+    if force {
+        warn!(
+            "Device '{}' is forcing the synchronization, deletion limit won't be enforced",
+            device.device_name
+        );
+    } else {
+        ensure_deletion_limit(&state, &slot.infos, &open_sync).await?;
+    }
+
+    ensure_enough_space(&slot_files_dir, &open_sync)?;
+
+    // Only the modification time of these files will be updated, so they must already be there
+    for (relative_path, mt) in &open_sync.diff_ops.touch_files {
+        let metadata = fs::metadata(slot_files_dir.join(native_path(relative_path)))
+            .await
+            .with_context(|| format!("File '{relative_path}' to update was not found"))
+            .map_err(handle_err!(BAD_REQUEST))?;
 
-        if open_sync.token != sync_token {
+        if !metadata.is_file() || metadata.len() != mt.size {
             throw_err!(
                 BAD_REQUEST,
-                "Provided synchronization token does not match currently open sync."
+                format!("File '{relative_path}' does not match the one to update")
             );
         }
+    }
 
-        let (file_id, metadata) = open_sync
-            .files
-            .get(&path)
-            .ok_or("Provided file was not found in the current synchronization process")
-            .map_err(handle_err!(BAD_REQUEST))?;
+    if dry_run {
+        return Ok(Json(BeginSyncResult::DryRun(open_sync.diff_ops)));
+    }
 
-        let tmp_path = state
-            .paths
-            .slot_pending_dir(&slot.infos, open_sync.id)
-            .join(file_id);
+    fs::create_dir(state.paths.slot_transfer_dir(&slot.infos, open_sync.id))
+        .await
+        .context("Failed to create the synchronization directory")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
 
-        (
-            tmp_path,
-            open_sync.id,
-            file_id.clone(),
-            *metadata,
-            slot.infos.clone(),
-        )
-    };
+    fs::create_dir(state.paths.slot_pending_dir(&slot.infos, open_sync.id))
+        .await
+        .context("Failed to create the pending transfers directory")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
 
-    if tmp_path.is_file() {
-        fs::remove_file(&tmp_path)
+    if open_sync.completion_tracking == CompletionTracking::Dir {
+        fs::create_dir(state.paths.slot_completion_dir(&slot.infos, open_sync.id))
             .await
-            .context("Temporary file already exists but it could not be deleted")
-            .map_err(handle_err!(BAD_REQUEST))?;
+            .context("Failed to create the complete transfers directory")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
     }
 
-    let mut tmp_file = File::create(&tmp_path)
+    open_sync
+        .save(&state.paths.slot_open_sync_file(&slot.infos, open_sync.id))
         .await
-        .context("Failed to create a temporary file")
         .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
 
-    let mut written = 0;
+    let snapshot_cache_file = state.paths.slot_snapshot_cache_file(&slot.infos);
 
-    while let Some(chunk) = stream.next().await {
-        let chunk = chunk.map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
-        written += chunk.len();
+    let snapshot_cache = slot
+        .snapshot_cache
+        .take(&snapshot_cache_file)
+        .await
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    preserve_in_history(
+        &state,
+        &slot.infos,
+        &open_sync,
+        open_sync
+            .diff_ops
+            .replace_dirs
+            .iter()
+            .chain(open_sync.diff_ops.delete_files.iter())
+            .cloned()
+            .collect(),
+    )
+    .await?;
 
-        tmp_file
-            .write_all(&chunk)
+    // Must come first, as other items may take their place
+    for relative_path in &open_sync.diff_ops.replace_dirs {
+        fs::remove_dir_all(slot_files_dir.join(native_path(relative_path)))
             .await
-            .context("Failed to write to temporary file")
+            .with_context(|| format!("Failed to remove directory at '{relative_path}'"))
             .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
     }
 
-    let SnapshotFileMetadata {
-        last_modif_date_s,
-        last_modif_date_ns,
-        size,
-    } = metadata;
+    for relative_path in &open_sync.diff_ops.delete_files {
+        fs::remove_file(slot_files_dir.join(native_path(relative_path)))
+            .await
+            .with_context(|| format!("Failed to remove file at '{relative_path}'"))
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
 
-    if u64::try_from(written).unwrap() != size {
-        throw_err!(
-            BAD_REQUEST,
-            "Provided size does not match transmitted content"
-        );
+    for (from, to) in &open_sync.diff_ops.move_dirs {
+        let to = slot_files_dir.join(native_path(to));
+
+        // The target's parent may be a new directory, which would otherwise only be created during finalization
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| format!("Failed to create parent directory of '{}'", to.display()))
+                .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+        }
+
+        fs::rename(slot_files_dir.join(native_path(from)), &to)
+            .await
+            .with_context(|| format!("Failed to move directory '{from}' to '{}'", to.display()))
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
     }
 
-    let tmp_path_bis = tmp_path.clone();
+    for relative_path in &open_sync.diff_ops.delete_empty_dirs {
+        fs::remove_dir(slot_files_dir.join(native_path(relative_path)))
+            .await
+            .with_context(|| format!("Failed to remove directory at '{relative_path}'"))
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
 
-    tokio::task::spawn_blocking(move || {
-        filetime::set_file_mtime(
-            tmp_path_bis,
-            FileTime::from_unix_time(last_modif_date_s as i64, last_modif_date_ns),
-        )
-        .context("Failed to set modification time")
-    })
-    .await
-    .context("Failed to run modification time setter")
-    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
-    .context("Failed to run modification time setter")
-    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    update_snapshot_cache(
+        &mut slot.snapshot_cache,
+        &snapshot_cache_file,
+        &slot_name,
+        snapshot_cache,
+        |cached| cached.apply_opening(&open_sync.diff_ops),
+    )
+    .await;
+
+    let sync_infos = SyncInfos {
+        sync_token: open_sync.token.to_owned(),
+
+        transfer_file_ids: open_sync
+            .files
+            .iter()
+            .map(|(relative_path, (id, _))| (relative_path.clone(), id.clone()))
+            .collect(),
+
+        transfer_size: open_sync
+            .diff_ops
+            .send_files
+            .iter()
+            .map(|(_, mt)| mt.size)
+            .sum(),
 
-    // Move file to its destination
+        completed_files: vec![],
+    };
 
-    let final_path = state.paths.slot_content_dir(&slot_infos).join(&path);
+    let audit_record = AuditRecord::new(
+        &device.device_name,
+        &slot_name,
+        AuditOperation::BeginSync,
+        &open_sync.diff,
+        sync_infos.transfer_size,
+    );
 
-    fs::rename(&tmp_path, &final_path)
+    if let Err(err) = audit_record
+        .append_to(&state.paths.slot_audit_log_file(&slot.infos))
         .await
-        .with_context(|| {
-            format!(
-                "Failed to move complete file '{path}' to '{}'",
-                final_path.display()
-            )
-        })
-        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    {
+        error!("Failed to write audit record: {err:?}");
+    }
 
-    // Create completion marker file
+    // This must come last, otherwise we have a begin synchronization even if we didn't go to the end of its preparation
+    slot.open_syncs.insert(open_sync.id, open_sync);
 
-    let marker_path = &state
-        .paths
-        .slot_completion_dir(&slot_infos, sync_id)
-        .join(&file_id);
+    Ok(Json(BeginSyncResult::Opened(sync_infos)))
+}
 
-    fs::write(&marker_path, "")
-        .await
-        .with_context(|| {
-            format!(
-                "Failed to create transfer completion marker file at '{}'",
-                marker_path.display()
-            )
-        })
-        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+// Store a slot's cached snapshot again once it was updated with the changes made to the slot's content
+// Failing to do so isn't fatal, the slot just has to be walked through again for the next snapshot
+async fn update_snapshot_cache(
+    snapshot_cache: &mut SlotSnapshotCache,
+    snapshot_cache_file: &Path,
+    slot_name: &str,
+    taken: Option<(CachedSnapshot, u64)>,
+    update: impl FnOnce(&mut CachedSnapshot) -> anyhow::Result<()>,
+) {
+    let Some((mut cached, content_version)) = taken else {
+        return;
+    };
+
+    let result = match update(&mut cached) {
+        Ok(()) => {
+            snapshot_cache
+                .store(snapshot_cache_file, cached, content_version)
+                .await
+        }
+
+        Err(err) => Err(err),
+    };
+
+    if let Err(err) = result {
+        error!("Failed to update the snapshot cache of slot '{slot_name}': {err:?}");
+    }
+}
+
+// Delay clients are asked to wait for when the maximum number of open synchronizations is reached
+const OPEN_SYNCS_RETRY_AFTER_SECS: u64 = 60;
+
+// Forcibly close the slot's open synchronizations which have been inactive for longer than the configured timeout
+async fn reclaim_stale_syncs(state: &HttpState, slot: &mut SlotSync) -> HttpResult<()> {
+    let Some(timeout) = state.backup_args.sync_lock_timeout else {
+        return Ok(());
+    };
+
+    let stale = slot
+        .open_syncs
+        .values()
+        .filter(|open_sync| open_sync.inactive_for() >= Duration::from_secs(timeout))
+        .map(|open_sync| open_sync.id)
+        .collect::<Vec<_>>();
+
+    for sync_id in stale {
+        let open_sync = slot.open_syncs.remove(&sync_id).unwrap();
+
+        warn!(
+            "!!! Forcibly closing synchronization of slot '{}' opened by device '{}', as it has been inactive for {} seconds !!!",
+            slot.infos.name(),
+            open_sync.opened_by.device_name,
+            open_sync.inactive_for().as_secs()
+        );
+
+        fs::remove_dir_all(state.paths.slot_transfer_dir(&slot.infos, sync_id))
+            .await
+            .context("Failed to remove the stale synchronization's directory")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    Ok(())
+}
+
+// A synchronization's directory may be (partially) removed out-of-band, e.g. by a cleanup script
+fn is_sync_dir_intact(state: &HttpState, slot_infos: &SlotInfos, open_sync: &OpenSync) -> bool {
+    state
+        .paths
+        .slot_pending_dir(slot_infos, open_sync.id)
+        .is_dir()
+        && (open_sync.completion_tracking != CompletionTracking::Dir
+            || state
+                .paths
+                .slot_completion_dir(slot_infos, open_sync.id)
+                .is_dir())
+}
+
+// Close a synchronization which can't be completed anymore, so the client can start a new one
+async fn close_broken_sync(state: &HttpState, slot: &mut SlotSync, sync_id: SyncId) -> HttpError {
+    if let Some(open_sync) = slot.open_syncs.remove(&sync_id) {
+        warn!(
+            "!!! Closing synchronization of slot '{}' opened by device '{}', as its directory is missing or incomplete !!!",
+            slot.infos.name(),
+            open_sync.opened_by.device_name
+        );
+    }
+
+    let transfer_dir = state.paths.slot_transfer_dir(&slot.infos, sync_id);
+
+    if transfer_dir.exists() {
+        if let Err(err) = fs::remove_dir_all(&transfer_dir).await {
+            error!("Failed to remove the broken synchronization's directory: {err}");
+        }
+    }
+
+    server_err!(
+        CONFLICT,
+        "The synchronization's directory on the server is missing or incomplete, so it was closed: please synchronize again"
+    )
+}
+
+// Move a file into place, which is atomic when both paths are on the same filesystem
+// Otherwise (e.g. with a transfer directory on another volume), the file is first copied next to its destination
+// so it still replaces the previous version atomically, but a crash during the copy may leave a temporary file behind
+async fn move_file(from: &Path, to: &Path, durable: bool) -> anyhow::Result<()> {
+    match fs::rename(from, to).await {
+        Ok(()) => return Ok(()),
+        Err(err) if err.kind() == ErrorKind::CrossesDevices => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let tmp_path = to.with_file_name(format!(
+        "{HARMONY_ITEMS_PREFIX}move-{}",
+        to.file_name().unwrap().to_string_lossy()
+    ));
+
+    fs::copy(from, &tmp_path)
+        .await
+        .context("Failed to copy file across filesystems")?;
+
+    // Copies don't keep the modification time
+    let mtime = fs::metadata(from)
+        .await
+        .and_then(|mt| mt.modified())
+        .context("Failed to get file's modification time")?;
+
+    filetime::set_file_mtime(&tmp_path, FileTime::from_system_time(mtime))
+        .context("Failed to set copied file's modification time")?;
+
+    if durable {
+        File::open(&tmp_path)
+            .await
+            .context("Failed to open copied file")?
+            .sync_all()
+            .await
+            .context("Failed to flush copied file to the disk")?;
+    }
+
+    fs::rename(&tmp_path, to)
+        .await
+        .context("Failed to move copied file into place")?;
+
+    fs::remove_file(from)
+        .await
+        .context("Failed to remove the original file after copying it")
+}
+
+// Protect slots against misconfigured clients (e.g. synchronizing an empty directory)
+async fn ensure_deletion_limit(
+    state: &HttpState,
+    slot_infos: &SlotInfos,
+    open_sync: &OpenSync,
+) -> HttpResult<()> {
+    let settings = read_slot_settings(state, slot_infos).await?;
+
+    let Some(max_percent) = settings.max_deletion_percent(state.backup_args.max_deletion_percent)
+    else {
+        return Ok(());
+    };
+
+    // Moved directories aren't deleted
+    let deleted = open_sync
+        .diff
+        .deleted
+        .iter()
+        .map(|(path, _)| path)
+        .chain(open_sync.diff.type_changed.iter().map(|(path, _)| path))
+        .filter(|path| !open_sync.diff_ops.is_moved(path))
+        .count();
+
+    if deleted == 0 {
+        return Ok(());
+    }
+
+    let content_dir = state.paths.slot_content_dir(slot_infos);
+
+    let total = tokio::task::spawn_blocking(move || count_items(&content_dir))
+        .await
+        .context("Failed to run the items counter")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+        .context("Failed to count the slot's items")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    if total == 0 {
+        return Ok(());
+    }
+
+    let percent = deleted as f64 * 100.0 / total as f64;
+
+    if percent <= max_percent {
+        return Ok(());
+    }
+
+    Err(server_err!(
+        PRECONDITION_FAILED,
+        format!(
+            "Synchronization would delete {deleted} of the slot's {total} items ({percent:.1}%), which exceeds the limit of {max_percent}%"
+        )
+    )
+    .with_header(
+        HeaderName::from_static(MASS_DELETION_HEADER),
+        HeaderValue::from_static("1"),
+    ))
+}
+
+// Items whose names only differ by case would replace each other on case-insensitive filesystems
+async fn ensure_no_case_collision(content_dir: &Path, open_sync: &OpenSync) -> HttpResult<()> {
+    let DiffApplyOps {
+        create_dirs,
+        create_hardlinks,
+        move_dirs,
+        delete_files,
+        delete_empty_dirs,
+        replace_dirs,
+        // Files to send are listed with their transfer IDs
+        send_files: _,
+        delta_files: _,
+        touch_files: _,
+        set_acls: _,
+        set_xattrs: _,
+    } = &open_sync.diff_ops;
+
+    let created = create_dirs
+        .iter()
+        .chain(open_sync.files.keys())
+        .chain(create_hardlinks.iter().map(|(path, _)| path))
+        .chain(move_dirs.iter().map(|(_, to)| to))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let removed = delete_files
+        .iter()
+        .chain(delete_empty_dirs)
+        .chain(replace_dirs)
+        .chain(move_dirs.iter().map(|(from, _)| from))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let content_dir = content_dir.to_owned();
+
+    let collision = tokio::task::spawn_blocking(move || {
+        find_case_collision(
+            &content_dir,
+            &created.iter().map(String::as_str).collect::<Vec<_>>(),
+            &removed.iter().map(String::as_str).collect::<Vec<_>>(),
+        )
+    })
+    .await
+    .context("Failed to run the case collision check task")
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    if let Some((existing, path)) = collision {
+        throw_err!(
+            CONFLICT,
+            format!(
+                "Item '{path}' would replace item '{existing}', as the slot's filesystem is case-insensitive"
+            )
+        );
+    }
+
+    Ok(())
+}
+
+// Ensure the slot's content didn't change since the client built its diff, e.g. because of another
+// synchronization, as the diff's deletions and modifications would then be based on outdated items
+async fn ensure_remote_unchanged(
+    state: &HttpState,
+    slot_name: &str,
+    slot: &SlotSync,
+    expected_remote: ExpectedRemote,
+) -> HttpResult<()> {
+    let ExpectedRemote {
+        mut snapshot_options,
+        fingerprint,
+    } = expected_remote;
+
+    // Same as for the snapshot route
+    let server_rules = read_slot_ignore_rules(state, &slot.infos).await?;
+    snapshot_options.merge_ignore_rules(&server_rules);
+
+    let current = match slot.snapshot_cache.get(&snapshot_options, None) {
+        Some(cached) => cached,
+        None => {
+            run_snapshot(
+                slot_name.to_owned(),
+                state.paths.slot_content_dir(&slot.infos),
+                snapshot_options,
+                None,
+            )
+            .await?
+        }
+    };
+
+    if current.snapshot.fingerprint() != fingerprint {
+        return Err(server_err!(
+            CONFLICT,
+            "Slot's content changed since it was snapshotted, a new snapshot is required"
+        )
+        .with_header(
+            HeaderName::from_static(REMOTE_CHANGED_HEADER),
+            HeaderValue::from_static("1"),
+        ));
+    }
+
+    Ok(())
+}
+
+fn count_items(dir: &Path) -> anyhow::Result<usize> {
+    let mut count = 0;
+
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory '{}'", dir.display()))?
+    {
+        let entry = entry
+            .with_context(|| format!("Failed to read entry in directory '{}'", dir.display()))?;
+
+        count += 1;
+
+        // Symbolic links are not followed
+        if entry
+            .file_type()
+            .with_context(|| format!("Failed to get type of item '{}'", entry.path().display()))?
+            .is_dir()
+        {
+            count += count_items(&entry.path())?;
+        }
+    }
+
+    Ok(count)
+}
+
+fn ensure_enough_space(slot_files_dir: &Path, open_sync: &OpenSync) -> HttpResult<()> {
+    let available = fs2::available_space(slot_files_dir)
+        .context("Failed to get the available space on the slot's filesystem")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    let transfer_size: u64 = open_sync
+        .diff_ops
+        .send_files
+        .iter()
+        .map(|(_, mt)| mt.size)
+        .sum();
+
+    // Files are deleted before any transfer begins, so their space can be reused
+    // Moved directories don't free anything as their files are kept
+    let freed_size: u64 = open_sync
+        .diff
+        .deleted
+        .iter()
+        .filter(|(path, _)| !open_sync.diff_ops.is_moved(path))
+        .map(|(_, DiffItemDeleted { prev })| prev)
+        .chain(
+            open_sync
+                .diff
+                .type_changed
+                .iter()
+                .map(|(_, DiffItemTypeChanged { prev, new: _ })| prev),
+        )
+        .filter_map(|mt| match mt {
+            SnapshotItemMetadata::Directory => None,
+            SnapshotItemMetadata::File(mt) => Some(mt.size),
+        })
+        .sum();
+
+    let required = transfer_size.saturating_sub(freed_size);
+
+    if required > available {
+        throw_err!(
+            INSUFFICIENT_STORAGE,
+            format!(
+                "Not enough space available for this synchronization: {required} bytes are required but only {available} bytes are available (missing {} bytes)",
+                required - available
+            )
+        );
+    }
+
+    Ok(())
+}
+
+fn ensure_admin_secret(state: &HttpState, admin_secret: &str) -> HttpResult<()> {
+    let Some(expected_admin_secret) = &state.backup_args.admin_secret else {
+        throw_err!(
+            FORBIDDEN,
+            "Administrative operations are disabled on this server"
+        );
+    };
+
+    // Hashes are compared in constant time (whatever the secrets' lengths), so the secret can't be guessed from response times
+    if blake3::hash(admin_secret.as_bytes()) != blake3::hash(expected_admin_secret.as_bytes()) {
+        throw_err!(FORBIDDEN, "Invalid admin secret provided");
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RotateSecretParams {
+    admin_secret: String,
+    new_secret: String,
+    // Revoke all access tokens and device keys, so every device has to use the new secret password
+    #[serde(default)]
+    revoke_access: bool,
+}
+
+// Change the secret password used by devices to get access tokens, e.g. after it leaked
+pub async fn rotate_secret(
+    State(state): State<HttpState>,
+    Extension(device): Extension<AuthenticatedDevice>,
+    Json(payload): Json<RotateSecretParams>,
+) -> HttpResult<()> {
+    let RotateSecretParams {
+        admin_secret,
+        new_secret,
+        revoke_access,
+    } = payload;
+
+    ensure_admin_secret(&state, &admin_secret)?;
+
+    if new_secret.is_empty() {
+        throw_err!(BAD_REQUEST, "New secret password must not be empty");
+    }
+
+    let secret_hash = tokio::task::spawn_blocking(move || hash_secret(&new_secret))
+        .await
+        .context("Failed to run the secret password hashing")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    let mut app_data = state.app_data.write().await;
+
+    app_data.set_secret_hash(secret_hash);
+
+    warn!(
+        "!!! Device '{}' rotated the secret password !!!",
+        device.device_name
+    );
+
+    if revoke_access {
+        let (tokens, keys) = app_data.revoke_all_access();
+
+        warn!("!!! Revoked {tokens} access token(s) and {keys} device key(s), all devices must use the new secret password !!!");
+    }
+
+    if let Err(err) = app_data.save(&state.paths.app_data_file()).await {
+        error!("Failed to save data file: {err:?}");
+        throw_err!(INTERNAL_SERVER_ERROR, "Failed to save app data file");
+    }
+
+    Ok(())
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResetSlotParams {
+    slot_name: String,
+    admin_secret: String,
+    // Must be the slot's name, to avoid wiping a slot by mistake
+    confirmation: String,
+    // Close the slot's open synchronization (if any) instead of refusing to reset it
+    #[serde(default)]
+    force: bool,
+}
+
+// Delete all the content of a slot, returning the number of removed files
+pub async fn reset_slot(
+    State(state): State<HttpState>,
+    Extension(device): Extension<AuthenticatedDevice>,
+    Json(payload): Json<ResetSlotParams>,
+) -> HttpResult<Json<usize>> {
+    let ResetSlotParams {
+        slot_name,
+        admin_secret,
+        confirmation,
+        force,
+    } = payload;
+
+    ensure_admin_secret(&state, &admin_secret)?;
+
+    if confirmation != slot_name {
+        throw_err!(
+            BAD_REQUEST,
+            "Confirmation must be the name of the slot to reset"
+        );
+    }
+
+    let mut slot = state
+        .slots
+        .get(&slot_name)
+        .context("Provided slot was not found")
+        .map_err(handle_err!(NOT_FOUND))?
+        .write()
+        .await;
+
+    if !slot.open_syncs.is_empty() && !force {
+        throw_err!(
+            CONFLICT,
+            "A synchronization is currently open for this slot"
+        );
+    }
+
+    for (sync_id, open_sync) in std::mem::take(&mut slot.open_syncs) {
+        warn!(
+            "!!! Closing synchronization of slot '{slot_name}' opened by device '{}' to reset the slot !!!",
+            open_sync.opened_by.device_name
+        );
+
+        fs::remove_dir_all(state.paths.slot_transfer_dir(&slot.infos, sync_id))
+            .await
+            .context("Failed to remove the open synchronization's directory")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    warn!(
+        "!!! Device '{}' is resetting slot '{slot_name}' !!!",
+        device.device_name
+    );
+
+    let snapshot_cache_file = state.paths.slot_snapshot_cache_file(&slot.infos);
+
+    slot.snapshot_cache
+        .take(&snapshot_cache_file)
+        .await
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    let slot_files_dir = state.paths.slot_content_dir(&slot.infos);
+
+    // The content directory itself is kept, as it may be a linked directory
+    let removed = tokio::task::spawn_blocking(move || remove_dir_content(&slot_files_dir))
+        .await
+        .context("Failed to run the slot's content removal")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+        .context("Failed to remove the slot's content")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    let hash_cache_file = state.paths.slot_hash_cache_file(&slot.infos);
+
+    if hash_cache_file.is_file() {
+        fs::remove_file(hash_cache_file)
+            .await
+            .context("Failed to remove the slot's hash cache")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    let audit_record = AuditRecord {
+        timestamp: SystemTime::now(),
+        device_name: &device.device_name,
+        slot_name: &slot_name,
+        operation: AuditOperation::ResetSlot,
+        added: 0,
+        modified: 0,
+        type_changed: 0,
+        deleted: removed,
+        bytes: 0,
+        path: None,
+    };
+
+    if let Err(err) = audit_record
+        .append_to(&state.paths.slot_audit_log_file(&slot.infos))
+        .await
+    {
+        error!("Failed to write audit record: {err:?}");
+    }
+
+    info!("Slot '{slot_name}' was reset ({removed} files removed)");
+
+    if state.backup_args.dedup {
+        collect_unused_objects(&state);
+    }
+
+    Ok(Json(removed))
+}
+
+fn remove_dir_content(dir: &Path) -> anyhow::Result<usize> {
+    let mut removed = 0;
+
+    for entry in std::fs::read_dir(dir)
+        .with_context(|| format!("Failed to read directory '{}'", dir.display()))?
+    {
+        let entry = entry
+            .with_context(|| format!("Failed to read entry in directory '{}'", dir.display()))?;
+
+        let path = entry.path();
+
+        // Symbolic links are not followed
+        let file_type = entry
+            .file_type()
+            .with_context(|| format!("Failed to get type of item '{}'", path.display()))?;
+
+        if file_type.is_dir() {
+            removed += remove_dir_content(&path)?;
+
+            std::fs::remove_dir(&path)
+                .with_context(|| format!("Failed to remove directory '{}'", path.display()))?;
+        } else {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("Failed to remove file '{}'", path.display()))?;
+
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct IsSyncOpenParams {
+    slot_name: String,
+    // Only consider the synchronization opened with this key
+    #[serde(default)]
+    opening_key: Option<String>,
+}
+
+// Only synchronizations which would be picked by `/sync/resume` are reported, as the synchronizations
+// opened by other devices don't prevent opening a new one
+pub async fn is_sync_open(
+    State(state): State<HttpState>,
+    Extension(device): Extension<AuthenticatedDevice>,
+    Json(payload): Json<IsSyncOpenParams>,
+) -> HttpResult<Json<bool>> {
+    let IsSyncOpenParams {
+        slot_name,
+        opening_key,
+    } = payload;
+
+    let slot = state
+        .slots
+        .get(&slot_name)
+        .context("Provided slot was not found")
+        .map_err(handle_err!(NOT_FOUND))?
+        .read()
+        .await;
+
+    Ok(Json(
+        resumable_sync(&slot, &device, opening_key.as_deref()).is_some(),
+    ))
+}
+
+#[derive(Serialize)]
+pub struct SlotStatus {
+    total_space: u64,
+    free_space: u64,
+    open_syncs: Vec<OpenSyncStatus>,
+}
+
+#[derive(Serialize)]
+pub struct OpenSyncStatus {
+    sync_id: String,
+    opened_by: String,
+    open_for_s: u64,
+    inactive_for_s: u64,
+    pending_transfers: usize,
+}
+
+// Meant to be consumed by monitoring tools
+pub async fn status(
+    State(state): State<HttpState>,
+) -> HttpResult<Json<BTreeMap<String, SlotStatus>>> {
+    let mut statuses = BTreeMap::new();
+
+    for (slot_name, slot) in state.slots.iter() {
+        let slot = slot.read().await;
+
+        let content_dir = state.paths.slot_content_dir(&slot.infos);
+
+        let total_space = fs2::total_space(&content_dir)
+            .with_context(|| format!("Failed to get the total space of slot '{slot_name}'"))
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+        let free_space = fs2::available_space(&content_dir)
+            .with_context(|| format!("Failed to get the available space of slot '{slot_name}'"))
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+        let mut open_syncs = vec![];
+
+        for open_sync in slot.open_syncs.values() {
+            let completed = open_sync
+                .files
+                .values()
+                .filter(|(id, _)| open_sync.is_completed(&state.paths, &slot.infos, id))
+                .count();
+
+            open_syncs.push(OpenSyncStatus {
+                sync_id: open_sync.id.to_string(),
+                opened_by: open_sync.opened_by.device_name.clone(),
+                open_for_s: open_sync.opened_at.elapsed().unwrap_or_default().as_secs(),
+                inactive_for_s: open_sync.inactive_for().as_secs(),
+                pending_transfers: open_sync.files.len().saturating_sub(completed),
+            });
+        }
+
+        statuses.insert(
+            slot_name.clone(),
+            SlotStatus {
+                total_space,
+                free_space,
+                open_syncs,
+            },
+        );
+    }
+
+    Ok(Json(statuses))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ResumeOpenSyncParams {
+    slot_name: String,
+    // Only resume the synchronization opened with this key
+    #[serde(default)]
+    opening_key: Option<String>,
+}
+
+pub async fn resume_open_sync(
+    State(state): State<HttpState>,
+    Extension(device): Extension<AuthenticatedDevice>,
+    Json(payload): Json<ResumeOpenSyncParams>,
+) -> HttpResult<Json<SyncInfos>> {
+    let ResumeOpenSyncParams {
+        slot_name,
+        opening_key,
+    } = payload;
+
+    info!(
+        "Device '{}' is resuming the open synchronization of slot '{slot_name}'",
+        device.device_name
+    );
+
+    let mut slot = state
+        .slots
+        .get(&slot_name)
+        .context("Provided slot was not found")
+        .map_err(handle_err!(NOT_FOUND))?
+        .write()
+        .await;
+
+    let slot_infos = slot.infos.clone();
+
+    let Some(sync_id) = resumable_sync(&slot, &device, opening_key.as_deref()) else {
+        throw_err!(
+            CONFLICT,
+            "No synchronization opened by this device is currently open for the provided slot"
+        )
+    };
+
+    if !is_sync_dir_intact(&state, &slot_infos, &slot.open_syncs[&sync_id]) {
+        return Err(close_broken_sync(&state, &mut slot, sync_id).await);
+    }
+
+    let open_sync = slot.open_syncs.get_mut(&sync_id).unwrap();
+
+    open_sync.touch();
+
+    let sync_token = open_sync.regenerate_access_token();
+
+    let mut remaining_files = HashMap::new();
+    let mut completed_files = vec![];
+
+    for (relative_path, (id, mt)) in &open_sync.files {
+        // Files of a prepared synchronization may already have been moved into place
+        if open_sync.commit_token.is_some() || open_sync.is_completed(&state.paths, &slot_infos, id)
+        {
+            completed_files.push(relative_path.clone());
+            continue;
+        }
+
+        remaining_files.insert(relative_path.clone(), (id.clone(), *mt));
+
+        let tmp_path = state
+            .paths
+            .slot_pending_dir(&slot_infos, open_sync.id)
+            .join(id);
+
+        // Files which were fully written but not marked as transferred are kept, so clients can
+        // check if they are intact instead of transferring them again (see `present_files`)
+        if tmp_path.exists()
+            && !tmp_path
+                .metadata()
+                .is_ok_and(|tmp_mt| tmp_mt.len() == mt.size)
+        {
+            fs::remove_file(&tmp_path)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to remove partially transferred file at '{}'",
+                        tmp_path.display()
+                    )
+                })
+                .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+        }
+    }
+
+    Ok(Json(SyncInfos {
+        sync_token,
+        transfer_file_ids: remaining_files
+            .iter()
+            .map(|(relative_path, (id, _))| (relative_path.clone(), id.clone()))
+            .collect(),
+        transfer_size: remaining_files.values().map(|(_, mt)| mt.size).sum(),
+        completed_files,
+    }))
+}
+
+// Pick the synchronization to resume: the one opened with the provided key if any, otherwise the
+// most recently active one opened by the device
+// Synchronizations opened by other devices are left alone, as they may still be running
+fn resumable_sync(
+    slot: &SlotSync,
+    device: &AuthenticatedDevice,
+    opening_key: Option<&str>,
+) -> Option<SyncId> {
+    slot.open_syncs
+        .values()
+        .filter(|open_sync| open_sync.opened_by.device_name == device.device_name)
+        .filter(|open_sync| {
+            opening_key.is_none() || open_sync.opening_key.as_deref() == opening_key
+        })
+        .min_by_key(|open_sync| open_sync.inactive_for())
+        .map(|open_sync| open_sync.id)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AbortFileParams {
+    slot_name: String,
+    sync_token: String,
+    path: String,
+}
+
+// Give up on transferring a file (e.g. because it can't be read by the client), so the rest of the
+// synchronization can be finalized
+// Returns the paths of all the files which won't be synchronized anymore
+pub async fn abort_file(
+    State(state): State<HttpState>,
+    Extension(device): Extension<AuthenticatedDevice>,
+    Json(payload): Json<AbortFileParams>,
+) -> HttpResult<Json<Vec<String>>> {
+    let AbortFileParams {
+        slot_name,
+        sync_token,
+        path,
+    } = payload;
+
+    let mut slot = state
+        .slots
+        .get(&slot_name)
+        .context("Provided slot was not found")
+        .map_err(handle_err!(NOT_FOUND))?
+        .write()
+        .await;
+
+    let slot_infos = slot.infos.clone();
+    let open_sync = open_sync_with_token(&mut slot, &sync_token)?;
+
+    if open_sync.commit_token.is_some() {
+        throw_err!(
+            CONFLICT,
+            "Synchronization was prepared for commit, files can't be aborted anymore"
+        );
+    }
+
+    open_sync.touch();
+
+    let (id, size, aborted) = open_sync.abort_file(&path)?;
+
+    for tmp_path in [
+        state
+            .paths
+            .slot_pending_dir(&slot_infos, open_sync.id)
+            .join(&id),
+        state
+            .paths
+            .slot_completion_dir(&slot_infos, open_sync.id)
+            .join(&id),
+    ] {
+        if tmp_path.exists() {
+            fs::remove_file(&tmp_path)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to remove transferred file at '{}'",
+                        tmp_path.display()
+                    )
+                })
+                .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+        }
+    }
+
+    open_sync
+        .save(&state.paths.slot_open_sync_file(&slot_infos, open_sync.id))
+        .await
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    warn!(
+        "Device '{}' aborted the transfer of file '{path}' in slot '{slot_name}' ({} file(s) won't be synchronized)",
+        device.device_name,
+        aborted.len()
+    );
+
+    let audit_record = AuditRecord {
+        timestamp: SystemTime::now(),
+        device_name: &device.device_name,
+        slot_name: &slot_name,
+        operation: AuditOperation::AbortFile,
+        added: 0,
+        modified: 0,
+        type_changed: 0,
+        deleted: 0,
+        bytes: size,
+        path: Some(&path),
+    };
+
+    if let Err(err) = audit_record
+        .append_to(&state.paths.slot_audit_log_file(&slot_infos))
+        .await
+    {
+        error!("Failed to write audit record: {err:?}");
+    }
+
+    Ok(Json(aborted))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncFinalizationParams {
+    slot_name: String,
+    sync_token: String,
+}
+
+// Finalize a synchronization in a single step (see `prepare_sync_commit` and `commit_sync`)
+pub async fn finalize_sync(
+    State(state): State<HttpState>,
+    Extension(device): Extension<AuthenticatedDevice>,
+    Json(payload): Json<SyncFinalizationParams>,
+) -> HttpResult<Json<()>> {
+    let SyncFinalizationParams {
+        slot_name,
+        sync_token,
+    } = payload;
+
+    let mut slot = lock_slot_for_finalization(&state, &slot_name).await?;
+
+    let commit_token = prepare_commit(&state, &mut slot, &slot_name, &sync_token).await?;
+
+    commit(
+        &state,
+        &mut slot,
+        &device,
+        slot_name,
+        &sync_token,
+        &commit_token,
+    )
+    .await
+    .map(Json)
+}
+
+// Ensure all files were transferred, returning a token to commit the synchronization with
+// Once prepared, no more file can be transferred and the synchronization can only be committed
+pub async fn prepare_sync_commit(
+    State(state): State<HttpState>,
+    Json(payload): Json<SyncFinalizationParams>,
+) -> HttpResult<Json<String>> {
+    let SyncFinalizationParams {
+        slot_name,
+        sync_token,
+    } = payload;
+
+    let mut slot = lock_slot_for_finalization(&state, &slot_name).await?;
+
+    prepare_commit(&state, &mut slot, &slot_name, &sync_token)
+        .await
+        .map(Json)
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SyncCommitParams {
+    slot_name: String,
+    sync_token: String,
+    commit_token: String,
+}
+
+// Apply a prepared synchronization
+// This is idempotent, so a failed commit can be retried (even after a restart of the server)
+pub async fn commit_sync(
+    State(state): State<HttpState>,
+    Extension(device): Extension<AuthenticatedDevice>,
+    Json(payload): Json<SyncCommitParams>,
+) -> HttpResult<Json<()>> {
+    let SyncCommitParams {
+        slot_name,
+        sync_token,
+        commit_token,
+    } = payload;
+
+    let mut slot = lock_slot_for_finalization(&state, &slot_name).await?;
+
+    commit(
+        &state,
+        &mut slot,
+        &device,
+        slot_name,
+        &sync_token,
+        &commit_token,
+    )
+    .await
+    .map(Json)
+}
+
+async fn lock_slot_for_finalization<'a>(
+    state: &'a HttpState,
+    slot_name: &str,
+) -> HttpResult<RwLockWriteGuard<'a, SlotSync>> {
+    Ok(state
+        .slots
+        .get(slot_name)
+        .context("Provided slot was not found")
+        .map_err(handle_err!(NOT_FOUND))?
+        // Getting an exclusive access right now is very important as it ensures that no
+        // other finalization process can happen simultaneously, which would be destructive
+        .write()
+        .await)
+}
+
+fn open_sync_with_token<'a>(
+    slot: &'a mut SlotSync,
+    sync_token: &str,
+) -> HttpResult<&'a mut OpenSync> {
+    if slot.open_syncs.is_empty() {
+        throw_err!(
+            NOT_FOUND,
+            "No synchronization is currently open for this slot"
+        );
+    }
+
+    slot.open_sync_by_token_mut(sync_token)
+        .context("Provided synchronization token does not match any open sync.")
+        .map_err(handle_err!(BAD_REQUEST))
+}
+
+async fn prepare_commit(
+    state: &HttpState,
+    slot: &mut SlotSync,
+    slot_name: &str,
+    sync_token: &str,
+) -> HttpResult<String> {
+    let slot_infos = slot.infos.clone();
+    let open_sync = open_sync_with_token(slot, sync_token)?;
+
+    // Preparing again must not validate the files again, as a commit may have already moved some of them
+    if let Some(commit_token) = &open_sync.commit_token {
+        return Ok(commit_token.clone());
+    }
+
+    if !is_sync_dir_intact(state, &slot_infos, open_sync) {
+        let sync_id = open_sync.id;
+        return Err(close_broken_sync(state, slot, sync_id).await);
+    }
+
+    // Each phase is logged as they may take a long time on large synchronizations
+    info!(
+        "Finalizing synchronization of slot '{slot_name}': checking {} transferred file(s)...",
+        open_sync.files.len()
+    );
+
+    for (relative_path, (id, _)) in &open_sync.files {
+        if !open_sync.is_completed(&state.paths, &slot_infos, id) {
+            throw_err!(
+                BAD_REQUEST,
+                format!("File '{relative_path}' has not been transferred yet!")
+            );
+        }
+    }
+
+    let commit_token = generate_id();
+
+    open_sync.commit_token = Some(commit_token.clone());
+
+    // Persisted so the commit can still happen after a restart
+    open_sync
+        .save(&state.paths.slot_open_sync_file(&slot_infos, open_sync.id))
+        .await
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    Ok(commit_token)
+}
+
+async fn commit(
+    state: &HttpState,
+    slot: &mut SlotSync,
+    device: &AuthenticatedDevice,
+    slot_name: String,
+    sync_token: &str,
+    commit_token: &str,
+) -> HttpResult<()> {
+    let slot_infos = slot.infos.clone();
+    let open_sync = open_sync_with_token(slot, sync_token)?;
+
+    match &open_sync.commit_token {
+        None => throw_err!(
+            CONFLICT,
+            "Synchronization must be prepared before being committed"
+        ),
+
+        Some(expected) if expected != commit_token => {
+            throw_err!(BAD_REQUEST, "Provided commit token is invalid")
+        }
+
+        Some(_) => {}
+    }
+
+    let sync_id = open_sync.id;
+
+    let snapshot_cache_file = state.paths.slot_snapshot_cache_file(&slot_infos);
+
+    let snapshot_cache = slot
+        .snapshot_cache
+        .take(&snapshot_cache_file)
+        .await
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    let open_sync = &slot.open_syncs[&sync_id];
+
+    let complete_dir = state.paths.slot_completion_dir(&slot_infos, sync_id);
+
+    let slot_files_dir = state.paths.slot_content_dir(&slot_infos);
+
+    let concurrency = state.backup_args.finalize_concurrency;
+
+    info!(
+        "Finalizing synchronization of slot '{slot_name}': creating {} directory(ies)...",
+        open_sync.diff_ops.create_dirs.len()
+    );
+
+    // Parents must be created before their children, so directories are created one depth level at a time
+    let mut dirs_by_depth = BTreeMap::<usize, Vec<PathBuf>>::new();
+
+    for relative_path in &open_sync.diff_ops.create_dirs {
+        dirs_by_depth
+            .entry(relative_path.matches('/').count())
+            .or_default()
+            .push(slot_files_dir.join(native_path(relative_path)));
+    }
+
+    for dirs in dirs_by_depth.into_values() {
+        run_concurrently(
+            concurrency,
+            dirs.into_iter()
+                .map(|path| async move {
+                    if path.is_dir() {
+                        return Ok(());
+                    }
+
+                    fs::create_dir(&path)
+                        .await
+                        .with_context(|| format!("Failed to create folder at '{}'", path.display()))
+                })
+                .collect(),
+        )
+        .await
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    info!(
+        "Finalizing synchronization of slot '{slot_name}': moving {} file(s) into place...",
+        open_sync.files.len()
+    );
+
+    let durable = state.backup_args.durable;
+
+    preserve_in_history(
+        state,
+        &slot_infos,
+        open_sync,
+        open_sync
+            .files
+            .keys()
+            .chain(
+                open_sync
+                    .diff_ops
+                    .create_hardlinks
+                    .iter()
+                    .map(|(path, _)| path),
+            )
+            .cloned()
+            .collect(),
+    )
+    .await?;
+
+    run_concurrently(
+        concurrency,
+        open_sync
+            .files
+            .iter()
+            .map(|(relative_path, (id, _))| {
+                let staged_path = open_sync.staged_path(&state.paths, &slot_infos, id);
+                let path = slot_files_dir.join(native_path(relative_path));
+                let relative_path = relative_path.clone();
+
+                async move {
+                    // All files were present when the commit was prepared, so it was moved by a previous attempt
+                    if !staged_path.is_file() {
+                        return Ok(());
+                    }
+
+                    move_file(&staged_path, &path, durable)
+                        .await
+                        .with_context(|| {
+                            format!("Failed to move transferred file to '{relative_path}'")
+                        })
+                }
+            })
+            .collect(),
+    )
+    .await
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    if !open_sync.diff_ops.create_hardlinks.is_empty() {
+        info!(
+            "Finalizing synchronization of slot '{slot_name}': creating {} hard link(s)...",
+            open_sync.diff_ops.create_hardlinks.len()
+        );
+    }
+
+    for (relative_path, target) in &open_sync.diff_ops.create_hardlinks {
+        create_hardlink(&slot_files_dir, relative_path, target)
+            .await
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    if !open_sync.diff_ops.touch_files.is_empty() {
+        info!(
+            "Finalizing synchronization of slot '{slot_name}': updating modification time of {} file(s)...",
+            open_sync.diff_ops.touch_files.len()
+        );
+    }
+
+    run_concurrently(
+        concurrency,
+        open_sync
+            .diff_ops
+            .touch_files
+            .iter()
+            .map(|(relative_path, mt)| {
+                let state = state.clone();
+                let slot_files_dir = slot_files_dir.clone();
+                let relative_path = relative_path.clone();
+                let mt = *mt;
+
+                async move { touch_file(&state, &slot_files_dir, &relative_path, mt).await }
+            })
+            .collect(),
+    )
+    .await
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    if !open_sync.diff_ops.set_acls.is_empty() {
+        info!(
+            "Finalizing synchronization of slot '{slot_name}': setting the ACL of {} item(s)...",
+            open_sync.diff_ops.set_acls.len()
+        );
+    }
+
+    // The content is already in place at this point, so failing to set an ACL is not fatal
+    for (relative_path, acl) in &open_sync.diff_ops.set_acls {
+        if let Err(err) = write_acl(&slot_files_dir.join(native_path(relative_path)), acl) {
+            warn!("Failed to set the ACL of '{relative_path}' in slot '{slot_name}': {err:?}");
+        }
+    }
+
+    if !open_sync.diff_ops.set_xattrs.is_empty() {
+        info!(
+            "Finalizing synchronization of slot '{slot_name}': setting the extended attributes of {} item(s)...",
+            open_sync.diff_ops.set_xattrs.len()
+        );
+    }
+
+    // Some attributes can only be set by privileged users (e.g. SELinux labels), which mustn't fail the finalization either
+    for (relative_path, xattrs) in &open_sync.diff_ops.set_xattrs {
+        if let Err(err) = write_xattrs(&slot_files_dir.join(native_path(relative_path)), xattrs) {
+            warn!("Failed to set the extended attributes of '{relative_path}' in slot '{slot_name}': {err:?}");
+        }
+    }
+
+    // Ensure all files moved to their destination are persisted before reporting success
+    if state.backup_args.durable {
+        let mut dirs = open_sync
+            .files
+            .keys()
+            .chain(open_sync.diff_ops.create_dirs.iter())
+            .chain(
+                open_sync
+                    .diff_ops
+                    .create_hardlinks
+                    .iter()
+                    .map(|(path, _)| path),
+            )
+            .chain(open_sync.diff_ops.delete_files.iter())
+            .chain(open_sync.diff_ops.delete_empty_dirs.iter())
+            .filter_map(|path| Path::new(path).parent())
+            .collect::<Vec<_>>();
+
+        dirs.sort();
+        dirs.dedup();
+
+        info!(
+            "Finalizing synchronization of slot '{slot_name}': flushing {} directory(ies) to the disk...",
+            dirs.len()
+        );
+
+        run_concurrently(
+            concurrency,
+            dirs.into_iter()
+                .map(|dir| {
+                    let path = slot_files_dir.join(dir);
+                    async move { sync_dir(&path).await }
+                })
+                .collect(),
+        )
+        .await
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    // Replaced files may have the same size and modification time as the previous ones
+    let hash_cache_file = state.paths.slot_hash_cache_file(&slot_infos);
+
+    if hash_cache_file.is_file() {
+        let changed = open_sync
+            .touched_paths()
+            .into_iter()
+            .map(str::to_owned)
+            .collect::<Vec<_>>();
+
+        let result = tokio::task::spawn_blocking(move || {
+            let mut hash_cache = HashCache::load(&hash_cache_file)?;
+            hash_cache.invalidate(&changed);
+            hash_cache.save(&hash_cache_file)
+        })
+        .await
+        .context("Failed to run the hash cache invalidation");
+
+        if let Err(err) = result.and_then(|result| result) {
+            error!("Failed to invalidate the hash cache, removing it: {err:?}");
+
+            fs::remove_file(state.paths.slot_hash_cache_file(&slot_infos))
+                .await
+                .context("Failed to remove the hash cache")
+                .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+        }
+    }
+
+    update_snapshot_cache(
+        &mut slot.snapshot_cache,
+        &snapshot_cache_file,
+        &slot_name,
+        snapshot_cache,
+        |cached| cached.apply_commit(&open_sync.diff_ops),
+    )
+    .await;
+
+    info!("Finalizing synchronization of slot '{slot_name}': cleaning up...");
+
+    // Items may have been removed by a previous attempt
+    let pending_dir = state.paths.slot_pending_dir(&slot_infos, open_sync.id);
+
+    if pending_dir.exists() {
+        fs::remove_dir(pending_dir)
+            .await
+            .context("Failed to remove the pending transfers directory")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    if complete_dir.exists() {
+        fs::remove_dir(&complete_dir)
+            .await
+            .context("Failed to remove the complete transfers directory")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    let journal_file = state
+        .paths
+        .slot_completion_journal_file(&slot_infos, sync_id);
+
+    if journal_file.exists() {
+        fs::remove_file(journal_file)
+            .await
+            .context("Failed to remove the completion journal")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    let open_sync_file = state.paths.slot_open_sync_file(&slot_infos, open_sync.id);
+
+    if open_sync_file.exists() {
+        fs::remove_file(open_sync_file)
+            .await
+            .context("Failed to remove the synchronization's state")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    fs::remove_dir(state.paths.slot_transfer_dir(&slot_infos, open_sync.id))
+        .await
+        .context("Failed to remove the slot directory")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    let payload = FinalizeHookPayload {
+        slot_name,
+        sync_id: open_sync.id.to_string(),
+        files_changed: open_sync.diff.added.len()
+            + open_sync.diff.modified.len()
+            + open_sync.diff.type_changed.len()
+            + open_sync.diff.deleted.len(),
+        bytes_transferred: open_sync
+            .diff_ops
+            .send_files
+            .iter()
+            .map(|(_, mt)| mt.size)
+            .sum(),
+    };
+
+    let audit_record = AuditRecord::new(
+        &device.device_name,
+        &payload.slot_name,
+        AuditOperation::FinalizeSync,
+        &open_sync.diff,
+        payload.bytes_transferred,
+    );
+
+    if let Err(err) = audit_record
+        .append_to(&state.paths.slot_audit_log_file(&slot_infos))
+        .await
+    {
+        error!("Failed to write audit record: {err:?}");
+    }
+
+    slot.open_syncs.remove(&sync_id);
+
+    info!(
+        "Synchronization of slot '{}' was finalized",
+        payload.slot_name
+    );
+
+    if let Some(keep) = read_slot_settings(state, &slot_infos)
+        .await?
+        .history(state.backup_args.history)
+    {
+        let history_dir = state.paths.slot_history_dir(&slot_infos);
+
+        let result = tokio::task::spawn_blocking(move || prune_history(&history_dir, keep))
+            .await
+            .context("Failed to run the history pruning");
+
+        match result.and_then(|result| result) {
+            Ok(0) => {}
+            Ok(removed) => info!(
+                "Removed {removed} old history point(s) of slot '{}'",
+                payload.slot_name
+            ),
+            Err(err) => error!(
+                "Failed to remove old history points of slot '{}': {err:?}",
+                payload.slot_name
+            ),
+        }
+    }
+
+    // Pruned history points may have been the last references to some objects
+    if state.backup_args.dedup {
+        collect_unused_objects(state);
+    }
+
+    trigger_finalize_hooks(&state.backup_args, payload);
+
+    Ok(())
+}
+
+// Keep the current version of items before they are deleted or replaced, if the slot has history enabled
+async fn preserve_in_history(
+    state: &HttpState,
+    slot_infos: &SlotInfos,
+    open_sync: &OpenSync,
+    relative_paths: Vec<String>,
+) -> HttpResult<()> {
+    if relative_paths.is_empty() {
+        return Ok(());
+    }
+
+    let settings = read_slot_settings(state, slot_infos).await?;
+
+    if settings.history(state.backup_args.history).is_none() {
+        return Ok(());
+    }
+
+    let content_dir = state.paths.slot_content_dir(slot_infos);
+
+    let point_dir = state
+        .paths
+        .slot_history_dir(slot_infos)
+        .join(history_point_name(open_sync.opened_at));
+
+    tokio::task::spawn_blocking(move || {
+        preserve_items(
+            &content_dir,
+            &point_dir,
+            relative_paths.iter().map(String::as_str),
+        )
+    })
+    .await
+    .context("Failed to run the history preservation")
+    .and_then(|result| result)
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))
+}
+
+// Objects are collected in the background, as it requires going through the whole pool
+fn collect_unused_objects(state: &HttpState) {
+    let objects_dir = state.paths.objects_dir();
+
+    tokio::task::spawn_blocking(move || match collect_garbage(&objects_dir) {
+        Ok(0) => {}
+        Ok(removed) => info!("Removed {removed} unused object(s) from the pool"),
+        Err(err) => error!("Failed to remove unused objects: {err:?}"),
+    });
+}
+
+// Run independent operations with at most `concurrency` of them at once, stopping at the first error
+async fn run_concurrently(
+    concurrency: usize,
+    tasks: Vec<impl Future<Output = anyhow::Result<()>> + Send + 'static>,
+) -> anyhow::Result<()> {
+    let mut task_pool = JoinSet::new();
+
+    for task in tasks {
+        while task_pool.len() >= concurrency {
+            task_pool
+                .join_next()
+                .await
+                .unwrap()
+                .context("Filesystem operation panicked")??;
+        }
+
+        task_pool.spawn(task);
+    }
+
+    while let Some(result) = task_pool.join_next().await {
+        result.context("Filesystem operation panicked")??;
+    }
+
+    Ok(())
+}
+
+async fn sync_dir(path: &Path) -> anyhow::Result<()> {
+    // Directories can't be opened as files on Windows, where metadata changes are journaled anyway
+    if cfg!(unix) {
+        File::open(path)
+            .await
+            .with_context(|| format!("Failed to open directory '{}'", path.display()))?
+            .sync_all()
+            .await
+            .with_context(|| {
+                format!("Failed to flush directory '{}' to the disk", path.display())
+            })?;
+    }
+
+    Ok(())
+}
+
+async fn create_hardlink(
+    slot_files_dir: &Path,
+    relative_path: &str,
+    target: &str,
+) -> anyhow::Result<()> {
+    let path = slot_files_dir.join(native_path(relative_path));
+    let target = slot_files_dir.join(native_path(target));
+
+    // Replace the previous version of the file
+    if path.is_file() {
+        fs::remove_file(&path)
+            .await
+            .with_context(|| format!("Failed to remove file at '{relative_path}'"))?;
+    }
+
+    let Err(err) = fs::hard_link(&target, &path).await else {
+        return Ok(());
+    };
+
+    // Some filesystems don't support hard links, so we fall back to a plain copy
+    warn!("Failed to create hard link at '{relative_path}', copying the file instead: {err}");
+
+    fs::copy(&target, &path)
+        .await
+        .with_context(|| format!("Failed to copy file to '{relative_path}'"))?;
+
+    let mtime = fs::metadata(&target)
+        .await
+        .with_context(|| format!("Failed to get metadata of file '{}'", target.display()))?
+        .modified()
+        .context("Failed to get modification time")?;
+
+    tokio::task::spawn_blocking(move || {
+        filetime::set_file_mtime(path, FileTime::from_system_time(mtime))
+            .context("Failed to set modification time")
+    })
+    .await
+    .context("Failed to run modification time setter")?
+}
+
+async fn touch_file(
+    state: &HttpState,
+    slot_files_dir: &Path,
+    relative_path: &str,
+    mt: SnapshotFileMetadata,
+) -> anyhow::Result<()> {
+    let path = slot_files_dir.join(native_path(relative_path));
+    let relative_path = relative_path.to_owned();
+
+    let SnapshotFileMetadata {
+        last_modif_date_s,
+        last_modif_date_ns,
+        size: _,
+    } = mt;
+
+    let mtime = FileTime::from_unix_time(last_modif_date_s as i64, last_modif_date_ns);
+
+    let dedup = state.backup_args.dedup;
+    let objects_dir = state.paths.objects_dir();
+
+    tokio::task::spawn_blocking(move || {
+        if !dedup {
+            return filetime::set_file_mtime(&path, mtime)
+                .with_context(|| format!("Failed to set modification time of '{relative_path}'"));
+        }
+
+        // Deduplicated files share their modification time with every other link to the same object,
+        // so the file is detached from its object first
+        let tmp_path = path.with_file_name(format!(
+            "{HARMONY_ITEMS_PREFIX}touch-{}",
+            path.file_name().unwrap().to_string_lossy()
+        ));
+
+        std::fs::copy(&path, &tmp_path)
+            .with_context(|| format!("Failed to copy file '{relative_path}'"))?;
+
+        filetime::set_file_mtime(&tmp_path, mtime)
+            .with_context(|| format!("Failed to set modification time of '{relative_path}'"))?;
+
+        std::fs::rename(&tmp_path, &path)
+            .with_context(|| format!("Failed to replace file '{relative_path}'"))?;
+
+        if let Err(err) =
+            deduplicate_file(&objects_dir, &path, (last_modif_date_s, last_modif_date_ns))
+        {
+            warn!("Failed to deduplicate file '{relative_path}': {err:?}");
+        }
+
+        Ok(())
+    })
+    .await
+    .context("Failed to run modification time setter")?
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SendFileParams {
+    slot_name: String,
+    sync_token: String,
+    path: String,
+}
+
+struct PendingTransfer {
+    tmp_path: PathBuf,
+    file_id: String,
+    // Set if the file was already fully transferred
+    completed: bool,
+    sync_id: SyncId,
+    metadata: SnapshotFileMetadata,
+    slot_infos: SlotInfos,
+    delta_eligible: bool,
+}
+
+async fn prepare_transfer(
+    state: &HttpState,
+    slot_name: &str,
+    sync_token: &str,
+    path: &str,
+) -> HttpResult<PendingTransfer> {
+    let slot = state
+        .slots
+        .get(slot_name)
+        .context("Provided slot was not found")
+        .map_err(handle_err!(NOT_FOUND))?
+        .read()
+        .await;
+
+    if slot.open_syncs.is_empty() {
+        throw_err!(
+            NOT_FOUND,
+            "No synchronization is currently open for this slot"
+        );
+    }
+
+    let open_sync = slot
+        .open_sync_by_token(sync_token)
+        .context("Provided synchronization token does not match any open sync.")
+        .map_err(handle_err!(BAD_REQUEST))?;
+
+    if open_sync.commit_token.is_some() {
+        throw_err!(
+            CONFLICT,
+            "Synchronization was prepared for commit, no more file can be transferred"
+        );
+    }
+
+    if !is_sync_dir_intact(state, &slot.infos, open_sync) {
+        let sync_id = open_sync.id;
+        drop(slot);
+
+        let mut slot = state.slots.get(slot_name).unwrap().write().await;
+        return Err(close_broken_sync(state, &mut slot, sync_id).await);
+    }
+
+    open_sync.touch();
+
+    let (file_id, metadata) = open_sync
+        .files
+        .get(path)
+        .ok_or("Provided file was not found in the current synchronization process")
+        .map_err(handle_err!(BAD_REQUEST))?;
+
+    let tmp_path = state
+        .paths
+        .slot_pending_dir(&slot.infos, open_sync.id)
+        .join(file_id);
+
+    Ok(PendingTransfer {
+        tmp_path,
+        file_id: file_id.clone(),
+        completed: open_sync.is_completed(&state.paths, &slot.infos, file_id),
+        sync_id: open_sync.id,
+        metadata: *metadata,
+        slot_infos: slot.infos.clone(),
+        delta_eligible: open_sync.delta_files.contains(path),
+    })
+}
+
+async fn complete_transfer(
+    state: &HttpState,
+    transfer: PendingTransfer,
+    path: &str,
+) -> HttpResult<()> {
+    let PendingTransfer {
+        tmp_path,
+        file_id,
+        completed: _,
+        sync_id,
+        metadata,
+        slot_infos,
+        delta_eligible: _,
+    } = transfer;
+
+    let SnapshotFileMetadata {
+        last_modif_date_s,
+        last_modif_date_ns,
+        size: _,
+    } = metadata;
+
+    let tmp_path_bis = tmp_path.clone();
+
+    tokio::task::spawn_blocking(move || {
+        filetime::set_file_mtime(
+            tmp_path_bis,
+            FileTime::from_unix_time(last_modif_date_s as i64, last_modif_date_ns),
+        )
+        .context("Failed to set modification time")
+    })
+    .await
+    .context("Failed to run modification time setter")
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+    .context("Failed to run modification time setter")
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    if state.backup_args.durable {
+        File::open(&tmp_path)
+            .await
+            .context("Failed to open transferred file")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+            .sync_all()
+            .await
+            .context("Failed to flush transferred file to the disk")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    // Deduplication is only an optimization, so the transfer doesn't fail if it can't be performed
+    if state.backup_args.dedup {
+        let objects_dir = state.paths.objects_dir();
+        let tmp_path = tmp_path.clone();
+
+        let result = tokio::task::spawn_blocking(move || {
+            deduplicate_file(
+                &objects_dir,
+                &tmp_path,
+                (last_modif_date_s, last_modif_date_ns),
+            )
+        })
+        .await
+        .context("Failed to run deduplication")
+        .and_then(|result| result);
+
+        if let Err(err) = result {
+            warn!("Failed to deduplicate file '{path}': {err:?}");
+        }
+    }
+
+    // Stage the file until the synchronization is finalized
+
+    let slot = state.slots.get(slot_infos.name()).unwrap().read().await;
+
+    let open_sync = slot
+        .open_syncs
+        .get(&sync_id)
+        .context("Synchronization was closed during the transfer")
+        .map_err(handle_err!(NOT_FOUND))?;
+
+    open_sync
+        .mark_completed(
+            &state.paths,
+            &slot_infos,
+            &file_id,
+            state.backup_args.durable,
+        )
+        .await
+        .with_context(|| format!("Failed to mark file '{path}' as transferred"))
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    // Mark the synchronization as active, as the transfer may have taken a long time
+    open_sync.touch();
+
+    Ok(())
+}
+
+// Write a request's body to the provided path, rejecting it if it goes over the provided size
+// When `framed` is set, the body is made of CRC-checked frames (see `harmony_differ::framing`)
+// Writes go through a buffer of the provided size, so small chunks don't each require a system call
+async fn write_body_to(
+    mut stream: BodyStream,
+    path: &Path,
+    max_size: u64,
+    framed: bool,
+    sparse: bool,
+    buffer_size: usize,
+) -> HttpResult<u64> {
+    if path.is_file() {
+        fs::remove_file(path)
+            .await
+            .context("Temporary file already exists but it could not be deleted")
+            .map_err(handle_err!(BAD_REQUEST))?;
+    }
+
+    let file = File::create(path)
+        .await
+        .context("Failed to create a temporary file")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    let mut file = tokio::io::BufWriter::with_capacity(buffer_size, file);
+
+    let mut written = 0;
+    let mut decoder = framed.then(FrameDecoder::default);
+    let mut sparse_decoder = sparse.then(SparseDecoder::default);
+
+    // Reject the transfer as soon as possible to avoid filling the disk with unexpected content
+    macro_rules! reject {
+        ($message: expr) => {{
+            drop(file);
+
+            if let Err(err) = fs::remove_file(path).await {
+                error!(
+                    "Failed to remove rejected temporary file at '{}': {err}",
+                    path.display()
+                );
+            }
+
+            throw_err!(BAD_REQUEST, $message);
+        }};
+    }
+
+    macro_rules! write_data {
+        ($data: expr) => {{
+            written += u64::try_from($data.len()).unwrap();
+
+            if written > max_size {
+                reject!("Transmitted content is larger than the provided size");
+            }
+
+            file.write_all($data)
+                .await
+                .context("Failed to write to temporary file")
+                .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+        }};
+    }
+
+    // Holes are skipped instead of being written, so they don't take any space on disk
+    macro_rules! skip_hole {
+        ($len: expr) => {{
+            written += $len;
+
+            if written > max_size {
+                reject!("Transmitted content is larger than the provided size");
+            }
+
+            file.seek(SeekFrom::Current(i64::try_from($len).unwrap()))
+                .await
+                .context("Failed to skip a hole in temporary file")
+                .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+        }};
+    }
+
+    macro_rules! write_payload {
+        ($payload: expr) => {{
+            match &mut sparse_decoder {
+                None => write_data!($payload),
+
+                Some(sparse_decoder) => match sparse_decoder.push($payload) {
+                    Ok(segments) => {
+                        for segment in segments {
+                            match segment {
+                                SparseSegment::Data(data) => write_data!(&data),
+                                SparseSegment::Hole(len) => skip_hole!(len),
+                            }
+                        }
+                    }
+
+                    Err(err) => reject!(format!("{err:#}")),
+                },
+            }
+        }};
+    }
+
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+        match &mut decoder {
+            None => write_payload!(&chunk),
+
+            Some(decoder) => match decoder.push(&chunk) {
+                Ok(payloads) => {
+                    for payload in payloads {
+                        write_payload!(&payload);
+                    }
+                }
+
+                Err(err) => reject!(format!("{err:#}")),
+            },
+        }
+    }
+
+    if let Some(decoder) = decoder {
+        if let Err(err) = decoder.finish() {
+            reject!(format!("{err:#}"));
+        }
+    }
+
+    // The file is used right after, so all the buffered data must be written to it
+    file.flush()
+        .await
+        .context("Failed to write to temporary file")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    if let Some(sparse_decoder) = sparse_decoder {
+        if let Err(err) = sparse_decoder.finish() {
+            reject!(format!("{err:#}"));
+        }
+
+        // Trailing holes must still be part of the file
+        file.get_ref()
+            .set_len(written)
+            .await
+            .context("Failed to set temporary file's length")
+            .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+    }
+
+    Ok(written)
+}
+
+pub async fn send_file(
+    Query(params): Query<SendFileParams>,
+    State(state): State<HttpState>,
+    Extension(device): Extension<AuthenticatedDevice>,
+    headers: HeaderMap,
+    stream: BodyStream,
+) -> HttpResult<Json<()>> {
+    let SendFileParams {
+        slot_name,
+        sync_token,
+        path,
+    } = params;
+
+    let framed = headers.contains_key(CRC_FRAMING_HEADER);
+    let sparse = headers.contains_key(SPARSE_ENCODING_HEADER);
+
+    debug!(
+        "Device '{}' is sending file '{path}' to slot '{slot_name}'",
+        device.device_name
+    );
+
+    // This contains quick, locking computing
+    // After this we can do the actual transfer without worrying about locking a concurrent request
+    let transfer = prepare_transfer(&state, &slot_name, &sync_token, &path).await?;
+
+    // Re-uploads are idempotent (e.g. when the response to a previous attempt was lost)
+    if transfer.completed {
+        debug!("File '{path}' was already transferred, ignoring re-upload");
+        return Ok(Json(()));
+    }
+
+    let size = transfer.metadata.size;
+
+    let written = write_body_to(
+        stream,
+        &transfer.tmp_path,
+        size,
+        framed,
+        sparse,
+        state.backup_args.write_buffer_size,
+    )
+    .await?;
+
+    if written != size {
+        throw_err!(
+            BAD_REQUEST,
+            "Provided size does not match transmitted content"
+        );
+    }
+
+    complete_transfer(&state, transfer, &path).await?;
+
+    Ok(Json(()))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PresentFilesParams {
+    slot_name: String,
+    sync_token: String,
+    // Content hash of the files to check, by transfer ID
+    files: HashMap<String, String>,
+}
+
+// Find the files of a synchronization the server already has, so clients don't transfer them again:
+// the ones already marked as transferred, the ones fully written by a previous attempt which failed
+// before marking them, and (with deduplication) the ones whose content was already uploaded before
+// Files found this way are marked as transferred, and their transfer IDs are returned
+pub async fn present_files(
+    State(state): State<HttpState>,
+    Extension(device): Extension<AuthenticatedDevice>,
+    Json(payload): Json<PresentFilesParams>,
+) -> HttpResult<Json<Vec<String>>> {
+    let PresentFilesParams {
+        slot_name,
+        sync_token,
+        files,
+    } = payload;
+
+    debug!(
+        "Device '{}' is checking which of {} file(s) are already present in slot '{slot_name}'",
+        device.device_name,
+        files.len()
+    );
+
+    for hash in files.values() {
+        // Hashes are used to build the path of deduplicated objects
+        if hash.len() != 64 || !hash.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+            throw_err!(BAD_REQUEST, format!("Invalid content hash: {hash}"));
+        }
+    }
+
+    let paths_by_id = {
+        let slot = state
+            .slots
+            .get(&slot_name)
+            .context("Provided slot was not found")
+            .map_err(handle_err!(NOT_FOUND))?
+            .read()
+            .await;
+
+        let open_sync = slot
+            .open_sync_by_token(&sync_token)
+            .context("Provided synchronization token does not match any open sync.")
+            .map_err(handle_err!(BAD_REQUEST))?;
+
+        open_sync
+            .files
+            .iter()
+            .filter(|(_, (id, _))| files.contains_key(id))
+            .map(|(relative_path, (id, _))| (id.clone(), relative_path.clone()))
+            .collect::<HashMap<_, _>>()
+    };
+
+    if let Some(id) = files.keys().find(|id| !paths_by_id.contains_key(*id)) {
+        throw_err!(
+            BAD_REQUEST,
+            format!("File '{id}' was not found in the current synchronization process")
+        );
+    }
+
+    let mut present = vec![];
+
+    for (id, hash) in files {
+        let path = &paths_by_id[&id];
+
+        let transfer = prepare_transfer(&state, &slot_name, &sync_token, path).await?;
+
+        if transfer.completed {
+            present.push(id);
+            continue;
+        }
+
+        let tmp_path = transfer.tmp_path.clone();
+        let metadata = transfer.metadata;
+        let object = state.backup_args.dedup.then(|| {
+            object_path(
+                &state.paths.objects_dir(),
+                &hash,
+                (metadata.last_modif_date_s, metadata.last_modif_date_ns),
+            )
+        });
+
+        let found = tokio::task::spawn_blocking(move || {
+            if tmp_path
+                .metadata()
+                .is_ok_and(|mt| mt.len() == metadata.size)
+                && content_hash(&tmp_path)? == hash
+            {
+                return Ok(true);
+            }
+
+            let Some(object) = object else {
+                return Ok(false);
+            };
+
+            if !object.metadata().is_ok_and(|mt| mt.len() == metadata.size) {
+                return Ok(false);
+            }
+
+            if tmp_path.exists() {
+                std::fs::remove_file(&tmp_path).context("Failed to remove pending file")?;
+            }
+
+            match std::fs::hard_link(&object, &tmp_path) {
+                Ok(()) => Ok(true),
+
+                // The object was collected in the meantime
+                Err(err) if err.kind() == ErrorKind::NotFound => Ok(false),
+
+                Err(err) => Err(err).context("Failed to link the existing object"),
+            }
+        })
+        .await
+        .context("Failed to run the presence check")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+        .with_context(|| format!("Failed to check if file '{path}' is present"))
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+        if found {
+            complete_transfer(&state, transfer, path).await?;
+            present.push(id);
+        }
+    }
+
+    Ok(Json(present))
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileSignatureParams {
+    slot_name: String,
+    sync_token: String,
+    path: String,
+}
+
+pub async fn file_signature(
+    State(state): State<HttpState>,
+    Json(payload): Json<FileSignatureParams>,
+) -> HttpResult<Json<Option<FileSignature>>> {
+    let FileSignatureParams {
+        slot_name,
+        sync_token,
+        path,
+    } = payload;
+
+    let transfer = prepare_transfer(&state, &slot_name, &sync_token, &path).await?;
+
+    if !transfer.delta_eligible {
+        return Ok(Json(None));
+    }
+
+    let prev_path = state
+        .paths
+        .slot_content_dir(&transfer.slot_infos)
+        .join(native_path(&path));
+
+    // Fall back to a full transfer if there is no previous version of the file
+    if !prev_path.is_file() {
+        return Ok(Json(None));
+    }
+
+    tokio::task::spawn_blocking(move || {
+        let file = std::fs::File::open(&prev_path).context("Failed to open file")?;
+        let size = file
+            .metadata()
+            .context("Failed to get file's metadata")?
+            .len();
+
+        compute_signature(file, size)
+    })
+    .await
+    .context("Failed to run signature computation")
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+    .context("Failed to compute file's signature")
+    .map(|signature| Json(Some(signature)))
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))
+}
+
+pub async fn send_file_delta(
+    Query(params): Query<SendFileParams>,
+    State(state): State<HttpState>,
+    Extension(device): Extension<AuthenticatedDevice>,
+    stream: BodyStream,
+) -> HttpResult<Json<()>> {
+    let SendFileParams {
+        slot_name,
+        sync_token,
+        path,
+    } = params;
+
+    debug!(
+        "Device '{}' is sending a delta of file '{path}' to slot '{slot_name}'",
+        device.device_name
+    );
+
+    let transfer = prepare_transfer(&state, &slot_name, &sync_token, &path).await?;
+
+    if !transfer.delta_eligible {
+        throw_err!(
+            BAD_REQUEST,
+            "Provided file cannot be transferred using a delta"
+        );
+    }
+
+    if transfer.completed {
+        debug!("File '{path}' was already transferred, ignoring re-upload");
+        return Ok(Json(()));
+    }
+
+    let size = transfer.metadata.size;
+
+    let delta_path = transfer.tmp_path.with_extension("delta");
+
+    // A delta is only useful if it's smaller than the file itself
+    write_body_to(
+        stream,
+        &delta_path,
+        size,
+        false,
+        false,
+        state.backup_args.write_buffer_size,
+    )
+    .await?;
+
+    let prev_path = state
+        .paths
+        .slot_content_dir(&transfer.slot_infos)
+        .join(native_path(&path));
+
+    let tmp_path = transfer.tmp_path.clone();
+    let delta_path_bis = delta_path.clone();
+
+    let written = tokio::task::spawn_blocking(move || {
+        let prev = std::fs::File::open(&prev_path)
+            .context("Failed to open previous version of the file")?;
+
+        let delta = std::fs::File::open(&delta_path_bis).context("Failed to open delta")?;
+
+        let tmp_file =
+            std::fs::File::create(&tmp_path).context("Failed to create a temporary file")?;
+
+        let mut writer = BufWriter::new(tmp_file);
+
+        let written = apply_delta(prev, delta, &mut writer)?;
+
+        writer
+            .flush()
+            .context("Failed to write to temporary file")?;
+
+        Ok::<_, anyhow::Error>(written)
+    })
+    .await
+    .context("Failed to run delta application")
+    .map_err(handle_err!(INTERNAL_SERVER_ERROR))?
+    .context("Failed to apply delta")
+    .map_err(handle_err!(BAD_REQUEST))?;
+
+    fs::remove_file(&delta_path)
+        .await
+        .context("Failed to remove delta file")
+        .map_err(handle_err!(INTERNAL_SERVER_ERROR))?;
+
+    if written != size {
+        throw_err!(
+            BAD_REQUEST,
+            "Provided size does not match reconstructed content"
+        );
+    }
+
+    complete_transfer(&state, transfer, &path).await?;
 
     Ok(Json(()))
 }
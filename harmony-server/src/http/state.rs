@@ -1,19 +1,32 @@
+use anyhow::{bail, Context, Result};
 use harmony_differ::{
     diffing::{Diff, DiffApplyOps},
     snapshot::SnapshotFileMetadata,
 };
+use log::{info, warn};
 use rand::{thread_rng, Rng};
-use std::{collections::HashMap, path::Path, sync::Arc};
-use tokio::sync::RwLock;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::{OwnedSemaphorePermit, RwLock, Semaphore},
+};
 
 use crate::{
     cmd::BackupArgs,
     data::{generate_id, AppData},
-    paths::{is_relative_linear_path, Paths, SlotInfos, SyncId},
+    paths::{is_relative_linear_path, CompletionTracking, Paths, SlotInfos, SyncId},
+    snapshot_cache::SlotSnapshotCache,
     throw_err,
 };
 
-use super::errors::HttpResult;
+use super::{auth::AuthenticatedDevice, errors::HttpResult};
 
 #[derive(Clone)]
 pub struct HttpState {
@@ -30,10 +43,21 @@ pub struct HttpState {
     // This allows to access multiple slots in writing mode at the same time, without compromising
     // on safety nor performance (as there is only one locking process overall).
     pub slots: Arc<HashMap<String, RwLock<SlotSync>>>,
+
+    // Limits the number of synchronizations open at the same time across all slots
+    pub open_syncs_limit: Option<Arc<Semaphore>>,
+
+    // Challenges sent to devices authenticating with their key, which can only be answered once
+    pub device_challenges: Arc<Mutex<HashMap<String, DeviceChallenge>>>,
 }
 
 impl HttpState {
-    pub fn new(args: BackupArgs, app_data: AppData, paths: Paths) -> Self {
+    pub fn new(
+        args: BackupArgs,
+        app_data: AppData,
+        paths: Paths,
+        max_open_syncs: Option<usize>,
+    ) -> Self {
         Self {
             slots: Arc::new(
                 args.slots
@@ -50,58 +74,561 @@ impl HttpState {
             backup_args: Arc::new(args),
             paths: Arc::new(paths),
             app_data: Arc::new(RwLock::new(app_data)),
+            open_syncs_limit: max_open_syncs.map(|max| Arc::new(Semaphore::new(max))),
+            device_challenges: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    // Get a permit to open a synchronization
+    // Returns `None` if the limit is reached, and `Some(None)` if there is no limit at all
+    pub fn try_acquire_open_sync_permit(&self) -> Option<Option<OwnedSemaphorePermit>> {
+        match &self.open_syncs_limit {
+            None => Some(None),
+            Some(limit) => Arc::clone(limit).try_acquire_owned().ok().map(Some),
         }
     }
 }
 
+pub struct DeviceChallenge {
+    pub public_key: String,
+    pub issued_at: Instant,
+}
+
+// Devices must answer challenges quickly, so unanswered ones don't pile up
+pub const DEVICE_CHALLENGE_VALIDITY: Duration = Duration::from_secs(60);
+
 pub struct SlotSync {
     pub infos: SlotInfos,
-    pub open_sync: Option<OpenSync>,
+    // Multiple synchronizations may be open at the same time, as long as they don't touch the same items
+    pub open_syncs: HashMap<SyncId, OpenSync>,
+    // Detected when the server starts
+    pub case_insensitive: bool,
+    // Only filled when snapshots are cached
+    pub snapshot_cache: SlotSnapshotCache,
 }
 
 impl SlotSync {
     fn new(infos: SlotInfos) -> Self {
         Self {
             infos,
-            open_sync: None,
+            open_syncs: HashMap::new(),
+            case_insensitive: false,
+            snapshot_cache: SlotSnapshotCache::default(),
         }
     }
+
+    // Restore the synchronizations which were open when the server was last stopped
+    pub async fn restore_open_syncs(&mut self, state: &HttpState) -> Result<()> {
+        let paths = &state.paths;
+
+        let mut entries = fs::read_dir(paths.slot_transfers_root_dir(&self.infos))
+            .await
+            .context("Failed to read the slot's transfers directory")?;
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context("Failed to read the slot's transfers directory")?
+        {
+            if !entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("open-sync-")
+            {
+                continue;
+            }
+
+            let open_sync_file = entry.path().join("sync.json");
+
+            if !open_sync_file.is_file() {
+                warn!(
+                    "Ignoring synchronization directory '{}' as it contains no synchronization state",
+                    entry.path().display()
+                );
+
+                continue;
+            }
+
+            // Restored synchronizations must be kept even if they exceed the limit
+            let permit = state.try_acquire_open_sync_permit().flatten();
+
+            let open_sync = OpenSync::load(&open_sync_file, paths, &self.infos, permit).await?;
+
+            if self.open_syncs.contains_key(&open_sync.id) {
+                bail!(
+                    "Found multiple states for synchronization {} of slot '{}'",
+                    open_sync.id,
+                    self.infos.name()
+                );
+            }
+
+            info!(
+                "Restored synchronization {} of slot '{}' opened by device '{}'",
+                open_sync.id,
+                self.infos.name(),
+                open_sync.opened_by.device_name
+            );
+
+            self.open_syncs.insert(open_sync.id, open_sync);
+        }
+
+        Ok(())
+    }
+
+    pub fn open_sync_by_token(&self, sync_token: &str) -> Option<&OpenSync> {
+        self.open_syncs
+            .values()
+            .find(|open_sync| open_sync.token == sync_token)
+    }
+
+    pub fn open_sync_by_token_mut(&mut self, sync_token: &str) -> Option<&mut OpenSync> {
+        self.open_syncs
+            .values_mut()
+            .find(|open_sync| open_sync.token == sync_token)
+    }
+
+    // Find an open synchronization touching the same items as the provided one
+    // Two paths overlap if they are the same or if one of them is an ancestor of the other, so the provided
+    // synchronization's paths and their ancestors are indexed to check each other path in a time proportional
+    // to its depth (this runs with the slot locked, so it must scale with large synchronizations)
+    pub fn find_overlapping_sync(&self, open_sync: &OpenSync) -> Option<(&OpenSync, String)> {
+        let paths = open_sync.touched_paths();
+
+        let touched = paths.iter().map(Path::new).collect::<HashSet<_>>();
+
+        let ancestors = paths
+            .iter()
+            .flat_map(|path| Path::new(path).ancestors().skip(1))
+            .collect::<HashSet<_>>();
+
+        self.open_syncs.values().find_map(|other| {
+            other
+                .touched_paths()
+                .into_iter()
+                .find(|other_path| {
+                    let other_path = Path::new(other_path);
+
+                    ancestors.contains(other_path)
+                        || other_path
+                            .ancestors()
+                            .any(|ancestor| touched.contains(ancestor))
+                })
+                .map(|path| (other, path.to_owned()))
+        })
+    }
 }
 
+// Lifecycle of a synchronization:
+//
+// 1. `begin_sync` deletes the removed items, moves the renamed directories and creates the
+//    synchronization's directories
+// 2. Each file is first written to the "pending" directory, then marked as transferred once fully
+//    received, depending on the synchronization's completion tracking (see `CompletionTracking`):
+//    either by moving it to the "complete" directory, or by appending its ID to the completion journal
+// 3. Finalization is made of two steps:
+//    - "prepare" ensures all files were transferred and generates a commit token, after which no
+//      file can be transferred anymore
+//    - "commit" creates the new directories and moves every complete file to its final location
+//    As the prepared state is persisted and committing is idempotent, a failed commit can be retried
+//
+// Transfers never touch the slot's content directory, which makes finalization the only point
+// where new content is committed, under an exclusive lock on the slot.
+//
+// The synchronization's state is persisted in its directory, so it survives server restarts and
+// isn't tied to the client which opened it: any client authenticated with the same device name can
+// call `/sync/resume` (which generates a new synchronization token) to get the files remaining to
+// transfer, as long as its own source directory contains them at the same relative paths.
+//
+// Multiple synchronizations can be open on the same slot, as long as the items they touch don't
+// overlap. As each one gets its own directory, they are transferred and finalized independently.
 pub struct OpenSync {
     pub id: SyncId,
     pub token: String,
+    pub opened_by: AuthenticatedDevice,
+    // Provided by the client when beginning the synchronization (see `/sync/is-open`)
+    pub opening_key: Option<String>,
     pub diff: Diff,
     pub diff_ops: DiffApplyOps,
     pub files: HashMap<String, (String, SnapshotFileMetadata)>,
+    pub delta_files: HashSet<String>,
+    // Set once the synchronization is prepared for commit
+    pub commit_token: Option<String>,
+    pub opened_at: SystemTime,
+    pub completion_tracking: CompletionTracking,
+    // IDs of the files recorded in the completion journal
+    journal: Mutex<HashSet<String>>,
+    last_activity: Mutex<SystemTime>,
+    // Released when the synchronization is closed
+    _permit: Option<OwnedSemaphorePermit>,
 }
 
 impl OpenSync {
-    pub fn new(diff: Diff) -> HttpResult<Self> {
+    pub fn new(
+        diff: Diff,
+        opened_by: AuthenticatedDevice,
+        opening_key: Option<String>,
+        completion_tracking: CompletionTracking,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> HttpResult<Self> {
         let diff_ops = diff.ops();
 
+        for (relative_path, target) in diff_ops
+            .create_hardlinks
+            .iter()
+            .chain(diff_ops.move_dirs.iter())
+        {
+            for path in [relative_path, target] {
+                if is_relative_linear_path(Path::new(path)) {
+                    throw_err!(
+                        BAD_REQUEST,
+                        format!(
+                            "Path is trying to escape or contains '.' / '..' components: {path}"
+                        )
+                    );
+                }
+            }
+        }
+
+        for relative_path in diff_ops
+            .touch_files
+            .iter()
+            .map(|(path, _)| path)
+            .chain(diff_ops.replace_dirs.iter())
+            .chain(diff_ops.set_acls.iter().map(|(path, _)| path))
+            .chain(diff_ops.set_xattrs.iter().map(|(path, _)| path))
+        {
+            if is_relative_linear_path(Path::new(relative_path)) {
+                throw_err!(
+                    BAD_REQUEST,
+                    format!("Path is trying to escape or contains '.' / '..' components: {relative_path}")
+                );
+            }
+        }
+
+        let files = diff_ops
+            .send_files
+            .into_iter()
+            .map(|(relative_path, mt)| {
+                if is_relative_linear_path(Path::new(&relative_path)) {
+                    throw_err!(BAD_REQUEST, format!("Path is trying to escape or contains '.' / '..' components: {relative_path}"));
+                }
+
+                Ok((relative_path.clone(), (file_transfer_id(&relative_path), mt)))
+            })
+            .collect::<Result<HashMap<_, _>, _>>()?;
+
+        // Files are stored under their ID during the transfer, so two of them sharing one would overwrite each other
+        let mut ids = HashSet::with_capacity(files.len());
+
+        if let Some((relative_path, _)) = files.iter().find(|(_, (id, _))| !ids.insert(id)) {
+            throw_err!(
+                INTERNAL_SERVER_ERROR,
+                format!("Transfer ID of file '{relative_path}' collides with another file's one")
+            );
+        }
+
         Ok(Self {
             id: SyncId(thread_rng().gen()),
             token: generate_id(),
-            files: diff_ops
-                .send_files
-                .into_iter()
-                .map(|(relative_path, mt)| {
-                    if is_relative_linear_path(Path::new(&relative_path)) {
-                        throw_err!(BAD_REQUEST, format!("Path is trying to escape or contains '.' / '..' components: {relative_path}"));
-                    }
-
-                    Ok((relative_path, (generate_id(), mt)))
-                })
-                .collect::<Result<_, _>>()?,
+            opened_by,
+            opening_key,
+            files,
+            delta_files: diff_ops.delta_files.into_iter().collect(),
             diff_ops: diff.ops(),
             diff,
+            commit_token: None,
+            opened_at: SystemTime::now(),
+            completion_tracking,
+            journal: Mutex::new(HashSet::new()),
+            last_activity: Mutex::new(SystemTime::now()),
+            _permit: permit,
+        })
+    }
+
+    pub fn touch(&self) {
+        *self.last_activity.lock().unwrap() = SystemTime::now();
+    }
+
+    pub fn inactive_for(&self) -> Duration {
+        self.last_activity
+            .lock()
+            .unwrap()
+            .elapsed()
+            .unwrap_or_default()
+    }
+
+    pub async fn save(&self, path: &Path) -> Result<()> {
+        let persisted = PersistedOpenSync {
+            id: self.id,
+            opened_by: self.opened_by.clone(),
+            opening_key: self.opening_key.as_deref(),
+            diff: &self.diff,
+            files: &self.files,
+            commit_token: self.commit_token.as_deref(),
+            opened_at: self.opened_at,
+            completion_tracking: self.completion_tracking,
+        };
+
+        let json = serde_json::to_string(&persisted)
+            .context("Failed to serialize the synchronization's state")?;
+
+        fs::write(path, json)
+            .await
+            .context("Failed to write the synchronization's state")
+    }
+
+    async fn load(
+        path: &Path,
+        paths: &Paths,
+        slot: &SlotInfos,
+        permit: Option<OwnedSemaphorePermit>,
+    ) -> Result<Self> {
+        let json = fs::read_to_string(path).await.with_context(|| {
+            format!(
+                "Failed to read synchronization state at '{}'",
+                path.display()
+            )
+        })?;
+
+        let PersistedOpenSync {
+            id,
+            opened_by,
+            opening_key,
+            diff,
+            files,
+            commit_token,
+            opened_at,
+            completion_tracking,
+        } = serde_json::from_str::<PersistedOpenSync<Diff, _, String>>(&json).with_context(
+            || {
+                format!(
+                    "Failed to parse synchronization state at '{}'",
+                    path.display()
+                )
+            },
+        )?;
+
+        let journal_file = paths.slot_completion_journal_file(slot, id);
+
+        // Lines may be truncated if the server stopped while writing them, in which case they just
+        // don't match any file, which will be transferred again
+        let journal = if journal_file.is_file() {
+            fs::read_to_string(&journal_file)
+                .await
+                .context("Failed to read the completion journal")?
+                .lines()
+                .map(str::to_owned)
+                .collect()
+        } else {
+            HashSet::new()
+        };
+
+        let diff_ops = diff.ops();
+
+        Ok(Self {
+            id,
+            // A new token will be generated when the synchronization is resumed
+            token: generate_id(),
+            opened_by,
+            opening_key,
+            files,
+            delta_files: diff_ops.delta_files.iter().cloned().collect(),
+            diff_ops,
+            diff,
+            commit_token,
+            opened_at,
+            completion_tracking,
+            journal: Mutex::new(journal),
+            last_activity: Mutex::new(SystemTime::now()),
+            _permit: permit,
         })
     }
 
+    // Where a file is kept once fully transferred, until the synchronization is committed
+    pub fn staged_path(&self, paths: &Paths, slot: &SlotInfos, file_id: &str) -> PathBuf {
+        match self.completion_tracking {
+            CompletionTracking::Dir => paths.slot_completion_dir(slot, self.id).join(file_id),
+            CompletionTracking::Journal => paths.slot_pending_dir(slot, self.id).join(file_id),
+        }
+    }
+
+    pub fn is_completed(&self, paths: &Paths, slot: &SlotInfos, file_id: &str) -> bool {
+        match self.completion_tracking {
+            CompletionTracking::Dir => self.staged_path(paths, slot, file_id).is_file(),
+            CompletionTracking::Journal => self.journal.lock().unwrap().contains(file_id),
+        }
+    }
+
+    // Mark a file written to the pending directory as fully transferred
+    pub async fn mark_completed(
+        &self,
+        paths: &Paths,
+        slot: &SlotInfos,
+        file_id: &str,
+        durable: bool,
+    ) -> Result<()> {
+        match self.completion_tracking {
+            CompletionTracking::Dir => {
+                let staged_path = self.staged_path(paths, slot, file_id);
+
+                fs::rename(
+                    paths.slot_pending_dir(slot, self.id).join(file_id),
+                    &staged_path,
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to move complete file to '{}'",
+                        staged_path.display()
+                    )
+                })
+            }
+
+            CompletionTracking::Journal => {
+                let mut journal = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(paths.slot_completion_journal_file(slot, self.id))
+                    .await
+                    .context("Failed to open the completion journal")?;
+
+                // Written at once so concurrent transfers can't interleave their entries
+                journal
+                    .write_all(format!("{file_id}\n").as_bytes())
+                    .await
+                    .context("Failed to write to the completion journal")?;
+
+                if durable {
+                    journal
+                        .sync_data()
+                        .await
+                        .context("Failed to flush the completion journal to the disk")?;
+                }
+
+                self.journal.lock().unwrap().insert(file_id.to_owned());
+
+                Ok(())
+            }
+        }
+    }
+
+    // Remove a file from the synchronization, along with the files which were to be hard linked to it
+    // Returns the file's transfer ID and size, and the paths of all the removed files
+    pub fn abort_file(&mut self, relative_path: &str) -> HttpResult<(String, u64, Vec<String>)> {
+        let Some((id, mt)) = self.files.remove(relative_path) else {
+            throw_err!(
+                BAD_REQUEST,
+                "Provided file was not found in the current synchronization process"
+            );
+        };
+
+        let aborted = std::iter::once(relative_path.to_owned())
+            .chain(
+                self.diff
+                    .hardlinks
+                    .iter()
+                    .filter(|(_, target)| target == relative_path)
+                    .map(|(path, _)| path.clone()),
+            )
+            .collect::<HashSet<_>>();
+
+        let Diff {
+            version: _,
+            added,
+            modified,
+            type_changed,
+            deleted: _,
+            hardlinks,
+            touched,
+            acls,
+            xattrs,
+        } = &mut self.diff;
+
+        added.retain(|(path, _)| !aborted.contains(path));
+        modified.retain(|(path, _)| !aborted.contains(path));
+        type_changed.retain(|(path, _)| !aborted.contains(path));
+        hardlinks.retain(|(path, _)| !aborted.contains(path));
+        touched.retain(|path| !aborted.contains(path));
+        acls.retain(|(path, _)| !aborted.contains(path));
+        xattrs.retain(|(path, _)| !aborted.contains(path));
+
+        self.diff_ops = self.diff.ops();
+        self.delta_files = self.diff_ops.delta_files.iter().cloned().collect();
+
+        Ok((id, mt.size, aborted.into_iter().collect()))
+    }
+
+    // Every item the synchronization deletes, moves, creates or updates
+    // The directories created to hold new files are not included, as they may be shared with other synchronizations
+    pub fn touched_paths(&self) -> Vec<&str> {
+        let DiffApplyOps {
+            create_dirs: _,
+            delete_files,
+            delete_empty_dirs,
+            replace_dirs,
+            move_dirs,
+            send_files: _,
+            delta_files: _,
+            create_hardlinks,
+            touch_files,
+            set_acls,
+            set_xattrs,
+        } = &self.diff_ops;
+
+        self.files
+            .keys()
+            .chain(delete_files)
+            .chain(delete_empty_dirs)
+            .chain(replace_dirs)
+            .chain(move_dirs.iter().flat_map(|(from, to)| [from, to]))
+            .chain(
+                create_hardlinks
+                    .iter()
+                    .flat_map(|(path, target)| [path, target]),
+            )
+            .chain(touch_files.iter().map(|(path, _)| path))
+            .chain(set_acls.iter().map(|(path, _)| path))
+            .chain(set_xattrs.iter().map(|(path, _)| path))
+            .map(String::as_str)
+            .collect()
+    }
+
     pub fn regenerate_access_token(&mut self) -> String {
         let id = generate_id();
         self.token = id.clone();
         id
     }
 }
+
+// Files are identified by a hash of their path during transfers, so the same file always gets the same ID,
+// whether the synchronization is resumed or opened again, and its transfer files can be traced back to it
+// Synchronizations restored from disk keep the IDs they were persisted with
+fn file_transfer_id(relative_path: &str) -> String {
+    let hash = blake3::hash(relative_path.as_bytes()).to_hex();
+
+    // 128 bits are more than enough to avoid accidental collisions
+    hash[..32].to_owned()
+}
+
+// Generic so it can be serialized from references to an existing synchronization
+#[derive(Serialize, Deserialize)]
+struct PersistedOpenSync<D, F, C> {
+    id: SyncId,
+    opened_by: AuthenticatedDevice,
+    #[serde(default)]
+    opening_key: Option<C>,
+    diff: D,
+    files: F,
+    #[serde(default)]
+    commit_token: Option<C>,
+    // Synchronizations persisted by older versions are considered as opened when restored
+    #[serde(default = "SystemTime::now")]
+    opened_at: SystemTime,
+    #[serde(default = "default_completion_tracking")]
+    completion_tracking: CompletionTracking,
+}
+
+// Synchronizations persisted by older versions always used a completion directory
+fn default_completion_tracking() -> CompletionTracking {
+    CompletionTracking::Dir
+}
@@ -0,0 +1,557 @@
+#![forbid(unsafe_code)]
+#![forbid(unused_must_use)]
+#![warn(unused_crate_dependencies)]
+
+use self::cmd::Args;
+use anyhow::{bail, Context, Result};
+use axum::Router;
+use colored::Colorize;
+use data::{
+    hash_secret, is_valid_secret_hash, verify_secret, AppData, SlotLink, MIN_ACCESS_TOKEN_LENGTH,
+};
+use fs2::FileExt;
+use harmony_differ::snapshot::HARMONY_ITEMS_PREFIX;
+use log::{info, warn};
+use paths::{Paths, SlotInfos};
+use std::{
+    net::SocketAddr,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+// Vendor OpenSSL inside the binary to avoid dependencies problem
+use openssl as _;
+
+// Only used by the binary
+use env_logger as _;
+
+mod audit;
+mod case;
+pub mod cmd;
+mod data;
+mod dedup;
+mod hashes;
+mod history;
+mod hooks;
+mod http;
+mod paths;
+mod snapshot_cache;
+
+// A server ready to handle requests, which can listen on the network or be driven in-process
+pub struct Server {
+    router: Router,
+    addr: SocketAddr,
+    // Held until the server stops, as two servers using the same data directory would overwrite each other's state
+    _lock: std::fs::File,
+}
+
+impl Server {
+    pub fn router(&self) -> Router {
+        self.router.clone()
+    }
+
+    pub async fn listen(self) -> Result<()> {
+        http::listen(self.router, self.addr).await
+    }
+}
+
+pub async fn setup(args: Args) -> Result<Server> {
+    let Args {
+        data_dir,
+        mut backup_args,
+        http_args,
+        logging_level: _,
+    } = args;
+
+    if !data_dir.is_dir() {
+        bail!("Provided data directory does not exist");
+    }
+
+    if let Some(transfer_dir) = &backup_args.transfer_dir {
+        if !transfer_dir.is_dir() {
+            bail!("Provided transfer directory does not exist");
+        }
+    }
+
+    let paths = Paths::new(data_dir.clone(), backup_args.transfer_dir.clone());
+
+    let lock = lock_data_dir(&paths.lock_file())?;
+
+    let app_data_file = paths.app_data_file();
+
+    let mut app_data = if app_data_file.exists() {
+        AppData::load(&app_data_file).await?
+    } else {
+        AppData::empty()
+    };
+
+    // The secret password is only kept hashed, so it isn't held in plain text once the server started
+    let secret = backup_args.secret.take();
+    let secret_hash = backup_args.secret_hash.take();
+
+    if let Some(secret_hash) = &secret_hash {
+        if !is_valid_secret_hash(secret_hash) {
+            bail!("Provided secret password hash is not a valid Argon2 hash");
+        }
+    }
+
+    if apply_secret(&mut app_data, secret, secret_hash)? {
+        app_data.save(&app_data_file).await?;
+    }
+
+    if backup_args.access_token_length < MIN_ACCESS_TOKEN_LENGTH {
+        bail!("Access tokens must be at least {MIN_ACCESS_TOKEN_LENGTH} characters long");
+    }
+
+    if backup_args.max_tokens_per_device == 0 {
+        bail!("Devices must be allowed to have at least one access token");
+    }
+
+    if backup_args.finalize_concurrency == 0 {
+        bail!("Finalizations must be allowed to run at least one operation at a time");
+    }
+
+    if backup_args
+        .max_deletion_percent
+        .is_some_and(|percent| !(0.0..=100.0).contains(&percent))
+    {
+        bail!("Maximum deletion percentage must be between 0 and 100");
+    }
+
+    if backup_args.dedup && !cfg!(unix) {
+        bail!("Deduplication is only supported on Unix-like systems");
+    }
+
+    if backup_args.slots.is_empty() {
+        bail!("Please provide at least one backup slot");
+    }
+
+    // Directories the server stores its own files in, which must not be part of any slot's content
+    let mut server_dirs = vec![(
+        "data directory",
+        fs::canonicalize(&data_dir)
+            .await
+            .context("Failed to canonicalize the data directory")?,
+    )];
+
+    if let Some(transfer_dir) = &backup_args.transfer_dir {
+        server_dirs.push((
+            "transfer directory",
+            fs::canonicalize(transfer_dir)
+                .await
+                .context("Failed to canonicalize the transfer directory")?,
+        ));
+    }
+
+    let mut slots_files_dir = Vec::<(&str, PathBuf)>::with_capacity(backup_args.slots.len());
+
+    for slot in &backup_args.slots {
+        let slot_dir = paths.slot_root_dir(slot);
+
+        if !slot_dir.is_dir() {
+            fs::create_dir_all(&slot_dir).await.with_context(|| {
+                format!(
+                    "Failed to create slot data directory at: {}",
+                    slot_dir.to_string_lossy().bright_magenta()
+                )
+            })?;
+        }
+
+        let slot_transfers_dir = paths.slot_transfers_root_dir(slot);
+
+        if !slot_transfers_dir.is_dir() {
+            fs::create_dir_all(&slot_transfers_dir)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to create slot transfers directory at: {}",
+                        slot_transfers_dir.to_string_lossy().bright_magenta()
+                    )
+                })?;
+        }
+
+        let slot_files_dir = paths.slot_content_dir(slot);
+
+        match slot.linked() {
+            Some(linked_dir) => check_linked_dir(linked_dir).await.with_context(|| {
+                format!(
+                    "Invalid linked directory ({}) for slot '{}'",
+                    linked_dir.to_string_lossy().bright_magenta(),
+                    slot.name().bright_blue()
+                )
+            })?,
+
+            None => {
+                if !slot_files_dir.is_dir() {
+                    fs::create_dir_all(&slot_files_dir).await.with_context(|| {
+                        format!(
+                            "Failed to create slot content directory at: {}",
+                            slot_files_dir.to_string_lossy().bright_magenta()
+                        )
+                    })?;
+                }
+            }
+        }
+
+        reconcile_slot_link(&paths, slot, backup_args.keep_relinked_syncs)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to update the linked directory of slot '{}'",
+                    slot.name().bright_blue()
+                )
+            })?;
+
+        let canonical_files_dir = fs::canonicalize(&slot_files_dir).await.with_context(|| {
+            format!(
+                "Failed to canonicalize slot content directory at: {}",
+                slot_files_dir.to_string_lossy().bright_magenta()
+            )
+        })?;
+
+        // Slots must not share any content, otherwise synchronizing one would alter the other
+        for (other_slot, other_files_dir) in &slots_files_dir {
+            if canonical_files_dir.starts_with(other_files_dir)
+                || other_files_dir.starts_with(&canonical_files_dir)
+            {
+                bail!(
+                    "Content directories of slots '{}' and '{}' overlap ({} and {})",
+                    other_slot.bright_blue(),
+                    slot.name().bright_blue(),
+                    other_files_dir.to_string_lossy().bright_magenta(),
+                    canonical_files_dir.to_string_lossy().bright_magenta()
+                );
+            }
+        }
+
+        // Snapshots of the slot would include the server's state, and synchronizations could delete it
+        for (server_dir_name, server_dir) in &server_dirs {
+            if server_dir.starts_with(&canonical_files_dir) {
+                bail!(
+                    "Content directory of slot '{}' ({}) contains the server's {server_dir_name} ({})",
+                    slot.name().bright_blue(),
+                    canonical_files_dir.to_string_lossy().bright_magenta(),
+                    server_dir.to_string_lossy().bright_magenta()
+                );
+            }
+        }
+
+        slots_files_dir.push((slot.name(), canonical_files_dir));
+
+        info!("Slot {} is ready", slot.name().bright_blue());
+    }
+
+    let addr = SocketAddr::from((http_args.addr, http_args.port));
+    let router = http::build_router(http_args, backup_args, app_data, paths).await?;
+
+    Ok(Server {
+        router,
+        addr,
+        _lock: lock,
+    })
+}
+
+// Store the secret password provided at startup, returns whether the application's data changed
+// A secret password differing from the stored one replaces it (e.g. after it leaked), which revokes
+// all access so every device has to use the new one
+fn apply_secret(
+    app_data: &mut AppData,
+    secret: Option<String>,
+    secret_hash: Option<String>,
+) -> Result<bool> {
+    let new_hash = match (app_data.secret_hash(), secret, secret_hash) {
+        (Some(_), None, None) => return Ok(false),
+
+        (None, None, None) => {
+            bail!("Please provide the secret password with '--secret' (or its hash with '--secret-hash')")
+        }
+
+        (Some(stored_hash), Some(secret), _) if verify_secret(stored_hash, &secret) => {
+            return Ok(false)
+        }
+
+        (Some(stored_hash), None, Some(secret_hash)) if secret_hash == stored_hash => {
+            return Ok(false)
+        }
+
+        (_, Some(secret), _) => hash_secret(&secret)?,
+        (_, None, Some(secret_hash)) => secret_hash,
+    };
+
+    if app_data.secret_hash().is_some() {
+        let (tokens, keys) = app_data.revoke_all_access();
+
+        warn!("!!! Secret password was changed with '--secret' or '--secret-hash', revoked {tokens} access token(s) and {keys} device key(s) !!!");
+    }
+
+    app_data.set_secret_hash(new_hash);
+
+    Ok(true)
+}
+
+// The lock is released when the returned file is closed, which the OS also does if the server is killed
+fn lock_data_dir(lock_file: &Path) -> Result<std::fs::File> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(lock_file)
+        .context("Failed to open the data directory's lock file")?;
+
+    if let Err(err) = file.try_lock_exclusive() {
+        if err.raw_os_error() == fs2::lock_contended_error().raw_os_error() {
+            bail!(
+                "Data directory is already used by another server instance (lock file: {})",
+                lock_file.to_string_lossy().bright_magenta()
+            );
+        }
+
+        return Err(err).context("Failed to lock the data directory");
+    }
+
+    Ok(file)
+}
+
+// Slots may be linked to another directory between two starts (e.g. after their content was moved),
+// in which case state computed from the previous directory's content must not be reused
+async fn reconcile_slot_link(paths: &Paths, slot: &SlotInfos, keep_open_syncs: bool) -> Result<()> {
+    let link_file = paths.slot_link_file(slot);
+
+    let link = SlotLink {
+        linked: slot.linked().map(Path::to_owned),
+    };
+
+    if link_file.is_file() {
+        let prev = SlotLink::load(&link_file).await?;
+
+        if prev == link {
+            return Ok(());
+        }
+
+        let mut entries = fs::read_dir(paths.slot_transfers_root_dir(slot))
+            .await
+            .context("Failed to read the slot's transfers directory")?;
+
+        let mut open_syncs = 0;
+
+        while let Some(entry) = entries
+            .next_entry()
+            .await
+            .context("Failed to read the slot's transfers directory")?
+        {
+            if entry
+                .file_name()
+                .to_string_lossy()
+                .starts_with("open-sync-")
+            {
+                open_syncs += 1;
+            }
+        }
+
+        // Open synchronizations were computed against the previous directory's content
+        if open_syncs > 0 && !keep_open_syncs {
+            bail!(
+                "Slot has {open_syncs} open synchronization(s), which must be finalized or aborted with the previous linked directory first (or use '--keep-relinked-syncs' if its content was moved as-is)"
+            );
+        }
+
+        info!(
+            "Slot {} was relinked from {} to {}",
+            slot.name().bright_blue(),
+            display_link(&prev).bright_magenta(),
+            display_link(&link).bright_magenta()
+        );
+
+        let hash_cache_file = paths.slot_hash_cache_file(slot);
+
+        if hash_cache_file.is_file() {
+            fs::remove_file(&hash_cache_file)
+                .await
+                .context("Failed to remove the slot's hash cache")?;
+        }
+
+        let snapshot_cache_file = paths.slot_snapshot_cache_file(slot);
+
+        if snapshot_cache_file.is_file() {
+            fs::remove_file(&snapshot_cache_file)
+                .await
+                .context("Failed to remove the slot's snapshot cache")?;
+        }
+
+        if prev.linked.is_some() && link.linked.is_none() {
+            warn!(
+                "Slot {} now stores its content in its data directory, content of the previous linked directory was left in place",
+                slot.name().bright_blue()
+            );
+        }
+    }
+
+    link.save(&link_file).await
+}
+
+fn display_link(link: &SlotLink) -> String {
+    match &link.linked {
+        Some(linked) => linked.to_string_lossy().into_owned(),
+        None => "the slot's data directory".to_owned(),
+    }
+}
+
+async fn check_linked_dir(linked_dir: &Path) -> Result<()> {
+    if !linked_dir.exists() {
+        bail!("Directory does not exist");
+    }
+
+    if !linked_dir.is_dir() {
+        bail!("Path is not a directory");
+    }
+
+    let probe_file = linked_dir.join(format!("{HARMONY_ITEMS_PREFIX}write-check"));
+
+    fs::write(&probe_file, [])
+        .await
+        .context("Directory is not writable")?;
+
+    fs::remove_file(&probe_file)
+        .await
+        .context("Failed to remove write check file")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, sync::OnceLock};
+
+    use anyhow::Result;
+    use clap::Parser;
+    use tempfile::TempDir;
+
+    use crate::{
+        cmd::Args,
+        data::{hash_secret, verify_secret, AppData},
+    };
+
+    use super::{apply_secret, setup, Server};
+
+    // Hashing is slow, so the same hash is used by all servers
+    fn secret_hash() -> &'static str {
+        static SECRET_HASH: OnceLock<String> = OnceLock::new();
+        SECRET_HASH.get_or_init(|| hash_secret("pw").unwrap())
+    }
+
+    async fn start(data_dir: &Path, slots: &[String]) -> Result<Server> {
+        let mut args = vec![
+            "harmony-server".to_owned(),
+            data_dir.to_string_lossy().into_owned(),
+            "--secret-hash".to_owned(),
+            secret_hash().to_owned(),
+        ];
+
+        for slot in slots {
+            args.extend(["--slots".to_owned(), slot.clone()]);
+        }
+
+        setup(Args::parse_from(args)).await
+    }
+
+    fn linked(name: &str, dir: &Path) -> String {
+        format!("{name}:{}", dir.display())
+    }
+
+    async fn start_err(data_dir: &Path, slots: &[String]) -> String {
+        format!("{:?}", start(data_dir, slots).await.err().unwrap())
+    }
+
+    #[tokio::test]
+    async fn refuses_overlapping_slots() {
+        let data_dir = TempDir::new().unwrap();
+        let content_dir = TempDir::new().unwrap();
+        let nested_dir = content_dir.path().join("nested");
+        fs::create_dir(&nested_dir).unwrap();
+
+        let a = linked("a", content_dir.path());
+        let b = linked("b", &nested_dir);
+
+        // Between two slots, whatever their order is
+        for slots in [
+            [a.clone(), b.clone()],
+            [b, a.clone()],
+            [a.clone(), linked("b", content_dir.path())],
+        ] {
+            let err = start_err(data_dir.path(), &slots).await;
+            assert!(err.contains("overlap"), "{err}");
+        }
+
+        // With the server's own directories, whether the slot contains them or is inside them
+        let err = start_err(data_dir.path(), &[linked("a", data_dir.path())]).await;
+        assert!(err.contains("data directory"), "{err}");
+
+        let data_dir_in_slot = content_dir.path().join("data");
+        fs::create_dir(&data_dir_in_slot).unwrap();
+
+        let err = start_err(&data_dir_in_slot, &[a]).await;
+        assert!(err.contains("data directory"), "{err}");
+
+        // Slots stored in the data directory don't overlap
+        start(data_dir.path(), &["a".to_owned(), "b".to_owned()])
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn refuses_a_second_server_on_the_same_data_dir() {
+        let data_dir = TempDir::new().unwrap();
+        let slots = ["s1".to_owned()];
+
+        let server = start(data_dir.path(), &slots).await.unwrap();
+
+        let err = start_err(data_dir.path(), &slots).await;
+        assert!(err.contains("already used by another server"), "{err}");
+
+        // The lock is released when the server stops
+        drop(server);
+        start(data_dir.path(), &slots).await.unwrap();
+    }
+
+    fn enrolled_app_data(secret: &str) -> AppData {
+        let mut app_data = AppData::empty();
+        app_data.set_secret_hash(hash_secret(secret).unwrap());
+        app_data.create_access_token("device".to_owned(), 32, 1);
+        app_data
+    }
+
+    #[test]
+    fn secret_is_required_on_first_start() {
+        let mut app_data = AppData::empty();
+
+        assert!(apply_secret(&mut app_data, None, None).is_err());
+        assert!(apply_secret(&mut app_data, Some("pw".to_owned()), None).unwrap());
+        assert!(verify_secret(app_data.secret_hash().unwrap(), "pw"));
+    }
+
+    #[test]
+    fn same_secret_keeps_access() {
+        let mut app_data = enrolled_app_data("pw");
+        let stored_hash = app_data.secret_hash().unwrap().to_owned();
+
+        assert!(!apply_secret(&mut app_data, None, None).unwrap());
+        assert!(!apply_secret(&mut app_data, Some("pw".to_owned()), None).unwrap());
+        assert!(!apply_secret(&mut app_data, None, Some(stored_hash)).unwrap());
+        assert_eq!(app_data.revoke_all_access(), (1, 0));
+    }
+
+    #[test]
+    fn different_secret_replaces_stored_one() {
+        let mut app_data = enrolled_app_data("pw");
+
+        assert!(apply_secret(&mut app_data, Some("new".to_owned()), None).unwrap());
+        assert!(verify_secret(app_data.secret_hash().unwrap(), "new"));
+        assert!(!verify_secret(app_data.secret_hash().unwrap(), "pw"));
+        assert_eq!(app_data.revoke_all_access(), (0, 0));
+
+        let mut app_data = enrolled_app_data("pw");
+        let new_hash = hash_secret("other").unwrap();
+
+        assert!(apply_secret(&mut app_data, None, Some(new_hash.clone())).unwrap());
+        assert_eq!(app_data.secret_hash(), Some(new_hash.as_str()));
+        assert_eq!(app_data.revoke_all_access(), (0, 0));
+    }
+}
@@ -1,4 +1,5 @@
 use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
 
 use std::{
     path::{Path, PathBuf},
@@ -7,11 +8,15 @@ use std::{
 
 pub struct Paths {
     data_dir: PathBuf,
+    transfer_dir: Option<PathBuf>,
 }
 
 impl Paths {
-    pub fn new(data_dir: PathBuf) -> Self {
-        Self { data_dir }
+    pub fn new(data_dir: PathBuf, transfer_dir: Option<PathBuf>) -> Self {
+        Self {
+            data_dir,
+            transfer_dir,
+        }
     }
 
     // pub fn data_dir(&self) -> &Path {
@@ -22,21 +27,73 @@ impl Paths {
         self.data_dir.join("state.json")
     }
 
+    pub fn lock_file(&self) -> PathBuf {
+        self.data_dir.join(".harmony.lock")
+    }
+
+    pub fn objects_dir(&self) -> PathBuf {
+        self.data_dir.join("objects")
+    }
+
     pub fn slot_root_dir(&self, slot: &SlotInfos) -> PathBuf {
         self.data_dir.join("slots").join(slot.name())
     }
 
+    pub fn slot_audit_log_file(&self, slot: &SlotInfos) -> PathBuf {
+        self.slot_root_dir(slot).join("audit.log")
+    }
+
+    pub fn slot_encryption_file(&self, slot: &SlotInfos) -> PathBuf {
+        self.slot_root_dir(slot).join("encryption.json")
+    }
+
+    pub fn slot_ignore_rules_file(&self, slot: &SlotInfos) -> PathBuf {
+        self.slot_root_dir(slot).join("ignore.json")
+    }
+
+    pub fn slot_settings_file(&self, slot: &SlotInfos) -> PathBuf {
+        self.slot_root_dir(slot).join("settings.json")
+    }
+
+    pub fn slot_link_file(&self, slot: &SlotInfos) -> PathBuf {
+        self.slot_root_dir(slot).join("link.json")
+    }
+
+    pub fn slot_hash_cache_file(&self, slot: &SlotInfos) -> PathBuf {
+        self.slot_root_dir(slot).join("file-hashes.json")
+    }
+
+    pub fn slot_snapshot_cache_file(&self, slot: &SlotInfos) -> PathBuf {
+        self.slot_root_dir(slot).join("snapshot-cache.json")
+    }
+
+    pub fn slot_history_dir(&self, slot: &SlotInfos) -> PathBuf {
+        self.slot_root_dir(slot).join("history")
+    }
+
     pub fn slot_content_dir(&self, slot: &SlotInfos) -> PathBuf {
         slot.linked()
             .map(Path::to_owned)
             .unwrap_or_else(|| self.slot_root_dir(slot).join("content"))
     }
 
+    // Contains the directories of the slot's open synchronizations
+    pub fn slot_transfers_root_dir(&self, slot: &SlotInfos) -> PathBuf {
+        match &self.transfer_dir {
+            Some(transfer_dir) => transfer_dir.join(slot.name()),
+            None => self.slot_root_dir(slot),
+        }
+    }
+
     pub fn slot_transfer_dir(&self, slot: &SlotInfos, SyncId(sync_id): SyncId) -> PathBuf {
-        self.slot_root_dir(slot)
+        self.slot_transfers_root_dir(slot)
             .join(format!("open-sync-{sync_id:x}"))
     }
 
+    pub fn slot_open_sync_file(&self, slot: &SlotInfos, sync_id: SyncId) -> PathBuf {
+        self.slot_transfer_dir(slot, sync_id).join("sync.json")
+    }
+
     pub fn slot_completion_dir(&self, slot: &SlotInfos, sync_id: SyncId) -> PathBuf {
         self.slot_transfer_dir(slot, sync_id).join("complete")
     }
@@ -44,6 +101,11 @@ impl Paths {
     pub fn slot_pending_dir(&self, slot: &SlotInfos, sync_id: SyncId) -> PathBuf {
         self.slot_transfer_dir(slot, sync_id).join("pending")
     }
+
+    pub fn slot_completion_journal_file(&self, slot: &SlotInfos, sync_id: SyncId) -> PathBuf {
+        self.slot_transfer_dir(slot, sync_id)
+            .join("complete.journal")
+    }
 }
 
 pub fn is_relative_linear_path(path: &Path) -> bool {
@@ -113,9 +175,25 @@ impl FromStr for SlotInfos {
     }
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct SyncId(pub u64);
 
+impl std::fmt::Display for SyncId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:x}", self.0)
+    }
+}
+
+// How a synchronization keeps track of the files which were fully transferred
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum CompletionTracking {
+    // Complete files are moved to a dedicated directory
+    Dir,
+    // Complete files stay where they were written, and their ID is appended to a single journal file
+    Journal,
+}
+
 static FORBIDDEN_CHARS: &[char] = &[
     '/', '\\', '<', '>', ':', '"', '|', '?', '*', '\r', '\n', '\x00',
 ];
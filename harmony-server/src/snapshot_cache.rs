@@ -0,0 +1,337 @@
+use std::{
+    collections::{BTreeMap, HashSet},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use harmony_differ::{
+    diffing::DiffApplyOps,
+    snapshot::{
+        IgnoreMatcher, SnapshotItem, SnapshotItemMetadata, SnapshotOptions, SnapshotResult,
+    },
+};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+// Snapshot of a slot's content, kept up to date with the synchronizations applied to it so the slot
+// doesn't have to be walked through again every time
+//
+// Changes made to the slot's content outside of synchronizations can't be detected, which is why
+// clients can still request a full walk through the slot (which then replaces the cached snapshot).
+#[derive(Serialize, Deserialize)]
+pub struct CachedSnapshot {
+    options: SnapshotOptions,
+    // Options the excluded items were listed with, if they were
+    excluded_options: Option<SnapshotOptions>,
+    result: SnapshotResult,
+}
+
+impl CachedSnapshot {
+    pub fn new(
+        options: SnapshotOptions,
+        excluded_options: Option<SnapshotOptions>,
+        result: &SnapshotResult,
+    ) -> Self {
+        Self {
+            options,
+            excluded_options,
+            result: result.clone(),
+        }
+    }
+
+    // Items created by synchronizations can only be filtered by the ignore rules, so snapshots built
+    // with other filters (or with ACLs and extended attributes, which the server may fail to set) are never cached
+    pub fn is_cacheable(options: &SnapshotOptions) -> bool {
+        let SnapshotOptions {
+            ignore_paths: _,
+            ignore_names: _,
+            ignore_exts: _,
+            ignore_globs: _,
+            max_depth,
+            modified_since,
+            skip_errors: _,
+            strict_special_files: _,
+            follow_symlinks: _,
+            include_paths,
+            include_globs,
+            acls,
+            xattrs,
+        } = options;
+
+        max_depth.is_none()
+            && modified_since.is_none()
+            && include_paths.is_empty()
+            && include_globs.is_empty()
+            && !acls
+            && !xattrs
+    }
+
+    fn get(
+        &self,
+        options: &SnapshotOptions,
+        excluded_options: Option<&SnapshotOptions>,
+    ) -> Option<SnapshotResult> {
+        if &self.options != options {
+            return None;
+        }
+
+        match excluded_options {
+            None => Some(SnapshotResult {
+                excluded: vec![],
+                ..self.result.clone()
+            }),
+
+            Some(excluded_options) if self.excluded_options.as_ref() == Some(excluded_options) => {
+                Some(self.result.clone())
+            }
+
+            Some(_) => None,
+        }
+    }
+
+    // Apply the operations performed when a synchronization is opened (removals and moves)
+    pub fn apply_opening(&mut self, ops: &DiffApplyOps) -> Result<()> {
+        let DiffApplyOps {
+            create_dirs: _,
+            send_files: _,
+            delete_files,
+            delete_empty_dirs,
+            delta_files: _,
+            create_hardlinks: _,
+            move_dirs,
+            touch_files: _,
+            replace_dirs,
+            set_acls: _,
+            set_xattrs: _,
+        } = ops;
+
+        self.update(|items| {
+            let removed = replace_dirs
+                .iter()
+                .chain(delete_files)
+                .map(String::as_str)
+                .collect::<HashSet<_>>();
+
+            items.retain(|path, _| {
+                !Path::new(path).ancestors().any(|ancestor| {
+                    ancestor
+                        .to_str()
+                        .is_some_and(|ancestor| removed.contains(ancestor))
+                })
+            });
+
+            for (from, to) in move_dirs {
+                let moved = items
+                    .keys()
+                    .filter(|path| Path::new(path).starts_with(from))
+                    .cloned()
+                    .collect::<Vec<_>>();
+
+                for path in moved {
+                    let mut item = items.remove(&path).unwrap();
+                    item.relative_path = format!("{to}{}", &path[from.len()..]);
+                    items.insert(item.relative_path.clone(), item);
+                }
+
+                // The target's parents are created when the directory is moved
+                for parent in Path::new(to).ancestors().skip(1) {
+                    if let Some(parent) = parent.to_str().filter(|parent| !parent.is_empty()) {
+                        items
+                            .entry(parent.to_owned())
+                            .or_insert_with(|| new_item(parent, SnapshotItemMetadata::Directory));
+                    }
+                }
+            }
+
+            for path in delete_empty_dirs {
+                items.remove(path);
+            }
+        })
+    }
+
+    // Apply the operations performed when a synchronization is committed (creations and updates)
+    pub fn apply_commit(&mut self, ops: &DiffApplyOps) -> Result<()> {
+        let DiffApplyOps {
+            create_dirs,
+            send_files,
+            delete_files: _,
+            delete_empty_dirs: _,
+            delta_files: _,
+            create_hardlinks,
+            move_dirs: _,
+            touch_files,
+            replace_dirs: _,
+            set_acls: _,
+            set_xattrs: _,
+        } = ops;
+
+        self.update(|items| {
+            for path in create_dirs {
+                items
+                    .entry(path.clone())
+                    .or_insert_with(|| new_item(path, SnapshotItemMetadata::Directory));
+            }
+
+            for (path, mt) in send_files.iter().chain(touch_files) {
+                items.insert(
+                    path.clone(),
+                    new_item(path, SnapshotItemMetadata::File(*mt)),
+                );
+            }
+
+            // Hard links share their target's metadata
+            for (path, target) in create_hardlinks {
+                if let Some(target) = items.get(target) {
+                    let metadata = target.metadata;
+                    items.insert(path.clone(), new_item(path, metadata));
+                }
+            }
+        })
+    }
+
+    // Update the items, then sort them again depending on the ignore rules as new items may be ignored
+    fn update(&mut self, update: impl FnOnce(&mut BTreeMap<String, SnapshotItem>)) -> Result<()> {
+        let SnapshotResult {
+            snapshot,
+            warnings: _,
+            skipped: _,
+            options: _,
+            excluded,
+            dirs_mtime: _,
+        } = &mut self.result;
+
+        let mut items = std::mem::take(&mut snapshot.items)
+            .into_iter()
+            .chain(std::mem::take(excluded))
+            .map(|item| (item.relative_path.clone(), item))
+            .collect::<BTreeMap<_, _>>();
+
+        update(&mut items);
+
+        let matcher = self.options.ignore_matcher()?;
+
+        let excluded_matcher = self
+            .excluded_options
+            .as_ref()
+            .map(SnapshotOptions::ignore_matcher)
+            .transpose()?;
+
+        // Sorted by path, so directories come before their content like in a walk
+        for (_, item) in items {
+            if !is_ignored(&matcher, &item) {
+                snapshot.items.push(item);
+            } else if excluded_matcher
+                .as_ref()
+                .is_some_and(|excluded_matcher| !is_ignored(excluded_matcher, &item))
+            {
+                excluded.push(item);
+            }
+        }
+
+        Ok(())
+    }
+
+    pub async fn load(path: &Path) -> Result<Self> {
+        let json = fs::read_to_string(path)
+            .await
+            .context("Failed to read the snapshot cache file")?;
+
+        serde_json::from_str(&json).context("Failed to parse the snapshot cache file")
+    }
+
+    async fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string(self).context("Failed to serialize the snapshot cache")?;
+
+        fs::write(path, json)
+            .await
+            .context("Failed to write the snapshot cache file")
+    }
+}
+
+// Cached snapshot of a slot, along with a counter of the changes made to the slot's content
+// so snapshots built while it was changing aren't cached
+#[derive(Default)]
+pub struct SlotSnapshotCache {
+    cached: Option<CachedSnapshot>,
+    content_version: u64,
+}
+
+impl SlotSnapshotCache {
+    pub fn new(cached: Option<CachedSnapshot>) -> Self {
+        Self {
+            cached,
+            content_version: 0,
+        }
+    }
+
+    pub fn get(
+        &self,
+        options: &SnapshotOptions,
+        excluded_options: Option<&SnapshotOptions>,
+    ) -> Option<SnapshotResult> {
+        self.cached
+            .as_ref()
+            .and_then(|cached| cached.get(options, excluded_options))
+    }
+
+    pub fn content_version(&self) -> u64 {
+        self.content_version
+    }
+
+    // Must be called before changing the slot's content, the cache being stored again once it was updated
+    // The cache file is removed so it isn't used after a restart if the changes are interrupted
+    // Returns the cache along with the content version to store it again with
+    pub async fn take(&mut self, path: &Path) -> Result<Option<(CachedSnapshot, u64)>> {
+        self.content_version += 1;
+
+        let Some(cached) = self.cached.take() else {
+            return Ok(None);
+        };
+
+        if path.is_file() {
+            fs::remove_file(path)
+                .await
+                .context("Failed to remove the snapshot cache file")?;
+        }
+
+        Ok(Some((cached, self.content_version)))
+    }
+
+    // Only stores the cache if the slot's content didn't change since the provided version
+    pub async fn store(
+        &mut self,
+        path: &Path,
+        cached: CachedSnapshot,
+        content_version: u64,
+    ) -> Result<()> {
+        if content_version != self.content_version {
+            return Ok(());
+        }
+
+        let result = cached.save(path).await;
+
+        // The cache is still valid for as long as the server runs
+        self.cached = Some(cached);
+
+        result
+    }
+}
+
+fn new_item(relative_path: &str, metadata: SnapshotItemMetadata) -> SnapshotItem {
+    SnapshotItem {
+        relative_path: relative_path.to_owned(),
+        metadata,
+        hardlink: None,
+        sparse: false,
+        acl: None,
+        xattrs: None,
+    }
+}
+
+fn is_ignored(matcher: &IgnoreMatcher<'_>, item: &SnapshotItem) -> bool {
+    let relative_path = Path::new(&item.relative_path);
+
+    matcher.ignores_path(relative_path)
+        || (matches!(item.metadata, SnapshotItemMetadata::File(_))
+            && matcher.ignores_ext(relative_path))
+}